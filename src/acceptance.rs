@@ -0,0 +1,57 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Feedback signals per language that the length adapts to.
+const WINDOW: usize = 20;
+
+/// Signals needed before a language's completion length adapts.
+const MIN_SAMPLES: usize = 3;
+
+/// Completion lengths never shrink below this fraction of the configured one.
+pub const MIN_LENGTH_FACTOR: f32 = 0.25;
+
+/// Languages tracked at once; feedback for others is ignored.
+const MAX_LANGUAGES: usize = 64;
+
+/// How much of each suggestion the user kept, per language, from
+/// `snek/completion/feedback`. When users keep taking only the start of
+/// suggestions, `length_factor` tells the backend to ask for shorter ones.
+#[derive(Default)]
+pub struct AcceptanceTracker {
+    windows: Mutex<HashMap<String, VecDeque<f32>>>,
+}
+
+impl AcceptanceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that `accepted_chars` of a `completion_chars` long
+    /// suggestion were kept.
+    pub fn record(&self, language_id: &str, accepted_chars: usize, completion_chars: usize) {
+        if completion_chars == 0 {
+            return;
+        }
+
+        let mut windows = self.windows.lock().unwrap();
+        if !windows.contains_key(language_id) && windows.len() >= MAX_LANGUAGES {
+            return;
+        }
+        let window = windows.entry(language_id.to_string()).or_default();
+        if window.len() == WINDOW {
+            window.pop_front();
+        }
+        window.push_back(accepted_chars.min(completion_chars) as f32 / completion_chars as f32);
+    }
+
+    /// Fraction of the configured completion length to request for
+    /// `language_id`: the average share of recent suggestions that was
+    /// kept, at least `MIN_LENGTH_FACTOR`. `None` until there is enough
+    /// feedback, or when suggestions are kept whole.
+    pub fn length_factor(&self, language_id: &str) -> Option<f32> {
+        let windows = self.windows.lock().unwrap();
+        let window = windows.get(language_id).filter(|w| w.len() >= MIN_SAMPLES)?;
+        let kept = window.iter().sum::<f32>() / window.len() as f32;
+        (kept < 1.0).then(|| kept.max(MIN_LENGTH_FACTOR))
+    }
+}
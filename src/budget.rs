@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::model::extract_line_range;
+use crate::snapshot::ContextSnapshot;
+
+/// Outcome of `RequestBudget::spend`.
+#[derive(Debug, PartialEq)]
+pub enum Spend {
+    /// The request may go ahead; `used` counts it.
+    Allowed { used: u64 },
+    /// The session's cap is reached. `first` is set only for the first
+    /// refusal, so the user is told once.
+    Refused { first: bool },
+}
+
+struct SessionCount {
+    used: u64,
+    warned: bool,
+}
+
+/// Counts model requests against `limits.max_requests_per_session`, per
+/// session. A session's count starts from its persisted `requests_used` the
+/// first time it is seen; roots of a multi-root workspace each have their
+/// own.
+#[derive(Default)]
+pub struct RequestBudget {
+    sessions: Mutex<HashMap<String, SessionCount>>,
+}
+
+impl RequestBudget {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes one request from `session_id`'s budget of `max`. `persisted`
+    /// is the count stored with the session, used when it becomes active.
+    pub fn spend(&self, session_id: &str, persisted: u64, max: u64) -> Spend {
+        let mut sessions = self.sessions.lock().unwrap();
+        let count = sessions
+            .entry(session_id.to_string())
+            .or_insert(SessionCount {
+                used: persisted,
+                warned: false,
+            });
+
+        if count.used >= max {
+            let first = !count.warned;
+            count.warned = true;
+            return Spend::Refused { first };
+        }
+
+        count.used += 1;
+        Spend::Allowed { used: count.used }
+    }
+}
+
+/// Rough characters per token of English text and code, for estimates
+/// where a real tokenizer isn't worth the dependency.
+pub const CHARS_PER_TOKEN: usize = 4;
+
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BudgetItem {
+    /// Markdown file name, or snippet URI and line range.
+    pub name: String,
+    pub tokens: usize,
+}
+
+/// Estimated tokens each piece of session context adds to the prompt.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ContextBudget {
+    pub session_id: String,
+    pub markdown: Vec<BudgetItem>,
+    pub snippets: Vec<BudgetItem>,
+    pub total_tokens: usize,
+    /// The session's `limits.max_tokens`, for comparison.
+    pub max_tokens: usize,
+    pub exceeds_max_tokens: bool,
+}
+
+impl ContextBudget {
+    /// Items are sorted largest first, so the one blowing the budget leads.
+    pub fn for_snapshot(snapshot: &ContextSnapshot) -> Self {
+        let mut markdown: Vec<BudgetItem> = snapshot
+            .markdown_cache
+            .iter()
+            .map(|(name, content)| BudgetItem {
+                name: name.clone(),
+                tokens: estimate_tokens(content),
+            })
+            .collect();
+
+        let mut snippets: Vec<BudgetItem> = snapshot
+            .code_contexts
+            .iter()
+            .map(|snippet| {
+                let code = if snippet.code.is_empty() {
+                    snapshot.file_cache.get(&snippet.uri).and_then(|content| {
+                        extract_line_range(
+                            content,
+                            snippet.start_line as usize,
+                            snippet.end_line as usize,
+                        )
+                    })
+                } else {
+                    Some(snippet.code.as_str())
+                };
+                BudgetItem {
+                    name: format!("{}:{}-{}", snippet.uri, snippet.start_line, snippet.end_line),
+                    tokens: code.map_or(0, estimate_tokens),
+                }
+            })
+            .collect();
+
+        for items in [&mut markdown, &mut snippets] {
+            items.sort_by(|a, b| b.tokens.cmp(&a.tokens).then_with(|| a.name.cmp(&b.name)));
+        }
+
+        let total_tokens = markdown.iter().chain(&snippets).map(|item| item.tokens).sum();
+        let max_tokens = snapshot.limits.max_tokens;
+        Self {
+            session_id: snapshot.session_id.clone(),
+            markdown,
+            snippets,
+            total_tokens,
+            max_tokens,
+            exceeds_max_tokens: total_tokens > max_tokens,
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+enum State {
+    Closed { failures: usize },
+    /// Requests fail fast until `until`.
+    Open { until: Instant },
+    /// The cooldown is over; one request at a time probes the endpoint.
+    HalfOpen { probing: bool },
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: State,
+    threshold: usize,
+    cooldown: Duration,
+}
+
+/// Stops sending requests to an endpoint after `threshold` consecutive
+/// failures, failing fast for `cooldown` before letting one request probe
+/// whether it recovered. A `threshold` of 0 disables it.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    inner: Mutex<Inner>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                state: State::Closed { failures: 0 },
+                threshold: 0,
+                cooldown: Duration::ZERO,
+            }),
+        }
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn configure(&self, threshold: usize, cooldown: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.threshold = threshold;
+        inner.cooldown = cooldown;
+        if threshold == 0 {
+            inner.state = State::Closed { failures: 0 };
+        }
+    }
+
+    /// `Ok` if a request may go ahead, otherwise how long until the breaker
+    /// lets one through.
+    pub fn check(&self) -> Result<(), Duration> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until } => {
+                let now = Instant::now();
+                if now < until {
+                    return Err(until - now);
+                }
+                eprintln!("[SNEK] Circuit breaker half-open, probing the model endpoint");
+                inner.state = State::HalfOpen { probing: true };
+                Ok(())
+            }
+            State::HalfOpen { probing: true } => Err(Duration::ZERO),
+            State::HalfOpen { probing: false } => {
+                inner.state = State::HalfOpen { probing: true };
+                Ok(())
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if matches!(inner.state, State::HalfOpen { .. }) {
+            eprintln!("[SNEK] Model endpoint recovered, circuit breaker closed");
+        }
+        inner.state = State::Closed { failures: 0 };
+    }
+
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.threshold == 0 {
+            return;
+        }
+
+        let failures = match inner.state {
+            State::Closed { failures } => failures + 1,
+            // A failed probe reopens right away
+            State::HalfOpen { .. } => inner.threshold,
+            State::Open { .. } => return,
+        };
+        inner.state = if failures >= inner.threshold {
+            eprintln!(
+                "[SNEK] {} consecutive model failures, circuit breaker open for {}ms",
+                failures,
+                inner.cooldown.as_millis()
+            );
+            State::Open {
+                until: Instant::now() + inner.cooldown,
+            }
+        } else {
+            State::Closed { failures }
+        };
+    }
+
+    /// The request ended without telling whether the endpoint works (e.g.
+    /// it was cancelled); a probe it was making is given back.
+    pub fn record_abandoned(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if let State::HalfOpen { probing: true } = inner.state {
+            inner.state = State::HalfOpen { probing: false };
+        }
+    }
+}
@@ -0,0 +1,371 @@
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_CAPACITY: usize = 128;
+
+const DEFAULT_PERSISTENT_ENTRIES: usize = 512;
+
+const DEFAULT_PERSISTENT_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+const DEFAULT_PERSISTENT_SAVE_DELAY: Duration = Duration::from_secs(2);
+
+/// Identifies the exact context a completion was generated for. Cursor
+/// positions that produce the same prefix and suffix share a key.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CompletionKey {
+    uri: String,
+    version: u64,
+    prefix_hash: u64,
+    suffix_hash: u64,
+}
+
+impl CompletionKey {
+    pub fn new(uri: &str, version: u64, prefix: &str, suffix: &str) -> Self {
+        Self {
+            uri: uri.to_string(),
+            version,
+            prefix_hash: hash_str(prefix),
+            suffix_hash: hash_str(suffix),
+        }
+    }
+}
+
+fn hash_str(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Completions for unchanged documents, so moving the cursor without
+/// editing doesn't call the model again for the same context.
+pub struct CompletionCache {
+    capacity: usize,
+    entries: Mutex<HashMap<CompletionKey, String>>,
+}
+
+impl Default for CompletionCache {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+}
+
+impl CompletionCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, key: &CompletionKey) -> Option<String> {
+        self.entries.lock().unwrap().get(key).cloned()
+    }
+
+    pub fn insert(&self, key: CompletionKey, completion: String) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            // Entries only live until the next edit, so a full reset is cheap
+            entries.clear();
+        }
+        entries.insert(key, completion);
+    }
+
+    /// Drops every entry for `uri`; called when the document changes.
+    pub fn invalidate(&self, uri: &str) {
+        self.entries.lock().unwrap().retain(|key, _| key.uri != uri);
+    }
+
+    /// Drops every entry; called when the whole context is replaced.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// What a completion on disk was generated from. Unlike `CompletionKey`,
+/// this leaves out the document version, which restarts from zero with
+/// the server; the prefix and suffix pin the document's text instead.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PersistentKey {
+    hash: String,
+    session_id: String,
+    session_version: u64,
+    model: String,
+}
+
+impl PersistentKey {
+    pub fn new(
+        uri: &str,
+        prefix: &str,
+        suffix: &str,
+        session_id: &str,
+        session_version: u64,
+        model: &str,
+    ) -> Self {
+        Self {
+            hash: format!("{:016x}", fnv1a(&[uri, prefix, suffix])),
+            session_id: session_id.to_string(),
+            session_version,
+            model: model.to_string(),
+        }
+    }
+}
+
+/// 64-bit FNV-1a over each part's length and bytes. Unlike `DefaultHasher`
+/// its output is fixed, so keys written by one build match the next.
+fn fnv1a(parts: &[&str]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    let mut hash = OFFSET_BASIS;
+    for part in parts {
+        for byte in (part.len() as u64).to_le_bytes().iter().chain(part.as_bytes()) {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(PRIME);
+        }
+    }
+    hash
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct PersistedCompletion {
+    session_id: String,
+    session_version: u64,
+    model: String,
+    completion: String,
+    /// Seconds since the Unix epoch.
+    created_at: u64,
+}
+
+/// The cache file and its contents, shared with the task that writes it.
+struct CacheFile {
+    path: PathBuf,
+    entries: Mutex<HashMap<String, PersistedCompletion>>,
+    save_pending: AtomicBool,
+    /// Held while writing, so two saves never share the temp file.
+    saving: Mutex<()>,
+}
+
+impl CacheFile {
+    /// Writes the current entries if a save is pending.
+    fn flush(&self) {
+        let _saving = self.saving.lock().unwrap();
+        if !self.save_pending.swap(false, Ordering::AcqRel) {
+            return;
+        }
+        let entries = self.entries.lock().unwrap().clone();
+        if let Err(e) = self.save(&entries) {
+            eprintln!(
+                "[SNEK] Failed to write completion cache {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+
+    /// Writes a temp file and renames it over the cache, so a crash
+    /// mid-write never leaves a truncated file behind.
+    fn save(&self, entries: &HashMap<String, PersistedCompletion>) -> std::io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let json = serde_json::to_string(entries)?;
+        let temp = self.path.with_extension("json.tmp");
+        std::fs::write(&temp, json)?;
+        std::fs::rename(&temp, &self.path)
+    }
+}
+
+/// Completions kept in a JSON file (`.snek/cache/completions.json`) so the
+/// first requests after a restart don't all go to the model. Disabled until
+/// `configure` turns it on, which also loads the file. Entries made for
+/// another session version or model are never served.
+///
+/// Inserts are written `save_delay` later on a blocking thread, so a burst
+/// of completions costs one write and none of them wait for the disk.
+pub struct PersistentCompletionCache {
+    max_entries: usize,
+    ttl: Duration,
+    save_delay: Duration,
+    enabled: AtomicBool,
+    file: Arc<CacheFile>,
+}
+
+impl PersistentCompletionCache {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            max_entries: DEFAULT_PERSISTENT_ENTRIES,
+            ttl: DEFAULT_PERSISTENT_TTL,
+            save_delay: DEFAULT_PERSISTENT_SAVE_DELAY,
+            enabled: AtomicBool::new(false),
+            file: Arc::new(CacheFile {
+                path,
+                entries: Mutex::new(HashMap::new()),
+                save_pending: AtomicBool::new(false),
+                saving: Mutex::new(()),
+            }),
+        }
+    }
+
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries.max(1);
+        self
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn with_save_delay(mut self, save_delay: Duration) -> Self {
+        self.save_delay = save_delay;
+        self
+    }
+
+    pub fn configure(&self, enabled: bool) {
+        let was_enabled = self.enabled.swap(enabled, Ordering::Relaxed);
+        if enabled && !was_enabled {
+            self.load();
+        }
+    }
+
+    pub fn get(&self, key: &PersistentKey) -> Option<String> {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        let mut entries = self.file.entries.lock().unwrap();
+        let entry = entries.get(&key.hash)?;
+        if entry.session_id == key.session_id
+            && entry.session_version == key.session_version
+            && entry.model == key.model
+            && !self.expired(entry)
+        {
+            return Some(entry.completion.clone());
+        }
+
+        // Stale: the session or model moved on since it was written
+        entries.remove(&key.hash);
+        None
+    }
+
+    pub fn insert(&self, key: PersistentKey, completion: String) {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+
+        {
+            let mut entries = self.file.entries.lock().unwrap();
+            entries.insert(
+                key.hash,
+                PersistedCompletion {
+                    session_id: key.session_id,
+                    session_version: key.session_version,
+                    model: key.model,
+                    completion,
+                    created_at: now_secs(),
+                },
+            );
+            if entries.len() > self.max_entries {
+                let mut by_age: Vec<(u64, String)> = entries
+                    .iter()
+                    .map(|(hash, entry)| (entry.created_at, hash.clone()))
+                    .collect();
+                by_age.sort();
+                let excess = entries.len() - self.max_entries;
+                for (_, hash) in by_age.into_iter().take(excess) {
+                    entries.remove(&hash);
+                }
+            }
+        }
+
+        self.schedule_save();
+    }
+
+    /// Writes pending inserts now rather than after `save_delay`.
+    pub fn flush(&self) {
+        self.file.flush();
+    }
+
+    pub fn len(&self) -> usize {
+        self.file.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Starts a delayed save unless one is already waiting. Outside a Tokio
+    /// runtime there is nothing to run it on, so it saves right away.
+    fn schedule_save(&self) {
+        if self.file.save_pending.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            self.file.flush();
+            return;
+        };
+
+        let file = self.file.clone();
+        let delay = self.save_delay;
+        runtime.spawn(async move {
+            tokio::time::sleep(delay).await;
+            let _ = tokio::task::spawn_blocking(move || file.flush()).await;
+        });
+    }
+
+    fn load(&self) {
+        let Ok(content) = std::fs::read_to_string(&self.file.path) else {
+            return;
+        };
+        let loaded: HashMap<String, PersistedCompletion> = match serde_json::from_str(&content) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                eprintln!(
+                    "[SNEK] Ignoring unreadable completion cache {}: {}",
+                    self.file.path.display(),
+                    e
+                );
+                return;
+            }
+        };
+
+        let mut entries = self.file.entries.lock().unwrap();
+        entries.extend(loaded.into_iter().filter(|(_, entry)| !self.expired(entry)));
+        eprintln!("[SNEK] Loaded {} cached completions from disk", entries.len());
+    }
+
+    fn expired(&self, entry: &PersistedCompletion) -> bool {
+        now_secs().saturating_sub(entry.created_at) >= self.ttl.as_secs()
+    }
+}
+
+impl Drop for PersistentCompletionCache {
+    /// Saves inserts still waiting for their delayed write.
+    fn drop(&mut self) {
+        self.file.flush();
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
@@ -0,0 +1,433 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::error::{Result, SnekError};
+use crate::languages;
+use crate::model::{
+    FallbackModel, HttpOptions, PromptOptions, Provider, RetryOptions, SamplingOptions,
+};
+
+#[cfg(all(feature = "default-openai", feature = "default-ollama"))]
+compile_error!("enable at most one of the `default-openai` and `default-ollama` features");
+
+/// Endpoint and model used when neither the environment nor
+/// `.snek/config.json` name one. Picked at build time: Cerebras unless the
+/// `default-openai` or `default-ollama` feature is enabled.
+#[cfg(feature = "default-openai")]
+pub const DEFAULT_API_URL: &str = "https://api.openai.com/v1/chat/completions";
+#[cfg(feature = "default-openai")]
+pub const DEFAULT_MODEL: &str = "gpt-4o-mini";
+
+#[cfg(feature = "default-ollama")]
+pub const DEFAULT_API_URL: &str = "http://localhost:11434/v1/chat/completions";
+#[cfg(feature = "default-ollama")]
+pub const DEFAULT_MODEL: &str = "qwen2.5-coder:7b";
+
+#[cfg(not(any(feature = "default-openai", feature = "default-ollama")))]
+pub const DEFAULT_API_URL: &str = "https://api.cerebras.ai/v1/chat/completions";
+#[cfg(not(any(feature = "default-openai", feature = "default-ollama")))]
+pub const DEFAULT_MODEL: &str = "qwen-3-235b-a22b-instruct-2507";
+
+pub const DEFAULT_FULL_FILE_THRESHOLD: usize = 1000;
+
+/// Documents larger than this are only read around the cursor.
+pub const DEFAULT_MAX_DOCUMENT_BYTES: usize = 4 * 1024 * 1024;
+
+/// Completion settings read from the client's `snek` configuration section.
+/// Unknown or missing keys fall back to the defaults below.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Settings {
+    /// Minimum number of non-whitespace characters before the cursor
+    /// (across the whole document) required to request a completion.
+    pub min_prefix_chars: usize,
+    /// Milliseconds the document must go without edits before completing.
+    pub min_idle_ms: u64,
+    /// Only complete right after one of these characters, unless the client
+    /// marks the request as explicitly invoked. Empty means always.
+    pub trigger_characters: Vec<String>,
+    /// Upper bounds on an inserted completion; `None` means unlimited.
+    pub max_completion_lines: Option<usize>,
+    pub max_completion_chars: Option<usize>,
+    /// Cut completions in brace languages after the line that closes the
+    /// block the cursor is in.
+    pub stop_at_block_end: bool,
+    /// Start completions on a new line at the end of a finished statement,
+    /// and keep them on the cursor's line mid-line.
+    pub adjust_newlines: bool,
+    /// File holding the API key; takes precedence over `snek.apiKey`.
+    pub api_key_file: Option<String>,
+    /// Proxy for model requests; see `HttpOptions::with_env_fallback`.
+    pub proxy: Option<String>,
+    pub no_proxy: Option<String>,
+    /// PEM client certificate and PKCS#8 key for mutual TLS, and extra CAs
+    /// to trust; see `HttpOptions`.
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub ca_bundle: Option<String>,
+    /// Skip server certificate verification. Off unless explicitly enabled.
+    pub insecure_skip_tls_verify: bool,
+    /// API flavor of the endpoint; enables provider-specific features such
+    /// as prompt caching.
+    pub provider: Provider,
+    /// Model tried when the primary fails; URL and key default to the primary's.
+    pub fallback_model: Option<String>,
+    pub fallback_api_url: Option<String>,
+    pub fallback_api_key: Option<String>,
+    /// Only send code snippets whose language matches the file being completed.
+    pub match_snippet_language: bool,
+    /// Include outlines of sibling files such as `foo_test.rs` for `foo.rs`.
+    pub sibling_context: bool,
+    /// Sibling naming conventions using `{stem}` and `{ext}`; `None` uses
+    /// `siblings::DEFAULT_PATTERNS`.
+    pub sibling_patterns: Option<Vec<String>>,
+    pub max_siblings: usize,
+    /// Include the import/use/include lines of the other open documents.
+    pub open_buffer_imports: bool,
+    /// Mirror open documents to `.snek/cache/documents` so completions work
+    /// right after a client reconnects.
+    pub persist_documents: bool,
+    /// Only persist documents that live inside the workspace.
+    pub restrict_to_workspace: bool,
+    /// Keep completions in `.snek/cache/completions.json` across restarts.
+    pub persistent_cache: bool,
+    /// Extra attempts, at rising temperature, when the model returns nothing.
+    pub empty_retries: usize,
+    /// Retries of failed model requests (5xx, 429, timeouts, dropped connections).
+    pub transient_retries: usize,
+    /// Consecutive failed requests before failing fast for
+    /// `circuit_breaker_cooldown_ms`; 0 disables the breaker.
+    pub circuit_breaker_threshold: usize,
+    pub circuit_breaker_cooldown_ms: u64,
+    /// Send a throwaway request after startup to open the connection and
+    /// warm the model before the first real completion.
+    pub warmup: bool,
+    /// Summarize the markdown context with the model once it exceeds this
+    /// many characters. Off when unset.
+    pub summarize_context_chars: Option<usize>,
+    /// Model used for the summary; `snek.model` when unset.
+    pub summary_model: Option<String>,
+    /// Sentinel marking the cursor in the prompt.
+    pub cursor_marker: Option<String>,
+    /// Order snippets and context files with uncommitted git changes first.
+    pub git_context_order: bool,
+    /// Include the enclosing function or type signature in the prompt.
+    pub scope_signature: bool,
+    /// Documents up to this many lines are sent whole; longer ones only
+    /// around the cursor. `None` always sends the whole document.
+    pub full_file_threshold: Option<usize>,
+    /// Documents larger than this many bytes are kept, but completions only
+    /// read the text around the cursor. `None` means no limit.
+    pub max_document_bytes: Option<usize>,
+    /// Collapse runs of three or more blank lines around the cursor to two.
+    pub collapse_blank_lines: bool,
+    /// Send the session's markdown, snippets and sibling files; when off,
+    /// only the file being completed.
+    pub context_enabled: bool,
+    /// Cap on the session's cached markdown and snippet files, in bytes.
+    pub max_cache_bytes: Option<usize>,
+    /// Reload the session from disk this often, in case the file watcher
+    /// missed an event. Off when unset.
+    pub resync_interval_secs: Option<u64>,
+    /// Sampling temperature of the first attempt.
+    pub temperature: f32,
+    /// Completion length in tokens; the session's `limits.max_tokens` when unset.
+    pub max_tokens: Option<usize>,
+    /// Language ids to complete in; every language when unset.
+    pub languages: Option<Vec<String>>,
+    /// Tell the model when the text before the cursor is its own accepted
+    /// suggestion, so it continues rather than restarts.
+    pub continuation: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            min_prefix_chars: 1,
+            min_idle_ms: 0,
+            trigger_characters: Vec::new(),
+            max_completion_lines: None,
+            max_completion_chars: None,
+            stop_at_block_end: false,
+            adjust_newlines: true,
+            api_key_file: None,
+            proxy: None,
+            no_proxy: None,
+            client_cert: None,
+            client_key: None,
+            ca_bundle: None,
+            insecure_skip_tls_verify: false,
+            provider: Provider::default(),
+            fallback_model: None,
+            fallback_api_url: None,
+            fallback_api_key: None,
+            match_snippet_language: false,
+            sibling_context: false,
+            sibling_patterns: None,
+            max_siblings: 2,
+            open_buffer_imports: false,
+            persist_documents: false,
+            restrict_to_workspace: true,
+            persistent_cache: false,
+            empty_retries: 0,
+            transient_retries: 0,
+            circuit_breaker_threshold: 5,
+            circuit_breaker_cooldown_ms: 30_000,
+            warmup: false,
+            summarize_context_chars: None,
+            summary_model: None,
+            cursor_marker: None,
+            git_context_order: false,
+            scope_signature: false,
+            full_file_threshold: Some(DEFAULT_FULL_FILE_THRESHOLD),
+            max_document_bytes: Some(DEFAULT_MAX_DOCUMENT_BYTES),
+            collapse_blank_lines: false,
+            context_enabled: true,
+            max_cache_bytes: None,
+            resync_interval_secs: None,
+            temperature: 0.0,
+            max_tokens: None,
+            languages: None,
+            continuation: true,
+        }
+    }
+}
+
+impl Settings {
+    pub fn http_options(&self) -> HttpOptions {
+        HttpOptions {
+            proxy: self.proxy.clone().filter(|p| !p.is_empty()),
+            https_proxy: None,
+            http_proxy: None,
+            no_proxy: self.no_proxy.clone().filter(|p| !p.is_empty()),
+            client_cert: self.client_cert.clone().filter(|p| !p.is_empty()),
+            client_key: self.client_key.clone().filter(|p| !p.is_empty()),
+            ca_bundle: self.ca_bundle.clone().filter(|p| !p.is_empty()),
+            insecure_skip_tls_verify: self.insecure_skip_tls_verify,
+        }
+        .with_env_fallback()
+    }
+
+    pub fn fallback(&self) -> Option<FallbackModel> {
+        let model_name = self.fallback_model.clone().filter(|m| !m.is_empty())?;
+        Some(FallbackModel {
+            model_name,
+            api_url: self.fallback_api_url.clone().filter(|u| !u.is_empty()),
+            api_key: self.fallback_api_key.clone().filter(|k| !k.is_empty()),
+        })
+    }
+
+    pub fn resync_interval(&self) -> Option<Duration> {
+        self.resync_interval_secs
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    pub fn retry_options(&self) -> RetryOptions {
+        RetryOptions {
+            empty_retries: self.empty_retries,
+            transient_retries: self.transient_retries,
+            circuit_breaker_threshold: self.circuit_breaker_threshold,
+            circuit_breaker_cooldown: Duration::from_millis(self.circuit_breaker_cooldown_ms),
+        }
+    }
+
+    pub fn sampling_options(&self) -> SamplingOptions {
+        SamplingOptions {
+            temperature: self.temperature,
+            max_tokens: self.max_tokens,
+        }
+    }
+
+    /// Whether completions are enabled for `language_id`.
+    pub fn language_enabled(&self, language_id: &str) -> bool {
+        self.languages
+            .as_ref()
+            .is_none_or(|languages| languages.iter().any(|l| l == language_id))
+    }
+
+    /// Whether the text before the cursor ends in a trigger (which may be
+    /// longer than one character, like `::`), or no triggers are configured.
+    pub fn triggered_by(&self, prefix: &str) -> bool {
+        self.trigger_characters.is_empty()
+            || self
+                .trigger_characters
+                .iter()
+                .any(|trigger| !trigger.is_empty() && prefix.ends_with(trigger.as_str()))
+    }
+
+    /// Triggers a completion fired by a trigger character must follow in a
+    /// `language` document: the configured ones, or the language's defaults.
+    pub fn triggers_for(&self, language: &str) -> Vec<&str> {
+        if self.trigger_characters.is_empty() {
+            languages::default_triggers(language).to_vec()
+        } else {
+            self.trigger_characters.iter().map(String::as_str).collect()
+        }
+    }
+
+    /// Characters advertised to clients using standard completion requests:
+    /// the last character of each configured trigger, or of every
+    /// language's default triggers.
+    pub fn advertised_trigger_characters(&self) -> Vec<String> {
+        let triggers: Vec<&str> = if self.trigger_characters.is_empty() {
+            languages::all_default_triggers().collect()
+        } else {
+            self.trigger_characters.iter().map(String::as_str).collect()
+        };
+
+        // LSP trigger characters are single characters; `::` fires on its second `:`
+        let mut characters: Vec<String> = vec![];
+        for last in triggers.iter().filter_map(|t| t.chars().next_back()) {
+            let last = last.to_string();
+            if !characters.contains(&last) {
+                characters.push(last);
+            }
+        }
+        characters
+    }
+
+    pub fn prompt_options(&self) -> PromptOptions {
+        let defaults = PromptOptions::default();
+        PromptOptions {
+            match_snippet_language: self.match_snippet_language,
+            sibling_context: self.sibling_context,
+            sibling_patterns: self
+                .sibling_patterns
+                .clone()
+                .filter(|p| !p.is_empty())
+                .unwrap_or(defaults.sibling_patterns),
+            max_siblings: self.max_siblings,
+            summarize_above_chars: self.summarize_context_chars.filter(|&n| n > 0),
+            summary_model: self.summary_model.clone().filter(|m| !m.is_empty()),
+            prioritize_git_changes: self.git_context_order,
+            scope_signature: self.scope_signature,
+            cursor_marker: self
+                .cursor_marker
+                .clone()
+                .filter(|m| !m.trim().is_empty())
+                .unwrap_or(defaults.cursor_marker),
+            cache_control: self.provider.supports_cache_control(),
+        }
+    }
+}
+
+/// Workspace directory used when no `--workspace-dir` flag is passed, for
+/// editors that can set the environment more easily than arguments.
+pub const WORKSPACE_DIR_ENV: &str = "SNEK_WORKSPACE_DIR";
+
+/// Where the workspace directories came from; see `resolve_workspace_dirs`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WorkspaceDirSource {
+    Flag,
+    Env,
+    /// Neither; `.snek` is searched for from the current directory.
+    CurrentDir,
+}
+
+/// The `--workspace-dir` flags if any were given, otherwise the value of
+/// `WORKSPACE_DIR_ENV` (`env_dir`) when it's set and non-empty.
+pub fn resolve_workspace_dirs(
+    flag_dirs: Vec<PathBuf>,
+    env_dir: Option<String>,
+) -> (Vec<PathBuf>, WorkspaceDirSource) {
+    if !flag_dirs.is_empty() {
+        return (flag_dirs, WorkspaceDirSource::Flag);
+    }
+    match env_dir.filter(|dir| !dir.trim().is_empty()) {
+        Some(dir) => (vec![PathBuf::from(dir)], WorkspaceDirSource::Env),
+        None => (vec![], WorkspaceDirSource::CurrentDir),
+    }
+}
+
+/// Loads `.snek/.env` and then `<workspace>/.env` into the process
+/// environment. Variables that are already set are never overridden, so the
+/// real environment wins over `.snek/.env`, which wins over the workspace file.
+pub fn load_env_files(snek_root: &Path) -> Vec<PathBuf> {
+    let mut candidates = vec![snek_root.join(".env")];
+    if let Some(workspace) = snek_root.parent() {
+        candidates.push(workspace.join(".env"));
+    }
+
+    let mut loaded = vec![];
+    for path in candidates {
+        if !path.is_file() {
+            continue;
+        }
+        match dotenvy::from_path(&path) {
+            Ok(()) => {
+                eprintln!("[SNEK] Loaded environment from {:?}", path);
+                loaded.push(path);
+            }
+            Err(e) => eprintln!("[SNEK] Failed to load {:?}: {}", path, e),
+        }
+    }
+    loaded
+}
+
+/// Startup configuration taken from `SNEK_*` environment variables.
+#[derive(Clone, Default)]
+pub struct EnvConfig {
+    pub api_key: Option<String>,
+    pub api_key_file: Option<String>,
+    pub api_url: Option<String>,
+    pub model: Option<String>,
+    /// Keep at most this many sessions, removing the least recently updated.
+    pub max_sessions: Option<usize>,
+}
+
+impl EnvConfig {
+    pub fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        Self {
+            api_key: var("SNEK_API_KEY"),
+            api_key_file: var("SNEK_API_KEY_FILE"),
+            api_url: var("SNEK_API_URL"),
+            model: var("SNEK_MODEL"),
+            max_sessions: var("SNEK_MAX_SESSIONS").and_then(|v| v.parse().ok()),
+        }
+    }
+
+    /// The key from `SNEK_API_KEY_FILE` if set, otherwise `SNEK_API_KEY`.
+    pub fn resolve_api_key(&self) -> Result<Option<String>> {
+        match self.api_key_file {
+            Some(ref path) => read_api_key_file(Path::new(path)).map(Some),
+            None => Ok(self.api_key.clone()),
+        }
+    }
+}
+
+/// Reads an API key from a mounted secret file, trimming the trailing newline.
+/// Warns when the file is readable by group or others.
+pub fn read_api_key_file(path: &Path) -> Result<String> {
+    let metadata = std::fs::metadata(path)
+        .map_err(SnekError::io(format!("API key file {} is not accessible", path.display())))?;
+    if !metadata.is_file() {
+        return Err(SnekError::Config(format!(
+            "API key file {} is not a regular file",
+            path.display()
+        )));
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = metadata.permissions().mode();
+        if mode & 0o077 != 0 {
+            eprintln!(
+                "[SNEK] Warning: API key file {} is accessible by other users (mode {:o}), consider chmod 600",
+                path.display(),
+                mode & 0o777
+            );
+        }
+    }
+
+    let content = std::fs::read_to_string(path)
+        .map_err(SnekError::io(format!("Failed to read API key file {}", path.display())))?;
+    let key = content.trim_end_matches(['\n', '\r']).to_string();
+    if key.trim().is_empty() {
+        return Err(SnekError::Config(format!("API key file {} is empty", path.display())));
+    }
+    Ok(key)
+}
@@ -0,0 +1,144 @@
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const DEFAULT_MAX_ENTRIES: usize = 32;
+
+/// Documents larger than this are never written to disk.
+const MAX_DOCUMENT_BYTES: usize = 1024 * 1024;
+
+#[derive(Serialize, Deserialize)]
+struct CachedDocument {
+    uri: String,
+    language_id: String,
+    text: String,
+}
+
+/// On-disk copy of open documents, so a client that reconnects after a
+/// crash can get completions before it re-sends `did_open`. Disabled until
+/// `configure` turns it on.
+pub struct DocumentCache {
+    dir: PathBuf,
+    workspace_root: Option<PathBuf>,
+    max_entries: usize,
+    enabled: AtomicBool,
+    restrict_to_workspace: AtomicBool,
+}
+
+impl DocumentCache {
+    pub fn new(dir: PathBuf, workspace_root: Option<PathBuf>) -> Self {
+        Self {
+            dir,
+            workspace_root,
+            max_entries: DEFAULT_MAX_ENTRIES,
+            enabled: AtomicBool::new(false),
+            restrict_to_workspace: AtomicBool::new(true),
+        }
+    }
+
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries.max(1);
+        self
+    }
+
+    pub fn configure(&self, enabled: bool, restrict_to_workspace: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+        self.restrict_to_workspace
+            .store(restrict_to_workspace, Ordering::Relaxed);
+    }
+
+    pub fn store(&self, uri: &str, language_id: &str, text: &str) {
+        if !self.accepts(uri) || text.len() > MAX_DOCUMENT_BYTES {
+            return;
+        }
+
+        let document = CachedDocument {
+            uri: uri.to_string(),
+            language_id: language_id.to_string(),
+            text: text.to_string(),
+        };
+        let result = std::fs::create_dir_all(&self.dir).and_then(|_| {
+            let json = serde_json::to_string(&document)?;
+            std::fs::write(self.entry_path(uri), json)
+        });
+        if let Err(e) = result {
+            eprintln!("[SNEK] Failed to cache document {}: {}", uri, e);
+            return;
+        }
+
+        self.evict();
+    }
+
+    /// Returns `(language_id, text)` for a cached copy of `uri`.
+    pub fn load(&self, uri: &str) -> Option<(String, String)> {
+        if !self.accepts(uri) {
+            return None;
+        }
+
+        let content = std::fs::read_to_string(self.entry_path(uri)).ok()?;
+        let document: CachedDocument = serde_json::from_str(&content).ok()?;
+        // Guard against hash collisions
+        (document.uri == uri).then_some((document.language_id, document.text))
+    }
+
+    pub fn remove(&self, uri: &str) {
+        let _ = std::fs::remove_file(self.entry_path(uri));
+    }
+
+    fn accepts(&self, uri: &str) -> bool {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return false;
+        }
+        if !self.restrict_to_workspace.load(Ordering::Relaxed) {
+            return true;
+        }
+
+        let Some(ref root) = self.workspace_root else {
+            return false;
+        };
+        url::Url::parse(uri)
+            .ok()
+            .and_then(|u| u.to_file_path().ok())
+            .is_some_and(|path| is_within(&path, root))
+    }
+
+    fn entry_path(&self, uri: &str) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        uri.hash(&mut hasher);
+        self.dir.join(format!("{:016x}.json", hasher.finish()))
+    }
+
+    /// Drops the least recently written entries beyond `max_entries`.
+    fn evict(&self) {
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return;
+        };
+
+        let mut files: Vec<(std::time::SystemTime, PathBuf)> = entries
+            .flatten()
+            .filter_map(|entry| {
+                let modified = entry.metadata().ok()?.modified().ok()?;
+                Some((modified, entry.path()))
+            })
+            .collect();
+
+        if files.len() <= self.max_entries {
+            return;
+        }
+
+        files.sort();
+        let excess = files.len() - self.max_entries;
+        for (_, path) in files.into_iter().take(excess) {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn is_within(path: &Path, root: &Path) -> bool {
+    match (path.canonicalize(), root.canonicalize()) {
+        (Ok(path), Ok(root)) => path.starts_with(root),
+        _ => path.starts_with(root),
+    }
+}
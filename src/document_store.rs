@@ -1,15 +1,52 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use crate::document_cache::DocumentCache;
+use crate::languages;
 
 #[derive(Clone, Debug)]
 struct DocumentContent {
     uri: String,
     language_id: String,
     text: String,
+    version: u64,
+    /// Larger than the store's `max_document_bytes`; completions only see
+    /// a window around the cursor.
+    oversized: bool,
 }
 
-#[derive(Default)]
+/// Open documents whose import lines are remembered; later ones are left out.
+const MAX_IMPORT_DOCUMENTS: usize = 32;
+
+/// Bytes around the cursor that completions in an oversized document see,
+/// three quarters of them before it.
+pub const OVERSIZED_WINDOW_BYTES: usize = 64 * 1024;
+
 pub struct DocumentStore {
     active_doc: RwLock<Option<DocumentContent>>,
+    last_edits: RwLock<HashMap<String, Instant>>,
+    /// Language and import lines of every open document, not just the
+    /// active one.
+    imports: RwLock<HashMap<String, (String, Vec<String>)>>,
+    cache: Option<DocumentCache>,
+    next_version: AtomicU64,
+    /// `usize::MAX` when documents of any size are handled whole.
+    max_document_bytes: AtomicUsize,
+}
+
+impl Default for DocumentStore {
+    fn default() -> Self {
+        Self {
+            active_doc: RwLock::default(),
+            last_edits: RwLock::default(),
+            imports: RwLock::default(),
+            cache: None,
+            next_version: AtomicU64::default(),
+            max_document_bytes: AtomicUsize::new(usize::MAX),
+        }
+    }
 }
 
 impl DocumentStore {
@@ -17,24 +54,114 @@ impl DocumentStore {
         Self::default()
     }
 
+    /// A store that mirrors open documents to `cache` once it is enabled.
+    pub fn with_cache(cache: DocumentCache) -> Self {
+        Self {
+            cache: Some(cache),
+            ..Self::default()
+        }
+    }
+
+    pub fn configure_cache(&self, enabled: bool, restrict_to_workspace: bool) {
+        if let Some(ref cache) = self.cache {
+            cache.configure(enabled, restrict_to_workspace);
+        }
+    }
+
+    /// Documents larger than `max_bytes` are still stored, but completions
+    /// only look at a window around the cursor. `None` lifts the limit.
+    pub fn configure_max_document_bytes(&self, max_bytes: Option<usize>) {
+        let max_bytes = max_bytes.unwrap_or(usize::MAX);
+        self.max_document_bytes.store(max_bytes, Ordering::Relaxed);
+        if let Some(ref mut content) = *self.active_doc.write().unwrap() {
+            content.oversized = self.check_size(&content.uri, &content.text, content.oversized);
+        }
+    }
+
+    /// Whether the open document at `uri` exceeds the size limit.
+    pub fn is_oversized(&self, uri: &str) -> bool {
+        let doc = self.active_doc.read().unwrap();
+        doc.as_ref()
+            .is_some_and(|content| content.uri == uri && content.oversized)
+    }
+
+    /// Whether `text` is over the limit, logging when a document becomes so.
+    fn check_size(&self, uri: &str, text: &str, was_oversized: bool) -> bool {
+        let max_bytes = self.max_document_bytes.load(Ordering::Relaxed);
+        let oversized = text.len() > max_bytes;
+        if oversized && !was_oversized {
+            eprintln!(
+                "[SNEK] {} is {} bytes, over the {} byte limit; completing from the text around the cursor",
+                uri,
+                text.len(),
+                max_bytes
+            );
+        }
+        oversized
+    }
+
     pub fn did_open(&self, uri: String, language_id: String, text: String) {
+        let language_id = if language_id.trim().is_empty() {
+            let inferred = languages::language_for_path(&uri).unwrap_or_default();
+            eprintln!(
+                "[SNEK] No language id for {}, using {:?} from its extension",
+                uri, inferred
+            );
+            inferred.to_string()
+        } else {
+            language_id
+        };
+
+        if let Some(ref cache) = self.cache {
+            cache.store(&uri, &language_id, &text);
+        }
+        self.update_imports(&uri, &language_id, &text);
+
+        let oversized = self.check_size(&uri, &text, false);
         let mut doc = self.active_doc.write().unwrap();
         *doc = Some(DocumentContent {
             uri,
             language_id,
             text,
+            version: self.bump_version(),
+            oversized,
         });
     }
 
     pub fn did_change(&self, uri: &str, text: String) {
+        self.last_edits
+            .write()
+            .unwrap()
+            .insert(uri.to_string(), Instant::now());
+        let language_id = self
+            .imports
+            .read()
+            .unwrap()
+            .get(uri)
+            .map(|(language_id, _)| language_id.clone());
+        if let Some(language_id) = language_id {
+            self.update_imports(uri, &language_id, &text);
+        }
+
         let mut doc = self.active_doc.write().unwrap();
         if let Some(ref mut content) = *doc
             && content.uri == uri {
+                if let Some(ref cache) = self.cache {
+                    cache.store(uri, &content.language_id, &text);
+                }
+                content.oversized = self.check_size(uri, &text, content.oversized);
                 content.text = text;
+                content.version = self.bump_version();
             }
     }
 
     pub fn did_close(&self, uri: &str) {
+        self.last_edits.write().unwrap().remove(uri);
+        self.imports.write().unwrap().remove(uri);
+        if let Some(ref cache) = self.cache {
+            cache.remove(uri);
+        }
+
         let mut doc = self.active_doc.write().unwrap();
         if let Some(ref content) = *doc
             && content.uri == uri {
@@ -42,12 +169,84 @@ impl DocumentStore {
             }
     }
 
+    /// URIs of the documents the server currently holds.
+    pub fn list_open_uris(&self) -> Vec<String> {
+        let doc = self.active_doc.read().unwrap();
+        doc.iter().map(|content| content.uri.clone()).collect()
+    }
+
+    /// Forgets every open document, including its on-disk copy, so a
+    /// client that drifted out of sync starts over from `did_open`.
+    pub fn clear(&self) {
+        self.last_edits.write().unwrap().clear();
+        self.imports.write().unwrap().clear();
+        if let Some(content) = self.active_doc.write().unwrap().take()
+            && let Some(ref cache) = self.cache
+        {
+            cache.remove(&content.uri);
+        }
+    }
+
+    /// Time since the last `did_change` for `uri`, or `None` if it hasn't
+    /// been edited since it was opened.
+    pub fn idle_for(&self, uri: &str) -> Option<Duration> {
+        self.last_edits
+            .read()
+            .unwrap()
+            .get(uri)
+            .map(|edited| edited.elapsed())
+    }
+
+    /// Changes every time the document's text does; unique across URIs.
+    pub fn version(&self, uri: &str) -> Option<u64> {
+        let doc = self.active_doc.read().unwrap();
+        doc.as_ref()
+            .filter(|content| content.uri == uri)
+            .map(|content| content.version)
+    }
+
+    /// Import lines of the open documents other than `uri`, by URI.
+    pub fn imports_except(&self, uri: &str) -> Vec<(String, Vec<String>)> {
+        let imports = self.imports.read().unwrap();
+        let mut others: Vec<(String, Vec<String>)> = imports
+            .iter()
+            .filter(|(other, (_, lines))| other.as_str() != uri && !lines.is_empty())
+            .map(|(other, (_, lines))| (other.clone(), lines.clone()))
+            .collect();
+        others.sort();
+        others
+    }
+
+    fn update_imports(&self, uri: &str, language_id: &str, text: &str) {
+        let mut imports = self.imports.write().unwrap();
+        if imports.len() >= MAX_IMPORT_DOCUMENTS && !imports.contains_key(uri) {
+            return;
+        }
+        imports.insert(
+            uri.to_string(),
+            (language_id.to_string(), languages::import_lines(text, language_id)),
+        );
+    }
+
+    pub fn language_id(&self, uri: &str) -> Option<String> {
+        let doc = self.active_doc.read().unwrap();
+        doc.as_ref()
+            .filter(|content| content.uri == uri)
+            .map(|content| content.language_id.clone())
+    }
+
+    fn bump_version(&self) -> u64 {
+        self.next_version.fetch_add(1, Ordering::Relaxed)
+    }
+
     pub fn get_context(
         &self,
         uri: &str,
         line: u32,
         character: u32,
     ) -> Option<(String, String, String)> {
+        self.restore_from_cache(uri);
+
         let doc = self.active_doc.read().unwrap();
         let content = doc.as_ref()?;
 
@@ -55,24 +254,175 @@ impl DocumentStore {
             return None;
         }
 
-        let lines: Vec<&str> = content.text.lines().collect();
-        let mut offset = 0;
+        let (prefix, suffix) = if content.oversized {
+            let offset = offset_at_position(&content.text, line, character);
+            let (prefix, suffix) = window_at_offset(&content.text, offset, OVERSIZED_WINDOW_BYTES);
+            (prefix.to_string(), suffix.to_string())
+        } else {
+            split_at_position(&content.text, line, character)
+        };
+        let language_id = content.language_id.clone();
 
-        for (i, line_text) in lines.iter().enumerate() {
-            if i < line as usize {
-                offset += line_text.len() + 1; // +1 for newline
-            } else if i == line as usize {
-                offset += character.min(line_text.len() as u32) as usize;
-                break;
-            }
+        Some((prefix, suffix, language_id))
+    }
+
+    /// After a reconnect the client may ask for completions before
+    /// re-opening the document; fall back to the on-disk copy.
+    fn restore_from_cache(&self, uri: &str) {
+        let Some(ref cache) = self.cache else {
+            return;
+        };
+        if self
+            .active_doc
+            .read()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|content| content.uri == uri)
+        {
+            return;
         }
 
-        offset = offset.min(content.text.len());
+        if let Some((language_id, text)) = cache.load(uri) {
+            eprintln!("[SNEK] Restored document from cache: {}", uri);
+            let oversized = self.check_size(uri, &text, false);
+            *self.active_doc.write().unwrap() = Some(DocumentContent {
+                uri: uri.to_string(),
+                language_id,
+                text,
+                version: self.bump_version(),
+                oversized,
+            });
+        }
+    }
+}
 
-        let prefix = content.text[..offset].to_string();
-        let suffix = content.text[offset..].to_string();
-        let language_id = content.language_id.clone();
+/// Splits `text` at a zero-based line and character into the text before
+/// and after it. Characters are UTF-16 code units, as in LSP positions, and
+/// a line ends before its `\n` or `\r\n`. Positions past the end of a line
+/// or of the text are clamped.
+pub fn split_at_position(text: &str, line: u32, character: u32) -> (String, String) {
+    let offset = offset_at_position(text, line, character);
+    (text[..offset].to_string(), text[offset..].to_string())
+}
 
-        Some((prefix, suffix, language_id))
+/// Byte offset of a zero-based line and character in `text`, clamped as in
+/// `split_at_position`.
+pub fn offset_at_position(text: &str, line: u32, character: u32) -> usize {
+    let mut offset = 0;
+
+    for (i, line_text) in text.split_inclusive('\n').enumerate() {
+        if i < line as usize {
+            offset += line_text.len();
+            continue;
+        }
+
+        let content = line_text.trim_end_matches('\n').trim_end_matches('\r');
+        let mut units = 0;
+        let column = content
+            .char_indices()
+            .find(|&(_, c)| {
+                units += c.len_utf16() as u32;
+                units > character
+            })
+            .map_or(content.len(), |(index, _)| index);
+        offset += column;
+        break;
+    }
+
+    offset
+}
+
+/// The text within `max_bytes` around byte `offset`, three quarters of it
+/// before. Each side is cut at a line break when it has one, otherwise at a
+/// character boundary.
+pub fn window_at_offset(text: &str, offset: usize, max_bytes: usize) -> (&str, &str) {
+    let before = max_bytes * 3 / 4;
+    let after = max_bytes - before;
+
+    let mut start = offset.saturating_sub(before);
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    if start > 0
+        && let Some(newline) = text[start..offset].find('\n')
+    {
+        start += newline + 1;
+    }
+
+    let mut end = offset.saturating_add(after).min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    if end < text.len()
+        && let Some(newline) = text[offset..end].rfind('\n')
+    {
+        end = offset + newline + 1;
+    }
+
+    (&text[start..offset], &text[offset..end])
+}
+
+/// The partial identifier that `prefix` ends with, e.g. `fib` in
+/// `let n = fib`; empty after whitespace or punctuation.
+pub fn word_before_cursor(prefix: &str) -> &str {
+    let start = prefix
+        .char_indices()
+        .rev()
+        .take_while(|&(_, c)| c.is_alphanumeric() || c == '_')
+        .last()
+        .map_or(prefix.len(), |(i, _)| i);
+    &prefix[start..]
+}
+
+/// Narrows a document split at the cursor to at most `max_lines` lines around
+/// it, three quarters of them before the cursor. Documents within `max_lines`
+/// are returned whole.
+pub fn window_context<'a>(prefix: &'a str, suffix: &'a str, max_lines: usize) -> (&'a str, &'a str) {
+    let total_lines = prefix.matches('\n').count() + suffix.matches('\n').count() + 1;
+    if total_lines <= max_lines {
+        return (prefix, suffix);
+    }
+
+    let prefix_lines = (max_lines * 3 / 4).max(1);
+    let suffix_lines = max_lines.saturating_sub(prefix_lines).max(1);
+
+    // The cursor's line counts toward both halves
+    let prefix_start = prefix
+        .rmatch_indices('\n')
+        .nth(prefix_lines - 1)
+        .map_or(0, |(i, _)| i + 1);
+    let suffix_end = suffix
+        .match_indices('\n')
+        .nth(suffix_lines - 1)
+        .map_or(suffix.len(), |(i, _)| i + 1);
+
+    (&prefix[prefix_start..], &suffix[..suffix_end])
+}
+
+/// Collapses runs of three or more blank lines to two, leaving the line the
+/// cursor is on (the end of `prefix` and the start of `suffix`) untouched.
+pub fn collapse_blank_lines(prefix: &str, suffix: &str) -> (String, String) {
+    let (before, cursor_start) = prefix.split_at(prefix.rfind('\n').map_or(0, |i| i + 1));
+    let (cursor_end, after) = suffix.split_at(suffix.find('\n').map_or(suffix.len(), |i| i + 1));
+    (
+        collapse_runs(before) + cursor_start,
+        cursor_end.to_string() + &collapse_runs(after),
+    )
+}
+
+fn collapse_runs(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut blank_run = 0;
+    for line in text.split_inclusive('\n') {
+        if line.trim().is_empty() {
+            blank_run += 1;
+            if blank_run > 2 {
+                continue;
+            }
+        } else {
+            blank_run = 0;
+        }
+        collapsed.push_str(line);
     }
+    collapsed
 }
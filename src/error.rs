@@ -0,0 +1,115 @@
+use std::path::PathBuf;
+
+/// Errors returned by the snek library. Only the binary (`main.rs` and
+/// `lsp::server`) folds them into `anyhow`.
+#[derive(Debug, thiserror::Error)]
+pub enum SnekError {
+    #[error("{context}: {source}")]
+    WorkspaceIo {
+        context: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Failed to parse {file}: {source}")]
+    SessionParse {
+        file: String,
+        #[source]
+        source: serde_json::Error,
+    },
+
+    #[error(
+        "{} exists but is not a directory; move or delete it so snek can keep its sessions there",
+        .0.display()
+    )]
+    SnekDirNotDirectory(PathBuf),
+
+    #[error("Session directory {path:?} is outside {root:?}")]
+    SessionOutsideRoot { path: PathBuf, root: PathBuf },
+
+    #[error("{0}")]
+    Config(String),
+
+    #[error("Lines {start}-{end} are outside {uri} ({lines} lines)")]
+    LineRange {
+        uri: String,
+        start: u32,
+        end: u32,
+        lines: usize,
+    },
+
+    #[error("Failed to watch session files: {0}")]
+    Watch(#[from] notify::Error),
+
+    #[error("Invalid proxy URL in snek.proxy / HTTPS_PROXY / HTTP_PROXY / ALL_PROXY: {0}")]
+    InvalidProxy(#[source] reqwest::Error),
+
+    #[error("Failed to build HTTP client: {0}")]
+    HttpClient(#[source] reqwest::Error),
+
+    #[error("{0}")]
+    ModelAuth(String),
+
+    #[error(
+        "No model configured. Set 'snek.model' in VSCode settings, \"model\" in .snek/config.json or SNEK_MODEL"
+    )]
+    ModelNotConfigured,
+
+    #[error("AI model request failed: {status} - {body}")]
+    ModelHttp { status: u16, body: String },
+
+    #[error("AI model endpoint redirected ({status}) to {location}; set snek.apiUrl to that URL")]
+    ModelRedirect { status: u16, location: String },
+
+    #[error("Failed to reach AI model: {0}")]
+    ModelTransport(#[source] reqwest::Error),
+
+    #[error("AI model request timed out")]
+    ModelTimeout,
+
+    #[error("Failed to parse AI model response: {0}")]
+    ModelResponse(String),
+
+    #[error("AI model {model} is failing, not retrying for {retry_after_ms}ms")]
+    CircuitOpen { model: String, retry_after_ms: u64 },
+
+    #[error("AI model returned an error: {0}")]
+    BadResponse(String),
+
+    #[error("AI model response was cut off: {0}")]
+    IncompleteResponse(String),
+
+    #[error("Prompt exceeds the model's context window: {0}")]
+    ContextTooLarge(String),
+
+    #[error("Completion request cancelled")]
+    Cancelled,
+}
+
+impl SnekError {
+    /// Worth trying again, possibly elsewhere: 5xx, 429, timeouts,
+    /// connection failures, responses cut off mid-body and an open circuit
+    /// breaker.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            SnekError::ModelHttp { status, .. } => *status >= 500 || *status == 429,
+            SnekError::ModelTransport(_)
+            | SnekError::ModelTimeout
+            | SnekError::IncompleteResponse(_)
+            | SnekError::CircuitOpen { .. } => true,
+            _ => false,
+        }
+    }
+
+    pub(crate) fn io(context: impl Into<String>) -> impl FnOnce(std::io::Error) -> SnekError {
+        let context = context.into();
+        move |source| SnekError::WorkspaceIo { context, source }
+    }
+
+    pub(crate) fn parse(file: impl Into<String>) -> impl FnOnce(serde_json::Error) -> SnekError {
+        let file = file.into();
+        move |source| SnekError::SessionParse { file, source }
+    }
+}
+
+pub type Result<T, E = SnekError> = std::result::Result<T, E>;
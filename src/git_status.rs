@@ -0,0 +1,96 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long one `git status` result is reused. Completions fire on nearly
+/// every keystroke; the working tree changes far less often.
+const STATUS_TTL: Duration = Duration::from_secs(5);
+
+/// Files with uncommitted changes in the repository containing `dir`, most
+/// recently modified first. Empty outside a git repository or when git is
+/// not installed.
+pub fn changed_files(dir: &Path) -> Vec<PathBuf> {
+    let Some(root) = git(dir, &["rev-parse", "--show-toplevel"]) else {
+        return vec![];
+    };
+    let root = PathBuf::from(root.trim_end());
+    let Some(status) = git(&root, &["status", "--porcelain=v1", "-z", "--untracked-files=all"])
+    else {
+        return vec![];
+    };
+
+    let mut files: Vec<(SystemTime, PathBuf)> = parse_porcelain(&status, &root)
+        .into_iter()
+        .filter_map(|path| {
+            let modified = std::fs::metadata(&path).ok()?.modified().ok()?;
+            Some((modified, path))
+        })
+        .collect();
+    files.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+    files.into_iter().map(|(_, path)| path).collect()
+}
+
+fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git").arg("-C").arg(dir).args(args).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Paths named in `git status --porcelain=v1 -z` output, joined to `root`.
+/// Renames and copies contribute their new path only.
+pub fn parse_porcelain(output: &str, root: &Path) -> Vec<PathBuf> {
+    let mut paths = vec![];
+    let mut entries = output.split('\0');
+    while let Some(entry) = entries.next() {
+        let (Some(status), Some(path)) = (entry.get(..2), entry.get(3..)) else {
+            continue;
+        };
+        if status.contains('R') || status.contains('C') {
+            // The original path follows as its own entry
+            entries.next();
+        }
+        paths.push(root.join(path));
+    }
+    paths
+}
+
+/// Stable-sorts `items` so those whose path is in `changed` come first, in
+/// `changed` order; the rest keep their relative order.
+pub fn prioritize<T>(items: &mut [T], changed: &[PathBuf], path_of: impl Fn(&T) -> Option<PathBuf>) {
+    if changed.is_empty() {
+        return;
+    }
+    items.sort_by_cached_key(|item| {
+        path_of(item)
+            .and_then(|path| changed.iter().position(|c| *c == path))
+            .unwrap_or(usize::MAX)
+    });
+}
+
+/// `changed_files`, reused for `STATUS_TTL` per directory.
+#[derive(Default)]
+pub struct GitStatusCache {
+    last: Mutex<Option<(PathBuf, Instant, Vec<PathBuf>)>>,
+}
+
+impl GitStatusCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn changed_files(&self, dir: &Path) -> Vec<PathBuf> {
+        if let Some((ref cached_dir, at, ref files)) = *self.last.lock().unwrap()
+            && cached_dir == dir
+            && at.elapsed() < STATUS_TTL
+        {
+            return files.clone();
+        }
+
+        let files = changed_files(dir);
+        *self.last.lock().unwrap() = Some((dir.to_path_buf(), Instant::now(), files.clone()));
+        files
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::RwLock;
+
+const DEFAULT_CAPACITY: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompletionOutcome {
+    Accepted,
+    Rejected,
+}
+
+impl CompletionOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CompletionOutcome::Accepted => "accepted",
+            CompletionOutcome::Rejected => "rejected",
+        }
+    }
+}
+
+/// Identifies the text before the cursor that a completion was made for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PrefixMark {
+    len: usize,
+    hash: u64,
+}
+
+impl PrefixMark {
+    pub fn new(prefix: &str) -> Self {
+        let mut hasher = DefaultHasher::new();
+        prefix.hash(&mut hasher);
+        Self {
+            len: prefix.len(),
+            hash: hasher.finish(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct HistoryEntries {
+    completions: HashMap<String, (String, Option<PrefixMark>)>,
+    order: VecDeque<String>,
+}
+
+/// Remembers the last completion returned for each URI so the client can
+/// report whether it was accepted. Bounded: the least recently updated URI
+/// is evicted once `capacity` is reached.
+pub struct CompletionHistory {
+    capacity: usize,
+    entries: RwLock<HistoryEntries>,
+}
+
+impl Default for CompletionHistory {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+}
+
+impl CompletionHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: RwLock::new(HistoryEntries::default()),
+        }
+    }
+
+    pub fn record(&self, uri: &str, completion: String) {
+        self.insert(uri, completion, None);
+    }
+
+    /// Records a completion along with the prefix it was made for, so a
+    /// follow-up request can tell whether it was accepted.
+    pub fn record_at(&self, uri: &str, completion: String, prefix: PrefixMark) {
+        self.insert(uri, completion, Some(prefix));
+    }
+
+    fn insert(&self, uri: &str, completion: String, prefix: Option<PrefixMark>) {
+        let mut entries = self.entries.write().unwrap();
+
+        if entries
+            .completions
+            .insert(uri.to_string(), (completion, prefix))
+            .is_some()
+        {
+            entries.order.retain(|u| u != uri);
+        }
+        entries.order.push_back(uri.to_string());
+
+        while entries.order.len() > self.capacity {
+            if let Some(oldest) = entries.order.pop_front() {
+                entries.completions.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn last(&self, uri: &str) -> Option<String> {
+        let entries = self.entries.read().unwrap();
+        entries.completions.get(uri).map(|(completion, _)| completion.clone())
+    }
+
+    /// When `prefix` is exactly the prefix the last completion for `uri` was
+    /// made for plus the start of that completion, returns the completion
+    /// and the accepted part. Any other edit before the cursor rules it out.
+    /// Accepted parts with fewer than `min_chars` non-whitespace characters
+    /// don't count.
+    pub fn continuation(&self, uri: &str, prefix: &str, min_chars: usize) -> Option<(String, String)> {
+        let entries = self.entries.read().unwrap();
+        let (completion, Some(mark)) = entries.completions.get(uri)? else {
+            return None;
+        };
+
+        let accepted = prefix.get(mark.len..)?;
+        if PrefixMark::new(&prefix[..mark.len]) != *mark || !completion.starts_with(accepted) {
+            return None;
+        }
+        if accepted.chars().filter(|c| !c.is_whitespace()).count() < min_chars.max(1) {
+            return None;
+        }
+        Some((completion.clone(), accepted.to_string()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap().completions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,583 @@
+/// Comment and string syntax for a language, keyed by LSP `language_id`.
+///
+/// Markers are matched in order, so longer markers that share a prefix with
+/// shorter ones (`"""` vs `"`, `--[[` vs `--`) must come first.
+#[derive(Debug, PartialEq, Eq)]
+pub struct LanguageProfile {
+    pub line_comments: &'static [&'static str],
+    pub block_comments: &'static [(&'static str, &'static str)],
+    pub string_delimiters: &'static [&'static str],
+    /// Blocks are delimited by `{}` rather than indentation or keywords.
+    pub braces: bool,
+}
+
+const C_LIKE: LanguageProfile = LanguageProfile {
+    line_comments: &["//"],
+    block_comments: &[("/*", "*/")],
+    string_delimiters: &["\"", "'"],
+    braces: true,
+};
+
+const RUST: LanguageProfile = LanguageProfile {
+    line_comments: &["//"],
+    block_comments: &[("/*", "*/")],
+    // `'` is left out: lifetimes would open a string that never closes
+    string_delimiters: &["\""],
+    braces: true,
+};
+
+const JAVASCRIPT: LanguageProfile = LanguageProfile {
+    line_comments: &["//"],
+    block_comments: &[("/*", "*/")],
+    string_delimiters: &["\"", "'", "`"],
+    braces: true,
+};
+
+const GO: LanguageProfile = LanguageProfile {
+    line_comments: &["//"],
+    block_comments: &[("/*", "*/")],
+    string_delimiters: &["\"", "'", "`"],
+    braces: true,
+};
+
+const PYTHON: LanguageProfile = LanguageProfile {
+    line_comments: &["#"],
+    block_comments: &[],
+    string_delimiters: &["\"\"\"", "'''", "\"", "'"],
+    braces: false,
+};
+
+const HASH_COMMENTS: LanguageProfile = LanguageProfile {
+    line_comments: &["#"],
+    block_comments: &[],
+    string_delimiters: &["\"", "'"],
+    braces: false,
+};
+
+const LUA: LanguageProfile = LanguageProfile {
+    line_comments: &["--"],
+    block_comments: &[("--[[", "]]")],
+    string_delimiters: &["\"", "'"],
+    braces: false,
+};
+
+const SQL: LanguageProfile = LanguageProfile {
+    line_comments: &["--"],
+    block_comments: &[("/*", "*/")],
+    string_delimiters: &["'", "\""],
+    braces: false,
+};
+
+const MARKUP: LanguageProfile = LanguageProfile {
+    line_comments: &[],
+    block_comments: &[("<!--", "-->")],
+    string_delimiters: &[],
+    braces: false,
+};
+
+const DEFAULT: LanguageProfile = LanguageProfile {
+    line_comments: &["//", "#"],
+    block_comments: &[("/*", "*/")],
+    string_delimiters: &["\"", "'"],
+    braces: false,
+};
+
+pub fn profile(language_id: &str) -> &'static LanguageProfile {
+    match language_id {
+        "rust" => &RUST,
+        "python" => &PYTHON,
+        "javascript" | "javascriptreact" | "typescript" | "typescriptreact" => &JAVASCRIPT,
+        "go" => &GO,
+        "c" | "cpp" | "objective-c" | "objective-cpp" | "java" | "csharp" | "kotlin"
+        | "swift" | "scala" | "dart" | "php" => &C_LIKE,
+        "lua" => &LUA,
+        "shellscript" | "ruby" | "perl" | "r" | "yaml" | "toml" | "dockerfile"
+        | "makefile" => &HASH_COMMENTS,
+        "sql" => &SQL,
+        "html" | "xml" | "markdown" => &MARKUP,
+        _ => &DEFAULT,
+    }
+}
+
+/// Triggers used when `snek.triggerCharacters` is empty: member access and
+/// calls everywhere, plus each language's own path and pointer operators.
+const DEFAULT_TRIGGERS: &[&str] = &[".", "("];
+const RUST_TRIGGERS: &[&str] = &[".", "::", "("];
+const C_TRIGGERS: &[&str] = &[".", "->", "("];
+const CPP_TRIGGERS: &[&str] = &[".", "->", "::", "("];
+const PHP_TRIGGERS: &[&str] = &["->", "::", "("];
+
+pub fn default_triggers(language_id: &str) -> &'static [&'static str] {
+    match language_id {
+        "rust" => RUST_TRIGGERS,
+        "c" | "objective-c" => C_TRIGGERS,
+        "cpp" | "objective-cpp" => CPP_TRIGGERS,
+        "php" => PHP_TRIGGERS,
+        _ => DEFAULT_TRIGGERS,
+    }
+}
+
+/// The default triggers of every language, for clients that register one
+/// set of trigger characters for all of them.
+pub fn all_default_triggers() -> impl Iterator<Item = &'static str> {
+    [DEFAULT_TRIGGERS, RUST_TRIGGERS, C_TRIGGERS, CPP_TRIGGERS, PHP_TRIGGERS]
+        .into_iter()
+        .flatten()
+        .copied()
+}
+
+/// LSP `language_id` of a file, guessed from the name or extension at the
+/// end of `path` (a path or URI), for clients that open documents without one.
+pub fn language_for_path(path: &str) -> Option<&'static str> {
+    let name = path.rsplit(['/', '\\']).next()?;
+    match name {
+        "Dockerfile" => return Some("dockerfile"),
+        "Makefile" | "makefile" | "GNUmakefile" => return Some("makefile"),
+        _ => {}
+    }
+
+    let (_, extension) = name.rsplit_once('.')?;
+    let language = match extension.to_ascii_lowercase().as_str() {
+        "rs" => "rust",
+        "py" | "pyi" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "jsx" => "javascriptreact",
+        "ts" | "mts" | "cts" => "typescript",
+        "tsx" => "typescriptreact",
+        "go" => "go",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hpp" | "hh" | "hxx" => "cpp",
+        "m" => "objective-c",
+        "mm" => "objective-cpp",
+        "java" => "java",
+        "cs" => "csharp",
+        "kt" | "kts" => "kotlin",
+        "swift" => "swift",
+        "scala" => "scala",
+        "dart" => "dart",
+        "php" => "php",
+        "lua" => "lua",
+        "sh" | "bash" | "zsh" => "shellscript",
+        "rb" => "ruby",
+        "pl" | "pm" => "perl",
+        "r" => "r",
+        "yaml" | "yml" => "yaml",
+        "toml" => "toml",
+        "json" => "json",
+        "sql" => "sql",
+        "html" | "htm" => "html",
+        "xml" => "xml",
+        "css" => "css",
+        "md" | "markdown" => "markdown",
+        "txt" => "plaintext",
+        _ => return None,
+    };
+    Some(language)
+}
+
+/// Returns true when code in language `a` is useful context for language `b`:
+/// the same id, or dialects of one family (TypeScript and JavaScript, C and C++).
+pub fn is_compatible(a: &str, b: &str) -> bool {
+    a == b || family(a).is_some_and(|f| family(b) == Some(f))
+}
+
+/// Prose rather than code: completions are taken mostly verbatim, without
+/// fence stripping or indentation fixes.
+pub fn is_prose(language_id: &str) -> bool {
+    matches!(language_id, "plaintext" | "markdown")
+}
+
+fn family(language_id: &str) -> Option<&'static str> {
+    match language_id {
+        "javascript" | "javascriptreact" | "typescript" | "typescriptreact" => Some("javascript"),
+        "c" | "cpp" | "objective-c" | "objective-cpp" => Some("c"),
+        _ => None,
+    }
+}
+
+/// Line starts of import statements per language, as in `get-imports.sh`.
+fn import_prefixes(language_id: &str) -> &'static [&'static str] {
+    match language_id {
+        "rust" => &["use ", "pub use ", "pub(crate) use ", "extern crate "],
+        "python" => &["import ", "from "],
+        "javascript" | "javascriptreact" | "typescript" | "typescriptreact" => {
+            &["import ", "import{", "export {", "export * from"]
+        }
+        "go" => &["import "],
+        "java" | "kotlin" | "scala" => &["import "],
+        "c" | "cpp" | "objective-c" | "objective-cpp" => &["#include", "#import"],
+        "csharp" => &["using "],
+        _ => &[],
+    }
+}
+
+/// The import, use and include statements of `text`, a file in
+/// `language_id`. Statements spanning lines (`use a::{` ... `};`, Go's
+/// `import (` ... `)`) are kept whole. Empty for unsupported languages.
+pub fn import_lines(text: &str, language_id: &str) -> Vec<String> {
+    let prefixes = import_prefixes(language_id);
+    if prefixes.is_empty() {
+        return vec![];
+    }
+
+    let mut imports = vec![];
+    let mut open: Option<(String, i64)> = None;
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if let Some((ref mut statement, ref mut depth)) = open {
+            statement.push('\n');
+            statement.push_str(line.trim_end());
+            *depth += bracket_balance(trimmed);
+            if *depth <= 0 {
+                imports.extend(open.take().map(|(statement, _)| statement));
+            }
+            continue;
+        }
+
+        if !prefixes.iter().any(|p| trimmed.starts_with(p)) {
+            continue;
+        }
+        let depth = bracket_balance(trimmed);
+        if depth > 0 {
+            open = Some((trimmed.to_string(), depth));
+        } else {
+            imports.push(trimmed.to_string());
+        }
+    }
+    // An unfinished statement at the end of the file is still worth sending
+    imports.extend(open.map(|(statement, _)| statement));
+    imports
+}
+
+fn bracket_balance(line: &str) -> i64 {
+    line.chars()
+        .map(|c| match c {
+            '{' | '(' => 1,
+            '}' | ')' => -1,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// How far back from the cursor `enclosing_function` looks.
+const MAX_SCOPE_LINES: usize = 500;
+
+/// Longest multi-line Python `def` header `enclosing_signature` follows.
+const MAX_HEADER_LINES: usize = 5;
+
+const FUNCTION_KEYWORDS: &[&str] = &["fn ", "func ", "function ", "def "];
+
+const CONTROL_KEYWORDS: &[&str] = &[
+    "if", "else", "for", "while", "loop", "match", "switch", "catch", "do", "try", "return",
+];
+
+const TYPE_KEYWORDS: &[&str] = &[
+    "class", "struct", "impl", "trait", "interface", "enum", "object", "module", "namespace",
+    "record",
+];
+
+const MODIFIERS: &[&str] = &[
+    "pub", "pub(crate)", "export", "default", "public", "private", "protected", "internal",
+    "abstract", "final", "static", "sealed", "data", "open", "unsafe", "async",
+];
+
+/// Name of the function the cursor (end of `prefix`) is inside, if any.
+/// Python-like languages are scoped by indentation, everything else by
+/// braces. A heuristic: strings and comments containing braces can fool it.
+pub fn enclosing_function(prefix: &str, language_id: &str) -> Option<String> {
+    enclosing_headers(prefix, language_id)
+        .iter()
+        .find_map(|header| header_function_name(header, language_id))
+}
+
+/// Header of the innermost function or type (class, impl, ...) around the
+/// cursor, on one line: `fn parse(input: &str) -> Result<Ast>`. Found the
+/// same way as `enclosing_function`.
+pub fn enclosing_signature(prefix: &str, language_id: &str) -> Option<String> {
+    enclosing_headers(prefix, language_id)
+        .into_iter()
+        .find(|header| {
+            header_function_name(header, language_id).is_some() || is_type_header(header)
+        })
+        .map(|header| header.split_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+/// Headers of the blocks around the cursor, innermost first.
+fn enclosing_headers(prefix: &str, language_id: &str) -> Vec<String> {
+    let lines: Vec<&str> = prefix.lines().collect();
+    let start = lines.len().saturating_sub(MAX_SCOPE_LINES);
+    let lines = &lines[start..];
+
+    match language_id {
+        "python" => headers_by_indent(lines, prefix.ends_with('\n')),
+        _ => headers_by_braces(lines),
+    }
+}
+
+fn headers_by_indent(lines: &[&str], cursor_on_new_line: bool) -> Vec<String> {
+    let indent_of = |line: &str| line.len() - line.trim_start().len();
+
+    let (mut scope_indent, above) = match lines.split_last() {
+        _ if cursor_on_new_line => (usize::MAX, lines),
+        Some((last, rest)) if !last.is_empty() => (indent_of(last), rest),
+        // A blank cursor line belongs to whatever block sits right above it
+        Some((_, rest)) => (usize::MAX, rest),
+        None => return vec![],
+    };
+
+    let mut headers = vec![];
+    for (idx, line) in above.iter().enumerate().rev() {
+        if line.trim().is_empty() || indent_of(line) >= scope_indent {
+            continue;
+        }
+        // `def f(a,\n      b):` continues until the line ending in `:`
+        let mut header = line.trim().to_string();
+        for next in above[idx + 1..].iter().take(MAX_HEADER_LINES - 1) {
+            if header.ends_with(':') {
+                break;
+            }
+            header.push(' ');
+            header.push_str(next.trim());
+        }
+        headers.push(header.trim_end_matches(':').to_string());
+        scope_indent = indent_of(line);
+    }
+    headers
+}
+
+fn headers_by_braces(lines: &[&str]) -> Vec<String> {
+    let mut headers = vec![];
+    let mut depth = 0usize;
+
+    for (idx, line) in lines.iter().enumerate().rev() {
+        for (pos, c) in line.char_indices().rev() {
+            match c {
+                '}' => depth += 1,
+                '{' if depth > 0 => depth -= 1,
+                '{' => {
+                    // An unmatched `{` opens a block around the cursor; its
+                    // header may span a few lines (`fn f(\n    a: i32,\n) {`)
+                    let mut header_start = idx;
+                    while header_start > idx.saturating_sub(3)
+                        && is_header_continuation(lines[header_start - 1])
+                    {
+                        header_start -= 1;
+                    }
+                    let mut header = lines[header_start..idx].join(" ");
+                    header.push(' ');
+                    header.push_str(&line[..pos]);
+                    let header = header.rsplit(['{', '}', ';']).next().unwrap_or_default();
+                    headers.push(header.trim().to_string());
+                }
+                _ => {}
+            }
+        }
+    }
+    headers
+}
+
+fn header_function_name(header: &str, language_id: &str) -> Option<String> {
+    match language_id {
+        // Only `def` opens a function; `with open(f) as g:` is not one
+        "python" => {
+            let declaration = header.strip_prefix("async ").unwrap_or(header);
+            identifier(declaration.strip_prefix("def ")?)
+        }
+        _ => function_name(header),
+    }
+}
+
+/// Whether `header` declares a type (`pub struct Foo`, `class Bar(Base)`,
+/// `impl<T> Trait for Baz<T>`).
+fn is_type_header(header: &str) -> bool {
+    header
+        .split_whitespace()
+        .find(|word| !MODIFIERS.contains(word))
+        .is_some_and(|word| {
+            TYPE_KEYWORDS
+                .iter()
+                .any(|kw| word == *kw || word.strip_prefix(kw).is_some_and(|rest| rest.starts_with('<')))
+        })
+}
+
+/// Whether `line` can be part of a signature that continues on the next line.
+fn is_header_continuation(line: &str) -> bool {
+    let trimmed = line.trim();
+    !trimmed.is_empty()
+        && !trimmed.starts_with('#')
+        && !trimmed.starts_with("//")
+        && !trimmed.ends_with(['{', '}', ';'])
+}
+
+fn function_name(header: &str) -> Option<String> {
+    for keyword in FUNCTION_KEYWORDS {
+        if let Some(pos) = header.rfind(keyword)
+            && (pos == 0 || !header[..pos].ends_with(|c: char| c.is_alphanumeric() || c == '_'))
+        {
+            let mut rest = header[pos + keyword.len()..].trim_start();
+            // Go methods: `func (s *Server) Handle(...)`
+            if rest.starts_with('(') {
+                rest = rest.split_once(')')?.1.trim_start();
+            }
+            return identifier(rest);
+        }
+    }
+
+    // C-like: `int main(void) {`, `public void run() {`. Calls, closures and
+    // control flow (`if (x) {`, `xs.map(|x| {`) are rejected.
+    let before_paren = header[..header.find('(')?].trim();
+    let first_word = before_paren.split_whitespace().next()?;
+    let name = before_paren
+        .rsplit(|c: char| c.is_whitespace() || c == '*' || c == '&')
+        .next()?;
+    if name.is_empty()
+        || name.len() == before_paren.len()
+        || CONTROL_KEYWORDS.contains(&first_word)
+        || before_paren.contains(['.', '=', ',', '|'])
+        || !name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == ':')
+    {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+fn identifier(text: &str) -> Option<String> {
+    let name: String = text
+        .chars()
+        .take_while(|c| c.is_alphanumeric() || *c == '_')
+        .collect();
+    (!name.is_empty()).then_some(name)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ScanState {
+    Code,
+    LineComment,
+    BlockComment(&'static str),
+    Str(&'static str),
+}
+
+/// Returns true when the end of `prefix` (the cursor) sits inside a comment.
+pub fn is_in_comment(prefix: &str, language_id: &str) -> bool {
+    matches!(
+        scan(prefix, profile(language_id)),
+        ScanState::LineComment | ScanState::BlockComment(_)
+    )
+}
+
+/// Returns true when the end of `prefix` (the cursor) sits inside a string literal.
+pub fn is_in_string(prefix: &str, language_id: &str) -> bool {
+    matches!(scan(prefix, profile(language_id)), ScanState::Str(_))
+}
+
+/// Byte offset of the end of the line of `completion` on which the block
+/// that was open at the cursor closes: the first line that ends with more
+/// `)`, `]` and `}` than it has opened since the cursor. Delimiters in
+/// comments and strings don't count. Always `None` for languages without
+/// braces.
+pub fn block_end(prefix: &str, completion: &str, language_id: &str) -> Option<usize> {
+    let profile = profile(language_id);
+    if !profile.braces {
+        return None;
+    }
+
+    let state = scan(prefix, profile);
+    let mut depth = 0i64;
+    let mut end = None;
+    scan_from(completion, profile, state, |i, c| {
+        match c {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            '\n' if depth < 0 => {
+                end = Some(i);
+                return false;
+            }
+            _ => {}
+        }
+        true
+    });
+    end.or((depth < 0).then_some(completion.len()))
+}
+
+fn scan(text: &str, profile: &LanguageProfile) -> ScanState {
+    scan_from(text, profile, ScanState::Code, |_, _| true)
+}
+
+/// Scans `text` starting in `state`, calling `on_code` with the offset of
+/// each character outside comments and strings until it returns false.
+fn scan_from(
+    text: &str,
+    profile: &LanguageProfile,
+    mut state: ScanState,
+    mut on_code: impl FnMut(usize, char) -> bool,
+) -> ScanState {
+    let mut i = 0;
+
+    while i < text.len() {
+        let rest = &text[i..];
+        let c = rest.chars().next().unwrap();
+
+        match state {
+            ScanState::Code => {
+                if let Some(&(open, close)) =
+                    profile.block_comments.iter().find(|(open, _)| rest.starts_with(open))
+                {
+                    state = ScanState::BlockComment(close);
+                    i += open.len();
+                    continue;
+                }
+                if let Some(marker) = profile.line_comments.iter().find(|m| rest.starts_with(*m)) {
+                    state = ScanState::LineComment;
+                    i += marker.len();
+                    continue;
+                }
+                if let Some(delim) = profile.string_delimiters.iter().find(|d| rest.starts_with(*d)) {
+                    state = ScanState::Str(delim);
+                    i += delim.len();
+                    continue;
+                }
+                if !on_code(i, c) {
+                    break;
+                }
+            }
+            ScanState::LineComment => {
+                if c == '\n' {
+                    state = ScanState::Code;
+                    if !on_code(i, c) {
+                        break;
+                    }
+                }
+            }
+            ScanState::BlockComment(close) => {
+                if rest.starts_with(close) {
+                    state = ScanState::Code;
+                    i += close.len();
+                    continue;
+                }
+            }
+            ScanState::Str(delim) => {
+                if c == '\\' {
+                    // Skip the escaped character
+                    i += 1;
+                    if let Some(next) = text[i..].chars().next() {
+                        i += next.len_utf8();
+                    }
+                    continue;
+                }
+                if rest.starts_with(delim) {
+                    state = ScanState::Code;
+                    i += delim.len();
+                    continue;
+                }
+                if c == '\n' && (delim == "\"" || delim == "'") {
+                    // Single-quoted strings don't span lines in most languages
+                    state = ScanState::Code;
+                }
+            }
+        }
+
+        i += c.len_utf8();
+    }
+
+    state
+}
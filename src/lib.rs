@@ -1,6 +1,23 @@
+pub mod acceptance;
+pub mod budget;
+pub mod circuit_breaker;
+pub mod completion_cache;
+pub mod config;
+pub mod document_cache;
 pub mod document_store;
+pub mod error;
+pub mod git_status;
+pub mod history;
+pub mod jsonc;
+pub mod languages;
 pub mod lsp;
+pub mod metrics;
 pub mod model;
+pub mod postprocess;
+pub mod project_config;
 pub mod session_io;
+pub mod siblings;
 pub mod snapshot;
+pub mod sse;
 pub mod watcher;
+pub mod workspaces;
@@ -1,5 +1,10 @@
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{watch, RwLock};
+use tokio_util::sync::CancellationToken;
 
 use arc_swap::ArcSwap;
 use serde::{Deserialize, Serialize};
@@ -8,27 +13,269 @@ use tower_lsp::jsonrpc;
 use tower_lsp::lsp_types::*;
 use tower_lsp::{Client, LanguageServer};
 
-use crate::document_store::DocumentStore;
-use crate::model::ModelClient;
-use crate::snapshot::ContextSnapshot;
+use crate::acceptance::AcceptanceTracker;
+use crate::budget::{ContextBudget, RequestBudget, Spend};
+use crate::completion_cache::{
+    CompletionCache, CompletionKey, PersistentCompletionCache, PersistentKey,
+};
+use crate::config::{read_api_key_file, Settings, DEFAULT_API_URL};
+use crate::document_store::{
+    collapse_blank_lines, split_at_position, window_context, word_before_cursor, DocumentStore,
+};
+use crate::error::SnekError;
+use crate::history::{CompletionHistory, CompletionOutcome, PrefixMark};
+use crate::languages;
+use crate::lsp::notifications::{
+    CompletionsStatus, CompletionsStatusParams, ContextChanged, ContextChangedParams,
+};
+use crate::model::{Completion, Continuation, ModelClient, RequestHints};
+use crate::project_config::ProjectConfig;
+use crate::postprocess::{
+    adjust_leading_newline, stop_at_block_end, trim_prefix_overlap, truncate_completion,
+};
+use crate::session_io::{
+    load_snapshot, record_requests_used, restore_context_snapshot, save_context_snapshot,
+};
+use crate::snapshot::{ContextFilter, ContextSnapshot};
+use crate::workspaces::Workspaces;
 
 #[derive(Debug, Deserialize)]
 pub struct InlineCompletionParams {
     pub text_document: TextDocumentIdentifier,
     pub position: Position,
+    /// The user's current selection, used to steer the completion.
+    #[serde(default)]
+    pub selection: Option<String>,
+    /// Ad-hoc context for this request only (e.g. the diagnostic at the cursor).
+    #[serde(default)]
+    pub extra_context: Option<String>,
+    /// A directive steering this completion only (e.g. "add error
+    /// handling"), for agents driving the editor.
+    #[serde(default)]
+    pub instruction: Option<String>,
+    /// Set by the client right after the user accepted (part of) the last
+    /// suggestion. Acceptances are also detected without it, but only of
+    /// at least `MIN_DETECTED_CONTINUATION_CHARS` characters.
+    #[serde(default)]
+    pub continuation: bool,
+    /// Overrides the document's language for this request, e.g. for SQL
+    /// embedded in a string or a script block inside HTML.
+    #[serde(default)]
+    pub language: Option<String>,
+    /// Overrides `snek.contextEnabled` for this request.
+    #[serde(default)]
+    pub context: Option<bool>,
+    /// The user asked for this completion (e.g. with a keybinding) rather
+    /// than it being triggered by typing; bypasses `snek.triggerCharacters`.
+    #[serde(default)]
+    pub invoked: bool,
+    /// Sampling temperature for this request only, e.g. to ask for a
+    /// different alternative; `snek.temperature` when unset.
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    /// The whole document, for clients completing text they never opened;
+    /// the store is bypassed and `language` gives its language.
+    #[serde(default)]
+    pub text: Option<String>,
+    /// Which markdown files to send for this request, overriding the
+    /// session's `context_filter`.
+    #[serde(default)]
+    pub context_filter: Option<ContextFilter>,
 }
 
+/// Language of `InlineCompletionParams::text` sent without a `language`.
+const INLINE_TEXT_LANGUAGE: &str = "plaintext";
+
+/// Shortest accepted text recognized as a continuation when the client
+/// doesn't flag it, so a single typed character matching the start of the
+/// last suggestion doesn't count.
+const MIN_DETECTED_CONTINUATION_CHARS: usize = 3;
+
 #[derive(Debug, Serialize)]
 pub struct InlineCompletionResponse {
     pub completion: String,
+    /// The session and context version the completion was made against,
+    /// so clients can tell when the context was reloaded: `version` is
+    /// session.json's, `reloads` goes up on every reload.
+    pub session_id: String,
+    pub version: u64,
+    pub reloads: u64,
+    /// For clients that replace a range rather than insert at the cursor:
+    /// the partial identifier before the cursor, and the text to replace it
+    /// with (that identifier followed by `completion`). Unset when there's
+    /// no partial identifier.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<Range>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub insert_text: Option<String>,
+    /// The model that produced the completion, as the API reported it.
+    /// Unset for completions served from the cache.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+impl InlineCompletionResponse {
+    fn new(completion: String, snapshot: &ContextSnapshot) -> Self {
+        Self {
+            completion,
+            session_id: snapshot.session_id.clone(),
+            version: snapshot.version,
+            reloads: snapshot.reloads,
+            range: None,
+            insert_text: None,
+            model: None,
+        }
+    }
+
+    /// Adds the replace-style `range` for `word`, the partial identifier
+    /// ending at `cursor`.
+    fn replacing(mut self, word: &str, cursor: Position) -> Self {
+        if !word.is_empty() && !self.completion.is_empty() {
+            let start = Position {
+                line: cursor.line,
+                character: cursor
+                    .character
+                    .saturating_sub(word.encode_utf16().count() as u32),
+            };
+            self.range = Some(Range { start, end: cursor });
+            self.insert_text = Some(format!("{}{}", word, self.completion));
+        }
+        self
+    }
+
+    fn empty(snapshot: &ContextSnapshot) -> Self {
+        Self::new(String::new(), snapshot)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompletionFeedbackParams {
+    pub text_document: TextDocumentIdentifier,
+}
+
+/// `snek/completion/feedback`: how much of the last suggestion for a
+/// document the user kept, e.g. only its first line.
+#[derive(Debug, Deserialize)]
+pub struct PartialAcceptanceParams {
+    pub text_document: TextDocumentIdentifier,
+    pub accepted_chars: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CompletionFeedbackResponse {
+    pub recorded: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScratchSessionResponse {
+    pub session_id: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OpenDocument {
+    pub uri: String,
+    pub version: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DocumentsListResponse {
+    pub documents: Vec<OpenDocument>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReloadConfigResponse {
+    /// The model completions now use.
+    pub model: String,
+    pub api_key_configured: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DocumentsResetResponse {
+    /// URIs the store held before the reset.
+    pub cleared: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportPromptResponse {
+    pub path: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ContextSnapshotParams {
+    pub name: String,
+    /// A document in the workspace root whose session is meant; the first
+    /// root's session when unset.
+    #[serde(default)]
+    pub text_document: Option<TextDocumentIdentifier>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ContextBudgetParams {
+    /// A document in the workspace root whose session is meant; the first
+    /// root's session when unset.
+    #[serde(default)]
+    pub text_document: Option<TextDocumentIdentifier>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SnapshotSaveResponse {
+    pub path: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SnapshotRestoreResponse {
+    pub session_id: String,
+    pub version: u64,
+}
+
+/// Written to the `.snek/` directory of the session by `snek/exportPrompt`.
+pub const LAST_PROMPT_FILE: &str = "last_prompt.md";
+
 pub struct Backend {
     pub client: Client,
     pub snapshot: Arc<ArcSwap<ContextSnapshot>>,
     pub documents: Arc<DocumentStore>,
     pub model: Arc<ModelClient>,
     pub api_key: Arc<RwLock<String>>,
+    pub last_completions: Arc<CompletionHistory>,
+    pub settings: Arc<RwLock<Settings>>,
+    /// Cancellation token of the in-flight completion for each URI; a new
+    /// request for the same URI supersedes (cancels) the previous one.
+    pub in_flight: Arc<Mutex<HashMap<String, CancellationToken>>>,
+    /// Post-processed completions keyed by the exact context they were made for.
+    pub completion_cache: Arc<CompletionCache>,
+    /// Completions kept across restarts (`snek.persistentCache`); `None`
+    /// when nothing may be written to `.snek`.
+    pub persistent_cache: Option<Arc<PersistentCompletionCache>>,
+    /// Share of recent suggestions the user kept, per language.
+    pub acceptance: Arc<AcceptanceTracker>,
+    /// Model requests made against the active session's request cap.
+    pub request_budget: Arc<RequestBudget>,
+    /// Per-root sessions of a multi-root workspace. Documents outside every
+    /// root, or any document when empty, use `snapshot`.
+    pub workspaces: Arc<Workspaces>,
+    /// The `.snek` directory `snapshot` belongs to; `None` when the
+    /// server wasn't started on one.
+    pub snek_root: Option<PathBuf>,
+    /// `.snek/config.json`, kept current by the watcher.
+    pub project_config: Arc<ArcSwap<ProjectConfig>>,
+    /// The project config the current settings were built from.
+    pub applied_project_config: Arc<Mutex<Arc<ProjectConfig>>>,
+    /// `SNEK_API_URL`, which wins over `apiUrl` in the project config.
+    pub api_url_override: Option<String>,
+    /// `SNEK_MODEL`, which wins over `model` in the project config.
+    pub model_override: Option<String>,
+    /// The client's last `snek` settings section, layered over the project config.
+    pub client_section: Arc<RwLock<Option<Value>>>,
+    /// `snek.resyncIntervalSecs`, for the watchers to subscribe to.
+    pub resync_interval: Arc<watch::Sender<Option<Duration>>>,
+    /// Never write to `.snek` (`--read-only`).
+    pub read_only: bool,
+    /// The user was told that no model is configured; once is enough.
+    pub missing_model_reported: Arc<AtomicBool>,
+    /// Whether completions were last reported enabled by
+    /// `snek/completionsStatus`; `None` before the first report.
+    pub completions_enabled: Arc<Mutex<Option<bool>>>,
 }
 
 impl Backend {
@@ -39,19 +286,300 @@ impl Backend {
         model: Arc<ModelClient>,
         api_key: Arc<RwLock<String>>,
     ) -> Self {
+        let project_config = Arc::new(ArcSwap::from_pointee(ProjectConfig::default()));
         Self {
             client,
             snapshot,
             documents,
             model,
             api_key,
+            last_completions: Arc::new(CompletionHistory::new()),
+            settings: Arc::new(RwLock::new(Settings::default())),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            completion_cache: Arc::new(CompletionCache::new()),
+            persistent_cache: None,
+            acceptance: Arc::new(AcceptanceTracker::new()),
+            request_budget: Arc::new(RequestBudget::new()),
+            workspaces: Arc::new(Workspaces::new()),
+            snek_root: None,
+            applied_project_config: Arc::new(Mutex::new(project_config.load_full())),
+            project_config,
+            api_url_override: None,
+            model_override: None,
+            client_section: Arc::new(RwLock::new(None)),
+            resync_interval: Arc::new(watch::channel(None).0),
+            read_only: false,
+            missing_model_reported: Arc::new(AtomicBool::new(false)),
+            completions_enabled: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Uses `project_config` for settings; call `apply_project_config` to
+    /// apply it before the client's configuration arrives.
+    pub fn with_project_config(mut self, project_config: Arc<ArcSwap<ProjectConfig>>) -> Self {
+        self.applied_project_config = Arc::new(Mutex::new(project_config.load_full()));
+        self.project_config = project_config;
+        self
+    }
+
+    /// Applies `.snek/config.json` under the client's settings, as of the
+    /// last time the client sent them (if ever).
+    pub async fn apply_project_config(&self) {
+        let project = self.project_config.load_full();
+        let previous = std::mem::replace(
+            &mut *self.applied_project_config.lock().unwrap(),
+            project.clone(),
+        );
+        if previous.api_url() != project.api_url() {
+            self.apply_api_url(&project).await;
+        }
+
+        let client_section = self.client_section.read().await.clone();
+        let merged = project.merge(client_section.as_ref());
+        match serde_json::from_value::<Settings>(merged.clone()) {
+            Ok(settings) => self.apply_settings(settings).await,
+            Err(e) => eprintln!("[SNEK] Invalid settings in .snek/config.json: {}", e),
+        }
+
+        let client_model = client_section
+            .as_ref()
+            .and_then(|section| section.get("model"))
+            .and_then(Value::as_str);
+        if let Some(model) = self.configured_model(client_model, &project) {
+            self.model.set_model_name(model.to_string()).await;
+            eprintln!("[SNEK] Model configured: {}", model);
+        }
+    }
+
+    /// The model completions use: the client's `snek.model`, else
+    /// `SNEK_MODEL`, else `model` in the project config.
+    fn configured_model<'a>(
+        &'a self,
+        client_model: Option<&'a str>,
+        project: &'a ProjectConfig,
+    ) -> Option<&'a str> {
+        client_model
+            .filter(|model| !model.is_empty())
+            .or(self.model_override.as_deref())
+            .or(project.model())
+    }
+
+    /// Points the model client at the project's changed `apiUrl`, or back
+    /// at the compiled-in default when it was removed. `SNEK_API_URL` wins.
+    async fn apply_api_url(&self, project: &ProjectConfig) {
+        if let Some(ref api_url) = self.api_url_override {
+            eprintln!(
+                "[SNEK] apiUrl in .snek/config.json changed, but SNEK_API_URL ({}) takes precedence",
+                api_url
+            );
+            return;
+        }
+        let api_url = project.api_url().unwrap_or(DEFAULT_API_URL).to_string();
+        eprintln!("[SNEK] Using API: {}", api_url);
+        self.model.set_api_url(api_url).await;
+    }
+
+    /// Re-applies settings when the watcher has reloaded `.snek/config.json`.
+    async fn refresh_project_config(&self) {
+        let current = self.project_config.load_full();
+        let applied = self.applied_project_config.lock().unwrap().clone();
+        if !Arc::ptr_eq(&applied, &current) {
+            eprintln!("[SNEK] Project config changed, reapplying settings");
+            self.apply_project_config().await;
+        }
+    }
+
+    async fn apply_settings(&self, settings: Settings) {
+        let http_options = settings.http_options();
+        if let Err(e) = self.model.set_http_options(&http_options).await {
+            eprintln!("[SNEK] {:#}", e);
+            self.client
+                .show_message(MessageType::ERROR, format!("Snek: {:#}", e))
+                .await;
+        } else if let Some(warning) = http_options.warning() {
+            self.client.show_message(MessageType::WARNING, warning).await;
+        }
+        self.model.set_fallback(settings.fallback()).await;
+        self.model.set_prompt_options(settings.prompt_options()).await;
+        self.model.set_retry_options(settings.retry_options()).await;
+        self.model.set_sampling_options(settings.sampling_options()).await;
+        self.documents.configure_cache(
+            settings.persist_documents && !self.snapshot.load().scratch,
+            settings.restrict_to_workspace,
+        );
+        self.documents
+            .configure_max_document_bytes(settings.max_document_bytes);
+        if let Some(ref cache) = self.persistent_cache {
+            cache.configure(settings.persistent_cache && !self.snapshot.load().scratch);
+        }
+        for snapshot in std::iter::once(&self.snapshot).chain(self.workspaces.snapshots()) {
+            let current = snapshot.load();
+            if current.max_cache_bytes != settings.max_cache_bytes {
+                let mut limited = (**current).clone();
+                limited.set_max_cache_bytes(settings.max_cache_bytes);
+                snapshot.store(Arc::new(limited));
+            }
         }
+        let resync_interval = settings.resync_interval();
+        self.resync_interval.send_if_modified(|current| {
+            let changed = *current != resync_interval;
+            *current = resync_interval;
+            changed
+        });
+        eprintln!("[SNEK] Settings loaded");
+        *self.settings.write().await = settings;
+    }
+
+    pub fn with_persistent_cache(mut self, cache: Option<PersistentCompletionCache>) -> Self {
+        self.persistent_cache = cache.map(Arc::new);
+        self
+    }
+
+    pub fn with_api_url_override(mut self, api_url: Option<String>) -> Self {
+        self.api_url_override = api_url;
+        self
+    }
+
+    pub fn with_model_override(mut self, model: Option<String>) -> Self {
+        self.model_override = model;
+        self
+    }
+
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    pub fn with_workspaces(mut self, workspaces: Workspaces) -> Self {
+        self.workspaces = Arc::new(workspaces);
+        self
+    }
+
+    pub fn with_snek_root(mut self, snek_root: PathBuf) -> Self {
+        self.snek_root = Some(snek_root);
+        self
+    }
+
+    /// Prefix, suffix and language for a request: from `params.text` when
+    /// the client sent the document inline, otherwise from the store.
+    fn context_for(
+        &self,
+        params: &InlineCompletionParams,
+    ) -> jsonrpc::Result<(String, String, String)> {
+        let uri = params.text_document.uri.as_str();
+        let Position { line, character } = params.position;
+        let language = params.language.clone().filter(|l| !l.trim().is_empty());
+
+        if let Some(ref text) = params.text {
+            let (prefix, suffix) = split_at_position(text, line, character);
+            let language = language.unwrap_or_else(|| INLINE_TEXT_LANGUAGE.to_string());
+            return Ok((prefix, suffix, language));
+        }
+
+        let (prefix, suffix, document_language) = self
+            .documents
+            .get_context(uri, line, character)
+            .ok_or_else(|| {
+                eprintln!("[SNEK] ERROR: Document not found in store: {}", uri);
+                jsonrpc::Error::invalid_params("Document not found or position invalid")
+            })?;
+        Ok((prefix, suffix, language.unwrap_or(document_language)))
+    }
+
+    /// Everything besides the document that shapes the prompt for
+    /// `params`: the client's hints and what the last suggestions tell.
+    /// `prefix` is the whole prefix, before `shape_context`.
+    fn request_hints(
+        &self,
+        params: &InlineCompletionParams,
+        settings: &Settings,
+        prefix: &str,
+        language: &str,
+    ) -> RequestHints {
+        let uri = params.text_document.uri.as_str();
+        let min_chars = if params.continuation {
+            1
+        } else {
+            MIN_DETECTED_CONTINUATION_CHARS
+        };
+        let continuation = settings
+            .continuation
+            .then(|| self.last_completions.continuation(uri, prefix, min_chars))
+            .flatten()
+            .map(|(suggested, accepted)| Continuation {
+                suggested,
+                accepted,
+            });
+        if let Some(ref continuation) = continuation {
+            eprintln!(
+                "[SNEK] Continuing {} of {} chars of the last suggestion",
+                continuation.accepted.len(),
+                continuation.suggested.len()
+            );
+        }
+
+        let non_blank = |text: &Option<String>| text.clone().filter(|s| !s.trim().is_empty());
+        RequestHints {
+            selection: non_blank(&params.selection),
+            extra_context: non_blank(&params.extra_context),
+            instruction: non_blank(&params.instruction),
+            continuation,
+            without_session_context: !params.context.unwrap_or(settings.context_enabled),
+            temperature: params.temperature,
+            context_filter: params.context_filter.clone(),
+            length_factor: self.acceptance.length_factor(language),
+            open_imports: self.open_imports(settings, uri, params.context),
+        }
+    }
+
+    /// Import lines of the documents open besides `uri`, when
+    /// `snek.openBufferImports` is on and the session context is sent.
+    fn open_imports(
+        &self,
+        settings: &Settings,
+        uri: &str,
+        context: Option<bool>,
+    ) -> Vec<(String, Vec<String>)> {
+        if settings.open_buffer_imports && context.unwrap_or(settings.context_enabled) {
+            self.documents.imports_except(uri)
+        } else {
+            vec![]
+        }
+    }
+
+    /// Session context for the document at `uri`.
+    pub fn snapshot_for(&self, uri: &str) -> Arc<ContextSnapshot> {
+        self.session_for(uri).load_full()
+    }
+
+    /// Where the session of the document at `uri` is kept: its workspace
+    /// root's, or `snapshot` outside every root.
+    fn session_for(&self, uri: &str) -> &Arc<ArcSwap<ContextSnapshot>> {
+        self.workspaces.snapshot_for(uri).unwrap_or(&self.snapshot)
+    }
+
+    /// `.snek` of the session `session_for(uri)` returns.
+    fn snek_root_for(&self, uri: &str) -> Option<&Path> {
+        self.workspaces
+            .snek_root_for(uri)
+            .or(self.snek_root.as_deref())
+    }
+
+    /// The session a request about `document` is for; the first root's
+    /// when it names none.
+    fn document_session(
+        &self,
+        document: Option<&TextDocumentIdentifier>,
+    ) -> &Arc<ArcSwap<ContextSnapshot>> {
+        document.map_or(&self.snapshot, |document| self.session_for(document.uri.as_str()))
     }
 
     pub async fn handle_inline_completion(
         &self,
         params: InlineCompletionParams,
     ) -> jsonrpc::Result<InlineCompletionResponse> {
+        self.model.metrics().inline_request();
+
         let uri = params.text_document.uri.to_string();
         let line = params.position.line;
         let character = params.position.character;
@@ -61,13 +589,10 @@ impl Backend {
             uri, line, character
         );
 
-        let (prefix, suffix, language) = self
-            .documents
-            .get_context(&uri, line, character)
-            .ok_or_else(|| {
-                eprintln!("[SNEK] ERROR: Document not found in store: {}", uri);
-                jsonrpc::Error::invalid_params("Document not found or position invalid")
-            })?;
+        check_temperature(params.temperature)?;
+
+        let (prefix, suffix, language) = self.context_for(&params)?;
+        let word = word_before_cursor(&prefix).to_string();
 
         eprintln!(
             "[SNEK] Context retrieved: language={}, prefix_len={}, suffix_len={}",
@@ -76,28 +601,600 @@ impl Backend {
             suffix.len()
         );
 
-        let snapshot = self.snapshot.load();
+        self.refresh_project_config().await;
+        let settings = self.settings.read().await.clone();
+        let snapshot = self.snapshot_for(&uri);
+
+        if !settings.language_enabled(&language) {
+            eprintln!("[SNEK] Completions are disabled for {}, skipping", language);
+            return Ok(InlineCompletionResponse::empty(&snapshot));
+        }
+
+        let prefix_chars = prefix.chars().filter(|c| !c.is_whitespace()).count();
+        if prefix_chars < settings.min_prefix_chars {
+            eprintln!(
+                "[SNEK] Prefix too short ({} < {} chars), skipping completion",
+                prefix_chars, settings.min_prefix_chars
+            );
+            return Ok(InlineCompletionResponse::empty(&snapshot));
+        }
+
+        if !params.invoked && !settings.triggered_by(&prefix) {
+            eprintln!("[SNEK] No trigger character before the cursor, skipping completion");
+            return Ok(InlineCompletionResponse::empty(&snapshot));
+        }
+
+        if let Some(idle) = self.documents.idle_for(&uri)
+            && idle < Duration::from_millis(settings.min_idle_ms)
+        {
+            eprintln!(
+                "[SNEK] Last edit {}ms ago (< {}ms), skipping completion",
+                idle.as_millis(),
+                settings.min_idle_ms
+            );
+            return Ok(InlineCompletionResponse::empty(&snapshot));
+        }
+
+        // Matched against the whole prefix, which windowing would shift
+        let prefix_mark = PrefixMark::new(&prefix);
+        let hints = self.request_hints(&params, &settings, &prefix, &language);
+
+        let (prefix, suffix) = shape_context(&settings, prefix, suffix);
+
         let api_key = self.api_key.read().await.clone();
 
-        let completion = self
+        // Client-supplied context, a continuation, a shortened length or other
+        // documents' imports change the prompt without changing the document
+        let cache_key = self
+            .documents
+            .version(&uri)
+            .filter(|_| {
+                params.selection.is_none()
+                    && params.extra_context.is_none()
+                    && params.instruction.is_none()
+                    && params.language.is_none()
+                    && params.context.is_none()
+                    && params.temperature.is_none()
+                    && params.text.is_none()
+                    && params.context_filter.is_none()
+                    && hints.continuation.is_none()
+                    && hints.length_factor.is_none()
+                    && hints.open_imports.is_empty()
+            })
+            .map(|version| CompletionKey::new(&uri, version, &prefix, &suffix));
+        if let Some(ref key) = cache_key
+            && let Some(completion) = self.completion_cache.get(key)
+        {
+            eprintln!("[SNEK] Reusing cached completion for unchanged context");
+            self.model.metrics().cache_hit();
+            self.last_completions
+                .record_at(&uri, completion.clone(), prefix_mark);
+            return Ok(InlineCompletionResponse::new(completion, &snapshot)
+                .replacing(&word, params.position));
+        }
+
+        // Survives restarts, so it can't rely on the document version
+        let persistent_key = match (&cache_key, &self.persistent_cache) {
+            (Some(_), Some(_)) => Some(PersistentKey::new(
+                &uri,
+                &prefix,
+                &suffix,
+                &snapshot.session_id,
+                snapshot.version,
+                &self.model.model_name().await,
+            )),
+            _ => None,
+        };
+        if let Some(ref key) = persistent_key
+            && let Some(ref cache) = self.persistent_cache
+            && let Some(completion) = cache.get(key)
+        {
+            eprintln!("[SNEK] Reusing completion cached on disk");
+            self.model.metrics().cache_hit();
+            self.last_completions
+                .record_at(&uri, completion.clone(), prefix_mark);
+            if let Some(key) = cache_key {
+                self.completion_cache.insert(key, completion.clone());
+            }
+            return Ok(InlineCompletionResponse::new(completion, &snapshot)
+                .replacing(&word, params.position));
+        }
+
+        if let Some(max) = snapshot.limits.max_requests_per_session {
+            match self
+                .request_budget
+                .spend(&snapshot.session_id, snapshot.requests_used, max)
+            {
+                Spend::Allowed { used } => {
+                    // Nothing on disk to keep the count in
+                    if !self.read_only
+                        && !snapshot.scratch
+                        && !snapshot.session_dir.as_os_str().is_empty()
+                    {
+                        let session_dir = snapshot.session_dir.clone();
+                        tokio::task::spawn_blocking(move || {
+                            if let Err(e) = record_requests_used(&session_dir, used) {
+                                eprintln!("[SNEK] Failed to record request count: {:#}", e);
+                            }
+                        });
+                    }
+                }
+                Spend::Refused { first } => {
+                    eprintln!(
+                        "[SNEK] Session {} reached its cap of {} requests, skipping completion",
+                        snapshot.session_id, max
+                    );
+                    if first {
+                        self.client
+                            .show_message(
+                                MessageType::WARNING,
+                                format!(
+                                    "Snek: session \"{}\" has used all {} of its completion requests \
+                                    (limits.max_requests_per_session). Raise the limit or switch sessions to continue.",
+                                    snapshot.session_id, max
+                                ),
+                            )
+                            .await;
+                    }
+                    return Ok(InlineCompletionResponse::empty(&snapshot));
+                }
+            }
+        }
+
+        let cancel = CancellationToken::new();
+        {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            if let Some(previous) = in_flight.insert(uri.clone(), cancel.clone()) {
+                previous.cancel();
+            }
+        }
+
+        let result = self
             .model
-            .complete(&snapshot, &prefix, &suffix, &language, &uri, &api_key)
-            .await
-            .map_err(|e| {
+            .complete_with_hints(
+                &snapshot, &prefix, &suffix, &language, &uri, &api_key, &cancel, &hints,
+            )
+            .await;
+
+        {
+            // Still registered unless a newer request replaced (and cancelled) us
+            let mut in_flight = self.in_flight.lock().unwrap();
+            if !cancel.is_cancelled() {
+                in_flight.remove(&uri);
+            }
+        }
+
+        if cancel.is_cancelled() {
+            eprintln!("[SNEK] Completion superseded by a newer request: {}", uri);
+            return Ok(InlineCompletionResponse::empty(&snapshot));
+        }
+
+        let Completion {
+            text: completion,
+            model: served_model,
+        } = match result {
+            Ok(completion) => completion,
+            Err(SnekError::ModelTimeout) => {
+                // The user has most likely typed on; nothing worth reporting
+                eprintln!("[SNEK] Model request timed out: {}", uri);
+                return Ok(InlineCompletionResponse::empty(&snapshot));
+            }
+            Err(SnekError::ModelNotConfigured) => {
+                let error_msg = SnekError::ModelNotConfigured.to_string();
+                eprintln!("[SNEK] {}", error_msg);
+                if !self.missing_model_reported.swap(true, Ordering::SeqCst) {
+                    self.client
+                        .show_message(MessageType::ERROR, format!("Snek: {}", error_msg))
+                        .await;
+                }
+                return Err(jsonrpc::Error {
+                    code: jsonrpc::ErrorCode::InvalidRequest,
+                    message: error_msg.into(),
+                    data: None,
+                });
+            }
+            Err(e) => {
                 let error_msg = format!("Model API error: {}", e);
                 eprintln!("[SNEK] {}", error_msg);
-                jsonrpc::Error {
-                    code: jsonrpc::ErrorCode::InternalError,
+                let code = match e {
+                    SnekError::ModelAuth(_) => jsonrpc::ErrorCode::InvalidRequest,
+                    _ => jsonrpc::ErrorCode::InternalError,
+                };
+                return Err(jsonrpc::Error {
+                    code,
                     message: error_msg.into(),
                     data: None,
-                }
-            })?;
+                });
+            }
+        };
+
+        // Leading whitespace is part of prose ("Hello" + " world")
+        let prose = languages::is_prose(&language);
+        let completion = if prose {
+            completion.as_str()
+        } else {
+            completion.trim_start()
+        };
+        let trimmed = trim_prefix_overlap(&prefix, completion);
+        if trimmed.len() != completion.len() {
+            eprintln!(
+                "[SNEK] Trimmed {} chars of echoed prefix from completion",
+                completion.len() - trimmed.len()
+            );
+        }
+
+        let capped = truncate_completion(
+            trimmed,
+            settings.max_completion_lines,
+            settings.max_completion_chars,
+        );
+        if capped.len() != trimmed.len() {
+            eprintln!(
+                "[SNEK] Completion truncated from {} to {} chars by length limits",
+                trimmed.len(),
+                capped.len()
+            );
+        }
+        let capped = if settings.stop_at_block_end {
+            let stopped = stop_at_block_end(&prefix, capped, &language);
+            if stopped.len() != capped.len() {
+                eprintln!(
+                    "[SNEK] Completion cut from {} to {} chars at the end of the block",
+                    capped.len(),
+                    stopped.len()
+                );
+            }
+            stopped
+        } else {
+            capped
+        };
+        let completion = if settings.adjust_newlines && !prose {
+            let adjusted = adjust_leading_newline(&prefix, &suffix, capped, &language);
+            if adjusted.len() != capped.len() {
+                eprintln!("[SNEK] Adjusted leading newline for the insertion point");
+            }
+            adjusted.into_owned()
+        } else {
+            capped.to_string()
+        };
 
-        let completion = completion.trim_start().to_string();
-        
         eprintln!("[SNEK] Completion generated: {} chars", completion.len());
 
-        Ok(InlineCompletionResponse { completion })
+        if !completion.is_empty() {
+            self.last_completions
+                .record_at(&uri, completion.clone(), prefix_mark);
+            if let Some(key) = cache_key {
+                self.completion_cache.insert(key, completion.clone());
+            }
+            if let Some(key) = persistent_key
+                && let Some(ref cache) = self.persistent_cache
+            {
+                cache.insert(key, completion.clone());
+            }
+        }
+
+        let mut response = InlineCompletionResponse::new(completion, &snapshot);
+        response.model = Some(served_model);
+        Ok(response.replacing(&word, params.position))
+    }
+
+    /// Swaps in an empty scratch session for the rest of this run. Open
+    /// documents are no longer mirrored to `.snek/cache`.
+    pub async fn handle_scratch_session(&self) -> jsonrpc::Result<ScratchSessionResponse> {
+        let scratch = ContextSnapshot::scratch();
+        let session_id = scratch.session_id.clone();
+        self.snapshot.store(Arc::new(scratch.clone()));
+        for snapshot in self.workspaces.snapshots() {
+            snapshot.store(Arc::new(scratch.clone()));
+        }
+        self.documents.configure_cache(false, true);
+        self.completion_cache.clear();
+        if let Some(ref cache) = self.persistent_cache {
+            cache.configure(false);
+        }
+        eprintln!("[SNEK] Switched to a scratch session");
+
+        self.client
+            .send_notification::<ContextChanged>(ContextChangedParams {
+                session_id: session_id.clone(),
+                version: 0,
+                reloads: 0,
+            })
+            .await;
+
+        Ok(ScratchSessionResponse { session_id })
+    }
+
+    /// Renders the prompt a `snek/inline` request with `params` would send
+    /// and writes it to `.snek/last_prompt.md`, for bug reports and prompt
+    /// tuning. The API key is never part of it.
+    pub async fn handle_export_prompt(
+        &self,
+        params: InlineCompletionParams,
+    ) -> jsonrpc::Result<ExportPromptResponse> {
+        check_temperature(params.temperature)?;
+
+        let uri = params.text_document.uri.to_string();
+        let (prefix, suffix, language) = self.context_for(&params)?;
+
+        self.refresh_project_config().await;
+        let settings = self.settings.read().await.clone();
+
+        let hints = self.request_hints(&params, &settings, &prefix, &language);
+        let (prefix, suffix) = shape_context(&settings, prefix, suffix);
+
+        let snapshot = self.snapshot_for(&uri);
+        let Some(snek_root) = self
+            .snek_root_for(&uri)
+            .filter(|_| !snapshot.scratch && !self.read_only)
+        else {
+            return Err(jsonrpc::Error::invalid_params(
+                "No .snek directory to export the prompt to (scratch session or read-only mode?)",
+            ));
+        };
+
+        let mut prompt = self
+            .model
+            .render_prompt(&snapshot, &prefix, &suffix, &language, &uri, &hints)
+            .await;
+        // In case the key shows up in the buffer or the context files
+        let api_key = self.api_key.read().await.clone();
+        if !api_key.is_empty() {
+            prompt = prompt.replace(&api_key, "[REDACTED]");
+        }
+
+        let path = snek_root.join(LAST_PROMPT_FILE);
+        tokio::fs::write(&path, prompt).await.map_err(|e| {
+            eprintln!("[SNEK] ERROR: Failed to write {}: {}", path.display(), e);
+            jsonrpc::Error::internal_error()
+        })?;
+        eprintln!("[SNEK] Exported prompt to {}", path.display());
+
+        Ok(ExportPromptResponse {
+            path: path.to_string_lossy().into_owned(),
+        })
+    }
+
+    /// `.snek` of the session a snapshot request is about, unless nothing
+    /// may be written to it.
+    fn writable_snek_root(
+        &self,
+        params: &ContextSnapshotParams,
+        snapshot: &ContextSnapshot,
+    ) -> jsonrpc::Result<PathBuf> {
+        let snek_root = match params.text_document {
+            Some(ref document) => self.snek_root_for(document.uri.as_str()),
+            None => self.snek_root.as_deref(),
+        };
+        snek_root
+            .filter(|_| !snapshot.scratch && !self.read_only)
+            .map(Path::to_path_buf)
+            .ok_or_else(|| {
+                jsonrpc::Error::invalid_params(
+                    "No .snek directory for context snapshots (scratch session or read-only mode?)",
+                )
+            })
+    }
+
+    /// Saves the context files of the session for `text_document` under
+    /// `name`, to go back to with `snek/snapshotRestore`.
+    pub async fn handle_snapshot_save(
+        &self,
+        params: ContextSnapshotParams,
+    ) -> jsonrpc::Result<SnapshotSaveResponse> {
+        let snapshot = self.document_session(params.text_document.as_ref()).load_full();
+        let snek_root = self.writable_snek_root(&params, &snapshot)?;
+        let path = save_context_snapshot(&snek_root, &snapshot.session_dir, &params.name)
+            .map_err(snapshot_error)?;
+        Ok(SnapshotSaveResponse {
+            path: path.to_string_lossy().into_owned(),
+        })
+    }
+
+    /// Puts the context files saved as `name` back into the session for
+    /// `text_document` and reloads it right away, without waiting for the
+    /// watcher.
+    pub async fn handle_snapshot_restore(
+        &self,
+        params: ContextSnapshotParams,
+    ) -> jsonrpc::Result<SnapshotRestoreResponse> {
+        let session = self.document_session(params.text_document.as_ref());
+        let current = session.load_full();
+        let snek_root = self.writable_snek_root(&params, &current)?;
+        restore_context_snapshot(&snek_root, &current.session_dir, &params.name)
+            .map_err(snapshot_error)?;
+
+        let mut restored = load_snapshot(&current.session_dir).map_err(snapshot_error)?;
+        restored.set_max_cache_bytes(current.max_cache_bytes);
+        restored.count_reload(&current);
+        let reloads = restored.reloads;
+        let response = SnapshotRestoreResponse {
+            session_id: restored.session_id.clone(),
+            version: restored.version,
+        };
+        session.store(Arc::new(restored));
+        self.completion_cache.clear();
+
+        self.client
+            .send_notification::<ContextChanged>(ContextChangedParams {
+                session_id: response.session_id.clone(),
+                version: response.version,
+                reloads,
+            })
+            .await;
+        Ok(response)
+    }
+
+    /// Whether a standard completion request fired by a trigger character
+    /// is right after one of the triggers for the document's language, so
+    /// e.g. a lone `:` in a Rust type annotation doesn't ask the model.
+    async fn after_trigger(&self, position: &TextDocumentPositionParams) -> bool {
+        let Position { line, character } = position.position;
+        let Some((prefix, _, language)) =
+            self.documents
+                .get_context(position.text_document.uri.as_str(), line, character)
+        else {
+            // Left for the inline handler to report
+            return true;
+        };
+        let settings = self.settings.read().await;
+        let triggered = settings
+            .triggers_for(&language)
+            .iter()
+            .any(|trigger| !trigger.is_empty() && prefix.ends_with(trigger));
+        if !triggered {
+            eprintln!("[SNEK] Not after a {} trigger, skipping completion", language);
+        }
+        triggered
+    }
+
+    /// The documents the server holds, to diagnose a client and server that
+    /// disagree about what is open.
+    pub async fn handle_documents_list(&self) -> jsonrpc::Result<DocumentsListResponse> {
+        let documents = self
+            .documents
+            .list_open_uris()
+            .into_iter()
+            .map(|uri| OpenDocument {
+                version: self.documents.version(&uri),
+                uri,
+            })
+            .collect();
+        Ok(DocumentsListResponse { documents })
+    }
+
+    pub async fn handle_documents_reset(&self) -> jsonrpc::Result<DocumentsResetResponse> {
+        let cleared = self.documents.list_open_uris();
+        self.documents.clear();
+        eprintln!("[SNEK] Document store reset ({} documents dropped)", cleared.len());
+        Ok(DocumentsResetResponse { cleared })
+    }
+
+    /// Sends `snek/completionsStatus` when the API key went missing or was
+    /// loaded since the last report.
+    async fn report_completions_status(&self) {
+        let enabled = !self.api_key.read().await.is_empty();
+        let previous = self.completions_enabled.lock().unwrap().replace(enabled);
+        if previous == Some(enabled) {
+            return;
+        }
+
+        let reason = (!enabled).then(|| {
+            "Snek completions are disabled: no API key configured. Set 'snek.apiKey' or 'snek.apiKeyFile'."
+                .to_string()
+        });
+        self.client
+            .send_notification::<CompletionsStatus>(CompletionsStatusParams { enabled, reason })
+            .await;
+    }
+
+    /// Pulls the configuration from the client again, for settings changes
+    /// the client didn't report.
+    pub async fn handle_reload_config(&self) -> jsonrpc::Result<ReloadConfigResponse> {
+        self.load_configuration().await.map_err(|e| jsonrpc::Error {
+            code: jsonrpc::ErrorCode::InternalError,
+            message: e.into(),
+            data: None,
+        })?;
+        Ok(ReloadConfigResponse {
+            model: self.model.model_name().await,
+            api_key_configured: !self.api_key.read().await.is_empty(),
+        })
+    }
+
+    /// Estimated tokens of each markdown file and snippet in the session of
+    /// `params.text_document`, to find what is making prompts slow or large.
+    pub async fn handle_context_budget(
+        &self,
+        params: ContextBudgetParams,
+    ) -> jsonrpc::Result<ContextBudget> {
+        let session = self.document_session(params.text_document.as_ref());
+        Ok(ContextBudget::for_snapshot(&session.load()))
+    }
+
+    pub async fn handle_completion_feedback(
+        &self,
+        params: CompletionFeedbackParams,
+        outcome: CompletionOutcome,
+    ) -> jsonrpc::Result<CompletionFeedbackResponse> {
+        let uri = params.text_document.uri.to_string();
+
+        let Some(completion) = self.last_completions.last(&uri) else {
+            eprintln!(
+                "[SNEK] Completion feedback ({}) for {} but no completion on record",
+                outcome.as_str(),
+                uri
+            );
+            return Ok(CompletionFeedbackResponse { recorded: false });
+        };
+
+        eprintln!(
+            "[SNEK] Completion {}: uri={}, {} chars",
+            outcome.as_str(),
+            uri,
+            completion.len()
+        );
+        self.client
+            .log_message(
+                MessageType::INFO,
+                format!("Snek completion {} ({} chars)", outcome.as_str(), completion.len()),
+            )
+            .await;
+
+        Ok(CompletionFeedbackResponse { recorded: true })
+    }
+
+    /// Records how much of the last suggestion for a document was kept, so
+    /// later requests in its language ask for shorter completions when
+    /// users keep taking only their start.
+    pub async fn handle_partial_acceptance(&self, params: PartialAcceptanceParams) {
+        let uri = params.text_document.uri.to_string();
+        let (Some(completion), Some(language)) = (
+            self.last_completions.last(&uri),
+            self.documents.language_id(&uri),
+        ) else {
+            eprintln!(
+                "[SNEK] Completion feedback for {} but no completion or document on record",
+                uri
+            );
+            return;
+        };
+
+        let completion_chars = completion.chars().count();
+        eprintln!(
+            "[SNEK] Completion feedback: uri={}, {} of {} chars kept",
+            uri, params.accepted_chars, completion_chars
+        );
+        self.acceptance
+            .record(&language, params.accepted_chars, completion_chars);
+        if let Some(factor) = self.acceptance.length_factor(&language) {
+            eprintln!(
+                "[SNEK] Requesting {:.0}% of max_tokens for {}",
+                factor * 100.0,
+                language
+            );
+        }
+    }
+
+    /// When `snek.warmup` is on, pings the model in the background so the
+    /// first completion doesn't pay for connection setup. Returns the task.
+    pub async fn start_warmup(&self) -> Option<tokio::task::JoinHandle<()>> {
+        if !self.settings.read().await.warmup {
+            return None;
+        }
+
+        let model = self.model.clone();
+        let api_key = self.api_key.read().await.clone();
+        Some(tokio::spawn(async move {
+            let started = std::time::Instant::now();
+            match model.ping(&api_key).await {
+                Ok(()) => eprintln!(
+                    "[SNEK] Model warm-up finished in {}ms",
+                    started.elapsed().as_millis()
+                ),
+                Err(e) => eprintln!("[SNEK] Model warm-up failed: {:#}", e),
+            }
+        }))
     }
 
     async fn load_configuration(&self) -> Result<(), String> {
@@ -110,11 +1207,57 @@ impl Backend {
                 scope_uri: None,
                 section: Some("snek.model".to_string()),
             },
+            ConfigurationItem {
+                scope_uri: None,
+                section: Some("snek".to_string()),
+            },
         ];
 
         match self.client.configuration(config_items).await {
             Ok(configs) => {
-                if let Some(Value::String(api_key)) = configs.get(0) {
+                let client_section = configs.get(2).filter(|section| section.is_object()).cloned();
+                *self.client_section.write().await = client_section.clone();
+
+                let project = self.project_config.load_full();
+                *self.applied_project_config.lock().unwrap() = project.clone();
+                let settings = match serde_json::from_value::<Settings>(
+                    project.merge(client_section.as_ref()),
+                ) {
+                    Ok(settings) => Some(settings),
+                    Err(e) => {
+                        eprintln!("[SNEK] Invalid snek settings: {}", e);
+                        self.client
+                            .show_message(
+                                MessageType::WARNING,
+                                format!("Invalid Snek settings, using defaults: {}", e),
+                            )
+                            .await;
+                        None
+                    }
+                };
+
+                let api_key_file = settings
+                    .as_ref()
+                    .and_then(|s| s.api_key_file.clone())
+                    .filter(|p| !p.is_empty());
+
+                if let Some(path) = api_key_file {
+                    match read_api_key_file(Path::new(&path)) {
+                        Ok(api_key) => {
+                            *self.api_key.write().await = api_key;
+                            eprintln!("[SNEK] API key loaded from snek.apiKeyFile");
+                            self.client
+                                .log_message(MessageType::INFO, "Snek API key configured from file")
+                                .await;
+                        }
+                        Err(e) => {
+                            eprintln!("[SNEK] {:#}", e);
+                            self.client
+                                .show_message(MessageType::ERROR, format!("Snek: {:#}", e))
+                                .await;
+                        }
+                    }
+                } else if let Some(Value::String(api_key)) = configs.first() {
                     if !api_key.is_empty() {
                         let mut key = self.api_key.write().await;
                         *key = api_key.clone();
@@ -122,6 +1265,8 @@ impl Backend {
                         self.client
                             .log_message(MessageType::INFO, "Snek API key configured")
                             .await;
+                    } else if !self.api_key.read().await.is_empty() {
+                        eprintln!("[SNEK] API key is empty in VSCode settings, keeping key from environment");
                     } else {
                         eprintln!("[SNEK] API key is empty in VSCode settings");
                         self.client
@@ -131,6 +1276,8 @@ impl Backend {
                             )
                             .await;
                     }
+                } else if !self.api_key.read().await.is_empty() {
+                    eprintln!("[SNEK] API key not found in VSCode settings, keeping key from environment");
                 } else {
                     eprintln!("[SNEK] API key not found in VSCode settings");
                     self.client
@@ -141,16 +1288,20 @@ impl Backend {
                         .await;
                 }
 
-                if let Some(Value::String(model)) = configs.get(1) {
-                    if !model.is_empty() {
-                        self.model.set_model_name(model.clone()).await;
-                        eprintln!("[SNEK] Model configured: {}", model);
-                        self.client
-                            .log_message(MessageType::INFO, format!("Snek model set to: {}", model))
-                            .await;
-                    }
+                let client_model = configs.get(1).and_then(Value::as_str);
+                if let Some(model) = self.configured_model(client_model, &project) {
+                    self.model.set_model_name(model.to_string()).await;
+                    eprintln!("[SNEK] Model configured: {}", model);
+                    self.client
+                        .log_message(MessageType::INFO, format!("Snek model set to: {}", model))
+                        .await;
+                }
+
+                if let Some(settings) = settings {
+                    self.apply_settings(settings).await;
                 }
 
+                self.report_completions_status().await;
                 Ok(())
             }
             Err(e) => {
@@ -161,14 +1312,69 @@ impl Backend {
     }
 }
 
+/// Highest temperature accepted from a client; OpenAI-compatible APIs
+/// reject anything above it.
+const MAX_REQUEST_TEMPERATURE: f32 = 2.0;
+
+fn check_temperature(temperature: Option<f32>) -> jsonrpc::Result<()> {
+    match temperature {
+        Some(t) if !(0.0..=MAX_REQUEST_TEMPERATURE).contains(&t) => Err(
+            jsonrpc::Error::invalid_params(format!(
+                "temperature must be between 0 and {}, got {}",
+                MAX_REQUEST_TEMPERATURE, t
+            )),
+        ),
+        _ => Ok(()),
+    }
+}
+
+fn snapshot_error(e: SnekError) -> jsonrpc::Error {
+    eprintln!("[SNEK] {}", e);
+    match e {
+        SnekError::Config(message) => jsonrpc::Error::invalid_params(message),
+        e => jsonrpc::Error {
+            code: jsonrpc::ErrorCode::InternalError,
+            message: e.to_string().into(),
+            data: None,
+        },
+    }
+}
+
+/// The part of the document sent to the model, per `settings`.
+fn shape_context(settings: &Settings, prefix: String, suffix: String) -> (String, String) {
+    let (prefix, suffix) = if settings.collapse_blank_lines {
+        collapse_blank_lines(&prefix, &suffix)
+    } else {
+        (prefix, suffix)
+    };
+    match settings.full_file_threshold {
+        Some(max_lines) => {
+            let (prefix, suffix) = window_context(&prefix, &suffix, max_lines);
+            (prefix.to_string(), suffix.to_string())
+        }
+        None => (prefix, suffix),
+    }
+}
+
 #[tower_lsp::async_trait]
 impl LanguageServer for Backend {
-    async fn initialize(&self, _params: InitializeParams) -> jsonrpc::Result<InitializeResult> {
+    async fn initialize(&self, params: InitializeParams) -> jsonrpc::Result<InitializeResult> {
+        // The client's `snek` settings, until `workspace/configuration` answers
+        if let Some(options) = params.initialization_options.filter(Value::is_object) {
+            *self.client_section.write().await = Some(options);
+            self.apply_project_config().await;
+        }
+        let trigger_characters = self.settings.read().await.advertised_trigger_characters();
+
         Ok(InitializeResult {
             capabilities: ServerCapabilities {
                 text_document_sync: Some(TextDocumentSyncCapability::Kind(
                     TextDocumentSyncKind::FULL,
                 )),
+                completion_provider: Some(CompletionOptions {
+                    trigger_characters: Some(trigger_characters),
+                    ..Default::default()
+                }),
                 ..Default::default()
             },
             server_info: Some(ServerInfo {
@@ -183,6 +1389,14 @@ impl LanguageServer for Backend {
             .log_message(MessageType::INFO, "Snek LSP initialized")
             .await;
 
+        let snapshot = self.snapshot.load_full();
+        if let Some(warning) = snapshot.limits.warning() {
+            self.client.show_message(MessageType::WARNING, warning).await;
+        }
+        if let Some(warning) = snapshot.skipped_sources_warning(None) {
+            self.client.show_message(MessageType::WARNING, warning).await;
+        }
+
         if let Err(e) = self.load_configuration().await {
             self.client
                 .log_message(
@@ -191,6 +1405,8 @@ impl LanguageServer for Backend {
                 )
                 .await;
         }
+
+        self.start_warmup().await;
     }
 
     async fn did_change_configuration(&self, _params: DidChangeConfigurationParams) {
@@ -208,6 +1424,55 @@ impl LanguageServer for Backend {
         Ok(())
     }
 
+    /// Standard completion requests, for clients without `snek/inline`:
+    /// the inline completion as a single item.
+    async fn completion(
+        &self,
+        params: CompletionParams,
+    ) -> jsonrpc::Result<Option<CompletionResponse>> {
+        let invoked = params
+            .context
+            .is_none_or(|context| context.trigger_kind == CompletionTriggerKind::INVOKED);
+        let position = params.text_document_position;
+        if !invoked && !self.after_trigger(&position).await {
+            return Ok(None);
+        }
+        let response = self
+            .handle_inline_completion(InlineCompletionParams {
+                text_document: position.text_document,
+                position: position.position,
+                selection: None,
+                extra_context: None,
+                instruction: None,
+                continuation: false,
+                language: None,
+                context: None,
+                invoked,
+                temperature: None,
+                text: None,
+                context_filter: None,
+            })
+            .await?;
+
+        if response.completion.is_empty() {
+            return Ok(None);
+        }
+        let label = response
+            .completion
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .unwrap_or_default()
+            .to_string();
+        Ok(Some(CompletionResponse::Array(vec![CompletionItem {
+            label,
+            kind: Some(CompletionItemKind::TEXT),
+            detail: Some("Snek".to_string()),
+            insert_text: Some(response.completion),
+            ..Default::default()
+        }])))
+    }
+
     async fn did_open(&self, params: DidOpenTextDocumentParams) {
         let doc = params.text_document;
         self.documents
@@ -216,13 +1481,24 @@ impl LanguageServer for Backend {
 
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
         let uri = params.text_document.uri.to_string();
-        if let Some(change) = params.content_changes.first() {
-            self.documents.did_change(&uri, change.text.clone());
+        if params.content_changes.is_empty() {
+            return;
+        }
+        // We advertise FULL sync, so each change carries the whole document;
+        // applied in order, the last one wins
+        for change in params.content_changes {
+            if change.range.is_some() {
+                eprintln!("[SNEK] Ignoring incremental change to {} (full sync expected)", uri);
+                continue;
+            }
+            self.documents.did_change(&uri, change.text);
         }
+        self.completion_cache.invalidate(&uri);
     }
 
     async fn did_close(&self, params: DidCloseTextDocumentParams) {
         let uri = params.text_document.uri.to_string();
         self.documents.did_close(&uri);
+        self.completion_cache.invalidate(&uri);
     }
 }
\ No newline at end of file
@@ -1,2 +1,3 @@
 pub mod backend;
-pub mod server;
+pub mod notifications;
+pub mod server;
\ No newline at end of file
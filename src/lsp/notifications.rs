@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+use tower_lsp::lsp_types::notification::Notification;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ContextChangedParams {
+    pub session_id: String,
+    pub version: u64,
+    /// Goes up on every reload, even when `version` stays the same.
+    pub reloads: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompletionsStatusParams {
+    pub enabled: bool,
+    /// Why completions are disabled, for the client to show until they are
+    /// enabled again.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Sent whenever completions become usable or unusable, e.g. when the API
+/// key goes missing or loads, so clients can keep a persistent indicator.
+pub enum CompletionsStatus {}
+
+impl Notification for CompletionsStatus {
+    type Params = CompletionsStatusParams;
+    const METHOD: &'static str = "snek/completionsStatus";
+}
+
+/// Sent after the watcher swaps in a new context snapshot.
+pub enum ContextChanged {}
+
+impl Notification for ContextChanged {
+    type Params = ContextChangedParams;
+    const METHOD: &'static str = "snek/contextChanged";
+}
@@ -1,3 +1,5 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
@@ -5,48 +7,168 @@ use anyhow::{Context, Result};
 use arc_swap::ArcSwap;
 use tower_lsp::{LspService, Server};
 
+use crate::config::{load_env_files, EnvConfig, DEFAULT_API_URL, DEFAULT_MODEL};
+use crate::completion_cache::PersistentCompletionCache;
+use crate::document_cache::DocumentCache;
 use crate::document_store::DocumentStore;
-use crate::lsp::backend::{Backend, InlineCompletionParams};
+use crate::history::CompletionOutcome;
+use crate::lsp::backend::{
+    Backend, CompletionFeedbackParams, ContextBudgetParams, ContextSnapshotParams,
+    InlineCompletionParams, PartialAcceptanceParams,
+};
+use crate::metrics;
 use crate::model::ModelClient;
-use crate::session_io::{find_workspace_root, load_snapshot, resolve_active_session};
+use crate::project_config::ProjectConfig;
+use crate::snapshot::ContextSnapshot;
+use crate::session_io::{
+    find_workspace_root, load_snapshot, locate_workspace_root, prune_sessions,
+    resolve_active_session,
+};
 use crate::watcher::SessionWatcher;
+use crate::workspaces::Workspaces;
 
-pub async fn serve_stdio(workspace_dir: Option<std::path::PathBuf>) -> Result<()> {
+/// Serves LSP over stdio. With several `workspace_dirs` (a multi-root
+/// workspace) each root gets its own `.snek` and session, and requests use
+/// the session of the root containing the document. The first root also
+/// provides the `.env` files and the document cache.
+///
+/// In `read_only` mode nothing is written to `.snek`: existing sessions are
+/// read (and watched) as usual, and without a `.snek` the server runs on an
+/// empty in-memory session as in `scratch` mode.
+pub async fn serve_stdio(
+    workspace_dirs: Vec<PathBuf>,
+    metrics_addr: Option<SocketAddr>,
+    scratch: bool,
+    read_only: bool,
+) -> Result<()> {
     eprintln!("[SNEK] Initializing workspace...");
 
-    let snek_root = find_workspace_root(workspace_dir).context("Failed to find or create .snek/ directory")?;
+    let workspace_dir = workspace_dirs.first().cloned();
+
+    let existing_root = if read_only && !scratch {
+        eprintln!("[SNEK] Read-only mode: nothing will be written to .snek/");
+        let root = locate_workspace_root(workspace_dir.clone())
+            .context("Failed to look for a .snek/ directory")?;
+        if root.is_none() {
+            eprintln!("[SNEK] Read-only mode: no .snek/ directory found, using an empty in-memory session");
+        }
+        root
+    } else {
+        None
+    };
+    let scratch = scratch || (read_only && existing_root.is_none());
+
+    // Scratch mode still reads the workspace's .env files but creates nothing
+    let snek_root = if let Some(root) = existing_root {
+        root
+    } else if scratch {
+        match workspace_dir {
+            Some(dir) => dir.join(".snek"),
+            None => std::env::current_dir()
+                .context("Failed to read current directory")?
+                .join(".snek"),
+        }
+    } else {
+        find_workspace_root(workspace_dir).context("Failed to find or create .snek/ directory")?
+    };
     eprintln!("[SNEK] Workspace root: {:?}", snek_root);
 
-    let session_dir =
-        resolve_active_session(&snek_root).context("Failed to resolve active session")?;
-    eprintln!("[SNEK] Active session: {:?}", session_dir);
+    load_env_files(&snek_root);
+    let env_config = EnvConfig::from_env();
 
-    let snapshot = load_snapshot(&session_dir).context("Failed to load session snapshot")?;
-    eprintln!(
-        "[SNEK] Loaded session: {} (version {})",
-        snapshot.session_id, snapshot.version
-    );
+    let snapshot = if scratch {
+        eprintln!("[SNEK] Scratch mode: using an empty session that is never written to disk");
+        ContextSnapshot::scratch()
+    } else {
+        load_session(&snek_root, &env_config, read_only)?
+    };
 
     let snapshot_arc = Arc::new(ArcSwap::from_pointee(snapshot));
 
-    eprintln!("[SNEK] Starting file watcher...");
-    let _watcher = SessionWatcher::start(snek_root.clone(), snapshot_arc.clone())?;
+    let project_config = ProjectConfig::load(&snek_root).unwrap_or_else(|e| {
+        eprintln!("[SNEK] Ignoring project config: {}", e);
+        ProjectConfig::default()
+    });
+    let project_config = Arc::new(ArcSwap::from_pointee(project_config));
+
+    let mut roots = vec![(snek_root.clone(), snapshot_arc.clone())];
+    let mut workspaces = Workspaces::new();
+    if !scratch && workspace_dirs.len() > 1 {
+        workspaces.add(workspace_dirs[0].clone(), snek_root.clone(), snapshot_arc.clone());
+        for dir in &workspace_dirs[1..] {
+            let root = if read_only {
+                let Some(root) = locate_workspace_root(Some(dir.clone()))
+                    .with_context(|| format!("Failed to look for a .snek/ directory in {:?}", dir))?
+                else {
+                    eprintln!("[SNEK] Read-only mode: no .snek/ directory in {:?}, skipping it", dir);
+                    continue;
+                };
+                root
+            } else {
+                find_workspace_root(Some(dir.clone())).with_context(|| {
+                    format!("Failed to find or create .snek/ directory in {:?}", dir)
+                })?
+            };
+            eprintln!("[SNEK] Workspace root: {:?}", root);
+            let snapshot =
+                Arc::new(ArcSwap::from_pointee(load_session(&root, &env_config, read_only)?));
+            workspaces.add(dir.clone(), root.clone(), snapshot.clone());
+            roots.push((root, snapshot));
+        }
+    }
 
-    let api_key = Arc::new(RwLock::new(String::new()));
-    let api_url = "https://api.cerebras.ai/v1/chat/completions".to_string();
-    let model_name = "qwen-3-235b-a22b-instruct-2507".to_string();
+    let env_api_key = env_config.resolve_api_key().unwrap_or_else(|e| {
+        eprintln!("[SNEK] {:#}", e);
+        None
+    });
+    let api_key = Arc::new(RwLock::new(env_api_key.clone().unwrap_or_default()));
+    let startup_config = project_config.load();
+    let api_url = env_config
+        .api_url
+        .clone()
+        .or_else(|| startup_config.api_url().map(str::to_string))
+        .unwrap_or_else(|| DEFAULT_API_URL.to_string());
+    let model_name = env_config
+        .model
+        .clone()
+        .unwrap_or_else(|| DEFAULT_MODEL.to_string());
 
-    eprintln!("[SNEK] Using Cerebras API: {}", api_url);
+    eprintln!("[SNEK] Using API: {}", api_url);
     eprintln!("[SNEK] Default model: {}", model_name);
-    eprintln!("[SNEK] API key will be loaded from VSCode settings after initialization");
+    if env_api_key.is_some() {
+        eprintln!("[SNEK] API key loaded from environment");
+    } else {
+        eprintln!("[SNEK] API key will be loaded from VSCode settings after initialization");
+    }
 
     let model = Arc::new(ModelClient::new(api_url, model_name));
-    let documents = Arc::new(DocumentStore::new());
+
+    if let Some(addr) = metrics_addr {
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to bind metrics server to {}", addr))?;
+        eprintln!("[SNEK] Serving metrics on http://{}/metrics", addr);
+        tokio::spawn(metrics::serve(listener, model.metrics().clone()));
+    }
+    let documents = if scratch || read_only {
+        Arc::new(DocumentStore::new())
+    } else {
+        Arc::new(DocumentStore::with_cache(DocumentCache::new(
+            snek_root.join("cache").join("documents"),
+            snek_root.parent().map(|p| p.to_path_buf()),
+        )))
+    };
+
+    let persistent_cache = (!scratch && !read_only).then(|| {
+        PersistentCompletionCache::new(snek_root.join("cache").join("completions.json"))
+    });
 
     let stdin = tokio::io::stdin();
     let stdout = tokio::io::stdout();
 
+    let mut lsp_client = None;
     let (service, socket) = LspService::build(|client| {
+        lsp_client = Some(client.clone());
         Backend::new(
             client,
             snapshot_arc.clone(),
@@ -54,6 +176,13 @@ pub async fn serve_stdio(workspace_dir: Option<std::path::PathBuf>) -> Result<()
             model.clone(),
             api_key.clone(),
         )
+        .with_workspaces(workspaces)
+        .with_snek_root(snek_root.clone())
+        .with_project_config(project_config.clone())
+        .with_api_url_override(env_config.api_url.clone())
+        .with_model_override(env_config.model.clone())
+        .with_persistent_cache(persistent_cache)
+        .with_read_only(read_only)
     })
     .custom_method(
         "snek/inline",
@@ -62,14 +191,127 @@ pub async fn serve_stdio(workspace_dir: Option<std::path::PathBuf>) -> Result<()
             async move { backend.handle_inline_completion(params).await }
         },
     )
+    .custom_method(
+        "snek/acceptedLast",
+        |backend: &Backend, params: CompletionFeedbackParams| {
+            let backend = backend.clone();
+            async move {
+                backend
+                    .handle_completion_feedback(params, CompletionOutcome::Accepted)
+                    .await
+            }
+        },
+    )
+    .custom_method(
+        "snek/rejectedLast",
+        |backend: &Backend, params: CompletionFeedbackParams| {
+            let backend = backend.clone();
+            async move {
+                backend
+                    .handle_completion_feedback(params, CompletionOutcome::Rejected)
+                    .await
+            }
+        },
+    )
+    .custom_method(
+        "snek/completion/feedback",
+        |backend: &Backend, params: PartialAcceptanceParams| {
+            let backend = backend.clone();
+            async move { backend.handle_partial_acceptance(params).await }
+        },
+    )
+    .custom_method(
+        "snek/exportPrompt",
+        |backend: &Backend, params: InlineCompletionParams| {
+            let backend = backend.clone();
+            async move { backend.handle_export_prompt(params).await }
+        },
+    )
+    .custom_method(
+        "snek/snapshotSave",
+        |backend: &Backend, params: ContextSnapshotParams| {
+            let backend = backend.clone();
+            async move { backend.handle_snapshot_save(params).await }
+        },
+    )
+    .custom_method(
+        "snek/snapshotRestore",
+        |backend: &Backend, params: ContextSnapshotParams| {
+            let backend = backend.clone();
+            async move { backend.handle_snapshot_restore(params).await }
+        },
+    )
+    .custom_method("snek/documents/list", |backend: &Backend, (): ()| {
+        let backend = backend.clone();
+        async move { backend.handle_documents_list().await }
+    })
+    .custom_method("snek/documents/reset", |backend: &Backend, (): ()| {
+        let backend = backend.clone();
+        async move { backend.handle_documents_reset().await }
+    })
+    .custom_method("snek/reloadConfig", |backend: &Backend, (): ()| {
+        let backend = backend.clone();
+        async move { backend.handle_reload_config().await }
+    })
+    .custom_method(
+        "snek/context/budget",
+        |backend: &Backend, params: ContextBudgetParams| {
+            let backend = backend.clone();
+            async move { backend.handle_context_budget(params).await }
+        },
+    )
+    .custom_method("snek/session/scratch", |backend: &Backend, (): ()| {
+        let backend = backend.clone();
+        async move { backend.handle_scratch_session().await }
+    })
     .finish();
 
+    // Project defaults apply until the client sends its configuration
+    service.inner().apply_project_config().await;
+
+    let mut _watchers = vec![];
+    if !scratch {
+        eprintln!("[SNEK] Starting file watcher...");
+        for (i, (root, snapshot)) in roots.into_iter().enumerate() {
+            // Only the first root's config.json feeds the settings
+            let config = (i == 0).then(|| project_config.clone());
+            _watchers.push(SessionWatcher::start_with_project_config(
+                root,
+                snapshot,
+                lsp_client.clone(),
+                config,
+                service.inner().resync_interval.subscribe(),
+            )?);
+        }
+    }
+
     eprintln!("[SNEK] Server ready, listening on stdio...");
     Server::new(stdin, stdout, socket).serve(service).await;
 
     Ok(())
 }
 
+/// Loads the active session of `snek_root`, pruning old sessions first when
+/// `SNEK_MAX_SESSIONS` is set (unless `read_only`).
+fn load_session(snek_root: &Path, env_config: &EnvConfig, read_only: bool) -> Result<ContextSnapshot> {
+    let session_dir =
+        resolve_active_session(snek_root).context("Failed to resolve active session")?;
+    eprintln!("[SNEK] Active session: {:?}", session_dir);
+
+    if let Some(max_sessions) = env_config.max_sessions.filter(|_| !read_only)
+        && let Err(e) = prune_sessions(snek_root, max_sessions)
+    {
+        eprintln!("[SNEK] Failed to prune old sessions: {}", e);
+    }
+
+    let snapshot = load_snapshot(&session_dir).context("Failed to load session snapshot")?;
+    eprintln!(
+        "[SNEK] Loaded session: {} (version {})",
+        snapshot.session_id, snapshot.version
+    );
+    Ok(snapshot)
+}
+
 impl Clone for Backend {
     fn clone(&self) -> Self {
         Self {
@@ -78,6 +320,24 @@ impl Clone for Backend {
             documents: self.documents.clone(),
             model: self.model.clone(),
             api_key: self.api_key.clone(),
+            last_completions: self.last_completions.clone(),
+            settings: self.settings.clone(),
+            in_flight: self.in_flight.clone(),
+            completion_cache: self.completion_cache.clone(),
+            persistent_cache: self.persistent_cache.clone(),
+            acceptance: self.acceptance.clone(),
+            request_budget: self.request_budget.clone(),
+            workspaces: self.workspaces.clone(),
+            snek_root: self.snek_root.clone(),
+            project_config: self.project_config.clone(),
+            api_url_override: self.api_url_override.clone(),
+            model_override: self.model_override.clone(),
+            applied_project_config: self.applied_project_config.clone(),
+            client_section: self.client_section.clone(),
+            resync_interval: self.resync_interval.clone(),
+            read_only: self.read_only,
+            missing_model_reported: self.missing_model_reported.clone(),
+            completions_enabled: self.completions_enabled.clone(),
         }
     }
 }
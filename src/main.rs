@@ -1,4 +1,5 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use snek::config::{resolve_workspace_dirs, WorkspaceDirSource, WORKSPACE_DIR_ENV};
 use snek::lsp::server;
 use std::env;
 use std::path::PathBuf;
@@ -8,15 +9,37 @@ async fn main() -> Result<()> {
     eprintln!("[SNEK] Starting Snek Language Server...");
 
     let args: Vec<String> = env::args().collect();
-    let workspace_dir = parse_workspace_dir(&args);
+    let (workspace_dirs, source) =
+        resolve_workspace_dirs(parse_workspace_dirs(&args), env::var(WORKSPACE_DIR_ENV).ok());
 
-    if let Some(ref dir) = workspace_dir {
-        eprintln!("[SNEK] Workspace directory provided: {}", dir.display());
-    } else {
-        eprintln!("[SNEK] No workspace directory provided, will search from current directory");
+    match source {
+        WorkspaceDirSource::Flag => {
+            for dir in &workspace_dirs {
+                eprintln!("[SNEK] Workspace directory provided: {}", dir.display());
+            }
+        }
+        WorkspaceDirSource::Env => {
+            for dir in &workspace_dirs {
+                eprintln!(
+                    "[SNEK] Workspace directory from {}: {}",
+                    WORKSPACE_DIR_ENV,
+                    dir.display()
+                );
+            }
+        }
+        WorkspaceDirSource::CurrentDir => {
+            eprintln!("[SNEK] No workspace directory provided, will search from current directory");
+        }
     }
 
-    match server::serve_stdio(workspace_dir).await {
+    let metrics_addr = parse_flag(&args, "--metrics-addr")
+        .map(|addr| addr.parse().with_context(|| format!("Invalid --metrics-addr: {}", addr)))
+        .transpose()?;
+
+    let scratch = args.iter().any(|arg| arg == "--scratch");
+    let read_only = args.iter().any(|arg| arg == "--read-only");
+
+    match server::serve_stdio(workspace_dirs, metrics_addr, scratch, read_only).await {
         Ok(()) => {
             eprintln!("[SNEK] Server shutdown gracefully");
             Ok(())
@@ -28,20 +51,33 @@ async fn main() -> Result<()> {
     }
 }
 
-
-
-fn parse_workspace_dir(args: &[String]) -> Option<PathBuf> {
+/// Every `--workspace-dir` (or `--workspace`), in order; repeated for
+/// multi-root workspaces.
+fn parse_workspace_dirs(args: &[String]) -> Vec<PathBuf> {
+    let mut dirs = vec![];
     for (i, arg) in args.iter().enumerate() {
         if arg == "--workspace-dir" || arg == "--workspace" {
             if let Some(path) = args.get(i + 1) {
-                return Some(PathBuf::from(path));
+                dirs.push(PathBuf::from(path));
             }
         } else if arg.starts_with("--workspace-dir=") {
             let path = arg.strip_prefix("--workspace-dir=").unwrap();
-            return Some(PathBuf::from(path));
+            dirs.push(PathBuf::from(path));
         } else if arg.starts_with("--workspace=") {
             let path = arg.strip_prefix("--workspace=").unwrap();
-            return Some(PathBuf::from(path));
+            dirs.push(PathBuf::from(path));
+        }
+    }
+    dirs
+}
+
+/// Value of `--name <value>` or `--name=<value>`.
+fn parse_flag<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    for (i, arg) in args.iter().enumerate() {
+        if arg == name {
+            return args.get(i + 1).map(String::as_str);
+        } else if let Some(value) = arg.strip_prefix(name).and_then(|v| v.strip_prefix('=')) {
+            return Some(value);
         }
     }
     None
@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::error::SnekError;
+
+/// Upper bounds, in seconds, of the model latency histogram buckets.
+const LATENCY_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Counters and a latency histogram for the completion path, rendered in
+/// the Prometheus text format. Always collected; only served when the
+/// server is started with `--metrics-addr`.
+pub struct Metrics {
+    inline_requests: AtomicU64,
+    cache_hits: AtomicU64,
+    model_requests: AtomicU64,
+    model_failures: Mutex<BTreeMap<String, u64>>,
+    latency_buckets: Vec<AtomicU64>,
+    latency_sum_micros: AtomicU64,
+    latency_count: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            inline_requests: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            model_requests: AtomicU64::new(0),
+            model_failures: Mutex::new(BTreeMap::new()),
+            latency_buckets: LATENCY_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            latency_sum_micros: AtomicU64::new(0),
+            latency_count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn inline_request(&self) {
+        self.inline_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records one HTTP attempt against a model, successful or not.
+    pub fn model_request(&self, elapsed: Duration, error: Option<&SnekError>) {
+        self.model_requests.fetch_add(1, Ordering::Relaxed);
+
+        let seconds = elapsed.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|le| seconds <= *le) {
+            self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency_sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.latency_count.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(error) = error {
+            *self
+                .model_failures
+                .lock()
+                .unwrap()
+                .entry(failure_reason(error))
+                .or_default() += 1;
+        }
+    }
+
+    /// The current values in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        counter(
+            &mut out,
+            "snek_inline_requests_total",
+            "Inline completion requests received",
+            self.inline_requests.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "snek_completion_cache_hits_total",
+            "Inline completions served from the completion cache",
+            self.cache_hits.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "snek_model_requests_total",
+            "HTTP requests sent to a model",
+            self.model_requests.load(Ordering::Relaxed),
+        );
+
+        let _ = writeln!(out, "# HELP snek_model_failures_total Failed model requests by reason");
+        let _ = writeln!(out, "# TYPE snek_model_failures_total counter");
+        for (reason, count) in self.model_failures.lock().unwrap().iter() {
+            let _ = writeln!(out, "snek_model_failures_total{{reason=\"{}\"}} {}", reason, count);
+        }
+
+        let name = "snek_model_request_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Latency of model requests", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (le, bucket) in LATENCY_BUCKETS.iter().zip(&self.latency_buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative);
+        }
+        let count = self.latency_count.load(Ordering::Relaxed);
+        let sum = self.latency_sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Label for a failed model request: the HTTP status when there is one.
+fn failure_reason(error: &SnekError) -> String {
+    match error {
+        SnekError::ModelHttp { status, .. } | SnekError::ModelRedirect { status, .. } => {
+            status.to_string()
+        }
+        SnekError::ModelAuth(_) => "auth".to_string(),
+        SnekError::ModelTimeout => "timeout".to_string(),
+        SnekError::ModelTransport(_) => "transport".to_string(),
+        SnekError::ModelResponse(_) => "invalid_response".to_string(),
+        SnekError::ContextTooLarge(_) => "context_too_large".to_string(),
+        _ => "other".to_string(),
+    }
+}
+
+/// Serves `GET /metrics` on `listener` until the process exits.
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &metrics).await {
+                eprintln!("[SNEK] Metrics connection failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    // Only the request line matters; headers and body are ignored
+    let mut buf = [0u8; 1024];
+    let n = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let mut parts = request.split_whitespace();
+
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            metrics.render(),
+        ),
+        _ => ("404 Not Found", "text/plain", "Not Found\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
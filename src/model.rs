@@ -1,7 +1,18 @@
-use anyhow::{Context, Result};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
 
-use crate::snapshot::ContextSnapshot;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::error::{Result, SnekError};
+use crate::git_status::{self, GitStatusCache};
+use crate::languages;
+use crate::metrics::Metrics;
+use crate::session_io;
+use crate::siblings;
+use crate::snapshot::{CacheKind, ContextFilter, ContextSnapshot, ProjectMeta};
 
 #[derive(Debug, Serialize, Deserialize)]
 struct OpenAIMessage {
@@ -11,20 +22,103 @@ struct OpenAIMessage {
     content: String,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     reasoning_content: Option<String>,
+    /// Length of the start of `content` that stays the same across a
+    /// session's requests, marked cacheable when non-zero; see
+    /// `PromptOptions::cache_control`.
+    #[serde(skip)]
+    cacheable_len: usize,
 }
 
 #[derive(Debug, Serialize)]
-struct OpenAIRequest {
-    model: String,
-    messages: Vec<OpenAIMessage>,
+struct OpenAIRequest<'a> {
+    model: &'a str,
+    messages: Vec<WireMessage<'a>>,
     temperature: f32,
     max_tokens: usize,
     stream: bool,
 }
 
+/// An `OpenAIMessage` as sent: plain text, or text parts with the stable
+/// one carrying a `cache_control` marker.
+#[derive(Debug, Serialize)]
+struct WireMessage<'a> {
+    role: &'a str,
+    content: WireContent<'a>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum WireContent<'a> {
+    Text(&'a str),
+    Parts(Vec<ContentPart<'a>>),
+}
+
+#[derive(Debug, Serialize)]
+struct ContentPart<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    text: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_control: Option<CacheControl>,
+}
+
+#[derive(Debug, Serialize)]
+struct CacheControl {
+    #[serde(rename = "type")]
+    kind: &'static str,
+}
+
+impl<'a> From<&'a OpenAIMessage> for WireMessage<'a> {
+    fn from(message: &'a OpenAIMessage) -> Self {
+        let content = if message.cacheable_len == 0 {
+            WireContent::Text(&message.content)
+        } else {
+            let (stable, volatile) = message.content.split_at(message.cacheable_len);
+            let mut parts = vec![ContentPart {
+                kind: "text",
+                text: stable,
+                cache_control: Some(CacheControl { kind: "ephemeral" }),
+            }];
+            if !volatile.is_empty() {
+                parts.push(ContentPart {
+                    kind: "text",
+                    text: volatile,
+                    cache_control: None,
+                });
+            }
+            WireContent::Parts(parts)
+        };
+        Self {
+            role: &message.role,
+            content,
+        }
+    }
+}
+
+/// API flavor behind `snek.apiUrl`, for features beyond the common
+/// OpenAI-compatible request.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    #[default]
+    OpenAi,
+    Anthropic,
+}
+
+impl Provider {
+    /// Whether the provider caches prompt prefixes marked with `cache_control`.
+    pub fn supports_cache_control(self) -> bool {
+        matches!(self, Provider::Anthropic)
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct OpenAIResponse {
     choices: Vec<OpenAIChoice>,
+    /// The model that actually answered, which routers and gateways may
+    /// pick differently from the one requested.
+    #[serde(default)]
+    model: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -32,26 +126,551 @@ struct OpenAIChoice {
     message: OpenAIMessage,
 }
 
+/// `{"error": ...}` body some gateways send with a 200 status.
+#[derive(Debug, Deserialize)]
+struct OpenAIErrorEnvelope {
+    error: OpenAIError,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OpenAIError {
+    Detailed { message: String },
+    Message(String),
+}
+
+impl OpenAIError {
+    fn message(self) -> String {
+        match self {
+            OpenAIError::Detailed { message } | OpenAIError::Message(message) => message,
+        }
+    }
+}
+
+/// Network settings for the HTTP client that talks to the model endpoint.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HttpOptions {
+    /// Proxy URL (`http://`, `https://` or `socks5://`) used for all requests.
+    pub proxy: Option<String>,
+    /// Proxies for `https://` and `http://` endpoints only, from the
+    /// environment when `proxy` is unset.
+    pub https_proxy: Option<String>,
+    pub http_proxy: Option<String>,
+    /// Comma-separated hosts/domains/CIDRs that bypass the proxy.
+    pub no_proxy: Option<String>,
+    /// PEM client certificate and PKCS#8 key for gateways that require
+    /// mutual TLS. Both or neither must be set.
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    /// PEM bundle of extra CA certificates to trust.
+    pub ca_bundle: Option<String>,
+    /// Accept any server certificate, e.g. a self-signed one on a dev model
+    /// server. Never set unless the user explicitly opted in.
+    pub insecure_skip_tls_verify: bool,
+}
+
+impl HttpOptions {
+    /// Fills unset fields from the environment. An explicit `snek.proxy`
+    /// proxies every request; otherwise `HTTPS_PROXY` applies to `https://`
+    /// endpoints and `HTTP_PROXY` to `http://` ones, each falling back to
+    /// `ALL_PROXY`. `NO_PROXY` supplies the exception list when
+    /// `snek.noProxy` is unset. The TLS paths fall back to
+    /// `SNEK_CLIENT_CERT`, `SNEK_CLIENT_KEY` and `SNEK_CA_BUNDLE`.
+    pub fn with_env_fallback(mut self) -> Self {
+        let first_var = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| std::env::var(name).ok().filter(|v| !v.is_empty()))
+        };
+        if self.proxy.is_none() {
+            let all_proxy = first_var(&["ALL_PROXY", "all_proxy"]);
+            self.https_proxy = self
+                .https_proxy
+                .or_else(|| first_var(&["HTTPS_PROXY", "https_proxy"]))
+                .or_else(|| all_proxy.clone());
+            self.http_proxy = self
+                .http_proxy
+                .or_else(|| first_var(&["HTTP_PROXY", "http_proxy"]))
+                .or(all_proxy);
+        }
+        if self.no_proxy.is_none() {
+            self.no_proxy = first_var(&["NO_PROXY", "no_proxy"]);
+        }
+
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        self.client_cert = self.client_cert.or_else(|| var("SNEK_CLIENT_CERT"));
+        self.client_key = self.client_key.or_else(|| var("SNEK_CLIENT_KEY"));
+        self.ca_bundle = self.ca_bundle.or_else(|| var("SNEK_CA_BUNDLE"));
+        self
+    }
+
+    /// Warning for the user while certificate verification is off.
+    pub fn warning(&self) -> Option<String> {
+        self.insecure_skip_tls_verify.then(|| {
+            "Snek: TLS certificate verification is DISABLED (snek.insecureSkipTlsVerify). \
+            Model requests, including your API key and code, can be intercepted. \
+            Only use this with a trusted self-hosted endpoint."
+                .to_string()
+        })
+    }
+}
+
+/// Redirects followed before giving up on the endpoint.
+pub const MAX_REDIRECTS: usize = 5;
+
+/// Only follows 307 and 308, which repeat the POST with its body; a 301,
+/// 302 or 303 would turn it into a GET, so it is returned as is instead.
+fn redirect_policy() -> reqwest::redirect::Policy {
+    reqwest::redirect::Policy::custom(|attempt| {
+        let status = attempt.status();
+        if status != reqwest::StatusCode::TEMPORARY_REDIRECT
+            && status != reqwest::StatusCode::PERMANENT_REDIRECT
+        {
+            return attempt.stop();
+        }
+        if attempt.previous().len() > MAX_REDIRECTS {
+            return attempt.error(format!("more than {} redirects", MAX_REDIRECTS));
+        }
+        eprintln!("[SNEK] Following {} redirect to {}", status, attempt.url());
+        attempt.follow()
+    })
+}
+
+pub fn build_http_client(options: &HttpOptions) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder().redirect(redirect_policy());
+
+    let proxies = [
+        (&options.proxy, "all"),
+        (&options.https_proxy, "https"),
+        (&options.http_proxy, "http"),
+    ];
+    for (proxy_url, requests) in proxies {
+        let Some(proxy_url) = proxy_url else {
+            continue;
+        };
+        let proxy = match requests {
+            "https" => reqwest::Proxy::https(proxy_url),
+            "http" => reqwest::Proxy::http(proxy_url),
+            _ => reqwest::Proxy::all(proxy_url),
+        };
+        let proxy = proxy
+            .map_err(SnekError::InvalidProxy)?
+            .no_proxy(options.no_proxy.as_deref().and_then(reqwest::NoProxy::from_string));
+        builder = builder.proxy(proxy);
+
+        let display = url::Url::parse(proxy_url)
+            .map(|u| format!("{}://{}", u.scheme(), u.host_str().unwrap_or_default()))
+            .unwrap_or_default();
+        eprintln!("[SNEK] Routing {} model requests through proxy {}", requests, display);
+    }
+
+    match (&options.client_cert, &options.client_key) {
+        (Some(cert_path), Some(key_path)) => {
+            let cert = read_pem(cert_path, "client certificate")?;
+            let key = read_pem(key_path, "client key")?;
+            let identity = reqwest::Identity::from_pkcs8_pem(&cert, &key).map_err(|e| {
+                SnekError::Config(format!(
+                    "Invalid client certificate {} or key {} (the key must be PKCS#8 PEM): {}",
+                    cert_path, key_path, e
+                ))
+            })?;
+            builder = builder.identity(identity);
+            eprintln!("[SNEK] Using client certificate {}", cert_path);
+        }
+        (None, None) => {}
+        _ => {
+            return Err(SnekError::Config(
+                "A client certificate and key must be configured together".to_string(),
+            ));
+        }
+    }
+
+    if let Some(ref bundle_path) = options.ca_bundle {
+        let bundle = read_pem(bundle_path, "CA bundle")?;
+        let certs = reqwest::Certificate::from_pem_bundle(&bundle)
+            .map_err(|e| SnekError::Config(format!("Invalid CA bundle {}: {}", bundle_path, e)))?;
+        if certs.is_empty() {
+            return Err(SnekError::Config(format!(
+                "CA bundle {} contains no certificates",
+                bundle_path
+            )));
+        }
+        eprintln!("[SNEK] Trusting {} CA certificates from {}", certs.len(), bundle_path);
+        for cert in certs {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    if let Some(warning) = options.warning() {
+        eprintln!("[SNEK] WARNING: {}", warning);
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    builder.build().map_err(SnekError::HttpClient)
+}
+
+fn read_pem(path: &str, what: &str) -> Result<Vec<u8>> {
+    std::fs::read(path).map_err(SnekError::io(format!("Failed to read {} {}", what, path)))
+}
+
+/// Secondary model tried when the primary fails with a server error,
+/// rate limit or connection problem. URL and key default to the primary's.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FallbackModel {
+    pub model_name: String,
+    pub api_url: Option<String>,
+    pub api_key: Option<String>,
+}
+
+/// Knobs that shape the prompt sent to the model.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PromptOptions {
+    /// Only send code snippets in a language compatible with the request's.
+    pub match_snippet_language: bool,
+    /// Include outlines of sibling files (e.g. `foo_test.rs` for `foo.rs`).
+    pub sibling_context: bool,
+    /// Sibling naming conventions; see `siblings::find_siblings`.
+    pub sibling_patterns: Vec<String>,
+    pub max_siblings: usize,
+    /// Send a model-written summary of the markdown context instead of the
+    /// files themselves once they exceed this many characters.
+    pub summarize_above_chars: Option<usize>,
+    /// Model used for that summary; the completion model when unset.
+    pub summary_model: Option<String>,
+    /// Spell out the full signature of the enclosing function or type,
+    /// not just the function's name.
+    pub scope_signature: bool,
+    /// List snippets and markdown files with uncommitted git changes first.
+    pub prioritize_git_changes: bool,
+    /// Sentinel marking the cursor position in the prompt. Replaced by a
+    /// variant when the buffer already contains it; see `cursor_marker`.
+    pub cursor_marker: String,
+    /// Mark the part of the prompt shared by a session's requests (system
+    /// prompt and session context) with `cache_control`, so the provider
+    /// can reuse it instead of processing it again.
+    pub cache_control: bool,
+}
+
+impl Default for PromptOptions {
+    fn default() -> Self {
+        Self {
+            match_snippet_language: false,
+            sibling_context: false,
+            sibling_patterns: siblings::DEFAULT_PATTERNS.iter().map(|p| p.to_string()).collect(),
+            max_siblings: 2,
+            summarize_above_chars: None,
+            summary_model: None,
+            scope_signature: false,
+            prioritize_git_changes: false,
+            cursor_marker: DEFAULT_CURSOR_MARKER.to_string(),
+            cache_control: false,
+        }
+    }
+}
+
+pub const DEFAULT_CURSOR_MARKER: &str = "<SNEK_CURSOR>";
+
+/// Returns `preferred` unless it already occurs in the text around the
+/// cursor, in which case the first of `<SNEK_CURSOR_1>`, `<SNEK_CURSOR_2>`,
+/// ... (numbered the same way for custom markers) that doesn't.
+pub fn cursor_marker(preferred: &str, prefix: &str, suffix: &str) -> String {
+    // Joined, so a marker split across the cursor also counts
+    let text = format!("{}{}", prefix, suffix);
+    if !text.contains(preferred) {
+        return preferred.to_string();
+    }
+
+    let (stem, close) = match preferred.strip_suffix('>') {
+        Some(stem) => (stem, ">"),
+        None => (preferred, ""),
+    };
+    let mut n = 1;
+    loop {
+        let marker = format!("{}_{}{}", stem, n, close);
+        if !text.contains(&marker) {
+            return marker;
+        }
+        n += 1;
+    }
+}
+
+/// What `build_messages` needs beyond the snapshot, gathered per request.
+#[derive(Default)]
+struct PromptInputs {
+    /// Sent instead of the markdown files when set.
+    markdown_summary: Option<String>,
+    /// Files with uncommitted git changes, most recently modified first.
+    changed_files: Vec<PathBuf>,
+    /// Related files and their outlines; see `siblings::find_siblings`.
+    sibling_outlines: Vec<(PathBuf, String)>,
+}
+
+/// Context the client attaches to a single request.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RequestHints {
+    /// The user's current selection.
+    pub selection: Option<String>,
+    /// Free-form context for this request only.
+    pub extra_context: Option<String>,
+    /// A directive for this completion only, e.g. "make this async".
+    pub instruction: Option<String>,
+    /// The previous suggestion, when the user accepted it right before the cursor.
+    pub continuation: Option<Continuation>,
+    /// Leave out the session's markdown, code snippets and sibling files,
+    /// sending only the file being completed.
+    pub without_session_context: bool,
+    /// Sampling temperature for this request; `SamplingOptions::temperature`
+    /// when unset.
+    pub temperature: Option<f32>,
+    /// Which markdown files to send, instead of the session's `context_filter`.
+    pub context_filter: Option<ContextFilter>,
+    /// Ask for this fraction of the usual `max_tokens`, e.g. because the user
+    /// keeps only the start of suggestions.
+    pub length_factor: Option<f32>,
+    /// Import lines of the other documents open in the editor, by URI.
+    pub open_imports: Vec<(String, Vec<String>)>,
+}
+
+impl RequestHints {
+    /// The markdown filter in effect: this request's, else the session's.
+    /// While one applies, the markdown is never summarized.
+    fn context_filter<'a>(&'a self, snapshot: &'a ContextSnapshot) -> Option<&'a ContextFilter> {
+        self.context_filter
+            .as_ref()
+            .or(snapshot.context_filter.as_ref())
+    }
+
+    /// `max_tokens` scaled by `length_factor`.
+    fn max_tokens(&self, max_tokens: usize) -> usize {
+        match self.length_factor {
+            Some(factor) => ((max_tokens as f32 * factor).ceil() as usize).clamp(1, max_tokens),
+            None => max_tokens,
+        }
+    }
+}
+
+/// A completion and the model that produced it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Completion {
+    pub text: String,
+    /// As reported by the response; the requested (or fallback) model
+    /// when the response doesn't say.
+    pub model: String,
+}
+
+/// A suggestion accepted, wholly or in part, right before the cursor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Continuation {
+    pub suggested: String,
+    /// The leading part of `suggested` now in the document.
+    pub accepted: String,
+}
+
+/// Longest selection, in characters, included in the prompt.
+const MAX_SELECTION_CHARS: usize = 8000;
+
+/// Longest request-scoped extra context, in characters.
+const MAX_EXTRA_CONTEXT_CHARS: usize = 4000;
+
+/// Longest per-request instruction, in characters.
+const MAX_INSTRUCTION_CHARS: usize = 1000;
+
+fn truncate_chars(text: &str, max_chars: usize) -> &str {
+    text.char_indices()
+        .nth(max_chars)
+        .map_or(text, |(end, _)| &text[..end])
+}
+
+/// Retry behavior for `ModelClient::complete`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RetryOptions {
+    /// Extra attempts when the model returns an empty completion; each one
+    /// raises the temperature by `EMPTY_RETRY_TEMPERATURE_STEP`.
+    pub empty_retries: usize,
+    /// Extra attempts on the primary model after a retryable error (see
+    /// `SnekError::is_retryable`), before moving on to the fallback.
+    pub transient_retries: usize,
+    /// Consecutive failed requests (after retries) that open the primary
+    /// model's circuit breaker; 0 disables it. See `CircuitBreaker`.
+    pub circuit_breaker_threshold: usize,
+    pub circuit_breaker_cooldown: Duration,
+}
+
+/// Wait before the first transient retry; doubled for each one after it,
+/// then jittered (see `jittered`).
+pub const TRANSIENT_RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+/// Somewhere between half and all of `backoff`, so clients that failed
+/// together don't all retry at the same moment.
+fn jittered(backoff: Duration) -> Duration {
+    let fraction = (uuid::Uuid::new_v4().as_u128() % 1000) as f64 / 1000.0;
+    backoff / 2 + (backoff / 2).mul_f64(fraction)
+}
+
+pub const EMPTY_RETRY_TEMPERATURE_STEP: f32 = 0.2;
+pub const MAX_RETRY_TEMPERATURE: f32 = 0.8;
+
+fn retry_temperature(base: f32, attempt: usize) -> f32 {
+    (base + attempt as f32 * EMPTY_RETRY_TEMPERATURE_STEP).min(MAX_RETRY_TEMPERATURE.max(base))
+}
+
+/// Sampling parameters for completion requests.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SamplingOptions {
+    /// Temperature of the first attempt.
+    pub temperature: f32,
+    /// Replaces the session's `limits.max_tokens` when set.
+    pub max_tokens: Option<usize>,
+}
+
+/// Where a request goes: the primary model or the fallback.
+#[derive(Clone, Copy)]
+struct Endpoint<'a> {
+    api_url: &'a str,
+    model_name: &'a str,
+    api_key: &'a str,
+}
+
+/// Error fragments OpenAI-compatible APIs use for an oversize prompt.
+const CONTEXT_TOO_LARGE_MARKERS: &[&str] = &[
+    "context_length_exceeded",
+    "maximum context length",
+    "context length",
+    "context window",
+    "too many tokens",
+    "prompt is too long",
+];
+
+fn transport_error(e: reqwest::Error) -> SnekError {
+    if e.is_timeout() {
+        SnekError::ModelTimeout
+    } else {
+        SnekError::ModelTransport(e)
+    }
+}
+
+fn is_context_too_large(status: reqwest::StatusCode, body: &str) -> bool {
+    let body = body.to_lowercase();
+    (status == reqwest::StatusCode::BAD_REQUEST || status == reqwest::StatusCode::PAYLOAD_TOO_LARGE)
+        && CONTEXT_TOO_LARGE_MARKERS.iter().any(|m| body.contains(m))
+}
+
+/// Longest markdown summary requested from the model.
+const SUMMARY_MAX_TOKENS: usize = 1024;
+
+/// Prefix/suffix kept, in characters, when retrying an oversize prompt.
+const TRIMMED_PREFIX_CHARS: usize = 4000;
+const TRIMMED_SUFFIX_CHARS: usize = 1000;
+
 pub struct ModelClient {
-    api_url: String,
+    api_url: tokio::sync::RwLock<String>,
     model_name: tokio::sync::RwLock<String>,
-    http_client: reqwest::Client,
+    fallback: tokio::sync::RwLock<Option<FallbackModel>>,
+    http_client: tokio::sync::RwLock<reqwest::Client>,
+    prompt_options: tokio::sync::RwLock<PromptOptions>,
+    retry_options: tokio::sync::RwLock<RetryOptions>,
+    sampling_options: tokio::sync::RwLock<SamplingOptions>,
+    breaker: CircuitBreaker,
+    metrics: Arc<Metrics>,
+    git_status: Arc<GitStatusCache>,
 }
 
 impl ModelClient {
     pub fn new(api_url: String, model_name: String) -> Self {
+        let http_client = build_http_client(&HttpOptions::default().with_env_fallback())
+            .unwrap_or_else(|e| {
+                eprintln!("[SNEK] {:#}, falling back to default HTTP client", e);
+                reqwest::Client::new()
+            });
+
         Self {
-            api_url,
+            api_url: tokio::sync::RwLock::new(api_url),
             model_name: tokio::sync::RwLock::new(model_name),
-            http_client: reqwest::Client::new(),
+            fallback: tokio::sync::RwLock::new(None),
+            http_client: tokio::sync::RwLock::new(http_client),
+            prompt_options: tokio::sync::RwLock::new(PromptOptions::default()),
+            retry_options: tokio::sync::RwLock::new(RetryOptions::default()),
+            sampling_options: tokio::sync::RwLock::new(SamplingOptions::default()),
+            breaker: CircuitBreaker::new(),
+            metrics: Arc::new(Metrics::new()),
+            git_status: Arc::new(GitStatusCache::new()),
         }
     }
 
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    pub async fn set_http_options(&self, options: &HttpOptions) -> Result<()> {
+        let client = build_http_client(options)?;
+        *self.http_client.write().await = client;
+        Ok(())
+    }
+
+    pub async fn api_url(&self) -> String {
+        self.api_url.read().await.clone()
+    }
+
+    pub async fn set_api_url(&self, api_url: String) {
+        *self.api_url.write().await = api_url;
+    }
+
+    pub async fn model_name(&self) -> String {
+        self.model_name.read().await.clone()
+    }
+
     pub async fn set_model_name(&self, model_name: String) {
         let mut name = self.model_name.write().await;
         *name = model_name;
     }
 
+    pub async fn set_fallback(&self, fallback: Option<FallbackModel>) {
+        *self.fallback.write().await = fallback;
+    }
+
+    pub async fn set_prompt_options(&self, options: PromptOptions) {
+        *self.prompt_options.write().await = options;
+    }
+
+    pub async fn set_retry_options(&self, options: RetryOptions) {
+        self.breaker
+            .configure(options.circuit_breaker_threshold, options.circuit_breaker_cooldown);
+        *self.retry_options.write().await = options;
+    }
+
+    pub async fn set_sampling_options(&self, options: SamplingOptions) {
+        *self.sampling_options.write().await = options;
+    }
+
+    /// Sends a one-token request to the primary model, opening a pooled
+    /// connection that the next completion can reuse.
+    pub async fn ping(&self, api_key: &str) -> Result<()> {
+        if api_key.is_empty() {
+            return Err(SnekError::ModelAuth("API key not configured".to_string()));
+        }
+
+        let model_name = self.model_name.read().await.clone();
+        if model_name.trim().is_empty() {
+            return Err(SnekError::ModelNotConfigured);
+        }
+        let api_url = self.api_url.read().await.clone();
+        let endpoint = Endpoint {
+            api_url: &api_url,
+            model_name: &model_name,
+            api_key,
+        };
+        let messages = [OpenAIMessage {
+            role: "user".to_string(),
+            content: "ping".to_string(),
+            reasoning_content: None,
+            cacheable_len: 0,
+        }];
+        self.request_completion(&endpoint, &messages, 1, 0.0, &CancellationToken::new())
+            .await?;
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub async fn complete(
         &self,
         snapshot: &ContextSnapshot,
@@ -60,82 +679,566 @@ impl ModelClient {
         language: &str,
         uri: &str,
         api_key: &str,
+        cancel: &CancellationToken,
     ) -> Result<String> {
+        self.complete_with_hints(
+            snapshot,
+            prefix,
+            suffix,
+            language,
+            uri,
+            api_key,
+            cancel,
+            &RequestHints::default(),
+        )
+        .await
+        .map(|completion| completion.text)
+    }
+
+    /// Like `complete`, with per-request context supplied by the client.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn complete_with_hints(
+        &self,
+        snapshot: &ContextSnapshot,
+        prefix: &str,
+        suffix: &str,
+        language: &str,
+        uri: &str,
+        api_key: &str,
+        cancel: &CancellationToken,
+        hints: &RequestHints,
+    ) -> Result<Completion> {
         if api_key.is_empty() {
-            anyhow::bail!(
+            return Err(SnekError::ModelAuth(
                 "API key not configured. Please add your API key in VSCode settings:\n\
                 File > Preferences > Settings > Search for 'snek.apiKey'"
+                    .to_string(),
+            ));
+        }
+
+        let model_name = self.model_name.read().await.clone();
+        if model_name.trim().is_empty() {
+            return Err(SnekError::ModelNotConfigured);
+        }
+        let sampling = self.sampling_options.read().await.clone();
+        let max_tokens = hints.max_tokens(sampling.max_tokens.unwrap_or(snapshot.limits.max_tokens));
+        let base_temperature = hints.temperature.unwrap_or(sampling.temperature);
+
+        let options = self.prompt_options.read().await.clone();
+        let api_url = self.api_url.read().await.clone();
+        let primary = Endpoint {
+            api_url: &api_url,
+            model_name: &model_name,
+            api_key,
+        };
+
+        let markdown_summary = if hints.without_session_context
+            || hints.context_filter(snapshot).is_some()
+        {
+            None
+        } else {
+            self.markdown_summary(snapshot, &options, &primary, cancel)
+                .await
+        };
+        let inputs = PromptInputs {
+            markdown_summary,
+            changed_files: self.changed_files(&options, uri).await,
+            sibling_outlines: self.sibling_outlines(&options, hints, uri).await,
+        };
+        let mut messages =
+            build_messages(snapshot, prefix, suffix, language, uri, &options, hints, &inputs);
+        let mut trimmed = false;
+        let empty_retries = self.retry_options.read().await.empty_retries;
+
+        let mut attempt = 0;
+        loop {
+            // Only empty-completion retries raise the temperature; a fresh
+            // sample at the base temperature would likely reproduce the same empty output
+            let temperature = retry_temperature(base_temperature, attempt);
+            let result = self
+                .request_with_fallback(&primary, &messages, max_tokens, temperature, cancel)
+                .await;
+
+            let completion = match result {
+                Ok(raw) if languages::is_prose(language) => Completion {
+                    text: raw.text.trim_end().to_string(),
+                    model: raw.model,
+                },
+                Ok(raw) => {
+                    let text = extract_code_from_response(&raw.text);
+                    eprintln!("[SNEK] Extracted completion length: {} chars", text.len());
+                    Completion {
+                        text,
+                        model: raw.model,
+                    }
+                }
+                Err(SnekError::ContextTooLarge(e)) if !trimmed => {
+                    eprintln!("[SNEK] Prompt too large ({:#}), retrying with trimmed context", e);
+                    messages =
+                        build_trimmed_messages(snapshot, prefix, suffix, language, uri, &options, hints);
+                    trimmed = true;
+                    continue;
+                }
+                Err(SnekError::ContextTooLarge(e)) => {
+                    eprintln!(
+                        "[SNEK] Warning: prompt still too large after trimming ({:#}), returning empty completion",
+                        e
+                    );
+                    return Ok(Completion {
+                        text: String::new(),
+                        model: model_name,
+                    });
+                }
+                Err(e) => return Err(e),
+            };
+
+            if !completion.text.trim().is_empty() || attempt >= empty_retries {
+                return Ok(completion);
+            }
+
+            attempt += 1;
+            eprintln!(
+                "[SNEK] Empty completion, retrying ({}/{}) at temperature {:.1}",
+                attempt,
+                empty_retries,
+                retry_temperature(base_temperature, attempt)
             );
         }
+    }
 
+    /// The prompt `complete_with_hints` would send, as markdown. A markdown
+    /// summary is only used if one is already cached; none is requested.
+    pub async fn render_prompt(
+        &self,
+        snapshot: &ContextSnapshot,
+        prefix: &str,
+        suffix: &str,
+        language: &str,
+        uri: &str,
+        hints: &RequestHints,
+    ) -> String {
         let model_name = self.model_name.read().await.clone();
+        let sampling = self.sampling_options.read().await.clone();
+        let max_tokens = hints.max_tokens(sampling.max_tokens.unwrap_or(snapshot.limits.max_tokens));
+        let options = self.prompt_options.read().await.clone();
+
+        let inputs = PromptInputs {
+            markdown_summary: options
+                .summarize_above_chars
+                .filter(|_| !hints.without_session_context)
+                .filter(|_| hints.context_filter(snapshot).is_none())
+                .filter(|&threshold| snapshot.markdown_chars() > threshold)
+                .and_then(|_| snapshot.markdown_summary.get(snapshot.markdown_hash())),
+            changed_files: self.changed_files(&options, uri).await,
+            sibling_outlines: self.sibling_outlines(&options, hints, uri).await,
+        };
+        let messages =
+            build_messages(snapshot, prefix, suffix, language, uri, &options, hints, &inputs);
+
+        let mut rendered = format!(
+            "# Snek prompt\n\n\
+            - Model: `{}`\n\
+            - File: `{}`\n\
+            - Language: `{}`\n\
+            - Session: `{}`\n\
+            - Temperature: {}\n\
+            - Max tokens: {}\n",
+            model_name,
+            uri,
+            language,
+            snapshot.session_id,
+            hints.temperature.unwrap_or(sampling.temperature),
+            max_tokens
+        );
+        for message in &messages {
+            rendered.push_str(&format!("\n## {}\n\n{}\n", message.role, message.content));
+        }
+        rendered
+    }
+
+    /// The snapshot's markdown summary, made with `summary_model` on first
+    /// use once the markdown exceeds `summarize_above_chars` and reused until
+    /// it changes. `None` means the markdown is sent as-is.
+    async fn markdown_summary(
+        &self,
+        snapshot: &ContextSnapshot,
+        options: &PromptOptions,
+        primary: &Endpoint<'_>,
+        cancel: &CancellationToken,
+    ) -> Option<String> {
+        let threshold = options.summarize_above_chars?;
+        let chars = snapshot.markdown_chars();
+        if chars <= threshold {
+            return None;
+        }
+
+        let hash = snapshot.markdown_hash();
+        if let Some(summary) = snapshot.markdown_summary.get(hash) {
+            return Some(summary);
+        }
+
+        eprintln!(
+            "[SNEK] Markdown context is {} chars (> {}), summarizing",
+            chars, threshold
+        );
+        let endpoint = Endpoint {
+            model_name: options.summary_model.as_deref().unwrap_or(primary.model_name),
+            ..*primary
+        };
+        let messages = build_summary_messages(snapshot);
+        match self
+            .request_completion(&endpoint, &messages, SUMMARY_MAX_TOKENS, 0.0, cancel)
+            .await
+            .map(|summary| summary.text)
+        {
+            Ok(summary) if !summary.trim().is_empty() => {
+                eprintln!("[SNEK] Markdown summarized to {} chars", summary.chars().count());
+                snapshot.markdown_summary.set(hash, summary.clone());
+                Some(summary)
+            }
+            Ok(_) => {
+                eprintln!("[SNEK] Warning: empty markdown summary, sending raw context");
+                None
+            }
+            Err(e) => {
+                eprintln!("[SNEK] Warning: markdown summary failed ({:#}), sending raw context", e);
+                None
+            }
+        }
+    }
+
+    /// Uncommitted files in the repository holding `uri`, when
+    /// `prioritize_git_changes` is on.
+    async fn changed_files(&self, options: &PromptOptions, uri: &str) -> Vec<PathBuf> {
+        if !options.prioritize_git_changes {
+            return vec![];
+        }
+        let Some(dir) = url::Url::parse(uri)
+            .ok()
+            .and_then(|u| u.to_file_path().ok())
+            .and_then(|path| path.parent().map(|p| p.to_path_buf()))
+        else {
+            return vec![];
+        };
+
+        let git_status = self.git_status.clone();
+        tokio::task::spawn_blocking(move || git_status.changed_files(&dir))
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Outlines of the files related to `uri`, when `sibling_context` is on.
+    /// Found and read on a blocking thread.
+    async fn sibling_outlines(
+        &self,
+        options: &PromptOptions,
+        hints: &RequestHints,
+        uri: &str,
+    ) -> Vec<(PathBuf, String)> {
+        if !options.sibling_context || hints.without_session_context {
+            return vec![];
+        }
+        let Some(file_path) = url::Url::parse(uri)
+            .ok()
+            .and_then(|u| u.to_file_path().ok())
+        else {
+            return vec![];
+        };
+
+        let patterns = options.sibling_patterns.clone();
+        let max_siblings = options.max_siblings;
+        tokio::task::spawn_blocking(move || {
+            siblings::find_siblings(&file_path, &patterns, max_siblings)
+                .into_iter()
+                .filter_map(|sibling| {
+                    let content = std::fs::read_to_string(&sibling).ok()?;
+                    let outline = siblings::outline(&content);
+                    Some((sibling, outline))
+                })
+                .collect()
+        })
+        .await
+        .unwrap_or_default()
+    }
+
+    /// Requests from `primary`, moving on to the fallback model (if any)
+    /// when the primary fails with a retryable error.
+    async fn request_with_fallback(
+        &self,
+        primary: &Endpoint<'_>,
+        messages: &[OpenAIMessage],
+        max_tokens: usize,
+        temperature: f32,
+        cancel: &CancellationToken,
+    ) -> Result<Completion> {
+        let transient_retries = self.retry_options.read().await.transient_retries;
+        let result = match self.breaker.check() {
+            Err(retry_after) => Err(SnekError::CircuitOpen {
+                model: primary.model_name.to_string(),
+                retry_after_ms: retry_after.as_millis() as u64,
+            }),
+            Ok(()) => {
+                let result = self
+                    .request_with_retries(
+                        primary,
+                        messages,
+                        max_tokens,
+                        temperature,
+                        cancel,
+                        transient_retries,
+                    )
+                    .await;
+                match result {
+                    Ok(_) => self.breaker.record_success(),
+                    Err(SnekError::Cancelled) => self.breaker.record_abandoned(),
+                    Err(ref e) if e.is_retryable() => self.breaker.record_failure(),
+                    // The endpoint answered, if only to reject the request
+                    Err(_) => self.breaker.record_success(),
+                }
+                result
+            }
+        };
+
+        let completion = match result {
+            Ok(completion) => {
+                log_served_by("model", primary.model_name, &completion.model);
+                completion
+            }
+            Err(e) if e.is_retryable() => {
+                let Some(fallback) = self.fallback.read().await.clone() else {
+                    return Err(e);
+                };
+
+                eprintln!(
+                    "[SNEK] Primary model {} failed ({:#}), trying fallback model {}",
+                    primary.model_name, e, fallback.model_name
+                );
+
+                let endpoint = Endpoint {
+                    api_url: fallback.api_url.as_deref().unwrap_or(primary.api_url),
+                    model_name: &fallback.model_name,
+                    api_key: fallback.api_key.as_deref().unwrap_or(primary.api_key),
+                };
+                let completion = self
+                    .request_completion(&endpoint, messages, max_tokens, temperature, cancel)
+                    .await?;
+
+                log_served_by("fallback model", &fallback.model_name, &completion.model);
+                completion
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(completion)
+    }
+
+    /// `request_completion`, retried up to `retries` times with exponential
+    /// backoff while it fails with a retryable error.
+    async fn request_with_retries(
+        &self,
+        endpoint: &Endpoint<'_>,
+        messages: &[OpenAIMessage],
+        max_tokens: usize,
+        temperature: f32,
+        cancel: &CancellationToken,
+        retries: usize,
+    ) -> Result<Completion> {
+        let mut attempt = 0;
+        loop {
+            let result = self
+                .request_completion(endpoint, messages, max_tokens, temperature, cancel)
+                .await;
+            match result {
+                Err(e) if e.is_retryable() && attempt < retries => {
+                    let backoff = jittered(TRANSIENT_RETRY_BACKOFF * 2u32.pow(attempt as u32));
+                    attempt += 1;
+                    eprintln!(
+                        "[SNEK] Request to {} failed ({}), retrying ({}/{}) in {}ms",
+                        endpoint.model_name,
+                        e,
+                        attempt,
+                        retries,
+                        backoff.as_millis()
+                    );
+                    tokio::select! {
+                        biased;
+                        _ = cancel.cancelled() => return Err(SnekError::Cancelled),
+                        _ = tokio::time::sleep(backoff) => {}
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Races the request against `cancel`; cancelling drops the in-flight
+    /// reqwest future, which closes the connection.
+    async fn request_completion(
+        &self,
+        endpoint: &Endpoint<'_>,
+        messages: &[OpenAIMessage],
+        max_tokens: usize,
+        temperature: f32,
+        cancel: &CancellationToken,
+    ) -> Result<Completion> {
+        let started = Instant::now();
+        let result = tokio::select! {
+            biased;
+            _ = cancel.cancelled() => {
+                eprintln!("[SNEK] Request to {} cancelled", endpoint.model_name);
+                return Err(SnekError::Cancelled);
+            }
+            result = self.send_request(endpoint, messages, max_tokens, temperature) => result,
+        };
+        self.metrics
+            .model_request(started.elapsed(), result.as_ref().err());
+        result
+    }
+
+    async fn send_request(
+        &self,
+        endpoint: &Endpoint<'_>,
+        messages: &[OpenAIMessage],
+        max_tokens: usize,
+        temperature: f32,
+    ) -> Result<Completion> {
+        let Endpoint {
+            api_url,
+            model_name,
+            api_key,
+        } = *endpoint;
 
         eprintln!("[SNEK] Request details:");
         eprintln!("  - Model: {}", model_name);
-        eprintln!("  - URL: {}", self.api_url);
-        eprintln!("  - Max tokens: {}", snapshot.limits.max_tokens);
-
-        let messages = build_messages(snapshot, prefix, suffix, language, uri);
+        eprintln!("  - URL: {}", api_url);
+        eprintln!("  - Max tokens: {}", max_tokens);
+        eprintln!("  - Temperature: {:.1}", temperature);
 
         let request = OpenAIRequest {
-            model: model_name.clone(),
-            messages,
-            temperature: 0.0,
-            max_tokens: snapshot.limits.max_tokens,
+            model: model_name,
+            messages: messages.iter().map(WireMessage::from).collect(),
+            temperature,
+            max_tokens,
             stream: false,
         };
 
-        let response = self
-            .http_client
-            .post(&self.api_url)
+        let http_client = self.http_client.read().await.clone();
+        let response = http_client
+            .post(api_url)
             .header("Authorization", format!("Bearer {}", api_key))
             .header("Content-Type", "application/json")
             .json(&request)
             .send()
             .await
-            .context("Failed to send request to AI model")?;
+            .map_err(transport_error)?;
 
         let status = response.status();
         eprintln!("[SNEK] Response status: {}", status);
 
+        if status.is_redirection() {
+            let location = response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("an unknown location")
+                .to_string();
+            eprintln!("[SNEK] Not following {} redirect to {}", status, location);
+            return Err(SnekError::ModelRedirect {
+                status: status.as_u16(),
+                location,
+            });
+        }
+
         if !status.is_success() {
             let body = response.text().await.unwrap_or_default();
             eprintln!("[SNEK] Error response body: {}", body);
-            anyhow::bail!("AI model request failed: {} - {}", status, body);
+            return Err(if is_context_too_large(status, &body) {
+                SnekError::ContextTooLarge(body)
+            } else if status == reqwest::StatusCode::UNAUTHORIZED
+                || status == reqwest::StatusCode::FORBIDDEN
+            {
+                SnekError::ModelAuth(format!("AI model rejected the API key: {} - {}", status, body))
+            } else {
+                SnekError::ModelHttp {
+                    status: status.as_u16(),
+                    body,
+                }
+            });
         }
 
-        let response_text = response.text().await.context("Failed to get response text")?;
+        let response_text = response
+            .text()
+            .await
+            .map_err(transport_error)?;
         eprintln!("[SNEK] Raw response: {}", &response_text[..response_text.len().min(500)]);
 
-        let response_body: OpenAIResponse = serde_json::from_str(&response_text)
-            .context("Failed to parse AI model response")?;
+        if let Ok(envelope) = serde_json::from_str::<OpenAIErrorEnvelope>(&response_text) {
+            return Err(SnekError::BadResponse(envelope.error.message()));
+        }
+
+        let response_body: OpenAIResponse =
+            serde_json::from_str(&response_text).map_err(|e| match e.classify() {
+                // The body ended mid-value: the connection dropped, not a bad payload
+                serde_json::error::Category::Eof => SnekError::IncompleteResponse(format!(
+                    "{} after {} bytes",
+                    e,
+                    response_text.len()
+                )),
+                _ => SnekError::ModelResponse(e.to_string()),
+            })?;
 
-        let raw_completion = response_body
+        let content = response_body
             .choices
             .first()
             .map(|c| c.message.content.clone())
             .unwrap_or_default();
 
-        let completion = extract_code_from_response(&raw_completion);
-
-        eprintln!("[SNEK] Raw completion length: {} chars", raw_completion.len());
-        eprintln!("[SNEK] Extracted completion length: {} chars", completion.len());
+        eprintln!("[SNEK] Raw completion length: {} chars", content.len());
+        Ok(Completion {
+            text: content,
+            model: response_body
+                .model
+                .unwrap_or_else(|| model_name.to_string()),
+        })
+    }
+}
 
-        Ok(completion)
+/// Logs which model served a completion, and the one the response named
+/// when a router picked another.
+fn log_served_by(role: &str, requested: &str, served: &str) {
+    if served == requested {
+        eprintln!("[SNEK] Completion served by {}: {}", role, requested);
+    } else {
+        eprintln!(
+            "[SNEK] Completion served by {}: {} (response model: {})",
+            role, requested, served
+        );
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn build_messages(
     snapshot: &ContextSnapshot,
     prefix: &str,
     suffix: &str,
     language: &str,
     uri: &str,
+    options: &PromptOptions,
+    hints: &RequestHints,
+    inputs: &PromptInputs,
 ) -> Vec<OpenAIMessage> {
+    let marker = cursor_marker(&options.cursor_marker, prefix, suffix);
+    if marker != options.cursor_marker {
+        eprintln!(
+            "[SNEK] Buffer contains {}, marking the cursor with {} instead",
+            options.cursor_marker, marker
+        );
+    }
+
     let mut messages = vec![];
 
     messages.push(OpenAIMessage {
-        role: "system".to_string(), /// TODO: refine the prompt
+        role: "system".to_string(), // TODO: refine the prompt
         content: "You are an AI code completion assistant.
         Generate code that naturally continues from the <CURSOR> position.
         The developer trusts you to understand what they are trying to make, and your continuation needs to be very helpful to not get in the way of the developer.
@@ -146,21 +1249,70 @@ fn build_messages(
             3. Keep it short and generate code block-by-block. Avoid generating several functions in one response, close that logical block and stop.
         Return ONLY the completion code without explanations, markdown formatting (```python ```, or ```rust ```, or ```typescript ``` or ANYTHING like that), or code fences.
         You will be given some context - markdown files that tell you about the developer intent, what are they making and what they want to achieve, or general information about the code base, it can be anything, most importantly the data is in natural language, may contain code snippets etc.
-        You will also be given code files that will give you more data about the code base".to_string(),
+        You will also be given code files that will give you more data about the code base"
+            .replace("<CURSOR>", &marker),
         reasoning_content: None,
+        cacheable_len: 0,
     });
 
+    if let Some(hint) = snapshot.meta.as_ref().and_then(ProjectMeta::hint) {
+        messages[0].content.push_str("\n        About the project: ");
+        messages[0].content.push_str(&hint);
+    }
+
+    if !hints.without_session_context {
+        for chat in &snapshot.chat_messages {
+            if !matches!(chat.role.as_str(), "system" | "user" | "assistant") {
+                eprintln!("[SNEK] Skipping chat.json message with role {:?}", chat.role);
+                continue;
+            }
+            messages.push(OpenAIMessage {
+                role: chat.role.clone(),
+                content: chat.content.clone(),
+                reasoning_content: None,
+                cacheable_len: 0,
+            });
+        }
+    }
+
     let mut context_msg = String::new();
 
-    if !snapshot.markdown_cache.is_empty() {
-        eprintln!("[SNEK] Including {} markdown files", snapshot.markdown_cache.len());
+    if hints.without_session_context {
+        eprintln!("[SNEK] Session context disabled, sending the file only");
+    } else if let Some(ref summary) = inputs.markdown_summary {
+        eprintln!("[SNEK] Including summary of {} markdown files", snapshot.markdown_cache.len());
+        for filename in snapshot.markdown_cache.keys() {
+            snapshot.mark_used(CacheKind::Markdown, filename);
+        }
+        context_msg.push_str("Here is a summary of some context you might need:\n\n");
+        context_msg.push_str(summary);
+        context_msg.push_str("\n\n---\n\n");
+    } else if let mut filenames = snapshot
+        .markdown_cache
+        .keys()
+        .filter(|name| hints.context_filter(snapshot).is_none_or(|f| f.allows(name)))
+        .collect::<Vec<&String>>()
+        && !filenames.is_empty()
+    {
+        eprintln!(
+            "[SNEK] Including {} of {} markdown files",
+            filenames.len(),
+            snapshot.markdown_cache.len()
+        );
         context_msg.push_str("Here is some context you might need:\n\n");
 
-        let mut filenames: Vec<&String> = snapshot.markdown_cache.keys().collect();
         filenames.sort();
+        if !inputs.changed_files.is_empty()
+            && let Some(context_dir) = session_io::resolve_context_dir(&snapshot.session_dir)
+        {
+            git_status::prioritize(&mut filenames, &inputs.changed_files, |name| {
+                context_dir.join(name).canonicalize().ok()
+            });
+        }
 
         for filename in filenames {
             if let Some(content) = snapshot.markdown_cache.get(filename) {
+                snapshot.mark_used(CacheKind::Markdown, filename);
                 context_msg.push_str(&format!("## {}\n\n", filename));
                 context_msg.push_str(content);
                 context_msg.push_str("\n\n---\n\n");
@@ -170,10 +1322,37 @@ fn build_messages(
         eprintln!("[SNEK] No markdown context available");
     }
 
-    if !snapshot.code_snippets.is_empty() {
-        eprintln!("[SNEK] Including {} code snippets", snapshot.code_snippets.len());
+    let mut snippets: Vec<_> = snapshot
+        .code_contexts
+        .iter()
+        .filter(|_| !hints.without_session_context)
+        .filter(|snippet| {
+            !options.match_snippet_language
+                || languages::is_compatible(&snippet.language_id, language)
+        })
+        .collect();
+
+    if snippets.len() < snapshot.code_contexts.len() && !hints.without_session_context {
+        eprintln!(
+            "[SNEK] Skipping {} code snippets not in {}",
+            snapshot.code_contexts.len() - snippets.len(),
+            language
+        );
+    }
+
+    git_status::prioritize(&mut snippets, &inputs.changed_files, |snippet| {
+        url::Url::parse(&snippet.uri)
+            .ok()?
+            .to_file_path()
+            .ok()?
+            .canonicalize()
+            .ok()
+    });
+
+    if !snippets.is_empty() {
+        eprintln!("[SNEK] Including {} code snippets", snippets.len());
         context_msg.push_str("Here are some code snippets that you might need:\n\n");
-        for (idx, snippet) in snapshot.code_snippets.iter().enumerate() {
+        for (idx, snippet) in snippets.iter().enumerate() {
             context_msg.push_str(&format!(
                 "Snippet {}\n\n:\n\n  URI: {}\n\n  Lines: {}-{}\n\n  Language: {}\n\n",
                 idx + 1,
@@ -186,18 +1365,18 @@ fn build_messages(
                 context_msg.push_str(&format!("  Description: {}\n", desc));
             }
 
-            if let Some(full_content) = snapshot.file_cache.get(&snippet.uri) {
-                let lines: Vec<&str> = full_content.lines().collect();
+            if !snippet.code.is_empty() {
+                context_msg.push_str(&format!("  Code:\n```\n{}\n```\n\n", snippet.code));
+            } else if let Some(full_content) = snapshot.file_cache.get(&snippet.uri) {
+                snapshot.mark_used(CacheKind::File, &snippet.uri);
                 let start = snippet.start_line as usize;
-                let end = (snippet.end_line as usize).min(lines.len());
+                let end = snippet.end_line as usize;
 
-                if start < lines.len() {
-                    let extracted_lines = &lines[start..end];
-                    let code = extracted_lines.join("\n");
+                if let Some(code) = extract_line_range(full_content, start, end) {
                     context_msg.push_str(&format!("  Code:\n```\n{}\n```\n\n", code));
                 } else {
                     eprintln!("[SNEK] Warning: Line range {}-{} exceeds file length {} for {}",
-                             start, end, lines.len(), snippet.uri);
+                             start, end, full_content.lines().count(), snippet.uri);
                     context_msg.push_str("  Code: [Invalid line range]\n\n");
                 }
             } else {
@@ -208,37 +1387,279 @@ fn build_messages(
         context_msg.push_str("---\n\n");
     }
 
+    if !inputs.sibling_outlines.is_empty() {
+        eprintln!(
+            "[SNEK] Including outlines of {} sibling files",
+            inputs.sibling_outlines.len()
+        );
+        context_msg.push_str("Here are outlines of files related to the current one:\n\n");
+        for (sibling, outline) in &inputs.sibling_outlines {
+            context_msg.push_str(&format!(
+                "## {}\n\n```\n{}\n```\n\n",
+                sibling.display(),
+                outline
+            ));
+        }
+        context_msg.push_str("---\n\n");
+    }
+
+    // Everything above is the same for every request in the session
+    let stable_len = context_msg.len();
+
+    if !hints.open_imports.is_empty() {
+        eprintln!(
+            "[SNEK] Including imports of {} other open files",
+            hints.open_imports.len()
+        );
+        context_msg.push_str("Here are the imports of other files open in the editor:\n\n");
+        for (other_uri, lines) in &hints.open_imports {
+            context_msg.push_str(&format!("## {}\n\n```\n{}\n```\n\n", other_uri, lines.join("\n")));
+        }
+        context_msg.push_str("---\n\n");
+    }
+
+    if let Some(ref selection) = hints.selection {
+        context_msg.push_str("The user has selected this code; the completion should relate to it:\n\n");
+        context_msg.push_str(&format!(
+            "```\n{}\n```\n\n---\n\n",
+            truncate_chars(selection, MAX_SELECTION_CHARS)
+        ));
+    }
+
+    if let Some(ref extra) = hints.extra_context {
+        context_msg.push_str("Additional context from the editor:\n\n");
+        context_msg.push_str(truncate_chars(extra, MAX_EXTRA_CONTEXT_CHARS));
+        context_msg.push_str("\n\n---\n\n");
+    }
+
+    if let Some(ref continuation) = hints.continuation {
+        context_msg.push_str("You previously suggested:\n\n");
+        context_msg.push_str(&format!(
+            "```\n{}\n```\n\n",
+            truncate_chars(&continuation.suggested, MAX_SELECTION_CHARS)
+        ));
+        if continuation.accepted == continuation.suggested {
+            context_msg.push_str("The user accepted it, and it ends right before the cursor.\n\n");
+        } else {
+            context_msg.push_str(&format!(
+                "The user accepted the beginning of it, up to the cursor:\n\n```\n{}\n```\n\n",
+                truncate_chars(&continuation.accepted, MAX_SELECTION_CHARS)
+            ));
+        }
+        context_msg.push_str(
+            "Continue from there coherently; do not repeat or restart it.\n\n---\n\n",
+        );
+    }
+
+    if let Some(ref instruction) = hints.instruction {
+        context_msg.push_str("For this completion only, the user asks you to:\n\n");
+        context_msg.push_str(truncate_chars(instruction.trim(), MAX_INSTRUCTION_CHARS));
+        context_msg.push_str("\n\nFollow this instruction in the code you generate.\n\n---\n\n");
+    }
+
     context_msg.push_str(&format!(
-        "Complete the following code.\n\n{}\n\n\n The cursor is at <CURSOR>. Generate the raw, full code that should be inserted at <CURSOR>. Do not include any explanations or markdown formatting. IMPORTANT: Ensure proper indentation - match the indentation level of the surrounding code context.\n\n",
+        "Complete the following code.\n\n{}\n\n\n The cursor is at {marker}. Generate the raw, full code that should be inserted at {marker}. Do not include any explanations or markdown formatting. IMPORTANT: Ensure proper indentation - match the indentation level of the surrounding code context.\n\n",
         language
     ));
 
     context_msg.push_str(&format!("File: {}\n\n", uri));
 
+    let signature = options
+        .scope_signature
+        .then(|| languages::enclosing_signature(prefix, language))
+        .flatten();
+    if let Some(signature) = signature {
+        eprintln!("[SNEK] Cursor is inside: {}", signature);
+        context_msg.push_str(&format!(
+            "The cursor is inside this declaration:\n```\n{}\n```\n\n",
+            signature
+        ));
+    } else if let Some(function) = languages::enclosing_function(prefix, language) {
+        eprintln!("[SNEK] Cursor is inside function: {}", function);
+        context_msg.push_str(&format!(
+            "You are completing inside function `{}`.\n\n",
+            function
+        ));
+    }
+
     context_msg.push_str(prefix);
-    context_msg.push_str("<CURSOR>");
+    context_msg.push_str(&marker);
     context_msg.push_str(suffix);
 
+    if options.cache_control
+        && stable_len == 0
+        && let Some(last) = messages.last_mut()
+    {
+        // No session context: the system prompt and chat turns are the stable part
+        last.cacheable_len = last.content.len();
+    }
     messages.push(OpenAIMessage {
         role: "user".to_string(),
         content: context_msg,
         reasoning_content: None,
+        cacheable_len: if options.cache_control { stable_len } else { 0 },
     });
 
     messages
 }
 
-fn extract_code_from_response(response: &str) -> String {
+/// Asks the model to condense the markdown context; see `markdown_summary`.
+fn build_summary_messages(snapshot: &ContextSnapshot) -> Vec<OpenAIMessage> {
+    let mut filenames: Vec<&String> = snapshot.markdown_cache.keys().collect();
+    filenames.sort();
+
+    let mut content = String::new();
+    for filename in filenames {
+        content.push_str(&format!("## {}\n\n{}\n\n", filename, snapshot.markdown_cache[filename]));
+    }
+
+    vec![
+        OpenAIMessage {
+            role: "system".to_string(),
+            content: "Summarize these project notes for a code completion model. Keep names, \
+                APIs, data shapes, conventions and the developer's intent; drop everything else. \
+                Reply with the summary only."
+                .to_string(),
+            reasoning_content: None,
+            cacheable_len: 0,
+        },
+        OpenAIMessage {
+            role: "user".to_string(),
+            content,
+            reasoning_content: None,
+            cacheable_len: 0,
+        },
+    ]
+}
+
+/// Fallback prompt for when the full one overflows the model's context
+/// window: no markdown, snippets or siblings, and only the text nearest the
+/// cursor.
+fn build_trimmed_messages(
+    snapshot: &ContextSnapshot,
+    prefix: &str,
+    suffix: &str,
+    language: &str,
+    uri: &str,
+    options: &PromptOptions,
+    hints: &RequestHints,
+) -> Vec<OpenAIMessage> {
+    let bare = ContextSnapshot {
+        session_id: snapshot.session_id.clone(),
+        version: snapshot.version,
+        limits: snapshot.limits.clone(),
+        session_dir: snapshot.session_dir.clone(),
+        // Standing instructions are short and still apply
+        chat_messages: snapshot.chat_messages.clone(),
+        ..ContextSnapshot::default()
+    };
+    let options = PromptOptions {
+        sibling_context: false,
+        cursor_marker: options.cursor_marker.clone(),
+        ..PromptOptions::default()
+    };
+
+    let prefix_start = prefix
+        .char_indices()
+        .rev()
+        .nth(TRIMMED_PREFIX_CHARS.saturating_sub(1))
+        .map_or(0, |(i, _)| i);
+    let suffix_end = suffix
+        .char_indices()
+        .nth(TRIMMED_SUFFIX_CHARS)
+        .map_or(suffix.len(), |(i, _)| i);
+
+    build_messages(
+        &bare,
+        &prefix[prefix_start..],
+        &suffix[..suffix_end],
+        language,
+        uri,
+        &options,
+        hints,
+        &PromptInputs::default(),
+    )
+}
+
+/// Lines `start..end` (0-based, end exclusive) of `content`, sliced out
+/// verbatim so CRLF line endings survive. Only the last line's terminator is
+/// dropped. `None` when `start` is past the end of the file.
+pub fn extract_line_range(content: &str, start: usize, end: usize) -> Option<&str> {
+    let lines: Vec<&str> = content.split_inclusive('\n').collect();
+    if start >= lines.len() {
+        return None;
+    }
+
+    let end = end.clamp(start, lines.len());
+    let from: usize = lines[..start].iter().map(|line| line.len()).sum();
+    let len: usize = lines[start..end].iter().map(|line| line.len()).sum();
+    let range = &content[from..from + len];
+
+    Some(
+        range
+            .strip_suffix('\n')
+            .map_or(range, |r| r.strip_suffix('\r').unwrap_or(r)),
+    )
+}
+
+/// Pulls the code out of a model response. When the response contains a
+/// complete fenced block anywhere (e.g. after "Here's the completion:"),
+/// only the first block's contents are returned; otherwise the response is
+/// used as-is.
+/// Fences follow CommonMark: backticks or tildes, indented by up to three
+/// spaces, closed by a run of the same character at least as long.
+pub fn extract_code_from_response(response: &str) -> String {
     let trimmed = response.trim();
+    let lines: Vec<&str> = trimmed.lines().collect();
 
-    if trimmed.starts_with("```") {
-        if let Some(first_newline) = trimmed.find('\n') {
-            let after_lang = &trimmed[first_newline + 1..];
-            if let Some(closing_fence_pos) = after_lang.rfind("```") {
-                return after_lang[..closing_fence_pos].trim().to_string();
-            }
-        }
+    // A fence without its closing line (e.g. a stray ``` after the code)
+    // doesn't make a block
+    let Some((body, fence)) = lines.iter().enumerate().find_map(|(open, line)| {
+        let fence = Fence::open(line)?;
+        let body = &lines[open + 1..];
+        let close = body.iter().position(|line| fence.is_closed_by(line))?;
+        Some((&body[..close], fence))
+    }) else {
+        return trimmed.to_string();
+    };
+
+    body
+        .iter()
+        .map(|line| fence.strip_indent(line))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+struct Fence {
+    indent: usize,
+    marker: char,
+    len: usize,
+}
+
+impl Fence {
+    /// Up to three spaces, then three or more backticks or tildes and an
+    /// optional info string.
+    fn open(line: &str) -> Option<Self> {
+        let rest = line.trim_start_matches(' ');
+        let indent = line.len() - rest.len();
+        let marker = rest.chars().next().filter(|&c| c == '`' || c == '~')?;
+        let len = rest.chars().take_while(|&c| c == marker).count();
+        (indent <= 3 && len >= 3).then_some(Self { indent, marker, len })
     }
 
-    trimmed.to_string()
+    fn is_closed_by(&self, line: &str) -> bool {
+        let rest = line.trim_start_matches(' ');
+        let run = rest.trim_end();
+        line.len() - rest.len() <= 3
+            && run.len() >= self.len
+            && run.chars().all(|c| c == self.marker)
+    }
+
+    /// Drops as much leading indentation as the opening fence had.
+    fn strip_indent<'a>(&self, line: &'a str) -> &'a str {
+        let spaces = line.len() - line.trim_start_matches(' ').len();
+        &line[spaces.min(self.indent)..]
+    }
 }
@@ -0,0 +1,177 @@
+use std::borrow::Cow;
+
+use crate::languages;
+
+/// Overlaps shorter than this many non-whitespace characters are left alone,
+/// so a completion that legitimately starts with the last typed character
+/// isn't clipped.
+const MIN_OVERLAP_CHARS: usize = 2;
+
+/// Only the tail of the prefix is compared against the completion.
+const MAX_OVERLAP_BYTES: usize = 1024;
+
+/// Removes text the model echoed from the end of `prefix`.
+///
+/// Finds the longest suffix of `prefix` that is also a prefix of
+/// `completion` and strips it. The overlap must start at a token boundary
+/// in the prefix, so for a prefix ending in `def fibona` both
+/// `def fibonacci(n):` and `fibonacci(n):` become `cci(n):`, while an
+/// overlap that begins mid-identifier (`ona` in `fibona`) is ignored.
+pub fn trim_prefix_overlap<'a>(prefix: &str, completion: &'a str) -> &'a str {
+    let max_len = completion.len().min(prefix.len()).min(MAX_OVERLAP_BYTES);
+
+    for len in (1..=max_len).rev() {
+        if !completion.is_char_boundary(len) {
+            continue;
+        }
+
+        let candidate = &completion[..len];
+        if !prefix.ends_with(candidate) {
+            continue;
+        }
+
+        if candidate.chars().filter(|c| !c.is_whitespace()).count() < MIN_OVERLAP_CHARS {
+            continue;
+        }
+
+        let overlap_start = prefix.len() - len;
+        if !starts_at_token_boundary(prefix, overlap_start) {
+            continue;
+        }
+
+        return &completion[len..];
+    }
+
+    completion
+}
+
+fn starts_at_token_boundary(text: &str, idx: usize) -> bool {
+    let before = text[..idx].chars().next_back();
+    let at = text[idx..].chars().next();
+
+    match (before, at) {
+        (None, _) => true,
+        (Some(b), Some(a)) => !(is_word_char(b) && is_word_char(a)),
+        (Some(_), None) => true,
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Caps a completion at `max_lines` lines and `max_chars` characters.
+///
+/// Both limits cut at a line boundary: the character cap keeps every whole
+/// line that fits and only splits a line when the first line alone is too
+/// long. `None` or `0` disables a limit.
+pub fn truncate_completion(
+    completion: &str,
+    max_lines: Option<usize>,
+    max_chars: Option<usize>,
+) -> &str {
+    let mut end = completion.len();
+
+    if let Some(max_lines) = max_lines.filter(|&n| n > 0)
+        && let Some((idx, _)) = completion.match_indices('\n').nth(max_lines - 1)
+    {
+        end = idx;
+    }
+
+    if let Some(max_chars) = max_chars.filter(|&n| n > 0)
+        && let Some((cap, _)) = completion[..end].char_indices().nth(max_chars)
+    {
+        end = completion[..cap].rfind('\n').unwrap_or(cap);
+    }
+
+    &completion[..end]
+}
+
+/// Cuts `completion` after the line that closes the block the cursor is
+/// in, so the model finishes the current function or statement instead of
+/// starting the next one. Completions in languages without braces, or that
+/// never leave the block, are returned whole.
+pub fn stop_at_block_end<'a>(prefix: &str, completion: &'a str, language_id: &str) -> &'a str {
+    match languages::block_end(prefix, completion, language_id) {
+        Some(end) => &completion[..end],
+        None => completion,
+    }
+}
+
+/// Line endings after which the next code belongs on a fresh line. Python
+/// blocks open with `:` instead of `{`.
+const LINE_TERMINATORS: &[char] = &['{', '}', ';'];
+
+/// Characters that continue the current line rather than start a new one.
+const LINE_CONTINUATIONS: &[char] = &['.', ',', ';', ')', ']', '}', '?', ':'];
+
+/// Keywords that conventionally follow a `}` on the same line.
+const SAME_LINE_KEYWORDS: &[&str] = &["else", "catch", "finally", "while"];
+
+/// Fits the start of `completion` to where the cursor sits.
+///
+/// Mid-line (text follows the cursor on the same line) a leading newline is
+/// stripped, since the completion continues the line. At the end of a line
+/// that ends a statement or opens a block (`{`, `;`, `:`...), a completion
+/// that starts a new statement gets a newline plus the line's indentation
+/// (one level deeper after an opener) prepended.
+pub fn adjust_leading_newline<'a>(
+    prefix: &str,
+    suffix: &str,
+    completion: &'a str,
+    language_id: &str,
+) -> Cow<'a, str> {
+    if completion.is_empty() {
+        return Cow::Borrowed(completion);
+    }
+
+    let rest_of_line = suffix.split('\n').next().unwrap_or_default();
+    if !rest_of_line.trim().is_empty() {
+        return Cow::Borrowed(completion.trim_start_matches(['\n', '\r']));
+    }
+
+    let current_line = prefix.rsplit('\n').next().unwrap_or_default();
+    let content = current_line.trim_end();
+    let Some(last) = content.chars().last() else {
+        return Cow::Borrowed(completion);
+    };
+
+    let opens_python_block = language_id == "python" && last == ':';
+    // `vec![0;` or `for (i = 0;` end in a terminator but aren't finished lines
+    let open_brackets = content.matches(['(', '[']).count();
+    let close_brackets = content.matches([')', ']']).count();
+    if completion.starts_with(['\n', '\r'])
+        || open_brackets > close_brackets
+        || !(LINE_TERMINATORS.contains(&last) || opens_python_block)
+        || completion.starts_with(LINE_CONTINUATIONS)
+        || (last == '}' && SAME_LINE_KEYWORDS.iter().any(|kw| completion.starts_with(kw)))
+    {
+        return Cow::Borrowed(completion);
+    }
+
+    let indent_len = current_line.len() - current_line.trim_start().len();
+    let mut indent = current_line[..indent_len].to_string();
+    if last == '{' || opens_python_block {
+        indent.push_str(indent_unit(prefix));
+    }
+
+    Cow::Owned(format!("\n{}{}", indent, completion))
+}
+
+/// The indentation step used in `text`: a tab or the smallest run of spaces.
+fn indent_unit(text: &str) -> &'static str {
+    let mut smallest = usize::MAX;
+    for line in text.lines() {
+        if line.starts_with('\t') {
+            return "\t";
+        }
+        let spaces = line.len() - line.trim_start_matches(' ').len();
+        if spaces > 0 && spaces < line.len() {
+            smallest = smallest.min(spaces);
+        }
+    }
+    match smallest {
+        2 => "  ",
+        _ => "    ",
+    }
+}
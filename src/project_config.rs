@@ -0,0 +1,107 @@
+use serde_json::{Map, Value};
+use std::path::Path;
+
+use crate::config::Settings;
+use crate::error::{Result, SnekError};
+use crate::jsonc;
+
+pub const PROJECT_CONFIG_FILE: &str = "config.json";
+
+/// Keys besides the `Settings` fields: the model and the endpoint. The API
+/// key is left out on purpose, as the file is meant to be committed.
+const STARTUP_KEYS: &[&str] = &["model", "apiUrl"];
+
+/// Per-project defaults from `.snek/config.json`, checked into the repo.
+/// Keys are those of the client's `snek` settings section, e.g.
+///
+/// ```json
+/// { "model": "llama-4-scout", "temperature": 0.2, "maxTokens": 800, "languages": ["rust"] }
+/// ```
+///
+/// Settings the client sends take precedence key by key. Besides the
+/// settings keys, `model` sets the model and `apiUrl` the endpoint, unless
+/// `SNEK_MODEL` or `SNEK_API_URL` is set.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProjectConfig {
+    pub values: Map<String, Value>,
+}
+
+impl ProjectConfig {
+    /// Reads `config.json` from `snek_root`; a missing file is an empty config.
+    pub fn load(snek_root: &Path) -> Result<Self> {
+        let path = snek_root.join(PROJECT_CONFIG_FILE);
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(SnekError::io("Failed to read config.json")(e)),
+        };
+
+        let values = serde_json::from_str(&jsonc::strip(&content))
+            .map_err(SnekError::parse(PROJECT_CONFIG_FILE))?;
+        let config = Self { values };
+        config.validate()?;
+        for key in config.unknown_keys() {
+            eprintln!("[SNEK] Warning: unknown key {:?} in .snek/config.json", key);
+        }
+        Ok(config)
+    }
+
+    /// Checks values against the settings they set, so a mistyped value
+    /// is reported instead of silently resetting every setting.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |message: String| SnekError::Config(format!("Invalid .snek/config.json: {}", message));
+
+        for key in STARTUP_KEYS {
+            if let Some(value) = self.values.get(*key)
+                && !value.is_string()
+                && !value.is_null()
+            {
+                return Err(invalid(format!("{:?} must be a string", key)));
+            }
+        }
+        serde_json::from_value::<Settings>(Value::Object(self.values.clone()))
+            .map_err(|e| invalid(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Keys that are neither settings nor `STARTUP_KEYS`, e.g. typos; they
+    /// are ignored.
+    pub fn unknown_keys(&self) -> Vec<&str> {
+        let Ok(Value::Object(settings)) = serde_json::to_value(Settings::default()) else {
+            return vec![];
+        };
+        self.values
+            .keys()
+            .map(String::as_str)
+            .filter(|key| !settings.contains_key(*key) && !STARTUP_KEYS.contains(key))
+            .collect()
+    }
+
+    pub fn model(&self) -> Option<&str> {
+        self.values
+            .get("model")
+            .and_then(Value::as_str)
+            .filter(|m| !m.is_empty())
+    }
+
+    pub fn api_url(&self) -> Option<&str> {
+        self.values
+            .get("apiUrl")
+            .and_then(Value::as_str)
+            .filter(|u| !u.is_empty())
+    }
+
+    /// This config with the client's `snek` section layered on top. Null
+    /// client values don't hide the project's.
+    pub fn merge(&self, client: Option<&Value>) -> Value {
+        let mut merged = self.values.clone();
+        if let Some(Value::Object(client)) = client {
+            for (key, value) in client {
+                if !value.is_null() {
+                    merged.insert(key.clone(), value.clone());
+                }
+            }
+        }
+        Value::Object(merged)
+    }
+}
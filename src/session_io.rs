@@ -1,8 +1,12 @@
-use anyhow::{Context, Result};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 
-use crate::snapshot::{CodeContext, ContextSnapshot, Limits};
+use crate::error::{Result, SnekError};
+use crate::jsonc;
+use crate::model::extract_line_range;
+use crate::snapshot::{
+    ChatMessage, CodeContext, ContextFilter, ContextSnapshot, Limits, ProjectMeta,
+};
 
 #[derive(Deserialize)]
 #[allow(dead_code)]
@@ -21,50 +25,117 @@ struct SessionJson {
     version: u64,
     limits: Limits,
     updated_at: String,
+    #[serde(default)]
+    requests_used: u64,
+    #[serde(default)]
+    context_filter: Option<ContextFilter>,
+    #[serde(default)]
+    meta: Option<ProjectMeta>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 #[allow(dead_code)]
 struct CodeSnippetsJson {
     schema: u32,
     snippets: Vec<CodeContext>,
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct ChatJson {
+    #[serde(default)]
+    schema: u32,
+    messages: Vec<ChatMessage>,
 }
 
 pub fn find_workspace_root(workspace_dir: Option<PathBuf>) -> Result<PathBuf> {
     if let Some(workspace) = workspace_dir {
         let snek_dir = workspace.join(".snek");
 
-        if snek_dir.exists() && snek_dir.is_dir() {
+        if is_snek_dir(&snek_dir)? {
             return Ok(snek_dir);
         }
 
-        std::fs::create_dir_all(&snek_dir)?;
-        initialize_default_session(&snek_dir)?;
+        create_snek_dir(&snek_dir)?;
         return Ok(snek_dir);
     }
 
-    let current = std::env::current_dir()?;
-    let mut path = current.as_path();
+    let current =
+        std::env::current_dir().map_err(SnekError::io("Failed to read current directory"))?;
+    find_workspace_root_from(&current)
+}
 
-    loop {
-        let snek_dir = path.join(".snek");
-        if snek_dir.exists() && snek_dir.is_dir() {
-            return Ok(snek_dir);
+/// Like `find_workspace_root`, but `None` instead of creating `.snek` when
+/// there is none; never writes anything.
+pub fn locate_workspace_root(workspace_dir: Option<PathBuf>) -> Result<Option<PathBuf>> {
+    if let Some(workspace) = workspace_dir {
+        let snek_dir = workspace.join(".snek");
+        return Ok(is_snek_dir(&snek_dir)?.then_some(snek_dir));
+    }
+
+    let current =
+        std::env::current_dir().map_err(SnekError::io("Failed to read current directory"))?;
+    search_ancestors(&current)
+}
+
+/// Most directories `find_workspace_root_from` looks at before giving up.
+const MAX_ANCESTORS: usize = 256;
+
+/// Nearest `.snek` directory in `start` or one of its ancestors, created in
+/// `start` when there is none. `start` is canonicalized first so the ascent
+/// follows real parents rather than symlinks, and stops on a repeated path
+/// or after `MAX_ANCESTORS` directories.
+pub fn find_workspace_root_from(start: &Path) -> Result<PathBuf> {
+    if let Some(snek_dir) = search_ancestors(start)? {
+        return Ok(snek_dir);
+    }
+
+    let snek_dir = start.join(".snek");
+    create_snek_dir(&snek_dir)?;
+    Ok(snek_dir)
+}
+
+fn search_ancestors(start: &Path) -> Result<Option<PathBuf>> {
+    let real_start = start.canonicalize().unwrap_or_else(|_| start.to_path_buf());
+    let mut visited = std::collections::HashSet::new();
+    let mut path = Some(real_start.as_path());
+
+    while let Some(dir) = path {
+        if visited.len() >= MAX_ANCESTORS || !visited.insert(dir.to_path_buf()) {
+            eprintln!("[SNEK] Warning: stopped searching for .snek at {:?}", dir);
+            break;
         }
 
-        match path.parent() {
-            Some(parent) => path = parent,
-            None => {
-                let snek_dir = current.join(".snek");
-                std::fs::create_dir_all(&snek_dir)?;
-                initialize_default_session(&snek_dir)?;
-                return Ok(snek_dir);
-            }
+        let snek_dir = dir.join(".snek");
+        if is_snek_dir(&snek_dir)? {
+            return Ok(Some(snek_dir));
         }
+        path = dir.parent();
     }
+
+    Ok(None)
+}
+
+/// Whether `snek_dir` exists. Anything other than a directory there is an
+/// error: it would only make creating the directory fail later, or send the
+/// search on to an unrelated ancestor.
+fn is_snek_dir(snek_dir: &Path) -> Result<bool> {
+    match std::fs::metadata(snek_dir) {
+        Ok(metadata) if metadata.is_dir() => Ok(true),
+        Ok(_) => Err(SnekError::SnekDirNotDirectory(snek_dir.to_path_buf())),
+        Err(_) => Ok(false),
+    }
+}
+
+fn create_snek_dir(snek_dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(snek_dir)
+        .and_then(|_| initialize_default_session(snek_dir))
+        .map_err(SnekError::io(format!("Failed to initialize {}", snek_dir.display())))
 }
 
-fn initialize_default_session(snek_root: &Path) -> Result<()> {
+fn initialize_default_session(snek_root: &Path) -> std::io::Result<()> {
     let session_id = uuid::Uuid::new_v4().to_string();
     let session_dir = snek_root.join("sessions").join(&session_id);
     std::fs::create_dir_all(&session_dir)?;
@@ -157,7 +228,7 @@ fn initialize_default_session(snek_root: &Path) -> Result<()> {
     Ok(())
 }
 
-fn write_script_file(snek_root: &Path, relative_path: &str, content: &str) -> Result<()> {
+fn write_script_file(snek_root: &Path, relative_path: &str, content: &str) -> std::io::Result<()> {
     let file_path = snek_root.join(relative_path);
     if !file_path.exists() {
         std::fs::write(&file_path, content)?;
@@ -177,68 +248,474 @@ fn write_script_file(snek_root: &Path, relative_path: &str, content: &str) -> Re
 
 pub fn resolve_active_session(snek_root: &Path) -> Result<PathBuf> {
     let active_path = snek_root.join("active.json");
-    let content = std::fs::read_to_string(&active_path).context("Failed to read active.json")?;
+    let content = std::fs::read_to_string(&active_path)
+        .map_err(SnekError::io("Failed to read active.json"))?;
     let active: ActiveJson =
-        serde_json::from_str(&content).context("Failed to parse active.json")?;
+        serde_json::from_str(&jsonc::strip(&content)).map_err(SnekError::parse("active.json"))?;
+
+    resolve_session_path(snek_root, &active.path)
+}
+
+/// Resolves the `path` from active.json. Relative paths are joined onto
+/// `snek_root`; absolute paths are used as-is (`join` would silently drop the
+/// root). Either way the result must resolve to a location inside
+/// `snek_root`.
+pub fn resolve_session_path(snek_root: &Path, path: &str) -> Result<PathBuf> {
+    let path = Path::new(path);
+    let session_dir = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        snek_root.join(path)
+    };
+
+    let root = snek_root
+        .canonicalize()
+        .map_err(SnekError::io(format!("Failed to resolve {:?}", snek_root)))?;
+    let resolved = session_dir.canonicalize().map_err(SnekError::io(format!(
+        "Session directory {:?} does not exist",
+        session_dir
+    )))?;
+    if !resolved.starts_with(&root) {
+        return Err(SnekError::SessionOutsideRoot {
+            path: resolved,
+            root,
+        });
+    }
+
+    Ok(session_dir)
+}
+
+/// How much of a snippet source `read_snippet_source` checks for NUL bytes.
+const BINARY_SNIFF_BYTES: usize = 8000;
 
-    Ok(snek_root.join(&active.path))
+/// Contents of a code snippet's source file, or `None` when it looks
+/// binary (a NUL byte near the start, as git judges it) or isn't UTF-8,
+/// which would only put garbage in the prompt.
+pub fn read_snippet_source(path: &Path) -> std::io::Result<Option<String>> {
+    let bytes = std::fs::read(path)?;
+    if bytes[..bytes.len().min(BINARY_SNIFF_BYTES)].contains(&0) {
+        eprintln!("[SNEK] Warning: skipping binary snippet source {}", path.display());
+        return Ok(None);
+    }
+    match String::from_utf8(bytes) {
+        Ok(content) => Ok(Some(content)),
+        Err(_) => {
+            eprintln!("[SNEK] Warning: skipping non-UTF-8 snippet source {}", path.display());
+            Ok(None)
+        }
+    }
 }
 
 pub fn load_snapshot(session_dir: &Path) -> Result<ContextSnapshot> {
     let session_path = session_dir.join("session.json");
-    let session_content =
-        std::fs::read_to_string(&session_path).context("Failed to read session.json")?;
-    let session: SessionJson =
-        serde_json::from_str(&session_content).context("Failed to parse session.json")?;
+    let session_content = std::fs::read_to_string(&session_path)
+        .map_err(SnekError::io("Failed to read session.json"))?;
+    let session: SessionJson = serde_json::from_str(&jsonc::strip(&session_content))
+        .map_err(SnekError::parse("session.json"))?;
 
     let snippets_path = session_dir.join("code_snippets.json");
     let code_snippets = if snippets_path.exists() {
-        let snippets_content =
-            std::fs::read_to_string(&snippets_path).context("Failed to read code_snippets.json")?;
-        let snippets: CodeSnippetsJson =
-            serde_json::from_str(&snippets_content).context("Failed to parse code_snippets.json")?;
-        snippets.snippets
+        let snippets_content = std::fs::read_to_string(&snippets_path)
+            .map_err(SnekError::io("Failed to read code_snippets.json"))?;
+        let snippets: CodeSnippetsJson = serde_json::from_str(&jsonc::strip(&snippets_content))
+            .map_err(SnekError::parse("code_snippets.json"))?;
+        dedup_snippets(snippets.snippets)
     } else {
         vec![]
     };
 
-    let mut markdown_cache = std::collections::HashMap::new();
-    let context_dir = session_dir.join("context");
-    if context_dir.exists() && context_dir.is_dir() {
-        if let Ok(entries) = std::fs::read_dir(&context_dir) {
-            for entry in entries.flatten() {
-                let path = entry.path();
-                if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("md") {
-                    if let Some(filename) = path.file_name().and_then(|n| n.to_str()) {
-                        if let Ok(content) = std::fs::read_to_string(&path) {
-                            markdown_cache.insert(filename.to_string(), content);
-                        }
-                    }
-                }
+    let mut snapshot = ContextSnapshot {
+        session_id: session.id,
+        version: session.version,
+        limits: session.limits,
+        context_filter: session.context_filter,
+        meta: session.meta,
+        session_dir: session_dir.to_path_buf(),
+        // Sessions from before requests_used.json kept the count in session.json
+        requests_used: read_requests_used(session_dir).unwrap_or(session.requests_used),
+        ..ContextSnapshot::default()
+    };
+
+    if let Some(context_dir) = resolve_context_dir(session_dir)
+        && let Ok(entries) = std::fs::read_dir(&context_dir)
+    {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_file()
+                && path.extension().and_then(|s| s.to_str()) == Some("md")
+                && let Some(filename) = path.file_name().and_then(|n| n.to_str())
+                && let Ok(content) = std::fs::read_to_string(&path)
+            {
+                snapshot.cache_markdown(filename.to_string(), content);
             }
         }
     }
 
-    let mut file_cache = std::collections::HashMap::new();
     for snippet in &code_snippets {
-        if !file_cache.contains_key(&snippet.uri) {
-            if let Ok(uri) = url::Url::parse(&snippet.uri) {
-                if let Ok(file_path) = uri.to_file_path() {
-                    if let Ok(content) = std::fs::read_to_string(&file_path) {
-                        file_cache.insert(snippet.uri.clone(), content);
-                    }
-                }
+        if !snapshot.file_cache.contains_key(&snippet.uri)
+            && let Ok(uri) = url::Url::parse(&snippet.uri)
+            && let Ok(file_path) = uri.to_file_path()
+        {
+            match read_snippet_source(&file_path) {
+                Ok(Some(content)) => snapshot.cache_file(snippet.uri.clone(), content),
+                Ok(None) => snapshot.set_source_skipped(&snippet.uri, true),
+                Err(_) => {}
             }
         }
     }
 
-    Ok(ContextSnapshot {
-        session_id: session.id,
-        version: session.version,
-        limits: session.limits,
-        session_dir: session_dir.to_path_buf(),
-        code_snippets,
-        markdown_cache,
-        file_cache,
+    if let Some(warning) = snapshot.limits.warning() {
+        eprintln!("[SNEK] {}", warning);
+    }
+
+    snapshot.code_contexts = code_snippets;
+    snapshot.chat_messages = load_chat_messages(session_dir)?;
+    Ok(snapshot)
+}
+
+/// Reads the lines `context` references from its file into `code` and
+/// records the file's modification time in `last_modified`.
+pub fn update_context_from_file(context: &mut CodeContext) -> Result<()> {
+    let path = url::Url::parse(&context.uri)
+        .ok()
+        .and_then(|uri| uri.to_file_path().ok())
+        .ok_or_else(|| SnekError::Config(format!("Not a file URI: {}", context.uri)))?;
+    let content = std::fs::read_to_string(&path)
+        .map_err(SnekError::io(format!("Failed to read {}", context.uri)))?;
+    let modified = std::fs::metadata(&path)
+        .and_then(|metadata| metadata.modified())
+        .map_err(SnekError::io(format!("Failed to stat {}", context.uri)))?;
+
+    let line_range_error = || SnekError::LineRange {
+        uri: context.uri.clone(),
+        start: context.start_line,
+        end: context.end_line,
+        lines: content.lines().count(),
+    };
+    // `extract_line_range` clamps an end past the last line; a stored
+    // range has to fit the file as a whole
+    if context.end_line as usize > content.split_inclusive('\n').count() {
+        return Err(line_range_error());
+    }
+    let code = extract_line_range(
+        &content,
+        context.start_line as usize,
+        context.end_line as usize,
+    )
+    .ok_or_else(line_range_error)?;
+
+    context.code = code.to_string();
+    context.last_modified = chrono::DateTime::<chrono::Utc>::from(modified).to_rfc3339();
+    Ok(())
+}
+
+/// The session's standing instructions from `chat.json`; none if it doesn't exist.
+pub fn load_chat_messages(session_dir: &Path) -> Result<Vec<ChatMessage>> {
+    let content = match std::fs::read_to_string(session_dir.join("chat.json")) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(SnekError::io("Failed to read chat.json")(e)),
+    };
+    let chat: ChatJson = serde_json::from_str(&jsonc::strip(&content))
+        .map_err(SnekError::parse("chat.json"))?;
+    Ok(chat.messages)
+}
+
+/// Where a session's model request count is kept, apart from the
+/// hand-edited `session.json`.
+const REQUESTS_USED_FILE: &str = "requests_used.json";
+
+#[derive(Deserialize, Serialize)]
+struct RequestsUsedJson {
+    requests_used: u64,
+}
+
+/// Serializes `record_requests_used` calls, which may come from several
+/// blocking threads at once.
+static REQUESTS_USED_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// The count stored by `record_requests_used`, if any.
+fn read_requests_used(session_dir: &Path) -> Option<u64> {
+    let content = std::fs::read_to_string(session_dir.join(REQUESTS_USED_FILE)).ok()?;
+    serde_json::from_str::<RequestsUsedJson>(&content)
+        .ok()
+        .map(|usage| usage.requests_used)
+}
+
+#[derive(Deserialize)]
+struct SessionRequestsUsed {
+    #[serde(default)]
+    requests_used: u64,
+}
+
+/// The session's model request count as `load_snapshot` reads it, without
+/// loading the rest of the session.
+fn stored_requests_used(session_dir: &Path) -> u64 {
+    read_requests_used(session_dir).unwrap_or_else(|| {
+        std::fs::read_to_string(session_dir.join("session.json"))
+            .ok()
+            .and_then(|content| {
+                serde_json::from_str::<SessionRequestsUsed>(&jsonc::strip(&content)).ok()
+            })
+            .map_or(0, |session| session.requests_used)
     })
 }
+
+/// Stores the session's model request count in `requests_used.json`,
+/// leaving `session.json` untouched. The count never goes down, so a write
+/// that lost a race with a later one is dropped.
+pub fn record_requests_used(session_dir: &Path, requests_used: u64) -> Result<()> {
+    let _guard = REQUESTS_USED_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    if read_requests_used(session_dir).is_some_and(|stored| stored >= requests_used) {
+        return Ok(());
+    }
+
+    let path = session_dir.join(REQUESTS_USED_FILE);
+    let json = serde_json::to_string(&RequestsUsedJson { requests_used })
+        .map_err(SnekError::parse(REQUESTS_USED_FILE))?;
+    let staged = staging_path(&path);
+    std::fs::write(&staged, json)
+        .and_then(|()| std::fs::rename(&staged, &path))
+        .map_err(SnekError::io(format!("Failed to write {}", REQUESTS_USED_FILE)))
+}
+
+/// Files of a session that `save_context_snapshot` captures, besides
+/// `context/`. A missing `code_snippets.json` is restored as missing.
+const SNAPSHOT_FILES: &[&str] = &["session.json", "code_snippets.json"];
+
+/// Where `save_context_snapshot` keeps `name`, after checking the name is
+/// a plain file name: letters, digits, `-`, `_` and `.`, not starting with
+/// a dot.
+pub fn context_snapshot_dir(snek_root: &Path, name: &str) -> Result<PathBuf> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(SnekError::Config(format!(
+            "Invalid snapshot name {:?}: use up to 64 letters, digits, '-', '_' or '.', not starting with '.'",
+            name
+        )));
+    }
+    Ok(snek_root.join("snapshots").join(name))
+}
+
+/// Copies the session's `session.json`, `code_snippets.json` and
+/// `context/` to `.snek/snapshots/<name>/`, replacing an older snapshot of
+/// that name. The copy is staged next to it and renamed into place, so a
+/// failed save leaves the old snapshot intact.
+pub fn save_context_snapshot(snek_root: &Path, session_dir: &Path, name: &str) -> Result<PathBuf> {
+    let target = context_snapshot_dir(snek_root, name)?;
+    let staged = staging_path(&target);
+
+    let result = (|| {
+        std::fs::create_dir_all(&staged)?;
+        for file in SNAPSHOT_FILES {
+            let source = session_dir.join(file);
+            if source.exists() {
+                std::fs::copy(&source, staged.join(file))?;
+            }
+        }
+        if let Some(context_dir) = resolve_context_dir(session_dir) {
+            copy_dir(&context_dir, &staged.join("context"))?;
+        }
+        replace_dir(&staged, &target)
+    })();
+    if let Err(e) = result {
+        let _ = std::fs::remove_dir_all(&staged);
+        return Err(SnekError::io(format!("Failed to save snapshot {:?}", name))(e));
+    }
+
+    eprintln!("[SNEK] Saved context snapshot {:?} to {:?}", name, target);
+    Ok(target)
+}
+
+/// Puts the files saved by `save_context_snapshot` back into the session.
+/// Each file and `context/` is swapped in whole. The session's
+/// `requests_used` is kept, so restoring doesn't reset the request cap.
+pub fn restore_context_snapshot(snek_root: &Path, session_dir: &Path, name: &str) -> Result<()> {
+    let source = context_snapshot_dir(snek_root, name)?;
+    if !source.join("session.json").is_file() {
+        return Err(SnekError::Config(format!("No context snapshot named {:?}", name)));
+    }
+    let requests_used = stored_requests_used(session_dir);
+
+    let result = (|| {
+        for file in SNAPSHOT_FILES {
+            let saved = source.join(file);
+            let target = session_dir.join(file);
+            if saved.exists() {
+                let staged = staging_path(&target);
+                std::fs::copy(&saved, &staged)?;
+                std::fs::rename(&staged, &target)?;
+            } else if target.exists() {
+                std::fs::remove_file(&target)?;
+            }
+        }
+
+        let context_dir =
+            resolve_context_dir(session_dir).unwrap_or_else(|| session_dir.join("context"));
+        let staged = staging_path(&context_dir);
+        let saved_context = source.join("context");
+        if saved_context.is_dir() {
+            copy_dir(&saved_context, &staged)?;
+        } else {
+            std::fs::create_dir_all(&staged)?;
+        }
+        replace_dir(&staged, &context_dir)
+    })();
+    result.map_err(SnekError::io(format!("Failed to restore snapshot {:?}", name)))?;
+
+    if requests_used > 0 {
+        record_requests_used(session_dir, requests_used)?;
+    }
+    eprintln!("[SNEK] Restored context snapshot {:?} into {:?}", name, session_dir);
+    Ok(())
+}
+
+/// A hidden sibling of `path` to build its replacement in.
+fn staging_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}.{}.tmp", name, uuid::Uuid::new_v4()))
+}
+
+/// Moves the directory `staged` to `target`, replacing what is there.
+fn replace_dir(staged: &Path, target: &Path) -> std::io::Result<()> {
+    if !target.exists() {
+        return std::fs::rename(staged, target);
+    }
+
+    let old = staging_path(target);
+    std::fs::rename(target, &old)?;
+    if let Err(e) = std::fs::rename(staged, target) {
+        let _ = std::fs::rename(&old, target);
+        return Err(e);
+    }
+    std::fs::remove_dir_all(&old)
+}
+
+/// Copies the files and directories under `source`. Symlinks are
+/// skipped, so a link can't pull in files from outside the session or
+/// loop back on itself.
+fn copy_dir(source: &Path, target: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(target)?;
+    for entry in std::fs::read_dir(source)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let path = entry.path();
+        if file_type.is_symlink() {
+            eprintln!("[SNEK] Skipping symlink {:?} in context snapshot", path);
+        } else if file_type.is_dir() {
+            copy_dir(&path, &target.join(entry.file_name()))?;
+        } else {
+            std::fs::copy(&path, target.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct SessionStamp {
+    #[serde(default)]
+    updated_at: String,
+}
+
+/// Deletes the least recently updated sessions under `sessions/` until at
+/// most `max_sessions` remain, and returns the removed directories. The
+/// active session is never removed, and only directories holding a
+/// readable `session.json` count as sessions. A missing or malformed
+/// `updated_at` sorts as oldest.
+pub fn prune_sessions(snek_root: &Path, max_sessions: usize) -> Result<Vec<PathBuf>> {
+    let sessions_dir = snek_root.join("sessions");
+    let active = resolve_active_session(snek_root)?;
+    let active = active.canonicalize().unwrap_or(active);
+
+    let entries = std::fs::read_dir(&sessions_dir)
+        .map_err(SnekError::io(format!("Failed to read {}", sessions_dir.display())))?;
+
+    let mut sessions = vec![];
+    for entry in entries.flatten() {
+        let dir = entry.path();
+        let Ok(content) = std::fs::read_to_string(dir.join("session.json")) else {
+            continue;
+        };
+        let Ok(stamp) = serde_json::from_str::<SessionStamp>(&jsonc::strip(&content)) else {
+            continue;
+        };
+        let updated_at = chrono::DateTime::parse_from_rfc3339(&stamp.updated_at).ok();
+        sessions.push((updated_at, dir));
+    }
+
+    if sessions.len() <= max_sessions {
+        return Ok(vec![]);
+    }
+
+    sessions.sort();
+    let excess = sessions.len() - max_sessions;
+    let mut removed = vec![];
+    for (_, dir) in sessions {
+        if removed.len() == excess {
+            break;
+        }
+        if dir.canonicalize().is_ok_and(|d| d == active) {
+            continue;
+        }
+        match std::fs::remove_dir_all(&dir) {
+            Ok(()) => {
+                eprintln!("[SNEK] Removed old session {:?}", dir);
+                removed.push(dir);
+            }
+            Err(e) => eprintln!("[SNEK] Failed to remove old session {:?}: {}", dir, e),
+        }
+    }
+    Ok(removed)
+}
+
+/// Drops snippets with the same uri and line range as an earlier one, e.g.
+/// from running `add-code-snippet.sh` twice.
+pub fn dedup_snippets(snippets: Vec<CodeContext>) -> Vec<CodeContext> {
+    let total = snippets.len();
+    let mut seen = std::collections::HashSet::new();
+    let unique: Vec<CodeContext> = snippets
+        .into_iter()
+        .filter(|s| seen.insert((s.uri.clone(), s.start_line, s.end_line)))
+        .collect();
+
+    if unique.len() < total {
+        eprintln!(
+            "[SNEK] Collapsed {} duplicate code snippets",
+            total - unique.len()
+        );
+    }
+    unique
+}
+
+/// Returns the real location of a session's `context/` directory.
+///
+/// `context/` (or the session directory itself) may be a symlink; it is
+/// canonicalized so loading and watching both operate on the resolved
+/// target. A symlink loop or dangling link fails to canonicalize and is
+/// treated as having no context rather than aborting the load.
+pub fn resolve_context_dir(session_dir: &Path) -> Option<PathBuf> {
+    let context_dir = session_dir.join("context");
+    if !context_dir.exists() {
+        return None;
+    }
+
+    match std::fs::canonicalize(&context_dir) {
+        Ok(resolved) if resolved.is_dir() => Some(resolved),
+        Ok(resolved) => {
+            eprintln!("[SNEK] Warning: context path is not a directory: {:?}", resolved);
+            None
+        }
+        Err(e) => {
+            eprintln!("[SNEK] Warning: Failed to resolve context dir {:?}: {}", context_dir, e);
+            None
+        }
+    }
+}
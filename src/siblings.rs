@@ -0,0 +1,81 @@
+use std::path::{Path, PathBuf};
+
+/// Naming conventions tried when looking for siblings of `<stem>.<ext>`.
+pub const DEFAULT_PATTERNS: &[&str] = &[
+    "{stem}_test.{ext}",
+    "{stem}.test.{ext}",
+    "{stem}_spec.{ext}",
+    "{stem}.spec.{ext}",
+    "test_{stem}.{ext}",
+];
+
+/// Longest outline kept per sibling file.
+const MAX_OUTLINE_LINES: usize = 40;
+
+const DECLARATION_KEYWORDS: &[&str] = &[
+    "fn ", "pub ", "impl ", "struct ", "enum ", "trait ", "mod ", "def ", "class ", "async ",
+    "function ", "export ", "interface ", "type ", "func ", "const ", "static ", "public ",
+    "private ", "protected ", "describe(", "it(", "test(",
+];
+
+/// Finds files next to `file` related to it by one of `patterns`, e.g.
+/// `foo_test.rs` for `foo.rs`. Patterns work both ways, so `foo.rs` is also
+/// found for `foo_test.rs`. At most `max` existing files are returned.
+pub fn find_siblings(file: &Path, patterns: &[String], max: usize) -> Vec<PathBuf> {
+    let (Some(dir), Some(name)) = (file.parent(), file.file_name().and_then(|n| n.to_str()))
+    else {
+        return vec![];
+    };
+    let Some((stem, ext)) = name.rsplit_once('.') else {
+        return vec![];
+    };
+
+    let mut candidates = vec![];
+    for pattern in patterns {
+        // `file` is the base: look for `<pattern>` next to it
+        candidates.push(pattern.replace("{stem}", stem).replace("{ext}", ext));
+
+        // `file` matches the pattern: look for the base it was derived from
+        if let Some(base_stem) = match_pattern(pattern, stem) {
+            candidates.push(format!("{}.{}", base_stem, ext));
+        }
+    }
+
+    let mut siblings: Vec<PathBuf> = vec![];
+    for candidate in candidates {
+        let path = dir.join(candidate);
+        if siblings.len() >= max {
+            break;
+        }
+        if path != file && path.is_file() && !siblings.contains(&path) {
+            siblings.push(path);
+        }
+    }
+    siblings
+}
+
+/// Returns the `{stem}` for which `pattern` yields a file named `stem`.
+fn match_pattern<'a>(pattern: &str, stem: &'a str) -> Option<&'a str> {
+    let pattern_stem = pattern.strip_suffix(".{ext}")?;
+    let (before, after) = pattern_stem.split_once("{stem}")?;
+    if before.is_empty() && after.is_empty() {
+        return None;
+    }
+    stem.strip_prefix(before)?
+        .strip_suffix(after)
+        .filter(|base| !base.is_empty())
+}
+
+/// A rough outline of `text`: the declaration lines (functions, types,
+/// tests), without bodies. Capped at `MAX_OUTLINE_LINES`.
+pub fn outline(text: &str) -> String {
+    text.lines()
+        .filter(|line| {
+            let trimmed = line.trim_start();
+            DECLARATION_KEYWORDS.iter().any(|kw| trimmed.starts_with(kw))
+        })
+        .map(|line| line.trim_end())
+        .take(MAX_OUTLINE_LINES)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -1,6 +1,9 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct CodeContext {
@@ -8,30 +11,441 @@ pub struct CodeContext {
     pub start_line: u32,
     pub end_line: u32,
     pub language_id: String,
+    /// The referenced lines as of `last_modified`; see
+    /// `session_io::update_context_from_file`. Sent instead of the live file
+    /// when set.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub code: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
+    /// RFC 3339 modification time of the file `code` was read from.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub last_modified: String,
+    /// Fields written by newer tooling, kept so a rewrite doesn't drop them.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A standing instruction from the session's `chat.json`, sent ahead of
+/// every completion request as a prior turn.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Limits {
     pub max_tokens: usize,
+    /// Model requests allowed per session before completions stop.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_requests_per_session: Option<u64>,
+}
+
+/// Longest `description` of a `ProjectMeta` sent to the model; the hint is
+/// meant to be a sentence or two.
+pub const MAX_META_DESCRIPTION_CHARS: usize = 500;
+
+/// The project's stack, from session.json's `meta`, e.g.
+/// `{ "language": "Python", "framework": "Django" }`. Unlike the markdown
+/// context it is never filtered or summarized.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct ProjectMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub framework: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+impl ProjectMeta {
+    /// One line for the system prompt, e.g. "This is a Python project
+    /// using Django."; `None` when every field is blank.
+    pub fn hint(&self) -> Option<String> {
+        let mut hint = match (non_blank(&self.language), non_blank(&self.framework)) {
+            (Some(language), Some(framework)) => {
+                format!("This is a {} project using {}.", language, framework)
+            }
+            (Some(language), None) => format!("This is a {} project.", language),
+            (None, Some(framework)) => format!("This project uses {}.", framework),
+            (None, None) => String::new(),
+        };
+        if let Some(description) = non_blank(&self.description) {
+            if !hint.is_empty() {
+                hint.push(' ');
+            }
+            hint.extend(description.chars().take(MAX_META_DESCRIPTION_CHARS));
+        }
+
+        (!hint.is_empty()).then_some(hint)
+    }
+}
+
+fn non_blank(value: &Option<String>) -> Option<&str> {
+    value.as_deref().map(str::trim).filter(|v| !v.is_empty())
+}
+
+/// Which of the session's markdown files join the prompt, by file name:
+/// those matching any `include` pattern (every file when there are none)
+/// and no `exclude` pattern. Patterns support `*` and `?`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct ContextFilter {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+}
+
+impl ContextFilter {
+    pub fn allows(&self, filename: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| glob_match(p, filename)))
+            && !self.exclude.iter().any(|p| glob_match(p, filename))
+    }
+}
+
+/// Whether `name` matches `pattern`, where `*` stands for any run of
+/// characters and `?` for any one.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was, and how much of `name` it has taken so far
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, taken)) => {
+                    p = star + 1;
+                    n = taken + 1;
+                    backtrack = Some((star, taken + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Below this, most completions get cut off mid-line.
+pub const MIN_USEFUL_MAX_TOKENS: usize = 64;
+
+impl Limits {
+    /// Advice for the user when `max_tokens` is too small to be useful.
+    pub fn warning(&self) -> Option<String> {
+        if self.max_tokens >= MIN_USEFUL_MAX_TOKENS {
+            return None;
+        }
+        Some(format!(
+            "Snek: session max_tokens is {}, so completions will likely be cut off. \
+            Consider raising \"limits.max_tokens\" in session.json to 256 or more.",
+            self.max_tokens
+        ))
+    }
 }
 
 impl Default for Limits {
     fn default() -> Self {
-        Self { max_tokens: 1600 }
+        Self {
+            max_tokens: 1600,
+            max_requests_per_session: None,
+        }
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct ContextSnapshot {
     pub session_id: String,
+    /// The `version` in session.json, bumped by whoever edits the session.
     pub version: u64,
+    /// Times new context was swapped in for this snapshot since startup,
+    /// whether or not `version` changed. Not part of `content_hash`.
+    pub reloads: u64,
     pub limits: Limits,
+    /// The session's `context_filter`, if any.
+    pub context_filter: Option<ContextFilter>,
+    /// The session's `meta`, if any.
+    pub meta: Option<ProjectMeta>,
     pub session_dir: PathBuf,
-    pub code_snippets: Vec<CodeContext>,
+    pub code_contexts: Vec<CodeContext>,
+    pub chat_messages: Vec<ChatMessage>,
     pub markdown_cache: HashMap<String, String>,
     pub file_cache: HashMap<String, String>,
+    /// Model-written summary of `markdown_cache`. Clones of a snapshot share
+    /// it; the watcher starts a fresh one whenever the markdown changes.
+    pub markdown_summary: MarkdownSummary,
+    /// Model requests already made in this session, as of loading it.
+    pub requests_used: u64,
+    /// In-memory session with no files behind it; see `scratch()`.
+    pub scratch: bool,
+    /// Cap on the combined size of `markdown_cache` and `file_cache`, in
+    /// bytes. Past it, the least recently used entries are evicted.
+    pub max_cache_bytes: Option<usize>,
+    pub cache_recency: CacheRecency,
+    /// Snippet URIs whose source files were left out of `file_cache`
+    /// because they look binary or aren't UTF-8.
+    pub skipped_sources: Vec<String>,
+}
+
+impl ContextSnapshot {
+    /// Throwaway session with no context. Nothing is read from or written to
+    /// `.snek/` for it, and the watcher leaves it alone.
+    pub fn scratch() -> Self {
+        Self {
+            session_id: "scratch".to_string(),
+            scratch: true,
+            ..Self::default()
+        }
+    }
+
+    /// Counts `self` as a reload of `previous`.
+    pub fn count_reload(&mut self, previous: &ContextSnapshot) {
+        self.reloads = previous.reloads + 1;
+    }
+
+    /// Records whether the source of the snippet at `uri` was skipped.
+    pub fn set_source_skipped(&mut self, uri: &str, skipped: bool) {
+        let listed = self.skipped_sources.iter().any(|s| s == uri);
+        if skipped && !listed {
+            self.skipped_sources.push(uri.to_string());
+        } else if !skipped && listed {
+            self.skipped_sources.retain(|s| s != uri);
+        }
+    }
+
+    /// Tells the user about snippet sources skipped here but not in
+    /// `before`, so each is reported once.
+    pub fn skipped_sources_warning(&self, before: Option<&ContextSnapshot>) -> Option<String> {
+        let new: Vec<&str> = self
+            .skipped_sources
+            .iter()
+            .filter(|uri| before.is_none_or(|before| !before.skipped_sources.contains(uri)))
+            .map(String::as_str)
+            .collect();
+        if new.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "Snek: left out code snippet sources that look binary or aren't UTF-8: {}",
+            new.join(", ")
+        ))
+    }
+
+    /// Hash of the markdown files' names and contents.
+    pub fn markdown_hash(&self) -> u64 {
+        let mut files: Vec<_> = self.markdown_cache.iter().collect();
+        files.sort();
+
+        let mut hasher = DefaultHasher::new();
+        files.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Hash of everything loaded from the session's files, to tell whether
+    /// a fresh load differs. Leaves out `requests_used`, which is only read
+    /// at startup.
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.session_id.hash(&mut hasher);
+        self.version.hash(&mut hasher);
+        self.limits.max_tokens.hash(&mut hasher);
+        self.limits.max_requests_per_session.hash(&mut hasher);
+        self.context_filter.hash(&mut hasher);
+        self.meta.hash(&mut hasher);
+        // `extra` holds arbitrary JSON, which has no `Hash`
+        serde_json::to_string(&self.code_contexts)
+            .unwrap_or_default()
+            .hash(&mut hasher);
+        for message in &self.chat_messages {
+            (&message.role, &message.content).hash(&mut hasher);
+        }
+        let mut markdown: Vec<_> = self.markdown_cache.iter().collect();
+        markdown.sort();
+        markdown.hash(&mut hasher);
+        let mut files: Vec<_> = self.file_cache.iter().collect();
+        files.sort();
+        files.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Combined size of the markdown files, in characters.
+    pub fn markdown_chars(&self) -> usize {
+        self.markdown_cache.values().map(|c| c.chars().count()).sum()
+    }
+
+    pub fn cache_markdown(&mut self, filename: String, content: String) {
+        self.cache_recency.touch(CacheKind::Markdown, &filename);
+        self.markdown_cache.insert(filename, content);
+        self.enforce_cache_limit();
+    }
+
+    pub fn cache_file(&mut self, uri: String, content: String) {
+        self.cache_recency.touch(CacheKind::File, &uri);
+        self.file_cache.insert(uri, content);
+        self.enforce_cache_limit();
+    }
+
+    /// Records that a prompt used a cached entry, so entries no prompt
+    /// reads are evicted first.
+    pub fn mark_used(&self, kind: CacheKind, key: &str) {
+        self.cache_recency.touch(kind, key);
+    }
+
+    pub fn remove_markdown(&mut self, filename: &str) {
+        self.cache_recency.forget(CacheKind::Markdown, filename);
+        self.markdown_cache.remove(filename);
+    }
+
+    pub fn remove_file(&mut self, uri: &str) {
+        self.cache_recency.forget(CacheKind::File, uri);
+        self.file_cache.remove(uri);
+    }
+
+    /// Combined size of `markdown_cache` and `file_cache`, in bytes.
+    pub fn cache_bytes(&self) -> usize {
+        self.markdown_cache
+            .values()
+            .chain(self.file_cache.values())
+            .map(String::len)
+            .sum()
+    }
+
+    pub fn set_max_cache_bytes(&mut self, max_cache_bytes: Option<usize>) {
+        self.max_cache_bytes = max_cache_bytes;
+        self.enforce_cache_limit();
+    }
+
+    /// Evicts the least recently used entries until the caches fit in
+    /// `max_cache_bytes`, then entries never recorded in `cache_recency`.
+    /// Returns how many were evicted.
+    pub fn enforce_cache_limit(&mut self) -> usize {
+        let Some(max) = self.max_cache_bytes else {
+            return 0;
+        };
+        let mut total = self.cache_bytes();
+        if total <= max {
+            return 0;
+        }
+
+        let mut evicted = 0;
+        while total > max {
+            let Some((kind, key)) = self
+                .cache_recency
+                .pop_oldest()
+                .or_else(|| self.unrecorded_entry())
+            else {
+                break;
+            };
+            let removed = match kind {
+                CacheKind::Markdown => self.markdown_cache.remove(&key),
+                CacheKind::File => self.file_cache.remove(&key),
+            };
+            if let Some(content) = removed {
+                total -= content.len();
+                evicted += 1;
+            }
+        }
+        eprintln!(
+            "[SNEK] Evicted {} cached files to stay within {} bytes",
+            evicted, max
+        );
+        evicted
+    }
+
+    /// A cached entry that was inserted without going through `cache_markdown`
+    /// or `cache_file`.
+    fn unrecorded_entry(&self) -> Option<(CacheKind, String)> {
+        self.markdown_cache
+            .keys()
+            .map(|key| (CacheKind::Markdown, key))
+            .chain(self.file_cache.keys().map(|key| (CacheKind::File, key)))
+            .find(|(kind, key)| !self.cache_recency.contains(*kind, key))
+            .map(|(kind, key)| (kind, key.clone()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CacheKind {
+    Markdown,
+    File,
+}
+
+/// When each `markdown_cache` and `file_cache` entry was last cached or
+/// used by a prompt. Prompts only hold a shared snapshot, hence the lock.
+#[derive(Debug, Default)]
+pub struct CacheRecency(Mutex<RecencyIndex>);
+
+#[derive(Clone, Debug, Default)]
+struct RecencyIndex {
+    ticks: HashMap<(CacheKind, String), u64>,
+    /// The same entries keyed by tick, least recently used first.
+    order: BTreeMap<u64, (CacheKind, String)>,
+    next_tick: u64,
+}
+
+impl Clone for CacheRecency {
+    fn clone(&self) -> Self {
+        Self(Mutex::new(self.0.lock().unwrap().clone()))
+    }
+}
+
+impl CacheRecency {
+    fn touch(&self, kind: CacheKind, key: &str) {
+        let mut index = self.0.lock().unwrap();
+        index.next_tick += 1;
+        let tick = index.next_tick;
+        let entry = (kind, key.to_string());
+        if let Some(previous) = index.ticks.insert(entry.clone(), tick) {
+            index.order.remove(&previous);
+        }
+        index.order.insert(tick, entry);
+    }
+
+    fn forget(&self, kind: CacheKind, key: &str) {
+        let mut index = self.0.lock().unwrap();
+        if let Some(tick) = index.ticks.remove(&(kind, key.to_string())) {
+            index.order.remove(&tick);
+        }
+    }
+
+    fn contains(&self, kind: CacheKind, key: &str) -> bool {
+        self.0.lock().unwrap().ticks.contains_key(&(kind, key.to_string()))
+    }
+
+    /// Removes and returns the least recently used entry.
+    fn pop_oldest(&self) -> Option<(CacheKind, String)> {
+        let mut index = self.0.lock().unwrap();
+        let (_, entry) = index.order.pop_first()?;
+        index.ticks.remove(&entry);
+        Some(entry)
+    }
+}
+
+/// A summary tagged with the `markdown_hash` it was made from, so a stale
+/// one is never served.
+#[derive(Clone, Debug, Default)]
+pub struct MarkdownSummary(Arc<Mutex<Option<(u64, String)>>>);
+
+impl MarkdownSummary {
+    pub fn get(&self, hash: u64) -> Option<String> {
+        match *self.0.lock().unwrap() {
+            Some((cached, ref summary)) if cached == hash => Some(summary.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn set(&self, hash: u64, summary: String) {
+        *self.0.lock().unwrap() = Some((hash, summary));
+    }
 }
 
 impl Default for ContextSnapshot {
@@ -39,11 +453,21 @@ impl Default for ContextSnapshot {
         Self {
             session_id: "default".to_string(),
             version: 0,
+            reloads: 0,
             limits: Limits::default(),
+            context_filter: None,
+            meta: None,
             session_dir: PathBuf::new(),
-            code_snippets: vec![],
+            code_contexts: vec![],
+            chat_messages: vec![],
             markdown_cache: HashMap::new(),
             file_cache: HashMap::new(),
+            markdown_summary: MarkdownSummary::default(),
+            requests_used: 0,
+            scratch: false,
+            max_cache_bytes: None,
+            cache_recency: CacheRecency::default(),
+            skipped_sources: vec![],
         }
     }
 }
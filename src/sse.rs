@@ -0,0 +1,74 @@
+/// `data` of the last event OpenAI-compatible servers send on a stream.
+pub const DONE: &str = "[DONE]";
+
+/// Incremental parser for a `text/event-stream` body, as sent by
+/// OpenAI-compatible endpoints when streaming. Feed it chunks as they
+/// arrive and it returns the `data` of every event they complete.
+///
+/// Comments (`: keep-alive`), blank keep-alive lines and fields other than
+/// `data` (`event:`, `id:`, `retry:`) carry no payload and are skipped.
+/// Several `data:` lines in one event are joined with `\n`, per the spec.
+#[derive(Debug, Default)]
+pub struct SseParser {
+    /// Bytes of a line not yet terminated; chunks may split lines and
+    /// even UTF-8 sequences.
+    pending: Vec<u8>,
+    /// Data of the event being read, `None` until its first `data:` line.
+    data: Option<String>,
+}
+
+impl SseParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.pending.extend_from_slice(chunk);
+
+        let mut events = vec![];
+        while let Some(end) = self.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches('\n').trim_end_matches('\r');
+            if let Some(data) = self.process_line(line) {
+                events.push(data);
+            }
+        }
+        events
+    }
+
+    /// The last event, when the stream ended without the blank line that
+    /// normally terminates it.
+    pub fn finish(&mut self) -> Option<String> {
+        let line = String::from_utf8_lossy(&std::mem::take(&mut self.pending)).into_owned();
+        if !line.is_empty() {
+            self.process_line(line.trim_end_matches('\r'));
+        }
+        self.data.take()
+    }
+
+    fn process_line(&mut self, line: &str) -> Option<String> {
+        if line.is_empty() {
+            // A blank line dispatches the event, if it had any data
+            return self.data.take();
+        }
+        if line.starts_with(':') {
+            return None;
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        if field == "data" {
+            match self.data {
+                Some(ref mut data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => self.data = Some(value.to_string()),
+            }
+        }
+        None
+    }
+}
@@ -1,159 +1,508 @@
-use anyhow::Result;
 use arc_swap::ArcSwap;
-use notify::{Config, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use notify_debouncer_full::{
+    new_debouncer, DebounceEventResult, DebouncedEvent, Debouncer, FileIdMap,
+};
 use std::collections::HashSet;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::mpsc;
-
-use crate::session_io::{load_snapshot, resolve_active_session};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use tokio::task::AbortHandle;
+use tower_lsp::lsp_types::MessageType;
+use tower_lsp::Client;
+
+use crate::error::Result;
+use crate::lsp::notifications::{ContextChanged, ContextChangedParams};
+use crate::project_config::{ProjectConfig, PROJECT_CONFIG_FILE};
+use crate::session_io::{
+    load_chat_messages, load_snapshot, read_snippet_source, resolve_active_session,
+    resolve_context_dir,
+};
 use crate::snapshot::ContextSnapshot;
 
+const DEBOUNCE_DURATION: Duration = Duration::from_millis(200);
+
+type SessionDebouncer = Debouncer<RecommendedWatcher, FileIdMap>;
+
+/// Wait before restarting a watch task that died, doubled per crash in a
+/// row up to the maximum.
+const RESTART_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
 pub struct SessionWatcher {
     _handle: tokio::task::JoinHandle<()>,
+    // Only read by `abort_watch_task`
+    #[cfg_attr(not(feature = "test-util"), allow(dead_code))]
+    current_task: Arc<Mutex<Option<AbortHandle>>>,
+    restarts: Arc<AtomicUsize>,
+}
+
+/// Everything one run of `watch_loop` owns.
+struct WatchState {
+    rx: mpsc::UnboundedReceiver<Vec<DebouncedEvent>>,
+    session_dir: PathBuf,
+    debouncer: SessionDebouncer,
+    watched_files: HashSet<PathBuf>,
 }
 
 impl SessionWatcher {
+    /// Starts watching the active session. notify's debouncer runs on its own
+    /// thread and hands batches of events to an unbounded channel, so the
+    /// backend never blocks on the async side. When a `client` is given, it
+    /// receives `snek/contextChanged` after every snapshot swap.
+    ///
+    /// The watch task is supervised: if it panics, it is set up again from
+    /// `active.json` after a backoff and the snapshot is reloaded to pick up
+    /// changes made in the meantime.
     pub fn start(
         snek_root: PathBuf,
         snapshot: Arc<ArcSwap<ContextSnapshot>>,
+        client: Option<Client>,
     ) -> Result<Self> {
-        let (tx, rx) = mpsc::channel(100);
+        Self::start_with_project_config(snek_root, snapshot, client, None, watch::channel(None).1)
+    }
 
-        let mut watcher = RecommendedWatcher::new(
-            move |res: notify::Result<Event>| {
-                if let Ok(event) = res {
-                    let _ = tx.blocking_send(event);
-                }
-            },
-            Config::default(),
-        )?;
+    /// Like `start`, also reloading `.snek/config.json` into `project_config`
+    /// when it changes, and reloading the session every `resync_interval`
+    /// (when set) in case an event was missed.
+    pub fn start_with_project_config(
+        snek_root: PathBuf,
+        snapshot: Arc<ArcSwap<ContextSnapshot>>,
+        client: Option<Client>,
+        project_config: Option<Arc<ArcSwap<ProjectConfig>>>,
+        resync_interval: watch::Receiver<Option<Duration>>,
+    ) -> Result<Self> {
+        let state = watch_session(&snek_root, &snapshot)?;
+        let current_task = Arc::new(Mutex::new(None));
+        let restarts = Arc::new(AtomicUsize::new(0));
+
+        let handle = tokio::spawn(supervise(
+            state,
+            snek_root,
+            snapshot,
+            client,
+            project_config,
+            resync_interval,
+            current_task.clone(),
+            restarts.clone(),
+        ));
+
+        Ok(Self {
+            _handle: handle,
+            current_task,
+            restarts,
+        })
+    }
 
-        let session_dir = resolve_active_session(&snek_root)?;
+    /// How many times the watch task has been restarted after dying.
+    pub fn restarts(&self) -> usize {
+        self.restarts.load(Ordering::SeqCst)
+    }
 
-        let active_path = snek_root.join("active.json");
-        if active_path.exists() {
-            watcher.watch(&active_path, RecursiveMode::NonRecursive)?;
-            eprintln!("[SNEK] Watching active.json for session changes");
+    /// Kills the running watch task the way a crash would, leaving the
+    /// supervisor to restart it.
+    #[cfg(feature = "test-util")]
+    pub fn abort_watch_task(&self) {
+        if let Some(task) = self.current_task.lock().unwrap().as_ref() {
+            task.abort();
         }
+    }
+}
 
-        let snippets_path = session_dir.join("code_snippets.json");
-        if snippets_path.exists() {
-            watcher.watch(&snippets_path, RecursiveMode::NonRecursive)?;
-        }
+/// Sets up a debouncer watching the active session's files.
+fn watch_session(snek_root: &Path, snapshot: &Arc<ArcSwap<ContextSnapshot>>) -> Result<WatchState> {
+    let (tx, rx) = mpsc::unbounded_channel();
 
-        let context_dir = session_dir.join("context");
-        if context_dir.exists() {
-            watcher.watch(&context_dir, RecursiveMode::Recursive)?;
+    let mut debouncer = new_debouncer(
+        DEBOUNCE_DURATION,
+        None,
+        move |res: DebounceEventResult| match res {
+            Ok(events) => {
+                let _ = tx.send(events);
+            }
+            Err(errors) => {
+                for e in errors {
+                    eprintln!("[SNEK] Watcher error: {}", e);
+                }
+            }
+        },
+    )?;
+
+    let session_dir = resolve_active_session(snek_root)?;
+
+    // The directory rather than the files: scripts replace active.json
+    // atomically (write a temp file, rename it over), which swaps the inode a
+    // file watch is tied to. Events are matched to the files by name.
+    watch_path(&mut debouncer, snek_root, RecursiveMode::NonRecursive)?;
+    eprintln!("[SNEK] Watching {:?} for session and config changes", snek_root);
+
+    let snippets_path = session_dir.join("code_snippets.json");
+    if snippets_path.exists() {
+        watch_path(&mut debouncer, &snippets_path, RecursiveMode::NonRecursive)?;
+    }
+
+    let chat_path = session_dir.join("chat.json");
+    if chat_path.exists() {
+        watch_path(&mut debouncer, &chat_path, RecursiveMode::NonRecursive)?;
+    }
+
+    if let Some(context_dir) = resolve_context_dir(&session_dir) {
+        watch_path(&mut debouncer, &context_dir, RecursiveMode::Recursive)?;
+    }
+
+    let current_snapshot = snapshot.load();
+    let mut watched_files: HashSet<PathBuf> = HashSet::new();
+
+    for ctx in &current_snapshot.code_contexts {
+        if let Ok(uri) = url::Url::parse(&ctx.uri)
+            && let Ok(file_path) = uri.to_file_path()
+            && file_path.exists()
+            && watch_path(&mut debouncer, &file_path, RecursiveMode::NonRecursive).is_ok()
+        {
+            watched_files.insert(file_path);
         }
+    }
 
-        let current_snapshot = snapshot.load();
-        let mut watched_files: HashSet<PathBuf> = HashSet::new();
+    Ok(WatchState {
+        rx,
+        session_dir,
+        debouncer,
+        watched_files,
+    })
+}
+
+/// Runs `watch_loop`, restarting it with backoff whenever it dies. Returns
+/// once the loop ends on its own (its channel closed).
+#[allow(clippy::too_many_arguments)]
+async fn supervise(
+    first: WatchState,
+    snek_root: PathBuf,
+    snapshot: Arc<ArcSwap<ContextSnapshot>>,
+    client: Option<Client>,
+    project_config: Option<Arc<ArcSwap<ProjectConfig>>>,
+    resync_interval: watch::Receiver<Option<Duration>>,
+    current_task: Arc<Mutex<Option<AbortHandle>>>,
+    restarts: Arc<AtomicUsize>,
+) {
+    let mut next = Some(first);
+    let mut backoff = RESTART_BACKOFF_MIN;
 
-        for ctx in &current_snapshot.code_snippets {
-            if let Ok(uri) = url::Url::parse(&ctx.uri) {
-                if let Ok(file_path) = uri.to_file_path() {
-                    if file_path.exists() && watcher.watch(&file_path, RecursiveMode::NonRecursive).is_ok() {
-                        watched_files.insert(file_path);
+    loop {
+        let state = match next.take() {
+            Some(state) => state,
+            None => match watch_session(&snek_root, &snapshot) {
+                Ok(state) => {
+                    restarts.fetch_add(1, Ordering::SeqCst);
+                    eprintln!("[SNEK] File watcher restarted");
+                    // Catch up on whatever changed while nothing was watching
+                    let before = snapshot.load_full();
+                    if !before.scratch {
+                        match load_snapshot(&state.session_dir) {
+                            Ok(mut fresh) => {
+                                fresh.set_max_cache_bytes(before.max_cache_bytes);
+                                store_reload(&snapshot, fresh)
+                            }
+                            Err(e) => eprintln!("[SNEK] Failed to reload session after restart: {}", e),
+                        }
                     }
+                    notify_if_changed(client.as_ref(), &before, &snapshot).await;
+                    state
+                }
+                Err(e) => {
+                    eprintln!(
+                        "[SNEK] ERROR: failed to restart file watcher ({}), retrying in {}s",
+                        e,
+                        backoff.as_secs()
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(RESTART_BACKOFF_MAX);
+                    continue;
                 }
+            },
+        };
+
+        let task = tokio::spawn(watch_loop(
+            state,
+            snek_root.clone(),
+            snapshot.clone(),
+            client.clone(),
+            project_config.clone(),
+            resync_interval.clone(),
+        ));
+        *current_task.lock().unwrap() = Some(task.abort_handle());
+        let started = Instant::now();
+
+        if let Err(e) = task.await {
+            eprintln!(
+                "[SNEK] ERROR: file watcher task died ({}), restarting in {}s",
+                e,
+                backoff.as_secs()
+            );
+            if let Some(ref client) = client {
+                client
+                    .show_message(
+                        MessageType::ERROR,
+                        "Snek: the file watcher crashed and is restarting; context changes may be missed until it is back.",
+                    )
+                    .await;
             }
+        } else {
+            return;
+        }
+
+        // Only crashes in quick succession keep growing the backoff
+        if started.elapsed() > RESTART_BACKOFF_MAX {
+            backoff = RESTART_BACKOFF_MIN;
         }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(RESTART_BACKOFF_MAX);
+    }
+}
 
-        let handle = tokio::spawn(async move {
-            watch_loop(rx, snek_root, session_dir, snapshot, watcher, watched_files).await;
-        });
+fn reload_project_config(
+    events: &[DebouncedEvent],
+    snek_root: &Path,
+    project_config: &ArcSwap<ProjectConfig>,
+) {
+    let config_path = snek_root.join(PROJECT_CONFIG_FILE);
+    if !events.iter().any(|event| event.paths.contains(&config_path)) {
+        return;
+    }
 
-        Ok(Self { _handle: handle })
+    match ProjectConfig::load(snek_root) {
+        Ok(config) => {
+            eprintln!("[SNEK] Reloaded {:?}", config_path);
+            project_config.store(Arc::new(config));
+        }
+        Err(e) => eprintln!("[SNEK] Keeping previous project config: {}", e),
     }
 }
 
+fn watch_path(debouncer: &mut SessionDebouncer, path: &Path, mode: RecursiveMode) -> notify::Result<()> {
+    debouncer.watcher().watch(path, mode)?;
+    debouncer.cache().add_root(path, mode);
+    Ok(())
+}
+
+fn unwatch_path(debouncer: &mut SessionDebouncer, path: &Path) {
+    let _ = debouncer.watcher().unwatch(path);
+    debouncer.cache().remove_root(path);
+}
+
 async fn watch_loop(
-    mut rx: mpsc::Receiver<Event>,
+    state: WatchState,
     snek_root: PathBuf,
-    mut session_dir: PathBuf,
     snapshot: Arc<ArcSwap<ContextSnapshot>>,
-    mut watcher: RecommendedWatcher,
-    mut watched_files: HashSet<PathBuf>,
+    client: Option<Client>,
+    project_config: Option<Arc<ArcSwap<ProjectConfig>>>,
+    mut resync_interval: watch::Receiver<Option<Duration>>,
 ) {
-    let debounce_duration = Duration::from_millis(200);
-    let mut pending_snippets_reload = false;
-    let mut pending_markdown_updates: HashSet<PathBuf> = HashSet::new();
-    let mut pending_code_updates: HashSet<PathBuf> = HashSet::new();
-    let mut pending_session_switch = false;
+    let WatchState {
+        mut rx,
+        mut session_dir,
+        mut debouncer,
+        mut watched_files,
+    } = state;
+
+    let mut resync_deadline = next_resync(&mut resync_interval);
+    // A dropped sender leaves the interval as it last was
+    let mut resync_configurable = true;
 
     loop {
-        tokio::select! {
-            Some(event) = rx.recv() => {
-                for path in &event.paths {
-                    if path.file_name() == Some(std::ffi::OsStr::new("active.json"))
-                        && path.parent() == Some(snek_root.as_path()) {
-                        eprintln!("[SNEK] active.json changed, scheduling session switch");
-                        pending_session_switch = true;
-                    }
-                    else if path.file_name() == Some(std::ffi::OsStr::new("code_snippets.json")) {
-                        eprintln!("[SNEK] code_snippets.json changed, scheduling reload");
-                        pending_snippets_reload = true;
-                    }
-                    else if path.extension() == Some(std::ffi::OsStr::new("md"))
-                        && path.starts_with(&session_dir.join("context")) {
-                        eprintln!("[SNEK] Markdown file changed: {:?}", path);
-                        pending_markdown_updates.insert(path.clone());
-                    }
-                    else if watched_files.contains(path) {
-                        eprintln!("[SNEK] Code file changed: {:?}", path);
-                        pending_code_updates.insert(path.clone());
-                    }
+        let events = tokio::select! {
+            events = rx.recv() => match events {
+                Some(events) => events,
+                None => break,
+            },
+            changed = resync_interval.changed(), if resync_configurable => {
+                if changed.is_err() {
+                    resync_configurable = false;
                 }
+                resync_deadline = next_resync(&mut resync_interval);
+                continue;
             }
-            _ = tokio::time::sleep(debounce_duration) => {
-                if pending_session_switch {
-                    match switch_session(
-                        &snek_root,
-                        &mut session_dir,
-                        &snapshot,
-                        &mut watcher,
-                        &mut watched_files,
-                    ) {
-                        Ok(()) => {
-                            pending_markdown_updates.clear();
-                            pending_code_updates.clear();
-                            pending_snippets_reload = false;
-                        }
-                        Err(e) => {
-                            eprintln!("[SNEK] Failed to switch session: {}", e);
-                        }
-                    }
-                    pending_session_switch = false;
-                    continue;
+            _ = sleep_until(resync_deadline) => {
+                let before = snapshot.load_full();
+                if let Err(e) = resync(
+                    &snek_root,
+                    &mut session_dir,
+                    &snapshot,
+                    &mut debouncer,
+                    &mut watched_files,
+                ) {
+                    eprintln!("[SNEK] Periodic resync failed: {}", e);
                 }
+                notify_if_changed(client.as_ref(), &before, &snapshot).await;
+                resync_deadline = next_resync(&mut resync_interval);
+                continue;
+            }
+        };
 
-                if pending_snippets_reload {
-                    if let Err(e) = reload_code_snippets(
-                        &snek_root,
-                        &session_dir,
-                        &snapshot,
-                        &mut watcher,
-                        &mut watched_files,
-                    ) {
-                        eprintln!("[SNEK] Failed to reload code snippets: {}", e);
-                    }
-                    pending_snippets_reload = false;
-                    pending_code_updates.clear();
-                }
+        if let Some(ref project_config) = project_config {
+            reload_project_config(&events, &snek_root, project_config);
+        }
 
-                if !pending_markdown_updates.is_empty() {
-                    update_markdown_cache(&session_dir, &snapshot, &pending_markdown_updates);
-                    pending_markdown_updates.clear();
-                }
+        let before = snapshot.load_full();
+        // A scratch session isn't backed by these files
+        if before.scratch {
+            continue;
+        }
+        let context_dir = resolve_context_dir(&session_dir);
+        let mut pending_snippets_reload = false;
+        let mut pending_chat_reload = false;
+        let mut pending_markdown_updates: HashSet<PathBuf> = HashSet::new();
+        let mut pending_code_updates: HashSet<PathBuf> = HashSet::new();
+        let mut pending_session_switch = false;
+
+        for path in events.iter().flat_map(|event| event.paths.iter()) {
+            if path.file_name() == Some(std::ffi::OsStr::new("active.json"))
+                && path.parent() == Some(snek_root.as_path()) {
+                eprintln!("[SNEK] active.json changed, scheduling session switch");
+                pending_session_switch = true;
+            }
+            else if path.file_name() == Some(std::ffi::OsStr::new("code_snippets.json")) {
+                eprintln!("[SNEK] code_snippets.json changed, scheduling reload");
+                pending_snippets_reload = true;
+            }
+            else if path.file_name() == Some(std::ffi::OsStr::new("chat.json")) {
+                eprintln!("[SNEK] chat.json changed, scheduling reload");
+                pending_chat_reload = true;
+            }
+            else if path.extension() == Some(std::ffi::OsStr::new("md"))
+                && context_dir.as_ref().is_some_and(|dir| path.starts_with(dir)) {
+                eprintln!("[SNEK] Markdown file changed: {:?}", path);
+                pending_markdown_updates.insert(path.clone());
+            }
+            else if watched_files.contains(path) {
+                eprintln!("[SNEK] Code file changed: {:?}", path);
+                pending_code_updates.insert(path.clone());
+            }
+        }
 
-                if !pending_code_updates.is_empty() {
-                    update_code_cache(&snapshot, &pending_code_updates);
-                    pending_code_updates.clear();
-                }
+        if pending_session_switch {
+            if let Err(e) = switch_session(
+                &snek_root,
+                &mut session_dir,
+                &snapshot,
+                &mut debouncer,
+                &mut watched_files,
+            ) {
+                eprintln!("[SNEK] Failed to switch session: {}", e);
             }
+            notify_if_changed(client.as_ref(), &before, &snapshot).await;
+            continue;
         }
+
+        if pending_snippets_reload {
+            if let Err(e) = reload_code_snippets(
+                &snek_root,
+                &session_dir,
+                &snapshot,
+                &mut debouncer,
+                &mut watched_files,
+            ) {
+                eprintln!("[SNEK] Failed to reload code snippets: {}", e);
+            }
+            pending_code_updates.clear();
+        }
+
+        if pending_chat_reload {
+            reload_chat_messages(&session_dir, &snapshot);
+        }
+
+        if !pending_markdown_updates.is_empty() {
+            update_markdown_cache(&session_dir, &snapshot, &pending_markdown_updates);
+        }
+
+        if !pending_code_updates.is_empty() {
+            update_code_cache(&snapshot, &pending_code_updates);
+        }
+
+        notify_if_changed(client.as_ref(), &before, &snapshot).await;
+    }
+
+    eprintln!("[SNEK] Watcher channel closed, stopping watch loop");
+}
+
+fn next_resync(
+    resync_interval: &mut watch::Receiver<Option<Duration>>,
+) -> Option<tokio::time::Instant> {
+    resync_interval
+        .borrow_and_update()
+        .map(|interval| tokio::time::Instant::now() + interval)
+}
+
+/// Sleeps until `deadline`, or forever without one.
+async fn sleep_until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Reloads the active session from disk and swaps it in if it differs from
+/// `snapshot`: a safety net for events the file watcher never delivered,
+/// as happens on network filesystems and some container mounts.
+fn resync(
+    snek_root: &Path,
+    session_dir: &mut PathBuf,
+    snapshot: &Arc<ArcSwap<ContextSnapshot>>,
+    debouncer: &mut SessionDebouncer,
+    watched_files: &mut HashSet<PathBuf>,
+) -> Result<()> {
+    let current = snapshot.load_full();
+    if current.scratch {
+        return Ok(());
+    }
+
+    if resolve_active_session(snek_root)? != *session_dir {
+        eprintln!("[SNEK] Periodic resync found a missed session switch");
+        return switch_session(snek_root, session_dir, snapshot, debouncer, watched_files);
+    }
+
+    let mut fresh = load_snapshot(session_dir)?;
+    fresh.set_max_cache_bytes(current.max_cache_bytes);
+    if fresh.content_hash() == current.content_hash() {
+        return Ok(());
+    }
+
+    eprintln!("[SNEK] Periodic resync found stale context, reloading session");
+    store_reloaded(fresh, snapshot, debouncer, watched_files);
+    Ok(())
+}
+
+async fn notify_if_changed(
+    client: Option<&Client>,
+    before: &Arc<ContextSnapshot>,
+    snapshot: &Arc<ArcSwap<ContextSnapshot>>,
+) {
+    let Some(client) = client else {
+        return;
+    };
+
+    let current = snapshot.load_full();
+    if Arc::ptr_eq(before, &current) {
+        return;
+    }
+
+    client
+        .send_notification::<ContextChanged>(ContextChangedParams {
+            session_id: current.session_id.clone(),
+            version: current.version,
+            reloads: current.reloads,
+        })
+        .await;
+
+    // Warn once per new value rather than on every reload
+    if current.limits.max_tokens != before.limits.max_tokens
+        && let Some(warning) = current.limits.warning()
+    {
+        client.show_message(MessageType::WARNING, warning).await;
+    }
+    if let Some(warning) = current.skipped_sources_warning(Some(before)) {
+        client.show_message(MessageType::WARNING, warning).await;
     }
 }
 
@@ -161,7 +510,7 @@ fn switch_session(
     snek_root: &Path,
     session_dir: &mut PathBuf,
     snapshot: &Arc<ArcSwap<ContextSnapshot>>,
-    watcher: &mut RecommendedWatcher,
+    debouncer: &mut SessionDebouncer,
     watched_files: &mut HashSet<PathBuf>,
 ) -> Result<()> {
     eprintln!("[SNEK] Switching session...");
@@ -177,41 +526,48 @@ fn switch_session(
 
     let old_snippets_path = session_dir.join("code_snippets.json");
     if old_snippets_path.exists() {
-        let _ = watcher.unwatch(&old_snippets_path);
+        unwatch_path(debouncer, &old_snippets_path);
     }
-    let old_context_dir = session_dir.join("context");
-    if old_context_dir.exists() {
-        let _ = watcher.unwatch(&old_context_dir);
+    let old_chat_path = session_dir.join("chat.json");
+    if old_chat_path.exists() {
+        unwatch_path(debouncer, &old_chat_path);
+    }
+    if let Some(old_context_dir) = resolve_context_dir(session_dir) {
+        unwatch_path(debouncer, &old_context_dir);
     }
 
     for file in watched_files.iter() {
-        let _ = watcher.unwatch(file);
+        unwatch_path(debouncer, file);
     }
     watched_files.clear();
 
-    let new_snapshot = load_snapshot(&new_session_dir)?;
+    let mut new_snapshot = load_snapshot(&new_session_dir)?;
+    new_snapshot.set_max_cache_bytes(snapshot.load().max_cache_bytes);
 
     let new_snippets_path = new_session_dir.join("code_snippets.json");
     if new_snippets_path.exists() {
-        watcher.watch(&new_snippets_path, RecursiveMode::NonRecursive)?;
+        watch_path(debouncer, &new_snippets_path, RecursiveMode::NonRecursive)?;
+    }
+    let new_chat_path = new_session_dir.join("chat.json");
+    if new_chat_path.exists() {
+        watch_path(debouncer, &new_chat_path, RecursiveMode::NonRecursive)?;
     }
-    let new_context_dir = new_session_dir.join("context");
-    if new_context_dir.exists() {
-        watcher.watch(&new_context_dir, RecursiveMode::Recursive)?;
+    if let Some(new_context_dir) = resolve_context_dir(&new_session_dir) {
+        watch_path(debouncer, &new_context_dir, RecursiveMode::Recursive)?;
     }
 
-    for snippet in &new_snapshot.code_snippets {
-        if let Ok(uri) = url::Url::parse(&snippet.uri) {
-            if let Ok(file_path) = uri.to_file_path() {
-                if file_path.exists() && watcher.watch(&file_path, RecursiveMode::NonRecursive).is_ok() {
-                    watched_files.insert(file_path);
-                }
-            }
+    for snippet in &new_snapshot.code_contexts {
+        if let Ok(uri) = url::Url::parse(&snippet.uri)
+            && let Ok(file_path) = uri.to_file_path()
+            && file_path.exists()
+            && watch_path(debouncer, &file_path, RecursiveMode::NonRecursive).is_ok()
+        {
+            watched_files.insert(file_path);
         }
     }
 
     *session_dir = new_session_dir;
-    snapshot.store(Arc::new(new_snapshot));
+    store_reload(snapshot, new_snapshot);
 
     eprintln!("[SNEK] Session switched successfully!");
     Ok(())
@@ -221,15 +577,29 @@ fn reload_code_snippets(
     _snek_root: &Path,
     session_dir: &Path,
     snapshot: &Arc<ArcSwap<ContextSnapshot>>,
-    watcher: &mut RecommendedWatcher,
+    debouncer: &mut SessionDebouncer,
     watched_files: &mut HashSet<PathBuf>,
 ) -> Result<()> {
     eprintln!("[SNEK] Reloading code_snippets.json...");
 
-    let new_snapshot = load_snapshot(session_dir)?;
+    let mut new_snapshot = load_snapshot(session_dir)?;
+    new_snapshot.set_max_cache_bytes(snapshot.load().max_cache_bytes);
+    store_reloaded(new_snapshot, snapshot, debouncer, watched_files);
+
+    eprintln!("[SNEK] Code snippets reloaded successfully");
+    Ok(())
+}
 
+/// Swaps in a reloaded snapshot of the same session, moving the file
+/// watches over to its snippets' files.
+fn store_reloaded(
+    new_snapshot: ContextSnapshot,
+    snapshot: &Arc<ArcSwap<ContextSnapshot>>,
+    debouncer: &mut SessionDebouncer,
+    watched_files: &mut HashSet<PathBuf>,
+) {
     let new_files: HashSet<PathBuf> = new_snapshot
-        .code_snippets
+        .code_contexts
         .iter()
         .filter_map(|ctx| {
             url::Url::parse(&ctx.uri)
@@ -240,24 +610,42 @@ fn reload_code_snippets(
 
     for old_file in watched_files.iter() {
         if !new_files.contains(old_file) {
-            let _ = watcher.unwatch(old_file);
+            unwatch_path(debouncer, old_file);
             eprintln!("[SNEK] Unwatched: {:?}", old_file);
         }
     }
 
     for new_file in &new_files {
-        if !watched_files.contains(new_file) && new_file.exists() {
-            if watcher.watch(new_file, RecursiveMode::NonRecursive).is_ok() {
-                eprintln!("[SNEK] Now watching: {:?}", new_file);
-            }
+        if !watched_files.contains(new_file)
+            && new_file.exists()
+            && watch_path(debouncer, new_file, RecursiveMode::NonRecursive).is_ok()
+        {
+            eprintln!("[SNEK] Now watching: {:?}", new_file);
         }
     }
 
     *watched_files = new_files;
+    store_reload(snapshot, new_snapshot);
+}
+
+/// Swaps in `new_snapshot`, counting it as a reload of the current one so
+/// clients see the context changed even when session.json's `version` did
+/// not.
+fn store_reload(snapshot: &ArcSwap<ContextSnapshot>, mut new_snapshot: ContextSnapshot) {
+    new_snapshot.count_reload(&snapshot.load());
     snapshot.store(Arc::new(new_snapshot));
+}
 
-    eprintln!("[SNEK] Code snippets reloaded successfully");
-    Ok(())
+fn reload_chat_messages(session_dir: &Path, snapshot: &Arc<ArcSwap<ContextSnapshot>>) {
+    match load_chat_messages(session_dir) {
+        Ok(chat_messages) => {
+            let mut new_snapshot = (**snapshot.load()).clone();
+            eprintln!("[SNEK] Reloaded {} chat.json messages", chat_messages.len());
+            new_snapshot.chat_messages = chat_messages;
+            store_reload(snapshot, new_snapshot);
+        }
+        Err(e) => eprintln!("[SNEK] Failed to reload chat.json: {}", e),
+    }
 }
 
 fn update_markdown_cache(
@@ -274,17 +662,19 @@ fn update_markdown_cache(
 
             if path.exists() {
                 if let Ok(content) = std::fs::read_to_string(path) {
-                    new_snapshot.markdown_cache.insert(filename_string.clone(), content);
+                    new_snapshot.cache_markdown(filename_string.clone(), content);
                     eprintln!("[SNEK] Updated markdown cache: {}", filename);
                 }
             } else {
-                new_snapshot.markdown_cache.remove(&filename_string);
+                new_snapshot.remove_markdown(&filename_string);
                 eprintln!("[SNEK] Removed from markdown cache: {}", filename);
             }
         }
     }
 
-    snapshot.store(Arc::new(new_snapshot));
+    // The old summary describes the old markdown
+    new_snapshot.markdown_summary = Default::default();
+    store_reload(snapshot, new_snapshot);
 }
 
 fn update_code_cache(
@@ -295,25 +685,33 @@ fn update_code_cache(
     let mut new_snapshot = (**current).clone();
 
     for path in changed_paths {
-        for snippet in &current.code_snippets {
-            if let Ok(uri) = url::Url::parse(&snippet.uri) {
-                if let Ok(snippet_path) = uri.to_file_path() {
-                    if snippet_path == *path {
-                        if path.exists() {
-                            if let Ok(content) = std::fs::read_to_string(path) {
-                                new_snapshot.file_cache.insert(snippet.uri.clone(), content);
-                                eprintln!("[SNEK] Updated file cache: {}", snippet.uri);
-                            }
-                        } else {
-                            new_snapshot.file_cache.remove(&snippet.uri);
-                            eprintln!("[SNEK] Removed from file cache: {}", snippet.uri);
+        for snippet in &current.code_contexts {
+            if let Ok(uri) = url::Url::parse(&snippet.uri)
+                && let Ok(snippet_path) = uri.to_file_path()
+                && snippet_path == *path
+            {
+                if path.exists() {
+                    match read_snippet_source(path) {
+                        Ok(Some(content)) => {
+                            new_snapshot.set_source_skipped(&snippet.uri, false);
+                            new_snapshot.cache_file(snippet.uri.clone(), content);
+                            eprintln!("[SNEK] Updated file cache: {}", snippet.uri);
                         }
-                        break;
+                        Ok(None) => {
+                            new_snapshot.set_source_skipped(&snippet.uri, true);
+                            new_snapshot.remove_file(&snippet.uri);
+                        }
+                        Err(_) => {}
                     }
+                } else {
+                    new_snapshot.set_source_skipped(&snippet.uri, false);
+                    new_snapshot.remove_file(&snippet.uri);
+                    eprintln!("[SNEK] Removed from file cache: {}", snippet.uri);
                 }
+                break;
             }
         }
     }
 
-    snapshot.store(Arc::new(new_snapshot));
+    store_reload(snapshot, new_snapshot);
 }
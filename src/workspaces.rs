@@ -0,0 +1,81 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+
+use crate::snapshot::ContextSnapshot;
+
+/// The session snapshot of each root in a multi-root workspace. Requests are
+/// routed to the root that contains the document being completed.
+#[derive(Default)]
+pub struct Workspaces {
+    roots: Vec<Root>,
+}
+
+struct Root {
+    workspace_dir: PathBuf,
+    snek_root: PathBuf,
+    snapshot: Arc<ArcSwap<ContextSnapshot>>,
+}
+
+impl Workspaces {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `snapshot`, a session of the `.snek` at `snek_root`, as
+    /// the context for files under `workspace_dir`.
+    pub fn add(
+        &mut self,
+        workspace_dir: PathBuf,
+        snek_root: PathBuf,
+        snapshot: Arc<ArcSwap<ContextSnapshot>>,
+    ) {
+        let workspace_dir = workspace_dir.canonicalize().unwrap_or(workspace_dir);
+        self.roots.push(Root {
+            workspace_dir,
+            snek_root,
+            snapshot,
+        });
+    }
+
+    /// Snapshot of the innermost root containing the file at `uri`, or `None`
+    /// when it belongs to no root or isn't a file URI.
+    pub fn snapshot_for(&self, uri: &str) -> Option<&Arc<ArcSwap<ContextSnapshot>>> {
+        self.root_for(uri).map(|root| &root.snapshot)
+    }
+
+    /// `.snek` of the root `snapshot_for` picks for `uri`.
+    pub fn snek_root_for(&self, uri: &str) -> Option<&Path> {
+        self.root_for(uri).map(|root| root.snek_root.as_path())
+    }
+
+    pub fn snapshots(&self) -> impl Iterator<Item = &Arc<ArcSwap<ContextSnapshot>>> {
+        self.roots.iter().map(|root| &root.snapshot)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.roots.is_empty()
+    }
+
+    fn root_for(&self, uri: &str) -> Option<&Root> {
+        let path = url::Url::parse(uri).ok()?.to_file_path().ok()?;
+        let path = canonicalize_existing(&path);
+        self.roots
+            .iter()
+            .filter(|root| path.starts_with(&root.workspace_dir))
+            .max_by_key(|root| root.workspace_dir.components().count())
+    }
+}
+
+/// Resolves symlinks in the longest existing ancestor of `path`, so unsaved
+/// files still match a canonicalized root.
+fn canonicalize_existing(path: &Path) -> PathBuf {
+    for ancestor in path.ancestors() {
+        if let Ok(resolved) = ancestor.canonicalize() {
+            let rest = path.strip_prefix(ancestor).unwrap_or(Path::new(""));
+            return resolved.join(rest);
+        }
+    }
+    path.to_path_buf()
+}
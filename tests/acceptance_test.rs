@@ -0,0 +1,29 @@
+//! Integration tests for acceptance module
+
+use snek::acceptance::{AcceptanceTracker, MIN_LENGTH_FACTOR};
+
+#[test]
+fn test_length_factor_follows_recent_feedback() {
+    let tracker = AcceptanceTracker::new();
+    tracker.record("rust", 10, 40);
+    tracker.record("rust", 20, 40);
+    assert_eq!(tracker.length_factor("rust"), None, "too little feedback");
+
+    tracker.record("rust", 30, 40);
+    assert_eq!(tracker.length_factor("rust"), Some(0.5));
+
+    // Nothing kept still asks for a minimum length
+    for _ in 0..20 {
+        tracker.record("rust", 0, 40);
+    }
+    assert_eq!(tracker.length_factor("rust"), Some(MIN_LENGTH_FACTOR));
+
+    // Only the last 20 signals count
+    for _ in 0..20 {
+        tracker.record("rust", 500, 40);
+    }
+    assert_eq!(tracker.length_factor("rust"), None, "whole suggestions kept");
+
+    tracker.record("python", 0, 0);
+    assert_eq!(tracker.length_factor("python"), None);
+}
@@ -0,0 +1,2132 @@
+//! Integration tests for the LSP backend
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use common::{completion_body, MockResponse, MockServer};
+use snek::document_store::DocumentStore;
+use snek::history::CompletionOutcome;
+use snek::lsp::backend::{
+    Backend, CompletionFeedbackParams, ContextBudgetParams, ContextSnapshotParams,
+    InlineCompletionParams, PartialAcceptanceParams,
+};
+use snek::model::{ModelClient, SamplingOptions};
+use snek::snapshot::ContextSnapshot;
+use tokio::sync::RwLock;
+use tower_lsp::jsonrpc::ErrorCode;
+use tower_lsp::lsp_types::{
+    CompletionContext, CompletionParams, CompletionResponse, CompletionTriggerKind,
+    InitializeParams, InitializeResult, Position, TextDocumentIdentifier,
+    TextDocumentPositionParams, Url,
+};
+use tower_lsp::{ClientSocket, LanguageServer, LspService};
+
+/// Helper to build a backend that is never connected to a real client
+fn create_test_backend() -> (LspService<Backend>, ClientSocket) {
+    create_backend_with_model("http://127.0.0.1:0/v1/chat/completions", "")
+}
+
+/// Helper to build a backend talking to `api_url` with `api_key`
+fn create_backend_with_model(api_url: &str, api_key: &str) -> (LspService<Backend>, ClientSocket) {
+    LspService::new(|client| {
+        Backend::new(
+            client,
+            Arc::new(ArcSwap::from_pointee(ContextSnapshot::default())),
+            Arc::new(DocumentStore::new()),
+            Arc::new(ModelClient::new(api_url.to_string(), "test-model".to_string())),
+            Arc::new(RwLock::new(api_key.to_string())),
+        )
+    })
+}
+
+fn feedback_params(uri: &str) -> CompletionFeedbackParams {
+    CompletionFeedbackParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::parse(uri).unwrap(),
+        },
+    }
+}
+
+fn inline_params(uri: &str, line: u32, character: u32) -> InlineCompletionParams {
+    InlineCompletionParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::parse(uri).unwrap(),
+        },
+        position: Position { line, character },
+        selection: None,
+        extra_context: None,
+        instruction: None,
+        continuation: false,
+        language: None,
+        context: None,
+        invoked: false,
+        temperature: None,
+        text: None,
+        context_filter: None,
+    }
+}
+
+#[tokio::test]
+async fn test_accept_last_completion() {
+    let (service, _socket) = create_test_backend();
+    let backend = service.inner();
+
+    let uri = "file:///test/file.rs";
+    backend
+        .last_completions
+        .record(uri, "println!(\"hi\");".to_string());
+
+    let response = backend
+        .handle_completion_feedback(feedback_params(uri), CompletionOutcome::Accepted)
+        .await
+        .unwrap();
+
+    assert!(response.recorded);
+    assert_eq!(
+        backend.last_completions.last(uri),
+        Some("println!(\"hi\");".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_feedback_without_completion() {
+    let (service, _socket) = create_test_backend();
+    let backend = service.inner();
+
+    let response = backend
+        .handle_completion_feedback(
+            feedback_params("file:///test/other.rs"),
+            CompletionOutcome::Rejected,
+        )
+        .await
+        .unwrap();
+
+    assert!(!response.recorded);
+}
+
+#[tokio::test]
+async fn test_empty_prefix_is_short_circuited() {
+    let (service, _socket) = create_test_backend();
+    let backend = service.inner();
+
+    let uri = "file:///test/empty.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "   \n\n".to_string());
+
+    // No API key is configured, so reaching the model would be an error
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 1, 0))
+        .await
+        .unwrap();
+
+    assert!(response.completion.is_empty());
+}
+
+#[tokio::test]
+async fn test_min_prefix_chars_counts_whole_prefix() {
+    let (service, _socket) = create_test_backend();
+    let backend = service.inner();
+    backend.settings.write().await.min_prefix_chars = 6;
+
+    let uri = "file:///test/short.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "fn a\n  b\n".to_string());
+
+    // "fn a" + "b" is only 4 non-whitespace characters
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 1, 3))
+        .await
+        .unwrap();
+    assert!(response.completion.is_empty());
+
+    backend.settings.write().await.min_prefix_chars = 4;
+
+    // Gate passes, so the request reaches the model and fails on the missing key
+    let result = backend
+        .handle_inline_completion(inline_params(uri, 1, 3))
+        .await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn test_trigger_characters_skip_other_positions() {
+    let (service, _socket) = create_test_backend();
+    let backend = service.inner();
+    backend.settings.write().await.trigger_characters = vec![".".to_string(), "(".to_string()];
+
+    let uri = "file:///test/trigger.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "let x = foo.ba".to_string());
+
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 0, 14))
+        .await
+        .unwrap();
+    assert!(response.completion.is_empty());
+
+    // Right after "." the request reaches the model and fails on the missing key
+    let result = backend
+        .handle_inline_completion(inline_params(uri, 0, 12))
+        .await;
+    assert!(result.is_err());
+
+    // As does an explicitly invoked one anywhere
+    let result = backend
+        .handle_inline_completion(InlineCompletionParams {
+            invoked: true,
+            ..inline_params(uri, 0, 14)
+        })
+        .await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn test_initialize_advertises_trigger_characters() {
+    let advertised = |result: InitializeResult| {
+        result
+            .capabilities
+            .completion_provider
+            .and_then(|options| options.trigger_characters)
+            .unwrap()
+    };
+
+    let (service, _socket) = create_test_backend();
+    let result = service
+        .inner()
+        .initialize(InitializeParams::default())
+        .await
+        .unwrap();
+    assert_eq!(advertised(result), vec![".", "(", ":", ">"]);
+
+    let (service, _socket) = create_test_backend();
+    let backend = service.inner();
+    let result = backend
+        .initialize(InitializeParams {
+            initialization_options: Some(serde_json::json!({ "triggerCharacters": ["->", "::", "."] })),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(advertised(result), vec![">", ":", "."]);
+
+    // The same triggers gate inline completions
+    let uri = "file:///test/arrow.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "self->x".to_string());
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 0, 7))
+        .await
+        .unwrap();
+    assert!(response.completion.is_empty());
+    let result = backend
+        .handle_inline_completion(inline_params(uri, 0, 6))
+        .await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn test_trigger_character_completion_uses_language_defaults() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("new()"))]).await;
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+
+    let triggered = |uri: &str, character: u32, trigger: &str| CompletionParams {
+        text_document_position: TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: Url::parse(uri).unwrap(),
+            },
+            position: Position { line: 0, character },
+        },
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
+        context: Some(CompletionContext {
+            trigger_kind: CompletionTriggerKind::TRIGGER_CHARACTER,
+            trigger_character: Some(trigger.to_string()),
+        }),
+    };
+
+    // A lone `:` is a type annotation in Rust, not a path
+    let uri = "file:///test/annotation.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "let v: Vec::".to_string());
+    assert!(backend.completion(triggered(uri, 6, ":")).await.unwrap().is_none());
+    assert!(server.requests().is_empty());
+
+    let response = backend.completion(triggered(uri, 12, ":")).await.unwrap();
+    let Some(CompletionResponse::Array(items)) = response else {
+        panic!("expected a completion after `::`");
+    };
+    assert_eq!(items[0].insert_text.as_deref(), Some("new()"));
+
+    // `->` is a C trigger, but means nothing in Python
+    let uri = "file:///test/arrow.py";
+    backend
+        .documents
+        .did_open(uri.to_string(), "python".to_string(), "def f() ->".to_string());
+    assert!(backend.completion(triggered(uri, 10, ">")).await.unwrap().is_none());
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn test_long_completion_is_truncated_to_max_lines() {
+    let long_completion = (1..=20)
+        .map(|i| format!("    step_{}();", i))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body(&long_completion))]).await;
+
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+    backend.settings.write().await.max_completion_lines = Some(3);
+
+    let uri = "file:///test/steps.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "fn run() {\n\n}".to_string());
+
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 1, 0))
+        .await
+        .unwrap();
+
+    assert_eq!(response.completion, "step_1();\n    step_2();\n    step_3();");
+}
+
+#[tokio::test]
+async fn test_long_completion_is_truncated_to_max_chars_at_line_boundary() {
+    let long_completion = (1..=20)
+        .map(|i| format!("    step_{}();", i))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body(&long_completion))]).await;
+
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+    backend.settings.write().await.max_completion_chars = Some(40);
+
+    let uri = "file:///test/steps.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "fn run() {\n\n}".to_string());
+
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 1, 0))
+        .await
+        .unwrap();
+
+    // A fourth line would pass 40 chars, so the cut falls after the third
+    assert_eq!(response.completion, "step_1();\n    step_2();\n    step_3();");
+}
+
+#[tokio::test]
+async fn test_response_range_covers_partial_identifier() {
+    let server = MockServer::start(vec![
+        MockResponse::new(200, completion_body("onacci(n - 1)")),
+        MockResponse::new(200, completion_body("fibonacci(1)")),
+    ])
+    .await;
+
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+
+    let uri = "file:///test/fib.rs";
+    backend.documents.did_open(
+        uri.to_string(),
+        "rust".to_string(),
+        "fn f(n: u32) -> u32 {\n    n * fib\n}".to_string(),
+    );
+
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 1, 11))
+        .await
+        .unwrap();
+    assert_eq!(response.completion, "onacci(n - 1)");
+    let range = response.range.unwrap();
+    assert_eq!(range.start, Position { line: 1, character: 8 });
+    assert_eq!(range.end, Position { line: 1, character: 11 });
+    assert_eq!(response.insert_text.as_deref(), Some("fibonacci(n - 1)"));
+
+    // Nothing to replace after punctuation
+    backend.documents.did_change(uri, "fn f() {\n    g(\n}".to_string());
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 1, 6))
+        .await
+        .unwrap();
+    assert!(response.range.is_none());
+    assert!(response.insert_text.is_none());
+}
+
+#[tokio::test]
+async fn test_response_range_counts_utf16_units() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("_count += 1;"))]).await;
+
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+
+    let uri = "file:///test/cafe.rs";
+    backend.documents.did_open(
+        uri.to_string(),
+        "rust".to_string(),
+        "fn f() {\n    café\n}".to_string(),
+    );
+
+    // `café` is four UTF-16 units but five bytes
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 1, 8))
+        .await
+        .unwrap();
+    let range = response.range.unwrap();
+    assert_eq!(range.start, Position { line: 1, character: 4 });
+    assert_eq!(range.end, Position { line: 1, character: 8 });
+    assert_eq!(response.insert_text.as_deref(), Some("café_count += 1;"));
+}
+
+#[tokio::test]
+async fn test_min_idle_ms_skips_completion_right_after_edit() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("()"))]).await;
+
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+    backend.settings.write().await.min_idle_ms = 60_000;
+
+    let uri = "file:///test/idle.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "fn run".to_string());
+
+    // Never edited since opening, so the gate doesn't apply
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 0, 6))
+        .await
+        .unwrap();
+    assert_eq!(response.completion, "()");
+    assert_eq!(server.requests().len(), 1);
+
+    backend.documents.did_change(uri, "fn run_".to_string());
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 0, 7))
+        .await
+        .unwrap();
+    assert!(response.completion.is_empty());
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn test_newer_request_supersedes_in_flight_completion() {
+    let server = MockServer::start(vec![
+        MockResponse::new(200, completion_body("slow()")).with_delay(Duration::from_secs(30)),
+        MockResponse::new(200, completion_body("fast()")),
+    ])
+    .await;
+
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner().clone();
+
+    let uri = "file:///test/supersede.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "fn run".to_string());
+
+    let first = tokio::spawn({
+        let backend = backend.clone();
+        async move { backend.handle_inline_completion(inline_params(uri, 0, 6)).await }
+    });
+    while server.requests().is_empty() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let second = backend
+        .handle_inline_completion(inline_params(uri, 0, 6))
+        .await
+        .unwrap();
+    assert_eq!(second.completion, "fast()");
+
+    let first = first.await.unwrap().unwrap();
+    assert!(first.completion.is_empty());
+    assert!(backend.in_flight.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_selection_is_included_in_prompt() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("()"))]).await;
+
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+
+    let uri = "file:///test/selection.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "fn run".to_string());
+
+    let mut params = inline_params(uri, 0, 6);
+    params.selection = Some("let retries = config.max_retries;".to_string());
+    backend.handle_inline_completion(params).await.unwrap();
+
+    let body = server.requests()[0].json();
+    let prompt = body["messages"][1]["content"].as_str().unwrap();
+    assert!(prompt.contains("The user has selected this code"));
+    assert!(prompt.contains("let retries = config.max_retries;"));
+}
+
+#[test]
+fn test_inline_params_without_selection() {
+    let params: InlineCompletionParams = serde_json::from_value(serde_json::json!({
+        "text_document": { "uri": "file:///test/a.rs" },
+        "position": { "line": 0, "character": 0 }
+    }))
+    .unwrap();
+    assert!(params.selection.is_none());
+}
+
+#[tokio::test]
+async fn test_identical_context_reuses_completion() {
+    let server = MockServer::start(vec![
+        MockResponse::new(200, completion_body("first()")),
+        MockResponse::new(200, completion_body("second()")),
+    ])
+    .await;
+
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+
+    let uri = "file:///test/cache.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "let x = ".to_string());
+
+    // Both positions are past the end of the line and clamp to the same offset
+    let first = backend
+        .handle_inline_completion(inline_params(uri, 0, 20))
+        .await
+        .unwrap();
+    let second = backend
+        .handle_inline_completion(inline_params(uri, 0, 40))
+        .await
+        .unwrap();
+    assert_eq!(first.completion, "first()");
+    assert_eq!(second.completion, "first()");
+    assert_eq!(server.requests().len(), 1);
+
+    // An edit bumps the document version, so the model is called again
+    backend.documents.did_change(uri, "let x = ".to_string());
+    let third = backend
+        .handle_inline_completion(inline_params(uri, 0, 20))
+        .await
+        .unwrap();
+    assert_eq!(third.completion, "second()");
+    assert_eq!(server.requests().len(), 2);
+}
+
+#[tokio::test]
+async fn test_open_buffer_imports_bypass_completion_cache() {
+    let server = MockServer::start(vec![
+        MockResponse::new(200, completion_body("first()")),
+        MockResponse::new(200, completion_body("second()")),
+    ])
+    .await;
+
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+    backend.settings.write().await.open_buffer_imports = true;
+
+    let other = "file:///test/other.rs";
+    backend
+        .documents
+        .did_open(other.to_string(), "rust".to_string(), "use std::fmt;
+".to_string());
+    let uri = "file:///test/cache.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "let x = ".to_string());
+
+    let first = backend
+        .handle_inline_completion(inline_params(uri, 0, 8))
+        .await
+        .unwrap();
+    // Editing another document changes the prompt but not this document
+    backend.documents.did_change(other, "use std::io;
+".to_string());
+    let second = backend
+        .handle_inline_completion(inline_params(uri, 0, 8))
+        .await
+        .unwrap();
+
+    assert_eq!(first.completion, "first()");
+    assert_eq!(second.completion, "second()");
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    assert!(requests[1].json().to_string().contains("use std::io;"));
+}
+
+#[tokio::test]
+async fn test_extra_context_is_included_in_prompt() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("()"))]).await;
+
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+
+    let uri = "file:///test/extra.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "fn run".to_string());
+
+    let mut params = inline_params(uri, 0, 6);
+    params.extra_context = Some(format!("error[E0425]: cannot find value `cfg`{}", "!".repeat(10_000)));
+    backend.handle_inline_completion(params).await.unwrap();
+
+    let body = server.requests()[0].json();
+    let prompt = body["messages"][1]["content"].as_str().unwrap();
+    assert!(prompt.contains("Additional context from the editor"));
+    assert!(prompt.contains("error[E0425]: cannot find value `cfg`"));
+    // Bounded: most of the padding is dropped
+    assert!(!prompt.contains(&"!".repeat(5_000)));
+}
+
+#[tokio::test]
+async fn test_missing_model_is_shown_once() {
+    use futures::StreamExt;
+    use tower::{Service, ServiceExt};
+    use tower_lsp::jsonrpc::Request;
+
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("x"))]).await;
+    let (mut service, mut socket) = LspService::new(|client| {
+        Backend::new(
+            client,
+            Arc::new(ArcSwap::from_pointee(ContextSnapshot::default())),
+            Arc::new(DocumentStore::new()),
+            Arc::new(ModelClient::new(
+                server.url("/v1/chat/completions"),
+                String::new(),
+            )),
+            Arc::new(RwLock::new("key".to_string())),
+        )
+    });
+    // Notifications are only delivered once the server is initialized
+    let initialize = Request::build("initialize")
+        .params(serde_json::json!({ "capabilities": {} }))
+        .id(1)
+        .finish();
+    service.ready().await.unwrap().call(initialize).await.unwrap();
+    let backend = service.inner();
+
+    let uri = "file:///test/unconfigured.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "let a = ".to_string());
+    for _ in 0..2 {
+        let err = backend
+            .handle_inline_completion(inline_params(uri, 0, 8))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidRequest);
+        assert!(err.message.contains("No model configured"));
+    }
+    assert!(server.requests().is_empty());
+
+    let mut shown = vec![];
+    while let Ok(Some(request)) =
+        tokio::time::timeout(Duration::from_millis(200), socket.next()).await
+    {
+        if request.method() == "window/showMessage" {
+            shown.push(request.params().unwrap()["message"].as_str().unwrap().to_string());
+        }
+    }
+    assert_eq!(shown.len(), 1);
+    assert!(shown[0].contains("snek.model"));
+}
+
+#[tokio::test]
+async fn test_reload_config_pulls_settings_again() {
+    use futures::{SinkExt, StreamExt};
+    use tower::{Service, ServiceExt};
+    use tower_lsp::jsonrpc::{Request, Response};
+
+    let (mut service, mut socket) = create_test_backend();
+    let initialize = Request::build("initialize")
+        .params(serde_json::json!({ "capabilities": {} }))
+        .id(1)
+        .finish();
+    service.ready().await.unwrap().call(initialize).await.unwrap();
+    let backend = service.inner().clone();
+
+    // Answers every configuration request with the next model name
+    let client = tokio::spawn(async move {
+        let mut models = vec!["second-model", "first-model"];
+        while let Some(request) = socket.next().await {
+            if request.method() != "workspace/configuration" {
+                continue;
+            }
+            let model = models.pop().unwrap();
+            let config = serde_json::json!(["key", model, { "model": model }]);
+            let response = Response::from_ok(request.id().unwrap().clone(), config);
+            socket.send(response).await.unwrap();
+            if models.is_empty() {
+                break;
+            }
+        }
+    });
+
+    let response = backend.handle_reload_config().await.unwrap();
+    assert_eq!(response.model, "first-model");
+    assert!(response.api_key_configured);
+
+    let response = backend.handle_reload_config().await.unwrap();
+    assert_eq!(response.model, "second-model");
+    assert_eq!(backend.model.model_name().await, "second-model");
+
+    client.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_completions_status_follows_api_key() {
+    use futures::{SinkExt, StreamExt};
+    use tower::{Service, ServiceExt};
+    use tower_lsp::jsonrpc::{Request, Response};
+
+    let (mut service, mut socket) = create_test_backend();
+    let initialize = Request::build("initialize")
+        .params(serde_json::json!({ "capabilities": {} }))
+        .id(1)
+        .finish();
+    service.ready().await.unwrap().call(initialize).await.unwrap();
+    let backend = service.inner().clone();
+
+    // Answers configuration requests with the next key, forwarding statuses
+    let (status_tx, mut status_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut keys = vec!["key", "", ""];
+        while let Some(request) = socket.next().await {
+            match request.method() {
+                "workspace/configuration" => {
+                    let config = serde_json::json!([keys.pop().unwrap(), "model", {}]);
+                    let response = Response::from_ok(request.id().unwrap().clone(), config);
+                    socket.send(response).await.unwrap();
+                }
+                "snek/completionsStatus" => {
+                    status_tx.send(request.params().unwrap().clone()).unwrap();
+                }
+                _ => {}
+            }
+        }
+    });
+    let mut next_status = || status_rx.try_recv().ok();
+
+    backend.handle_reload_config().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let status = next_status().unwrap();
+    assert_eq!(status["enabled"], false);
+    assert!(status["reason"].as_str().unwrap().contains("no API key"));
+
+    // Unchanged, so not reported again
+    backend.handle_reload_config().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(next_status().is_none());
+
+    backend.handle_reload_config().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let status = next_status().unwrap();
+    assert_eq!(status["enabled"], true);
+    assert!(status.get("reason").is_none());
+}
+
+#[tokio::test]
+async fn test_instruction_applies_to_one_request() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("()"))]).await;
+
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+
+    let uri = "file:///test/instruction.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "fn fetch".to_string());
+
+    let mut params = inline_params(uri, 0, 8);
+    params.instruction = Some(format!("  make this async{}", "!".repeat(5_000)));
+    backend.handle_inline_completion(params).await.unwrap();
+
+    let body = server.requests()[0].json();
+    let prompt = body["messages"][1]["content"].as_str().unwrap();
+    assert!(prompt.contains("For this completion only, the user asks you to:\n\nmake this async"));
+    assert!(!prompt.contains(&"!".repeat(2_000)));
+
+    // Not kept for the next request
+    backend.handle_inline_completion(inline_params(uri, 0, 8)).await.unwrap();
+    let body = server.requests()[1].json();
+    let prompt = body["messages"][1]["content"].as_str().unwrap();
+    assert!(!prompt.contains("make this async"));
+}
+
+#[tokio::test]
+async fn test_context_filter_selects_markdown_files() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("()"))]).await;
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+
+    let mut snapshot = ContextSnapshot {
+        context_filter: Some(snek::snapshot::ContextFilter {
+            include: vec!["api-*.md".to_string()],
+            exclude: vec![],
+        }),
+        ..ContextSnapshot::default()
+    };
+    for name in ["api-auth.md", "api-billing.md", "design.md", "roadmap.md"] {
+        snapshot.cache_markdown(name.to_string(), format!("CONTENT OF {}", name));
+    }
+    backend.snapshot.store(Arc::new(snapshot));
+
+    let uri = "file:///test/filtered.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "fn run".to_string());
+    let prompt_of = |request: usize| {
+        server.requests()[request].json()["messages"][1]["content"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    };
+
+    backend.handle_inline_completion(inline_params(uri, 0, 6)).await.unwrap();
+    let prompt = prompt_of(0);
+    assert!(prompt.contains("CONTENT OF api-auth.md"));
+    assert!(prompt.contains("CONTENT OF api-billing.md"));
+    assert!(!prompt.contains("CONTENT OF design.md"));
+    assert!(!prompt.contains("CONTENT OF roadmap.md"));
+
+    // A request's own filter replaces the session's
+    let mut params = inline_params(uri, 0, 6);
+    params.context_filter = Some(snek::snapshot::ContextFilter {
+        include: vec![],
+        exclude: vec!["api-*".to_string(), "roadmap.md".to_string()],
+    });
+    backend.handle_inline_completion(params).await.unwrap();
+    let prompt = prompt_of(1);
+    assert!(prompt.contains("CONTENT OF design.md"));
+    assert!(!prompt.contains("CONTENT OF api-auth.md"));
+    assert!(!prompt.contains("CONTENT OF roadmap.md"));
+}
+
+#[tokio::test]
+async fn test_project_meta_is_in_system_prompt() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("()"))]).await;
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+
+    let session_dir = tempfile::tempdir().unwrap();
+    let session = serde_json::json!({
+        "schema": 1,
+        "id": "django",
+        "name": "django",
+        "version": 1,
+        "limits": { "max_tokens": 2000 },
+        "updated_at": "2025-11-03T00:00:00Z",
+        "meta": { "language": "Python", "framework": "Django" }
+    });
+    std::fs::write(session_dir.path().join("session.json"), session.to_string()).unwrap();
+    let snapshot = snek::session_io::load_snapshot(session_dir.path()).unwrap();
+    backend.snapshot.store(Arc::new(snapshot));
+
+    let uri = "file:///test/views.py";
+    backend
+        .documents
+        .did_open(uri.to_string(), "python".to_string(), "def index".to_string());
+    backend.handle_inline_completion(inline_params(uri, 0, 9)).await.unwrap();
+
+    let body = server.requests()[0].json();
+    assert_eq!(body["messages"][0]["role"], "system");
+    let system = body["messages"][0]["content"].as_str().unwrap();
+    assert!(system.ends_with("About the project: This is a Python project using Django."));
+}
+
+#[tokio::test]
+async fn test_language_override_is_used_in_prompt() {
+    let server = MockServer::start(vec![
+        MockResponse::new(200, completion_body(" FROM users")),
+        MockResponse::new(200, completion_body(" FROM users")),
+    ])
+    .await;
+
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+
+    let uri = "file:///test/query.py";
+    backend.documents.did_open(
+        uri.to_string(),
+        "python".to_string(),
+        "rows = db.execute(\"SELECT *".to_string(),
+    );
+
+    let mut params = inline_params(uri, 0, 26);
+    params.language = Some("sql".to_string());
+    backend.handle_inline_completion(params).await.unwrap();
+
+    let body = server.requests()[0].json();
+    let prompt = body["messages"][1]["content"].as_str().unwrap();
+    assert!(prompt.contains("Complete the following code.\n\nsql\n"));
+
+    // Without the override the document's own language is used again
+    backend
+        .handle_inline_completion(inline_params(uri, 0, 26))
+        .await
+        .unwrap();
+    let body = server.requests()[1].json();
+    let prompt = body["messages"][1]["content"].as_str().unwrap();
+    assert!(prompt.contains("Complete the following code.\n\npython\n"));
+}
+
+#[tokio::test]
+async fn test_temperature_override_is_sent_for_one_request() {
+    let server = MockServer::start(vec![
+        MockResponse::new(200, completion_body("(1)")),
+        MockResponse::new(200, completion_body("(2)")),
+    ])
+    .await;
+
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+    backend
+        .model
+        .set_sampling_options(SamplingOptions {
+            temperature: 0.2,
+            ..Default::default()
+        })
+        .await;
+
+    let uri = "file:///test/alt.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "call".to_string());
+
+    let response = backend
+        .handle_inline_completion(InlineCompletionParams {
+            temperature: Some(1.5),
+            ..inline_params(uri, 0, 4)
+        })
+        .await
+        .unwrap();
+    assert_eq!(response.completion, "(1)");
+    assert_eq!(server.requests()[0].json()["temperature"], 1.5);
+
+    // The next request is back on the configured temperature
+    backend
+        .handle_inline_completion(inline_params(uri, 0, 4))
+        .await
+        .unwrap();
+    let temperature = server.requests()[1].json()["temperature"].as_f64().unwrap();
+    assert!((temperature - 0.2).abs() < 1e-6);
+
+    let error = backend
+        .handle_inline_completion(InlineCompletionParams {
+            temperature: Some(2.5),
+            ..inline_params(uri, 0, 4)
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(error.code, ErrorCode::InvalidParams);
+    assert_eq!(server.requests().len(), 2);
+}
+
+#[tokio::test]
+async fn test_inline_text_completes_without_open_document() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body(" * 2"))]).await;
+
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+
+    let uri = "file:///test/never_opened.py";
+    let response = backend
+        .handle_inline_completion(InlineCompletionParams {
+            text: Some("def double(x):\n    return x\n".to_string()),
+            language: Some("python".to_string()),
+            ..inline_params(uri, 1, 12)
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(response.completion, "* 2");
+    assert!(backend.documents.list_open_uris().is_empty());
+    let body = server.requests()[0].json();
+    let prompt = body["messages"][1]["content"].as_str().unwrap();
+    assert!(prompt.contains("Complete the following code.\n\npython\n"));
+    assert!(prompt.contains("    return x"));
+}
+
+#[tokio::test]
+async fn test_blank_line_gaps_are_collapsed_when_enabled() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("()"))]).await;
+
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+    backend.settings.write().await.collapse_blank_lines = true;
+
+    let uri = "file:///test/sparse.rs";
+    let text = format!("fn first() {{}}{}fn run", "\n".repeat(40));
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), text);
+    backend
+        .handle_inline_completion(inline_params(uri, 40, 6))
+        .await
+        .unwrap();
+
+    let body = server.requests()[0].json();
+    let prompt = body["messages"][1]["content"].as_str().unwrap();
+    assert!(prompt.contains("fn first() {}\n\n\nfn run"));
+    assert!(!prompt.contains("\n\n\n\n"));
+}
+
+#[tokio::test]
+async fn test_documents_list_and_reset() {
+    let (service, _socket) = create_test_backend();
+    let backend = service.inner();
+
+    let uri = "file:///test/open.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "fn main() {}".to_string());
+
+    let listed = backend.handle_documents_list().await.unwrap();
+    assert_eq!(listed.documents.len(), 1);
+    assert_eq!(listed.documents[0].uri, uri);
+    assert_eq!(listed.documents[0].version, backend.documents.version(uri));
+
+    let reset = backend.handle_documents_reset().await.unwrap();
+    assert_eq!(reset.cleared, vec![uri.to_string()]);
+    assert!(backend.handle_documents_list().await.unwrap().documents.is_empty());
+}
+
+#[tokio::test]
+async fn test_session_context_can_be_disabled() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("()"))]).await;
+
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+
+    let mut snapshot = ContextSnapshot::default();
+    snapshot
+        .markdown_cache
+        .insert("notes.md".to_string(), "PROJECT NOTES".to_string());
+    snapshot.code_contexts.push(snek::snapshot::CodeContext {
+        uri: "file:///lib.rs".to_string(),
+        start_line: 0,
+        end_line: 1,
+        language_id: "rust".to_string(),
+        code: String::new(),
+        description: None,
+        last_modified: String::new(),
+        extra: Default::default(),
+    });
+    snapshot
+        .file_cache
+        .insert("file:///lib.rs".to_string(), "fn helper() {}\n".to_string());
+    backend.snapshot.store(Arc::new(snapshot));
+    backend.settings.write().await.context_enabled = false;
+
+    let uri = "file:///test/plain.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "fn run".to_string());
+    backend
+        .handle_inline_completion(inline_params(uri, 0, 6))
+        .await
+        .unwrap();
+
+    let prompt = |i: usize| {
+        server.requests()[i].json()["messages"][1]["content"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    };
+    let without = prompt(0);
+    assert!(!without.contains("PROJECT NOTES"));
+    assert!(!without.contains("code snippets"));
+    assert!(without.contains("fn run"));
+
+    // A request can still ask for it
+    let mut params = inline_params(uri, 0, 6);
+    params.context = Some(true);
+    backend.handle_inline_completion(params).await.unwrap();
+    let with = prompt(1);
+    assert!(with.contains("PROJECT NOTES"));
+    assert!(with.contains("fn helper() {}"));
+}
+
+#[tokio::test]
+async fn test_did_change_applies_every_change_in_order() {
+    use tower_lsp::lsp_types::{
+        DidChangeTextDocumentParams, Range, TextDocumentContentChangeEvent,
+        VersionedTextDocumentIdentifier,
+    };
+    use tower_lsp::LanguageServer;
+
+    let (service, _socket) = create_test_backend();
+    let backend = service.inner();
+
+    let uri = "file:///test/changes.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "v0".to_string());
+
+    let full = |text: &str| TextDocumentContentChangeEvent {
+        range: None,
+        range_length: None,
+        text: text.to_string(),
+    };
+    let params = |content_changes| DidChangeTextDocumentParams {
+        text_document: VersionedTextDocumentIdentifier {
+            uri: Url::parse(uri).unwrap(),
+            version: 2,
+        },
+        content_changes,
+    };
+
+    backend
+        .did_change(params(vec![full("fn first"), full("fn second")]))
+        .await;
+    let (prefix, _, _) = backend.documents.get_context(uri, 0, 9).unwrap();
+    assert_eq!(prefix, "fn second");
+
+    // Ranged changes would be misapplied as whole documents
+    let ranged = TextDocumentContentChangeEvent {
+        range: Some(Range::default()),
+        range_length: Some(0),
+        text: "x".to_string(),
+    };
+    backend.did_change(params(vec![ranged])).await;
+    backend.did_change(params(vec![])).await;
+    let (prefix, _, _) = backend.documents.get_context(uri, 0, 9).unwrap();
+    assert_eq!(prefix, "fn second");
+}
+
+#[tokio::test]
+async fn test_markdown_completion_is_not_fence_stripped() {
+    let example = " like this:\n\n```rust\nfn main() {}\n```";
+    let server = MockServer::start(vec![
+        MockResponse::new(200, completion_body(example)),
+        MockResponse::new(200, completion_body(example)),
+    ])
+    .await;
+
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+
+    let uri = "file:///test/README.md";
+    backend.documents.did_open(
+        uri.to_string(),
+        "markdown".to_string(),
+        "Call it".to_string(),
+    );
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 0, 7))
+        .await
+        .unwrap();
+    assert_eq!(response.completion, example);
+
+    // Code still gets the fence stripped
+    let uri = "file:///test/main.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "// Call it".to_string());
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 0, 10))
+        .await
+        .unwrap();
+    assert_eq!(response.completion, "fn main() {}");
+}
+
+#[tokio::test]
+async fn test_untitled_document_gets_completion() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("(1, 2)"))]).await;
+
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+    // Both resolve the document to a path on disk, which it doesn't have
+    let mut settings = backend.settings.read().await.clone();
+    settings.sibling_context = true;
+    settings.git_context_order = true;
+    backend.model.set_prompt_options(settings.prompt_options()).await;
+
+    let uri = "untitled:Untitled-1";
+    backend
+        .documents
+        .did_open(uri.to_string(), "python".to_string(), "print(add".to_string());
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 0, 9))
+        .await
+        .unwrap();
+    assert_eq!(response.completion, "(1, 2)");
+
+    let body = server.requests()[0].json();
+    let prompt = body["messages"][1]["content"].as_str().unwrap();
+    assert!(prompt.contains("File: untitled:Untitled-1"));
+}
+
+#[tokio::test]
+async fn test_warmup_pings_model_when_enabled() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("pong"))]).await;
+
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+
+    // Off by default
+    assert!(backend.start_warmup().await.is_none());
+    assert!(server.requests().is_empty());
+
+    backend.settings.write().await.warmup = true;
+    backend.start_warmup().await.unwrap().await.unwrap();
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].json()["max_tokens"], 1);
+    assert_eq!(requests[0].header("authorization"), Some("Bearer key"));
+}
+
+#[tokio::test]
+async fn test_session_request_cap_blocks_further_requests() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("x"))]).await;
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+
+    let session_dir = tempfile::TempDir::new().unwrap();
+    let session = serde_json::json!({
+        "schema": 1,
+        "id": "metered",
+        "name": "metered",
+        "version": 1,
+        "limits": { "max_tokens": 2000, "max_requests_per_session": 3 },
+        "updated_at": "2025-11-03T00:00:00Z",
+        "requests_used": 1
+    });
+    std::fs::write(
+        session_dir.path().join("session.json"),
+        serde_json::to_string_pretty(&session).unwrap(),
+    )
+    .unwrap();
+    backend
+        .snapshot
+        .store(Arc::new(snek::session_io::load_snapshot(session_dir.path()).unwrap()));
+
+    let uri = "file:///test/metered.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "let a = ".to_string());
+
+    // One request was already used before the restart, so two remain
+    for expected in ["x", "x", "", ""] {
+        backend.documents.did_change(uri, "let a = ".to_string());
+        let response = backend
+            .handle_inline_completion(inline_params(uri, 0, 8))
+            .await
+            .unwrap();
+        assert_eq!(response.completion, expected);
+    }
+    assert_eq!(server.requests().len(), 2);
+
+    // The count is written in the background, next to session.json
+    let mut persisted = 0;
+    for _ in 0..50 {
+        persisted = snek::session_io::load_snapshot(session_dir.path()).unwrap().requests_used;
+        if persisted == 3 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert_eq!(persisted, 3);
+    let session_json = std::fs::read_to_string(session_dir.path().join("session.json")).unwrap();
+    assert_eq!(session_json, serde_json::to_string_pretty(&session).unwrap());
+
+    // Another session starts from its own count
+    let other_dir = tempfile::TempDir::new().unwrap();
+    backend.snapshot.store(Arc::new(ContextSnapshot {
+        session_id: "other".to_string(),
+        session_dir: other_dir.path().to_path_buf(),
+        limits: snek::snapshot::Limits {
+            max_requests_per_session: Some(1),
+            ..Default::default()
+        },
+        ..ContextSnapshot::default()
+    }));
+    backend.documents.did_change(uri, "let a = ".to_string());
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 0, 8))
+        .await
+        .unwrap();
+    assert_eq!(response.completion, "x");
+}
+
+#[tokio::test]
+async fn test_read_only_backend_writes_nothing() {
+    use snek::session_io::{find_workspace_root, load_snapshot, resolve_active_session};
+
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let snek_root = find_workspace_root(Some(temp_dir.path().to_path_buf())).unwrap();
+    let session_dir = resolve_active_session(&snek_root).unwrap();
+    let session_path = session_dir.join("session.json");
+    let mut session: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&session_path).unwrap()).unwrap();
+    session["limits"]["max_requests_per_session"] = serde_json::json!(5);
+    std::fs::write(&session_path, serde_json::to_string_pretty(&session).unwrap()).unwrap();
+
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("x"))]).await;
+    let snapshot = Arc::new(ArcSwap::from_pointee(load_snapshot(&session_dir).unwrap()));
+    let (service, _socket) = LspService::new(|client| {
+        Backend::new(
+            client,
+            snapshot.clone(),
+            Arc::new(DocumentStore::new()),
+            Arc::new(ModelClient::new(
+                server.url("/v1/chat/completions"),
+                "test-model".to_string(),
+            )),
+            Arc::new(RwLock::new("key".to_string())),
+        )
+        .with_read_only(true)
+    });
+    let backend = service.inner();
+
+    let before = read_tree(&snek_root);
+    let uri = "file:///test/read_only.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "let a = ".to_string());
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 0, 8))
+        .await
+        .unwrap();
+    assert_eq!(response.completion, "x");
+    assert!(backend
+        .handle_export_prompt(inline_params(uri, 0, 8))
+        .await
+        .is_err());
+
+    // Neither the request count nor the prompt export touched .snek
+    assert_eq!(read_tree(&snek_root), before);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_response_carries_session_version() {
+    use snek::session_io::{find_workspace_root, load_snapshot, resolve_active_session};
+    use snek::watcher::SessionWatcher;
+
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let snek_root = find_workspace_root(Some(temp_dir.path().to_path_buf())).unwrap();
+    let session_dir = resolve_active_session(&snek_root).unwrap();
+
+    let server = MockServer::start(vec![
+        MockResponse::new(200, completion_body("x")),
+        MockResponse::new(200, completion_body("y")),
+    ])
+    .await;
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+    backend
+        .snapshot
+        .store(Arc::new(load_snapshot(&session_dir).unwrap()));
+    let session = backend.snapshot.load_full();
+    let _watcher =
+        SessionWatcher::start(snek_root.clone(), backend.snapshot.clone(), None).unwrap();
+
+    let uri = "file:///test/versioned.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "let a = ".to_string());
+
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 0, 8))
+        .await
+        .unwrap();
+    assert_eq!(response.completion, "x");
+    assert_eq!(response.session_id, session.session_id);
+    assert_eq!(response.version, session.version);
+    assert_eq!(response.reloads, 0);
+
+    // Editing context doesn't touch session.json's version, but still counts
+    std::fs::write(session_dir.join("context").join("notes.md"), "# Notes").unwrap();
+    for _ in 0..50 {
+        if backend.snapshot.load().markdown_cache.contains_key("notes.md") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    backend.documents.did_change(uri, "let a = ".to_string());
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 0, 8))
+        .await
+        .unwrap();
+    assert_eq!(response.completion, "y");
+    assert_eq!(response.version, session.version);
+    assert!(response.reloads > 0);
+}
+
+#[tokio::test]
+async fn test_response_reports_the_model_that_answered() {
+    let routed = serde_json::json!({
+        "model": "routed-model",
+        "choices": [{ "message": { "role": "assistant", "content": "x" } }]
+    });
+    let server = MockServer::start(vec![
+        MockResponse::new(200, routed.to_string()),
+        MockResponse::new(200, completion_body("y")),
+    ])
+    .await;
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+
+    let uri = "file:///test/routed.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "let a = ".to_string());
+
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 0, 8))
+        .await
+        .unwrap();
+    assert_eq!(response.completion, "x");
+    assert_eq!(response.model.as_deref(), Some("routed-model"));
+    assert_eq!(server.requests()[0].json()["model"], "test-model");
+
+    // Without a `model` field, the requested model is reported
+    backend.documents.did_change(uri, "let a = 1;\nlet b = ".to_string());
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 1, 8))
+        .await
+        .unwrap();
+    assert_eq!(response.completion, "y");
+    assert_eq!(response.model.as_deref(), Some("test-model"));
+}
+
+/// Every file under `dir` with its contents, sorted by path
+fn read_tree(dir: &std::path::Path) -> Vec<(std::path::PathBuf, Vec<u8>)> {
+    let mut files = vec![];
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(dir).unwrap().flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else {
+                let contents = std::fs::read(&path).unwrap();
+                files.push((path, contents));
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_scratch_session_is_empty_and_writes_nothing() {
+    use snek::session_io::{find_workspace_root, load_snapshot, resolve_active_session};
+    use snek::watcher::SessionWatcher;
+
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let snek_root = find_workspace_root(Some(temp_dir.path().to_path_buf())).unwrap();
+    let session_dir = resolve_active_session(&snek_root).unwrap();
+    std::fs::write(session_dir.join("context").join("notes.md"), "PERSISTED NOTES").unwrap();
+
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("x"))]).await;
+    let snapshot = Arc::new(ArcSwap::from_pointee(load_snapshot(&session_dir).unwrap()));
+    let (service, _socket) = LspService::new(|client| {
+        Backend::new(
+            client,
+            snapshot.clone(),
+            Arc::new(DocumentStore::new()),
+            Arc::new(ModelClient::new(
+                server.url("/v1/chat/completions"),
+                "test-model".to_string(),
+            )),
+            Arc::new(RwLock::new("key".to_string())),
+        )
+    });
+    let backend = service.inner();
+    let _watcher = SessionWatcher::start(snek_root.clone(), snapshot.clone(), None).unwrap();
+
+    let response = backend.handle_scratch_session().await.unwrap();
+    assert_eq!(response.session_id, "scratch");
+
+    let scratch = snapshot.load();
+    assert!(scratch.scratch);
+    assert!(scratch.code_contexts.is_empty());
+    assert!(scratch.markdown_cache.is_empty());
+    assert!(scratch.file_cache.is_empty());
+
+    // The watcher leaves the scratch session alone
+    std::fs::write(session_dir.join("context").join("more.md"), "MORE NOTES").unwrap();
+    tokio::time::sleep(Duration::from_millis(1000)).await;
+    assert!(snapshot.load().scratch);
+    assert!(snapshot.load().markdown_cache.is_empty());
+
+    let before = read_tree(&snek_root);
+    let uri = "file:///test/scratch.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "let a = ".to_string());
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 0, 8))
+        .await
+        .unwrap();
+    assert_eq!(response.completion, "x");
+
+    let prompt = server.requests()[0].json().to_string();
+    assert!(!prompt.contains("PERSISTED NOTES"));
+    assert_eq!(read_tree(&snek_root), before);
+}
+
+#[tokio::test]
+async fn test_requests_use_the_session_of_their_workspace_root() {
+    use snek::session_io::{find_workspace_root, load_snapshot, resolve_active_session};
+    use snek::workspaces::Workspaces;
+
+    let server = MockServer::start(vec![
+        MockResponse::new(200, completion_body("a")),
+        MockResponse::new(200, completion_body("b")),
+    ])
+    .await;
+
+    let mut workspaces = Workspaces::new();
+    let mut snapshots = vec![];
+    let mut dirs = vec![];
+    for notes in ["NOTES FOR FRONTEND", "NOTES FOR BACKEND"] {
+        let dir = tempfile::TempDir::new().unwrap();
+        let snek_root = find_workspace_root(Some(dir.path().to_path_buf())).unwrap();
+        let session_dir = resolve_active_session(&snek_root).unwrap();
+        std::fs::write(session_dir.join("context").join("notes.md"), notes).unwrap();
+
+        let snapshot = Arc::new(ArcSwap::from_pointee(load_snapshot(&session_dir).unwrap()));
+        workspaces.add(dir.path().to_path_buf(), snek_root, snapshot.clone());
+        snapshots.push(snapshot);
+        dirs.push(dir);
+    }
+
+    let (service, _socket) = LspService::new(|client| {
+        Backend::new(
+            client,
+            snapshots[0].clone(),
+            Arc::new(DocumentStore::new()),
+            Arc::new(ModelClient::new(
+                server.url("/v1/chat/completions"),
+                "test-model".to_string(),
+            )),
+            Arc::new(RwLock::new("key".to_string())),
+        )
+        .with_workspaces(workspaces)
+    });
+    let backend = service.inner();
+
+    for dir in &dirs {
+        let uri = Url::from_file_path(dir.path().join("src").join("main.rs"))
+            .unwrap()
+            .to_string();
+        backend
+            .documents
+            .did_open(uri.clone(), "rust".to_string(), "let a = ".to_string());
+        backend
+            .handle_inline_completion(inline_params(&uri, 0, 8))
+            .await
+            .unwrap();
+    }
+
+    let requests = server.requests();
+    let first = requests[0].json().to_string();
+    let second = requests[1].json().to_string();
+    assert!(first.contains("NOTES FOR FRONTEND") && !first.contains("NOTES FOR BACKEND"));
+    assert!(second.contains("NOTES FOR BACKEND") && !second.contains("NOTES FOR FRONTEND"));
+}
+
+#[tokio::test]
+async fn test_full_file_threshold_windows_only_large_documents() {
+    let server = MockServer::start(vec![
+        MockResponse::new(200, completion_body("x")),
+        MockResponse::new(200, completion_body("x")),
+    ])
+    .await;
+
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+    backend.settings.write().await.full_file_threshold = Some(20);
+
+    let small: Vec<String> = (0..20).map(|i| format!("let small_{} = {};", i, i)).collect();
+    let uri = "file:///test/small.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), small.join("\n"));
+    backend
+        .handle_inline_completion(inline_params(uri, 10, 0))
+        .await
+        .unwrap();
+
+    let large: Vec<String> = (0..200).map(|i| format!("let large_{} = {};", i, i)).collect();
+    let uri = "file:///test/large.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), large.join("\n"));
+    backend
+        .handle_inline_completion(inline_params(uri, 100, 0))
+        .await
+        .unwrap();
+
+    let requests = server.requests();
+    let prompt = requests[0].json().to_string();
+    assert!(prompt.contains("let small_0 = 0;"));
+    assert!(prompt.contains("let small_19 = 19;"));
+
+    let prompt = requests[1].json().to_string();
+    assert!(prompt.contains("let large_99 = 99;"));
+    assert!(prompt.contains("let large_100 = 100;"));
+    assert!(!prompt.contains("let large_0 = 0;"));
+    assert!(!prompt.contains("let large_199 = 199;"));
+}
+
+#[tokio::test]
+async fn test_accepted_suggestion_is_continued() {
+    let server = MockServer::start(vec![
+        MockResponse::new(200, completion_body("items.iter().map(|i| i.name).collect()")),
+        MockResponse::new(200, completion_body("x")),
+        MockResponse::new(200, completion_body("items.len()")),
+        MockResponse::new(200, completion_body("x")),
+    ])
+    .await;
+
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+
+    let uri = "file:///test/continue.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "let names = ".to_string());
+    backend
+        .handle_inline_completion(inline_params(uri, 0, 12))
+        .await
+        .unwrap();
+
+    // The user accepts the first few words of the suggestion
+    backend.documents.did_change(uri, "let names = items.iter()".to_string());
+    backend
+        .handle_inline_completion(inline_params(uri, 0, 24))
+        .await
+        .unwrap();
+
+    // Accepting, but with an edit elsewhere before the cursor
+    backend.documents.did_change(uri, "let count = ".to_string());
+    backend
+        .handle_inline_completion(inline_params(uri, 0, 12))
+        .await
+        .unwrap();
+    backend.documents.did_change(uri, "let total = items.len()".to_string());
+    backend
+        .handle_inline_completion(inline_params(uri, 0, 23))
+        .await
+        .unwrap();
+
+    let requests = server.requests();
+    let prompt = |i: usize| requests[i].json()["messages"][1]["content"].as_str().unwrap().to_string();
+
+    let continued = prompt(1);
+    assert!(continued.contains("You previously suggested:"));
+    assert!(continued.contains("```\nitems.iter().map(|i| i.name).collect()\n```"));
+    assert!(continued.contains("up to the cursor:\n\n```\nitems.iter()\n```"));
+
+    assert!(!prompt(0).contains("You previously suggested"));
+    assert!(!prompt(3).contains("You previously suggested"));
+}
+
+#[tokio::test]
+async fn test_project_config_api_url_change_is_applied() {
+    use snek::project_config::ProjectConfig;
+
+    let first = MockServer::start(vec![MockResponse::new(200, completion_body("first"))]).await;
+    let second = MockServer::start(vec![MockResponse::new(200, completion_body("second"))]).await;
+
+    let config_with_url = |url: &str| {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let config = serde_json::json!({ "apiUrl": url });
+        std::fs::write(temp_dir.path().join("config.json"), config.to_string()).unwrap();
+        Arc::new(ProjectConfig::load(temp_dir.path()).unwrap())
+    };
+    let project_config = Arc::new(ArcSwap::new(config_with_url(&first.url("/v1/chat/completions"))));
+
+    let make_backend = |api_url_override: Option<String>| {
+        LspService::new(|client| {
+            Backend::new(
+                client,
+                Arc::new(ArcSwap::from_pointee(ContextSnapshot::default())),
+                Arc::new(DocumentStore::new()),
+                Arc::new(ModelClient::new(
+                    first.url("/v1/chat/completions"),
+                    "test-model".to_string(),
+                )),
+                Arc::new(RwLock::new("key".to_string())),
+            )
+            .with_project_config(project_config.clone())
+            .with_api_url_override(api_url_override)
+        })
+    };
+    let (service, _socket) = make_backend(None);
+    let backend = service.inner();
+    let (pinned_service, _pinned_socket) =
+        make_backend(Some(first.url("/v1/chat/completions")));
+    let pinned = pinned_service.inner();
+
+    // The watcher reloads config.json with a new endpoint
+    project_config.store(config_with_url(&second.url("/v1/chat/completions")));
+
+    let uri = "file:///test/endpoint.rs";
+    for backend in [backend, pinned] {
+        backend
+            .documents
+            .did_open(uri.to_string(), "rust".to_string(), "let a = ".to_string());
+    }
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 0, 8))
+        .await
+        .unwrap();
+    assert_eq!(response.completion, "second");
+    assert_eq!(backend.model.api_url().await, second.url("/v1/chat/completions"));
+
+    // SNEK_API_URL keeps the endpoint
+    let response = pinned
+        .handle_inline_completion(inline_params(uri, 0, 8))
+        .await
+        .unwrap();
+    assert_eq!(response.completion, "first");
+    assert_eq!(first.requests().len(), 1);
+    assert_eq!(second.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn test_snek_model_wins_over_project_model() {
+    use snek::project_config::ProjectConfig;
+
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    std::fs::write(temp_dir.path().join("config.json"), r#"{ "model": "project-model" }"#).unwrap();
+    let project_config = Arc::new(ArcSwap::from_pointee(
+        ProjectConfig::load(temp_dir.path()).unwrap(),
+    ));
+
+    let make_backend = |model_override: Option<String>| {
+        LspService::new(|client| {
+            Backend::new(
+                client,
+                Arc::new(ArcSwap::from_pointee(ContextSnapshot::default())),
+                Arc::new(DocumentStore::new()),
+                Arc::new(ModelClient::new(String::new(), "default-model".to_string())),
+                Arc::new(RwLock::new("key".to_string())),
+            )
+            .with_project_config(project_config.clone())
+            .with_model_override(model_override)
+        })
+    };
+
+    let (service, _socket) = make_backend(None);
+    service.inner().apply_project_config().await;
+    assert_eq!(service.inner().model.model_name().await, "project-model");
+
+    let (service, _socket) = make_backend(Some("env-model".to_string()));
+    service.inner().apply_project_config().await;
+    assert_eq!(service.inner().model.model_name().await, "env-model");
+}
+
+#[tokio::test]
+async fn test_project_config_applies_before_client_config() {
+    use snek::project_config::ProjectConfig;
+
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("x"))]).await;
+
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    std::fs::write(
+        temp_dir.path().join("config.json"),
+        r#"{
+  // Checked in with the project
+  "model": "project-model",
+  "temperature": 0.4,
+  "maxTokens": 321,
+  "languages": ["rust"],
+}"#,
+    )
+    .unwrap();
+    let project_config = Arc::new(ArcSwap::from_pointee(
+        ProjectConfig::load(temp_dir.path()).unwrap(),
+    ));
+
+    let (service, _socket) = LspService::new(|client| {
+        Backend::new(
+            client,
+            Arc::new(ArcSwap::from_pointee(ContextSnapshot::default())),
+            Arc::new(DocumentStore::new()),
+            Arc::new(ModelClient::new(
+                server.url("/v1/chat/completions"),
+                "default-model".to_string(),
+            )),
+            Arc::new(RwLock::new("key".to_string())),
+        )
+        .with_project_config(project_config)
+    });
+    let backend = service.inner();
+    backend.apply_project_config().await;
+
+    let uri = "file:///test/project.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "let a = ".to_string());
+    backend
+        .handle_inline_completion(inline_params(uri, 0, 8))
+        .await
+        .unwrap();
+
+    // Not in the project's languages
+    let uri = "file:///test/project.py";
+    backend
+        .documents
+        .did_open(uri.to_string(), "python".to_string(), "a = ".to_string());
+    let response = backend
+        .handle_inline_completion(inline_params(uri, 0, 4))
+        .await
+        .unwrap();
+    assert!(response.completion.is_empty());
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    let body = requests[0].json();
+    assert_eq!(body["model"], "project-model");
+    assert_eq!(body["max_tokens"], 321);
+    assert!((body["temperature"].as_f64().unwrap() - 0.4).abs() < 1e-6);
+}
+
+#[tokio::test]
+async fn test_export_prompt_writes_last_prompt() {
+    use snek::session_io::{find_workspace_root, load_snapshot, resolve_active_session};
+
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let snek_root = find_workspace_root(Some(temp_dir.path().to_path_buf())).unwrap();
+    let session_dir = resolve_active_session(&snek_root).unwrap();
+    std::fs::write(session_dir.join("context").join("notes.md"), "PROJECT NOTES").unwrap();
+
+    let (service, _socket) = LspService::new(|client| {
+        Backend::new(
+            client,
+            Arc::new(ArcSwap::from_pointee(load_snapshot(&session_dir).unwrap())),
+            Arc::new(DocumentStore::new()),
+            Arc::new(ModelClient::new(
+                "http://127.0.0.1:0/v1/chat/completions".to_string(),
+                "test-model".to_string(),
+            )),
+            Arc::new(RwLock::new("sk-secret-key".to_string())),
+        )
+        .with_snek_root(snek_root.clone())
+    });
+    let backend = service.inner();
+
+    let uri = "file:///test/export.rs";
+    backend.documents.did_open(
+        uri.to_string(),
+        "rust".to_string(),
+        "const KEY: &str = \"sk-secret-key\";\nfn run".to_string(),
+    );
+
+    let response = backend
+        .handle_export_prompt(inline_params(uri, 1, 6))
+        .await
+        .unwrap();
+    assert_eq!(response.path, snek_root.join("last_prompt.md").to_string_lossy());
+
+    let exported = std::fs::read_to_string(&response.path).unwrap();
+    assert!(exported.starts_with("# Snek prompt"));
+    assert!(exported.contains("- Model: `test-model`"));
+    assert!(exported.contains("\n## system\n"));
+    assert!(exported.contains("\n## user\n"));
+    assert!(exported.contains("PROJECT NOTES"));
+    assert!(exported.contains("fn run"));
+    assert!(!exported.contains("sk-secret-key"));
+}
+
+#[tokio::test]
+async fn test_export_prompt_uses_the_tracked_snek_root() {
+    let workspace = tempfile::TempDir::new().unwrap();
+    let snek_root = workspace.path().join(".snek");
+    std::fs::create_dir_all(&snek_root).unwrap();
+    // A session kept outside .snek, as an absolute session path allows
+    let elsewhere = tempfile::TempDir::new().unwrap();
+    let session_dir = elsewhere.path().join("a/b/session");
+    std::fs::create_dir_all(&session_dir).unwrap();
+
+    let (service, _socket) = LspService::new(|client| {
+        Backend::new(
+            client,
+            Arc::new(ArcSwap::from_pointee(ContextSnapshot {
+                session_dir: session_dir.clone(),
+                ..ContextSnapshot::default()
+            })),
+            Arc::new(DocumentStore::new()),
+            Arc::new(ModelClient::new(String::new(), "test-model".to_string())),
+            Arc::new(RwLock::new(String::new())),
+        )
+        .with_snek_root(snek_root.clone())
+    });
+    let backend = service.inner();
+
+    let uri = "file:///test/export.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "fn run".to_string());
+    let response = backend
+        .handle_export_prompt(inline_params(uri, 0, 6))
+        .await
+        .unwrap();
+    assert_eq!(response.path, snek_root.join("last_prompt.md").to_string_lossy());
+    assert!(!elsewhere.path().join("a/last_prompt.md").exists());
+}
+
+#[tokio::test]
+async fn test_snapshot_restore_reloads_session() {
+    let workspace = tempfile::tempdir().unwrap();
+    let (service, _socket) = LspService::new(|client| {
+        Backend::new(
+            client,
+            Arc::new(ArcSwap::from_pointee(ContextSnapshot::default())),
+            Arc::new(DocumentStore::new()),
+            Arc::new(ModelClient::new(String::new(), "test-model".to_string())),
+            Arc::new(RwLock::new(String::new())),
+        )
+        .with_snek_root(workspace.path().join(".snek"))
+    });
+    let backend = service.inner();
+
+    let session_dir = workspace.path().join(".snek/sessions/tuning");
+    std::fs::create_dir_all(session_dir.join("context")).unwrap();
+    let session = serde_json::json!({
+        "schema": 1,
+        "id": "tuning",
+        "name": "tuning",
+        "version": 1,
+        "limits": { "max_tokens": 2000 },
+        "updated_at": "2025-11-03T00:00:00Z"
+    });
+    std::fs::write(session_dir.join("session.json"), session.to_string()).unwrap();
+    std::fs::write(session_dir.join("context/intent.md"), "known good").unwrap();
+    let snapshot = snek::session_io::load_snapshot(&session_dir).unwrap();
+    backend.snapshot.store(Arc::new(snapshot));
+
+    let name = |name: &str| ContextSnapshotParams {
+        name: name.to_string(),
+        text_document: None,
+    };
+    let saved = backend.handle_snapshot_save(name("base")).await.unwrap();
+    assert!(saved.path.ends_with("snapshots/base"));
+
+    std::fs::write(session_dir.join("context/intent.md"), "experiment").unwrap();
+    let restored = backend.handle_snapshot_restore(name("base")).await.unwrap();
+    assert_eq!(restored.session_id, "tuning");
+    assert_eq!(
+        backend.snapshot.load().markdown_cache.get("intent.md").map(String::as_str),
+        Some("known good")
+    );
+
+    let error = backend.handle_snapshot_save(name("../base")).await.unwrap_err();
+    assert_eq!(error.code, ErrorCode::InvalidParams);
+
+    backend.handle_scratch_session().await.unwrap();
+    let error = backend.handle_snapshot_restore(name("base")).await.unwrap_err();
+    assert_eq!(error.code, ErrorCode::InvalidParams);
+}
+
+#[tokio::test]
+async fn test_snapshots_use_the_session_of_their_workspace_root() {
+    use snek::session_io::{find_workspace_root, load_snapshot, resolve_active_session};
+    use snek::workspaces::Workspaces;
+
+    let mut workspaces = Workspaces::new();
+    let mut snapshots = vec![];
+    let mut dirs = vec![];
+    for notes in ["FRONTEND", "BACKEND"] {
+        let dir = tempfile::TempDir::new().unwrap();
+        let snek_root = find_workspace_root(Some(dir.path().to_path_buf())).unwrap();
+        let session_dir = resolve_active_session(&snek_root).unwrap();
+        std::fs::write(session_dir.join("context").join("notes.md"), notes).unwrap();
+
+        let snapshot = Arc::new(ArcSwap::from_pointee(load_snapshot(&session_dir).unwrap()));
+        workspaces.add(dir.path().to_path_buf(), snek_root, snapshot.clone());
+        snapshots.push((snapshot, session_dir));
+        dirs.push(dir);
+    }
+
+    let (service, _socket) = LspService::new(|client| {
+        Backend::new(
+            client,
+            snapshots[0].0.clone(),
+            Arc::new(DocumentStore::new()),
+            Arc::new(ModelClient::new(String::new(), "test-model".to_string())),
+            Arc::new(RwLock::new("key".to_string())),
+        )
+        .with_workspaces(workspaces)
+    });
+    let backend = service.inner();
+
+    let backend_params = |name: &str| ContextSnapshotParams {
+        name: name.to_string(),
+        text_document: Some(TextDocumentIdentifier {
+            uri: Url::from_file_path(dirs[1].path().join("src").join("main.rs")).unwrap(),
+        }),
+    };
+    backend.handle_snapshot_save(backend_params("base")).await.unwrap();
+    assert!(dirs[1].path().join(".snek/snapshots/base/session.json").is_file());
+
+    let (backend_snapshot, backend_session) = &snapshots[1];
+    std::fs::write(backend_session.join("context").join("notes.md"), "EXPERIMENT").unwrap();
+    backend.handle_snapshot_restore(backend_params("base")).await.unwrap();
+
+    let notes = |snapshot: &Arc<ArcSwap<ContextSnapshot>>| {
+        snapshot.load().markdown_cache.get("notes.md").cloned()
+    };
+    assert_eq!(notes(backend_snapshot).as_deref(), Some("BACKEND"));
+    assert!(backend_snapshot.load().reloads > 0);
+    // The first root's session is left alone
+    assert_eq!(notes(&snapshots[0].0).as_deref(), Some("FRONTEND"));
+    assert_eq!(snapshots[0].0.load().reloads, 0);
+    assert!(!dirs[0].path().join(".snek/snapshots").exists());
+}
+
+#[tokio::test]
+async fn test_context_budget_uses_the_session_of_the_document_root() {
+    use snek::budget::ContextBudget;
+    use snek::session_io::{find_workspace_root, load_snapshot, resolve_active_session};
+    use snek::workspaces::Workspaces;
+
+    let mut workspaces = Workspaces::new();
+    let mut snapshots = vec![];
+    let mut dirs = vec![];
+    for notes in ["frontend.md", "backend.md"] {
+        let dir = tempfile::TempDir::new().unwrap();
+        let snek_root = find_workspace_root(Some(dir.path().to_path_buf())).unwrap();
+        let session_dir = resolve_active_session(&snek_root).unwrap();
+        std::fs::write(session_dir.join("context").join(notes), "# Notes").unwrap();
+
+        let snapshot = Arc::new(ArcSwap::from_pointee(load_snapshot(&session_dir).unwrap()));
+        workspaces.add(dir.path().to_path_buf(), snek_root, snapshot.clone());
+        snapshots.push(snapshot);
+        dirs.push(dir);
+    }
+
+    let (service, _socket) = LspService::new(|client| {
+        Backend::new(
+            client,
+            snapshots[0].clone(),
+            Arc::new(DocumentStore::new()),
+            Arc::new(ModelClient::new(String::new(), "test-model".to_string())),
+            Arc::new(RwLock::new("key".to_string())),
+        )
+        .with_workspaces(workspaces)
+    });
+    let backend = service.inner();
+
+    let markdown = |budget: &ContextBudget| -> Vec<String> {
+        budget.markdown.iter().map(|item| item.name.clone()).collect()
+    };
+    let budget = backend
+        .handle_context_budget(ContextBudgetParams {
+            text_document: Some(TextDocumentIdentifier {
+                uri: Url::from_file_path(dirs[1].path().join("src").join("main.rs")).unwrap(),
+            }),
+        })
+        .await
+        .unwrap();
+    assert!(markdown(&budget).contains(&"backend.md".to_string()));
+    assert!(!markdown(&budget).contains(&"frontend.md".to_string()));
+
+    let budget = backend
+        .handle_context_budget(ContextBudgetParams::default())
+        .await
+        .unwrap();
+    assert!(markdown(&budget).contains(&"frontend.md".to_string()));
+}
+
+#[tokio::test]
+async fn test_partial_acceptance_shortens_completions() {
+    let suggestion = "let a = 1;\nlet b = 2;\nlet c = 3;\nlet d = 4;";
+    let server = MockServer::start(
+        (0..5)
+            .map(|_| MockResponse::new(200, completion_body(suggestion)))
+            .collect(),
+    )
+    .await;
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+
+    let uri = "file:///test/short.rs";
+    let max_tokens = |request: usize| server.requests()[request].json()["max_tokens"].as_u64().unwrap();
+    let keep_first_line = || PartialAcceptanceParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::parse(uri).unwrap(),
+        },
+        accepted_chars: "let a = 1;".len(),
+    };
+
+    for request in 0..4 {
+        // A new document version each time, so nothing comes from the cache
+        let text = format!("fn main() {{\n    // {}\n    ", request);
+        backend
+            .documents
+            .did_open(uri.to_string(), "rust".to_string(), text);
+        backend
+            .handle_inline_completion(inline_params(uri, 2, 4))
+            .await
+            .unwrap();
+        backend.handle_partial_acceptance(keep_first_line()).await;
+    }
+
+    // Not enough feedback yet for the first three requests
+    assert_eq!(max_tokens(0), 1600);
+    assert_eq!(max_tokens(2), 1600);
+    // Under a quarter of each suggestion kept: the shortest length is requested
+    assert_eq!(max_tokens(3), 400);
+
+    // Other languages keep the full length
+    backend
+        .documents
+        .did_open("file:///test/other.py".to_string(), "python".to_string(), "x = ".to_string());
+    backend
+        .handle_inline_completion(inline_params("file:///test/other.py", 0, 4))
+        .await
+        .unwrap();
+    assert_eq!(max_tokens(4), 1600);
+}
+
+#[tokio::test]
+async fn test_open_buffer_imports_are_included() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("()"))]).await;
+    let (service, _socket) = create_backend_with_model(&server.url("/v1/chat/completions"), "key");
+    let backend = service.inner();
+    backend.settings.write().await.open_buffer_imports = true;
+
+    backend.documents.did_open(
+        "file:///test/parser.rs".to_string(),
+        "rust".to_string(),
+        "use std::collections::HashMap;\nuse crate::token::{\n    Token,\n    Span,\n};\n\nfn parse_body() -> HashMap<Span, Token> {\n    todo!()\n}\n"
+            .to_string(),
+    );
+    let uri = "file:///test/main.rs";
+    backend.documents.did_open(
+        uri.to_string(),
+        "rust".to_string(),
+        "use crate::parser;\nfn main() { parser".to_string(),
+    );
+    backend.handle_inline_completion(inline_params(uri, 1, 18)).await.unwrap();
+
+    let prompt = server.requests()[0].json()["messages"][1]["content"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    assert!(prompt.contains(
+        "## file:///test/parser.rs\n\n```\nuse std::collections::HashMap;\nuse crate::token::{\n    Token,\n    Span,\n};\n```"
+    ));
+    assert!(!prompt.contains("fn parse_body"));
+    assert!(!prompt.contains("## file:///test/main.rs"));
+}
@@ -0,0 +1,81 @@
+//! Integration tests for budget module
+
+use snek::budget::{estimate_tokens, ContextBudget};
+use snek::snapshot::{CodeContext, ContextSnapshot, Limits};
+
+fn snippet(uri: &str, start_line: u32, end_line: u32, code: &str) -> CodeContext {
+    CodeContext {
+        uri: uri.to_string(),
+        start_line,
+        end_line,
+        language_id: "rust".to_string(),
+        code: code.to_string(),
+        description: None,
+        last_modified: String::new(),
+        extra: Default::default(),
+    }
+}
+
+#[test]
+fn test_estimate_tokens_rounds_up() {
+    assert_eq!(estimate_tokens(""), 0);
+    assert_eq!(estimate_tokens("abc"), 1);
+    assert_eq!(estimate_tokens("abcdefgh"), 2);
+    assert_eq!(estimate_tokens("abcdefghi"), 3);
+}
+
+#[test]
+fn test_context_budget_sums_items() {
+    let mut snapshot = ContextSnapshot {
+        session_id: "budgeted".to_string(),
+        code_contexts: vec![
+            snippet("file:///src/stored.rs", 0, 2, &"s".repeat(40)),
+            snippet("file:///src/live.rs", 1, 3, ""),
+            snippet("file:///src/missing.rs", 0, 1, ""),
+        ],
+        limits: Limits {
+            max_tokens: 100,
+            ..Limits::default()
+        },
+        ..ContextSnapshot::default()
+    };
+    snapshot.cache_markdown("small.md".to_string(), "m".repeat(10));
+    snapshot.cache_markdown("large.md".to_string(), "m".repeat(400));
+    snapshot.cache_file(
+        "file:///src/live.rs".to_string(),
+        "skipped\nkept one\nkept two\nskipped\n".to_string(),
+    );
+
+    let budget = ContextBudget::for_snapshot(&snapshot);
+
+    assert_eq!(budget.session_id, "budgeted");
+    let markdown: Vec<(&str, usize)> = budget
+        .markdown
+        .iter()
+        .map(|item| (item.name.as_str(), item.tokens))
+        .collect();
+    assert_eq!(markdown, vec![("large.md", 100), ("small.md", 3)]);
+    let snippets: Vec<(&str, usize)> = budget
+        .snippets
+        .iter()
+        .map(|item| (item.name.as_str(), item.tokens))
+        .collect();
+    assert_eq!(
+        snippets,
+        vec![
+            ("file:///src/stored.rs:0-2", 10),
+            ("file:///src/live.rs:1-3", estimate_tokens("kept one\nkept two")),
+            ("file:///src/missing.rs:0-1", 0),
+        ]
+    );
+
+    let sum: usize = budget
+        .markdown
+        .iter()
+        .chain(&budget.snippets)
+        .map(|item| item.tokens)
+        .sum();
+    assert_eq!(budget.total_tokens, sum);
+    assert_eq!(budget.max_tokens, 100);
+    assert!(budget.exceeds_max_tokens);
+}
@@ -0,0 +1,51 @@
+//! Integration tests for circuit_breaker module
+
+use std::time::Duration;
+
+use snek::circuit_breaker::CircuitBreaker;
+
+#[test]
+fn test_breaker_disabled_by_default() {
+    let breaker = CircuitBreaker::new();
+    for _ in 0..10 {
+        breaker.record_failure();
+    }
+    assert!(breaker.check().is_ok());
+}
+
+#[test]
+fn test_breaker_opens_after_consecutive_failures() {
+    let breaker = CircuitBreaker::new();
+    breaker.configure(3, Duration::from_secs(60));
+
+    breaker.record_failure();
+    breaker.record_failure();
+    // A success in between starts the count over
+    breaker.record_success();
+    breaker.record_failure();
+    breaker.record_failure();
+    assert!(breaker.check().is_ok());
+
+    breaker.record_failure();
+    let retry_after = breaker.check().unwrap_err();
+    assert!(retry_after > Duration::from_secs(59));
+}
+
+#[test]
+fn test_half_open_allows_one_probe() {
+    let breaker = CircuitBreaker::new();
+    breaker.configure(1, Duration::ZERO);
+    breaker.record_failure();
+
+    assert!(breaker.check().is_ok());
+    assert!(breaker.check().is_err());
+
+    // A cancelled probe frees the slot; a failed one reopens the breaker
+    breaker.record_abandoned();
+    assert!(breaker.check().is_ok());
+    breaker.record_failure();
+    assert!(breaker.check().is_ok());
+    breaker.record_success();
+    assert!(breaker.check().is_ok());
+    assert!(breaker.check().is_ok());
+}
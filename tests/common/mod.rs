@@ -0,0 +1,208 @@
+//! Shared helpers for integration tests
+
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A request captured by `MockServer`
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    pub request_line: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl RecordedRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).unwrap()
+    }
+}
+
+/// A canned response served by `MockServer`
+#[derive(Clone, Debug)]
+pub struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    pub delay: Option<Duration>,
+}
+
+impl MockResponse {
+    pub fn new(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+            body: body.into(),
+            delay: None,
+        }
+    }
+
+    /// Holds the response back for `delay` after the request is read
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+/// Builds an OpenAI-style chat completion body returning `content`
+pub fn completion_body(content: &str) -> String {
+    serde_json::json!({
+        "choices": [{ "message": { "role": "assistant", "content": content } }]
+    })
+    .to_string()
+}
+
+/// Minimal HTTP/1.1 server that replays `responses` in order (repeating the
+/// last one) and records every request it receives.
+pub struct MockServer {
+    pub addr: SocketAddr,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl MockServer {
+    pub async fn start(responses: Vec<MockResponse>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let dropped = Arc::new(AtomicUsize::new(0));
+
+        let recorded = requests.clone();
+        let dropped_count = dropped.clone();
+        tokio::spawn(async move {
+            let mut served = 0usize;
+            while let Ok((stream, _)) = listener.accept().await {
+                let response = responses
+                    .get(served)
+                    .or_else(|| responses.last())
+                    .cloned()
+                    .unwrap_or_else(|| MockResponse::new(500, "no response configured"));
+                served += 1;
+
+                let recorded = recorded.clone();
+                let dropped = dropped_count.clone();
+                tokio::spawn(async move {
+                    handle_connection(stream, &response, &recorded, &dropped).await;
+                });
+            }
+        });
+
+        Self {
+            addr,
+            requests,
+            dropped,
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Number of connections the client closed before the response was sent
+    pub fn dropped_connections(&self) -> usize {
+        self.dropped.load(Ordering::SeqCst)
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    response: &MockResponse,
+    recorded: &Mutex<Vec<RecordedRequest>>,
+    dropped: &AtomicUsize,
+) {
+    let Some(request) = read_request(&mut stream).await else {
+        return;
+    };
+    recorded.lock().unwrap().push(request);
+
+    if let Some(delay) = response.delay {
+        let mut probe = [0u8; 1];
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = stream.read(&mut probe) => {
+                // Any read result before the response means the client hung up
+                dropped.fetch_add(1, Ordering::SeqCst);
+                return;
+            }
+        }
+    }
+
+    let mut raw = format!("HTTP/1.1 {} Mock\r\n", response.status);
+    for (name, value) in &response.headers {
+        raw.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    raw.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.body.len(),
+        response.body
+    ));
+    let _ = stream.write_all(raw.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+async fn read_request(stream: &mut TcpStream) -> Option<RecordedRequest> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    let header_end = loop {
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default().to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+
+    let content_length = headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.parse::<usize>().ok())
+        .unwrap_or(0);
+
+    while buf.len() < header_end + content_length {
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let body = String::from_utf8_lossy(&buf[header_end..]).to_string();
+
+    Some(RecordedRequest {
+        request_line,
+        headers,
+        body,
+    })
+}
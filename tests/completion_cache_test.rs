@@ -0,0 +1,134 @@
+//! Integration tests for completion_cache module
+
+use std::time::Duration;
+
+use snek::completion_cache::{
+    CompletionCache, CompletionKey, PersistentCompletionCache, PersistentKey,
+};
+
+#[test]
+fn test_same_context_hits() {
+    let cache = CompletionCache::new();
+    cache.insert(CompletionKey::new("file:///a.rs", 1, "let x = ", ""), "1;".to_string());
+
+    assert_eq!(
+        cache.get(&CompletionKey::new("file:///a.rs", 1, "let x = ", "")),
+        Some("1;".to_string())
+    );
+    assert!(cache.get(&CompletionKey::new("file:///a.rs", 2, "let x = ", "")).is_none());
+    assert!(cache.get(&CompletionKey::new("file:///a.rs", 1, "let y = ", "")).is_none());
+}
+
+#[test]
+fn test_invalidate_only_drops_uri() {
+    let cache = CompletionCache::new();
+    cache.insert(CompletionKey::new("file:///a.rs", 1, "a", ""), "a".to_string());
+    cache.insert(CompletionKey::new("file:///b.rs", 2, "b", ""), "b".to_string());
+
+    cache.invalidate("file:///a.rs");
+    assert_eq!(cache.len(), 1);
+    assert!(cache.get(&CompletionKey::new("file:///b.rs", 2, "b", "")).is_some());
+}
+
+#[test]
+fn test_capacity_is_bounded() {
+    let cache = CompletionCache::with_capacity(2);
+    for i in 0..5 {
+        cache.insert(CompletionKey::new("file:///a.rs", i, "", ""), i.to_string());
+    }
+    assert!(cache.len() <= 2);
+}
+
+#[test]
+fn test_persistent_cache_survives_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("cache").join("completions.json");
+    let key = || PersistentKey::new("file:///a.rs", "let x = ", "", "session", 3, "gpt-test");
+
+    let cache = PersistentCompletionCache::new(path.clone());
+    cache.insert(key(), "ignored;".to_string());
+    assert!(!path.exists(), "disabled caches never write");
+    cache.configure(true);
+    cache.insert(key(), "1;".to_string());
+    drop(cache);
+
+    // A new server process reads what the last one wrote
+    let restarted = PersistentCompletionCache::new(path.clone());
+    assert!(restarted.get(&key()).is_none(), "disabled caches never read");
+    restarted.configure(true);
+    assert_eq!(restarted.get(&key()), Some("1;".to_string()));
+    assert!(!path.with_extension("json.tmp").exists());
+
+    let other_version = PersistentKey::new("file:///a.rs", "let x = ", "", "session", 4, "gpt-test");
+    assert!(restarted.get(&other_version).is_none());
+    let other_model = PersistentKey::new("file:///a.rs", "let x = ", "", "session", 3, "other");
+    assert!(restarted.get(&other_model).is_none());
+}
+
+#[test]
+fn test_persistent_cache_expires_and_is_bounded() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("completions.json");
+    let key = |i: usize| PersistentKey::new("file:///a.rs", &i.to_string(), "", "s", 1, "m");
+
+    let cache = PersistentCompletionCache::new(path.clone()).with_max_entries(2);
+    cache.configure(true);
+    for i in 0..5 {
+        cache.insert(key(i), i.to_string());
+    }
+    assert_eq!(cache.len(), 2);
+
+    let expired = PersistentCompletionCache::new(path).with_ttl(Duration::ZERO);
+    expired.configure(true);
+    assert!(expired.is_empty());
+}
+
+#[test]
+fn test_persistent_key_is_stable_across_builds() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("completions.json");
+    // Written by an earlier build; the key must not depend on the toolchain
+    let created_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let saved = serde_json::json!({
+        "e37d080f9913bf10": {
+            "session_id": "session",
+            "session_version": 3,
+            "model": "gpt-test",
+            "completion": "1;",
+            "created_at": created_at
+        }
+    });
+    std::fs::write(&path, saved.to_string()).unwrap();
+
+    let cache = PersistentCompletionCache::new(path);
+    cache.configure(true);
+    let key = PersistentKey::new("file:///a.rs", "let x = ", "", "session", 3, "gpt-test");
+    assert_eq!(cache.get(&key), Some("1;".to_string()));
+}
+
+#[tokio::test]
+async fn test_persistent_cache_batches_saves() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("completions.json");
+    let key = |i: usize| PersistentKey::new("file:///a.rs", &i.to_string(), "", "s", 1, "m");
+
+    let cache = PersistentCompletionCache::new(path.clone()).with_save_delay(Duration::from_millis(50));
+    cache.configure(true);
+    for i in 0..3 {
+        cache.insert(key(i), i.to_string());
+    }
+    assert!(!path.exists(), "inserts are written after the delay");
+
+    for _ in 0..50 {
+        if path.exists() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let restarted = PersistentCompletionCache::new(path);
+    restarted.configure(true);
+    assert_eq!(restarted.len(), 3);
+}
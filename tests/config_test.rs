@@ -0,0 +1,150 @@
+//! Integration tests for config module
+
+use snek::config::{
+    load_env_files, read_api_key_file, resolve_workspace_dirs, EnvConfig, Settings,
+    WorkspaceDirSource, DEFAULT_API_URL, DEFAULT_MODEL,
+};
+use std::path::PathBuf;
+
+#[test]
+fn test_settings_defaults() {
+    let settings: Settings = serde_json::from_value(serde_json::json!({})).unwrap();
+    assert_eq!(settings.min_prefix_chars, 1);
+}
+
+#[test]
+fn test_compiled_default_endpoint_matches_features() {
+    let expected = if cfg!(feature = "default-openai") {
+        ("https://api.openai.com/v1/chat/completions", "gpt-4o-mini")
+    } else if cfg!(feature = "default-ollama") {
+        ("http://localhost:11434/v1/chat/completions", "qwen2.5-coder:7b")
+    } else {
+        (
+            "https://api.cerebras.ai/v1/chat/completions",
+            "qwen-3-235b-a22b-instruct-2507",
+        )
+    };
+    assert_eq!((DEFAULT_API_URL, DEFAULT_MODEL), expected);
+}
+
+#[test]
+fn test_settings_from_client_section() {
+    let section = serde_json::json!({
+        "model": "ignored-here",
+        "minPrefixChars": 8
+    });
+    let settings: Settings = serde_json::from_value(section).unwrap();
+    assert_eq!(settings.min_prefix_chars, 8);
+}
+
+#[test]
+fn test_insecure_skip_tls_verify_is_opt_in() {
+    let settings: Settings = serde_json::from_value(serde_json::json!({})).unwrap();
+    assert!(!settings.http_options().insecure_skip_tls_verify);
+
+    let section = serde_json::json!({ "insecureSkipTlsVerify": true });
+    let settings: Settings = serde_json::from_value(section).unwrap();
+    assert!(settings.http_options().insecure_skip_tls_verify);
+}
+
+#[test]
+fn test_anthropic_provider_enables_cache_control() {
+    let settings: Settings = serde_json::from_value(serde_json::json!({})).unwrap();
+    assert!(!settings.prompt_options().cache_control);
+
+    let section = serde_json::json!({ "provider": "anthropic" });
+    let settings: Settings = serde_json::from_value(section).unwrap();
+    assert!(settings.prompt_options().cache_control);
+}
+
+#[test]
+fn test_workspace_dir_env_used_only_without_flag() {
+    let flagged = vec![PathBuf::from("/from/flag")];
+    assert_eq!(
+        resolve_workspace_dirs(flagged.clone(), Some("/from/env".to_string())),
+        (flagged, WorkspaceDirSource::Flag)
+    );
+    assert_eq!(
+        resolve_workspace_dirs(vec![], Some("/from/env".to_string())),
+        (vec![PathBuf::from("/from/env")], WorkspaceDirSource::Env)
+    );
+    assert_eq!(
+        resolve_workspace_dirs(vec![], Some(" ".to_string())),
+        (vec![], WorkspaceDirSource::CurrentDir)
+    );
+    assert_eq!(
+        resolve_workspace_dirs(vec![], None),
+        (vec![], WorkspaceDirSource::CurrentDir)
+    );
+}
+
+#[test]
+fn test_load_env_files_from_snek_dir() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let snek_root = temp_dir.path().join(".snek");
+    std::fs::create_dir_all(&snek_root).unwrap();
+
+    std::fs::write(snek_root.join(".env"), "SNEK_API_KEY=key-from-dotenv\n").unwrap();
+
+    let loaded = load_env_files(&snek_root);
+    assert_eq!(loaded, vec![snek_root.join(".env")]);
+    assert_eq!(EnvConfig::from_env().api_key.as_deref(), Some("key-from-dotenv"));
+}
+
+#[test]
+fn test_snek_env_wins_over_workspace_env() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let snek_root = temp_dir.path().join(".snek");
+    std::fs::create_dir_all(&snek_root).unwrap();
+
+    std::fs::write(snek_root.join(".env"), "SNEK_TEST_DOTENV_ORDER=snek\n").unwrap();
+    std::fs::write(temp_dir.path().join(".env"), "SNEK_TEST_DOTENV_ORDER=workspace\n").unwrap();
+
+    let loaded = load_env_files(&snek_root);
+    assert_eq!(loaded.len(), 2);
+    assert_eq!(std::env::var("SNEK_TEST_DOTENV_ORDER").unwrap(), "snek");
+}
+
+#[test]
+fn test_read_api_key_file_trims_newline() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let key_path = temp_dir.path().join("api-key");
+    std::fs::write(&key_path, "sk-from-file\n").unwrap();
+
+    assert_eq!(read_api_key_file(&key_path).unwrap(), "sk-from-file");
+}
+
+#[test]
+fn test_read_api_key_file_errors() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+
+    let missing = temp_dir.path().join("missing");
+    let err = read_api_key_file(&missing).unwrap_err();
+    assert!(err.to_string().contains("not accessible"));
+
+    let empty = temp_dir.path().join("empty");
+    std::fs::write(&empty, "\n").unwrap();
+    let err = read_api_key_file(&empty).unwrap_err();
+    assert!(err.to_string().contains("is empty"));
+}
+
+#[test]
+fn test_env_key_file_takes_precedence() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let key_path = temp_dir.path().join("api-key");
+    std::fs::write(&key_path, "sk-from-file\r\n").unwrap();
+
+    let env_config = EnvConfig {
+        api_key: Some("sk-inline".to_string()),
+        api_key_file: Some(key_path.to_string_lossy().to_string()),
+        ..Default::default()
+    };
+    assert_eq!(env_config.resolve_api_key().unwrap().as_deref(), Some("sk-from-file"));
+}
+
+#[test]
+fn test_settings_api_key_file() {
+    let settings: Settings =
+        serde_json::from_value(serde_json::json!({ "apiKeyFile": "/run/secrets/snek" })).unwrap();
+    assert_eq!(settings.api_key_file.as_deref(), Some("/run/secrets/snek"));
+}
@@ -0,0 +1,118 @@
+//! Integration tests for document_cache module
+
+use snek::document_cache::DocumentCache;
+use snek::document_store::DocumentStore;
+use tempfile::TempDir;
+
+fn file_uri(path: &std::path::Path) -> String {
+    url::Url::from_file_path(path).unwrap().to_string()
+}
+
+fn enabled_cache(workspace: &TempDir) -> DocumentCache {
+    let cache = DocumentCache::new(
+        workspace.path().join(".snek/cache/documents"),
+        Some(workspace.path().to_path_buf()),
+    );
+    cache.configure(true, true);
+    cache
+}
+
+#[test]
+fn test_reconnect_with_warm_cache() {
+    let workspace = TempDir::new().unwrap();
+    let uri = file_uri(&workspace.path().join("main.rs"));
+
+    let before = DocumentStore::with_cache(enabled_cache(&workspace));
+    before.did_open(uri.clone(), "rust".to_string(), "fn main() {\n".to_string());
+    before.did_change(&uri, "fn main() {\n    run();\n".to_string());
+    drop(before);
+
+    // A fresh store after reconnect serves the document before did_open
+    let after = DocumentStore::with_cache(enabled_cache(&workspace));
+    let (prefix, _, language) = after.get_context(&uri, 1, 10).unwrap();
+    assert_eq!(prefix, "fn main() {\n    run();");
+    assert_eq!(language, "rust");
+
+    // The next did_open re-syncs with the client's view
+    after.did_open(uri.clone(), "rust".to_string(), "fn other() {}".to_string());
+    let (prefix, _, _) = after.get_context(&uri, 0, 8).unwrap();
+    assert_eq!(prefix, "fn other");
+}
+
+#[test]
+fn test_cache_disabled_by_default() {
+    let workspace = TempDir::new().unwrap();
+    let uri = file_uri(&workspace.path().join("main.rs"));
+    let cache_dir = workspace.path().join(".snek/cache/documents");
+
+    let store = DocumentStore::with_cache(DocumentCache::new(
+        cache_dir.clone(),
+        Some(workspace.path().to_path_buf()),
+    ));
+    store.did_open(uri, "rust".to_string(), "fn main() {}".to_string());
+    assert!(!cache_dir.exists());
+}
+
+#[test]
+fn test_restrict_to_workspace() {
+    let workspace = TempDir::new().unwrap();
+    let elsewhere = TempDir::new().unwrap();
+    let outside_uri = file_uri(&elsewhere.path().join("secret.rs"));
+
+    let cache = enabled_cache(&workspace);
+    cache.store(&outside_uri, "rust", "let token = 1;");
+    assert!(cache.load(&outside_uri).is_none());
+
+    cache.configure(true, false);
+    cache.store(&outside_uri, "rust", "let token = 1;");
+    assert_eq!(
+        cache.load(&outside_uri),
+        Some(("rust".to_string(), "let token = 1;".to_string()))
+    );
+}
+
+#[test]
+fn test_cache_is_bounded() {
+    let workspace = TempDir::new().unwrap();
+    let cache = enabled_cache(&workspace).with_max_entries(2);
+
+    for name in ["a.rs", "b.rs", "c.rs"] {
+        let uri = file_uri(&workspace.path().join(name));
+        cache.store(&uri, "rust", name);
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+
+    let entries = std::fs::read_dir(workspace.path().join(".snek/cache/documents"))
+        .unwrap()
+        .count();
+    assert_eq!(entries, 2);
+    assert!(cache.load(&file_uri(&workspace.path().join("a.rs"))).is_none());
+    assert!(cache.load(&file_uri(&workspace.path().join("c.rs"))).is_some());
+}
+
+#[test]
+fn test_did_close_removes_cached_copy() {
+    let workspace = TempDir::new().unwrap();
+    let uri = file_uri(&workspace.path().join("main.rs"));
+
+    let store = DocumentStore::with_cache(enabled_cache(&workspace));
+    store.did_open(uri.clone(), "rust".to_string(), "fn main() {}".to_string());
+    store.did_close(&uri);
+
+    let after = DocumentStore::with_cache(enabled_cache(&workspace));
+    assert!(after.get_context(&uri, 0, 0).is_none());
+}
+
+#[test]
+fn test_clear_removes_cached_copy() {
+    let workspace = TempDir::new().unwrap();
+    let uri = file_uri(&workspace.path().join("main.rs"));
+
+    let store = DocumentStore::with_cache(enabled_cache(&workspace));
+    store.did_open(uri.clone(), "rust".to_string(), "fn main() {}".to_string());
+    store.clear();
+
+    // Not restored from disk either
+    assert!(store.get_context(&uri, 0, 0).is_none());
+    assert!(store.list_open_uris().is_empty());
+}
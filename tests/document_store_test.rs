@@ -1,6 +1,9 @@
 //! Integration tests for document_store module
 
-use snek::document_store::DocumentStore;
+use snek::document_store::{
+    collapse_blank_lines, split_at_position, window_at_offset, window_context, word_before_cursor,
+    DocumentStore, OVERSIZED_WINDOW_BYTES,
+};
 
 #[test]
 fn test_did_open_and_get_context() {
@@ -21,6 +24,19 @@ fn test_did_open_and_get_context() {
     assert!(suffix.contains("println!"));
 }
 
+#[test]
+fn test_empty_language_id_is_inferred_from_extension() {
+    let store = DocumentStore::new();
+
+    store.did_open("file:///test/script.py".to_string(), String::new(), "x = 1".to_string());
+    let (_, _, language) = store.get_context("file:///test/script.py", 0, 5).unwrap();
+    assert_eq!(language, "python");
+
+    store.did_open("file:///test/notes.unknown".to_string(), " ".to_string(), "x".to_string());
+    let (_, _, language) = store.get_context("file:///test/notes.unknown", 0, 1).unwrap();
+    assert_eq!(language, "");
+}
+
 #[test]
 fn test_did_change() {
     let store = DocumentStore::new();
@@ -90,3 +106,162 @@ fn test_prefix_suffix_split() {
     assert_eq!(prefix, "abc\n");
     assert_eq!(suffix, "def\nghi");
 }
+
+#[test]
+fn test_idle_for_tracks_last_change() {
+    let store = DocumentStore::new();
+    let uri = "file:///test/file.rs".to_string();
+
+    store.did_open(uri.clone(), "rust".to_string(), "a".to_string());
+    assert!(store.idle_for(&uri).is_none());
+
+    store.did_change(&uri, "ab".to_string());
+    assert!(store.idle_for(&uri).unwrap() < std::time::Duration::from_secs(60));
+
+    store.did_close(&uri);
+    assert!(store.idle_for(&uri).is_none());
+}
+
+#[test]
+fn test_split_at_position_clamps_past_line_end() {
+    let (prefix, suffix) = split_at_position("ab\ncd\n", 0, 10);
+    assert_eq!(prefix, "ab");
+    assert_eq!(suffix, "\ncd\n");
+
+    let (prefix, suffix) = split_at_position("ab\ncd", 5, 0);
+    assert_eq!(prefix, "ab\ncd");
+    assert_eq!(suffix, "");
+}
+
+#[test]
+fn test_split_at_position_edge_cases() {
+    // Empty document
+    assert_eq!(split_at_position("", 0, 0), (String::new(), String::new()));
+    assert_eq!(split_at_position("", 3, 7), (String::new(), String::new()));
+
+    // The empty line after a trailing newline
+    let (prefix, suffix) = split_at_position("ab\n", 1, 0);
+    assert_eq!(prefix, "ab\n");
+    assert_eq!(suffix, "");
+
+    // CRLF line endings: the cursor never lands between `\r` and `\n`
+    let (prefix, suffix) = split_at_position("ab\r\ncd\r\n", 0, 9);
+    assert_eq!(prefix, "ab");
+    assert_eq!(suffix, "\r\ncd\r\n");
+    let (prefix, suffix) = split_at_position("ab\r\ncd\r\n", 1, 1);
+    assert_eq!(prefix, "ab\r\nc");
+    assert_eq!(suffix, "d\r\n");
+
+    // Columns count UTF-16 code units, never splitting a character
+    let (prefix, suffix) = split_at_position("let größe = 1;", 0, 8);
+    assert_eq!(prefix, "let größ");
+    assert_eq!(suffix, "e = 1;");
+    let (prefix, suffix) = split_at_position("s = \"🐍x\"", 0, 7);
+    assert_eq!(prefix, "s = \"🐍");
+    assert_eq!(suffix, "x\"");
+    let (prefix, _) = split_at_position("🐍x", 0, 1);
+    assert_eq!(prefix, "");
+}
+
+#[test]
+fn test_word_before_cursor() {
+    assert_eq!(word_before_cursor("let n = fib"), "fib");
+    assert_eq!(word_before_cursor("self.max_len"), "max_len");
+    assert_eq!(word_before_cursor("call("), "");
+    assert_eq!(word_before_cursor("x = größe"), "größe");
+    assert_eq!(word_before_cursor(""), "");
+}
+
+#[test]
+fn test_window_context_keeps_small_documents_whole() {
+    let (prefix, suffix) = window_context("a\nb\nc", "d\ne\n", 10);
+    assert_eq!(prefix, "a\nb\nc");
+    assert_eq!(suffix, "d\ne\n");
+}
+
+#[test]
+fn test_window_context_narrows_large_documents_around_cursor() {
+    let lines: Vec<String> = (0..100).map(|i| format!("line {}", i)).collect();
+    let text = lines.join("\n");
+    let cursor = text.find("line 50").unwrap() + "line".len();
+    let (prefix, suffix) = window_context(&text[..cursor], &text[cursor..], 8);
+
+    // Six lines up to and including the cursor's, two from it onwards
+    assert_eq!(prefix, "line 45\nline 46\nline 47\nline 48\nline 49\nline");
+    assert_eq!(suffix, " 50\nline 51\n");
+}
+
+#[test]
+fn test_collapse_blank_lines_keeps_cursor_lines() {
+    let (prefix, suffix) = collapse_blank_lines("fn a() {}\n\n\n\n\n\nfn b", "() {}\n\n  \n\n\n}\n");
+    assert_eq!(prefix, "fn a() {}\n\n\nfn b");
+    assert_eq!(suffix, "() {}\n\n  \n}\n");
+
+    // Blank lines the cursor sits between are left alone
+    let (prefix, suffix) = collapse_blank_lines("x\n\n\n\n   ", "   \n\n\n\ny");
+    assert_eq!(prefix, "x\n\n\n   ");
+    assert_eq!(suffix, "   \n\n\ny");
+}
+
+#[test]
+fn test_list_open_uris_and_clear() {
+    let store = DocumentStore::new();
+    assert!(store.list_open_uris().is_empty());
+
+    let uri = "file:///test/file.rs".to_string();
+    store.did_open(uri.clone(), "rust".to_string(), "fn main() {}".to_string());
+    assert_eq!(store.list_open_uris(), vec![uri.clone()]);
+
+    store.clear();
+    assert!(store.list_open_uris().is_empty());
+    assert!(store.get_context(&uri, 0, 0).is_none());
+}
+
+#[test]
+fn test_oversized_document_is_read_around_the_cursor() {
+    let store = DocumentStore::new();
+    store.configure_max_document_bytes(Some(1024 * 1024));
+
+    let uri = "file:///test/generated.rs";
+    let line = "let value = 42; // padding to make the line longer\n";
+    let lines = 200_000;
+    let text = line.repeat(lines);
+    assert!(text.len() > 8 * 1024 * 1024);
+    store.did_open(uri.to_string(), "rust".to_string(), text);
+    assert!(store.is_oversized(uri));
+
+    let cursor_line = 150_000;
+    let (prefix, suffix, language) = store.get_context(uri, cursor_line, 4).unwrap();
+    assert_eq!(language, "rust");
+    assert!(prefix.len() + suffix.len() <= OVERSIZED_WINDOW_BYTES);
+    assert!(prefix.len() > OVERSIZED_WINDOW_BYTES / 2);
+    assert!(prefix.starts_with("let value"));
+    assert!(prefix.ends_with("\nlet "));
+    assert!(suffix.starts_with("value = 42;"));
+    assert!(suffix.ends_with('\n'));
+
+    // Lifting the limit hands out the whole document again
+    store.configure_max_document_bytes(None);
+    assert!(!store.is_oversized(uri));
+    let (prefix, suffix, _) = store.get_context(uri, cursor_line, 4).unwrap();
+    assert_eq!(prefix.len(), cursor_line as usize * line.len() + 4);
+    assert_eq!(prefix.len() + suffix.len(), lines * line.len());
+
+    // Shrinking it below the document's size flags it on the next edit too
+    store.configure_max_document_bytes(Some(16));
+    store.did_change(uri, "short".to_string());
+    assert!(!store.is_oversized(uri));
+    store.did_change(uri, "x".repeat(17));
+    assert!(store.is_oversized(uri));
+}
+
+#[test]
+fn test_window_at_offset_never_splits_a_character() {
+    let text = "ééééé";
+    let (before, after) = window_at_offset(text, 4, 4);
+    assert_eq!(before, "é");
+    assert_eq!(after, "");
+
+    let (before, after) = window_at_offset("ab\ncd\nef", 4, 100);
+    assert_eq!((before, after), ("ab\nc", "d\nef"));
+}
@@ -0,0 +1,43 @@
+//! Integration tests for git_status module
+
+use std::path::{Path, PathBuf};
+
+use snek::git_status::{changed_files, parse_porcelain, prioritize};
+use tempfile::TempDir;
+
+#[test]
+fn test_parse_porcelain() {
+    let root = Path::new("/repo");
+    let status = " M src/lib.rs\0?? notes.md\0R  src/new.rs\0src/old.rs\0A  docs/a b.md\0";
+    assert_eq!(
+        parse_porcelain(status, root),
+        vec![
+            PathBuf::from("/repo/src/lib.rs"),
+            PathBuf::from("/repo/notes.md"),
+            PathBuf::from("/repo/src/new.rs"),
+            PathBuf::from("/repo/docs/a b.md"),
+        ]
+    );
+    assert!(parse_porcelain("", root).is_empty());
+}
+
+#[test]
+fn test_prioritize_puts_changed_files_first() {
+    let root = Path::new("/repo");
+    let changed = parse_porcelain(" M c.rs\0 M a.rs\0", root);
+
+    let mut files = vec!["a.rs", "b.rs", "c.rs", "d.rs"];
+    prioritize(&mut files, &changed, |name| Some(root.join(name)));
+    assert_eq!(files, vec!["c.rs", "a.rs", "b.rs", "d.rs"]);
+
+    let mut untouched = vec!["b.rs", "a.rs"];
+    prioritize(&mut untouched, &[], |name| Some(root.join(name)));
+    assert_eq!(untouched, vec!["b.rs", "a.rs"]);
+}
+
+#[test]
+fn test_changed_files_outside_git_is_empty() {
+    let temp_dir = TempDir::new().unwrap();
+    std::fs::write(temp_dir.path().join("a.rs"), "fn a() {}").unwrap();
+    assert!(changed_files(temp_dir.path()).is_empty());
+}
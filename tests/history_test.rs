@@ -0,0 +1,51 @@
+//! Integration tests for history module
+
+use snek::history::{CompletionHistory, PrefixMark};
+
+#[test]
+fn test_record_and_last() {
+    let history = CompletionHistory::new();
+
+    history.record("file:///a.rs", "first".to_string());
+    history.record("file:///a.rs", "second".to_string());
+
+    assert_eq!(history.last("file:///a.rs"), Some("second".to_string()));
+    assert_eq!(history.last("file:///b.rs"), None);
+    assert_eq!(history.len(), 1);
+}
+
+#[test]
+fn test_evicts_oldest_uri() {
+    let history = CompletionHistory::with_capacity(2);
+
+    history.record("file:///a.rs", "a".to_string());
+    history.record("file:///b.rs", "b".to_string());
+    // Touch a.rs again so b.rs becomes the oldest entry
+    history.record("file:///a.rs", "a2".to_string());
+    history.record("file:///c.rs", "c".to_string());
+
+    assert_eq!(history.len(), 2);
+    assert_eq!(history.last("file:///b.rs"), None);
+    assert_eq!(history.last("file:///a.rs"), Some("a2".to_string()));
+    assert_eq!(history.last("file:///c.rs"), Some("c".to_string()));
+}
+
+#[test]
+fn test_continuation_requires_unchanged_prefix() {
+    let history = CompletionHistory::new();
+    let uri = "file:///a.rs";
+    history.record_at(uri, "foo(bar);".to_string(), PrefixMark::new("let x = "));
+
+    assert_eq!(
+        history.continuation(uri, "let x = foo(", 3),
+        Some(("foo(bar);".to_string(), "foo(".to_string()))
+    );
+    // Too little accepted, text that isn't the suggestion, or an edit elsewhere
+    assert_eq!(history.continuation(uri, "let x = f", 3), None);
+    assert_eq!(history.continuation(uri, "let x = bar(", 3), None);
+    assert_eq!(history.continuation(uri, "let y = foo(", 3), None);
+
+    // Recorded without a prefix, nothing to compare against
+    history.record(uri, "foo(bar);".to_string());
+    assert_eq!(history.continuation(uri, "let x = foo(", 3), None);
+}
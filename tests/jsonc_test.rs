@@ -0,0 +1,32 @@
+//! Integration tests for jsonc module
+
+use snek::jsonc::strip;
+
+#[test]
+fn test_strip_comments() {
+    let input = "{\n  // the session\n  \"id\": \"a\", /* inline */ \"version\": 1\n}";
+    let value: serde_json::Value = serde_json::from_str(&strip(input)).unwrap();
+    assert_eq!(value["id"], "a");
+    assert_eq!(value["version"], 1);
+}
+
+#[test]
+fn test_strip_trailing_commas() {
+    let input = "{\"a\": [1, 2, ],\n \"b\": {\"c\": true,},\n}";
+    let value: serde_json::Value = serde_json::from_str(&strip(input)).unwrap();
+    assert_eq!(value["a"], serde_json::json!([1, 2]));
+    assert_eq!(value["b"]["c"], true);
+}
+
+#[test]
+fn test_strip_keeps_string_contents() {
+    let input = r#"{"url": "https://example.com/*x*/", "s": "a \" // b,}"}"#;
+    assert_eq!(strip(input), input);
+}
+
+#[test]
+fn test_strip_keeps_line_numbers() {
+    let input = "{\n/* one\ntwo */\n\"a\": oops\n}";
+    let err = serde_json::from_str::<serde_json::Value>(&strip(input)).unwrap_err();
+    assert_eq!(err.line(), 4);
+}
@@ -0,0 +1,160 @@
+//! Integration tests for languages module
+
+use snek::languages::{
+    default_triggers, enclosing_function, enclosing_signature, import_lines, is_compatible,
+    is_in_comment, is_in_string, language_for_path, profile,
+};
+
+#[test]
+fn test_language_for_path() {
+    assert_eq!(language_for_path("file:///src/main.rs"), Some("rust"));
+    assert_eq!(language_for_path("file:///app/View.TSX"), Some("typescriptreact"));
+    assert_eq!(language_for_path("C:\\repo\\setup.py"), Some("python"));
+    assert_eq!(language_for_path("file:///repo/Dockerfile"), Some("dockerfile"));
+    assert_eq!(language_for_path("file:///repo/.gitignore"), None);
+    assert_eq!(language_for_path("file:///repo/LICENSE"), None);
+}
+
+#[test]
+fn test_default_triggers_follow_the_language() {
+    assert_eq!(default_triggers("rust"), [".", "::", "("]);
+    assert!(default_triggers("c").contains(&"->"));
+    assert!(default_triggers("cpp").contains(&"::"));
+    assert_eq!(default_triggers("php"), ["->", "::", "("]);
+    assert_eq!(default_triggers("python"), [".", "("]);
+    assert_eq!(default_triggers("unknown-language"), [".", "("]);
+}
+
+#[test]
+fn test_rust_comments() {
+    assert!(is_in_comment("fn main() {\n    // compute the ", "rust"));
+    assert!(is_in_comment("/* multi\n line ", "rust"));
+    assert!(!is_in_comment("/* done */ let x = ", "rust"));
+    assert!(!is_in_comment("// old comment\nlet x = ", "rust"));
+    assert!(!is_in_comment("let url = \"http://", "rust"));
+}
+
+#[test]
+fn test_rust_strings() {
+    assert!(is_in_string("let s = \"hello ", "rust"));
+    assert!(is_in_string("let s = \"say \\\"hi", "rust"));
+    assert!(!is_in_string("let s = \"hi\"; ", "rust"));
+    // Lifetimes must not be mistaken for char literals
+    assert!(!is_in_string("fn f<'a>(x: &'a str) -> ", "rust"));
+}
+
+#[test]
+fn test_python_comments_and_strings() {
+    assert!(is_in_comment("def f():\n    # TODO ", "python"));
+    assert!(!is_in_comment("x = '#not a comment' ", "python"));
+    assert!(is_in_string("def f():\n    \"\"\"Docstring\n    spanning lines ", "python"));
+    assert!(!is_in_string("s = \"\"\"done\"\"\"\n", "python"));
+}
+
+#[test]
+fn test_unknown_language_uses_default_profile() {
+    assert_eq!(profile("some-new-lang"), profile(""));
+    assert!(is_in_comment("value = 1 # note ", "some-new-lang"));
+    assert!(is_in_comment("value = 1 // note ", "some-new-lang"));
+    assert!(!is_in_comment("value = 1 ", "some-new-lang"));
+}
+
+#[test]
+fn test_is_compatible() {
+    assert!(is_compatible("rust", "rust"));
+    assert!(is_compatible("typescript", "javascriptreact"));
+    assert!(is_compatible("c", "cpp"));
+    assert!(!is_compatible("python", "rust"));
+    assert!(!is_compatible("java", "kotlin"));
+}
+
+#[test]
+fn test_enclosing_function_rust() {
+    let prefix = "struct Parser;\n\nimpl Parser {\n    pub fn parse_expr(&mut self, input: &str) -> Expr {\n        if input.is_empty() {\n            return Expr::Empty;\n        }\n        let tokens = input.split(' ').map(|t| {\n            t.trim()\n        });\n        let ";
+    assert_eq!(enclosing_function(prefix, "rust").as_deref(), Some("parse_expr"));
+
+    let after = "fn done() {\n    1\n}\n\n";
+    assert_eq!(enclosing_function(after, "rust"), None);
+}
+
+#[test]
+fn test_enclosing_function_multiline_signature() {
+    let prefix = "fn build(\n    name: &str,\n    size: usize,\n) -> Widget {\n    Widget::new(";
+    assert_eq!(enclosing_function(prefix, "rust").as_deref(), Some("build"));
+}
+
+#[test]
+fn test_enclosing_function_c_like() {
+    let prefix = "#include <stdio.h>\n\nint main(int argc, char **argv) {\n    if (argc > 1) {\n        printf(";
+    assert_eq!(enclosing_function(prefix, "c").as_deref(), Some("main"));
+
+    let go = "func (s *Server) Handle(w http.ResponseWriter) {\n\tfor {\n\t\t";
+    assert_eq!(enclosing_function(go, "go").as_deref(), Some("Handle"));
+}
+
+#[test]
+fn test_enclosing_function_python() {
+    let prefix = "class Cart:\n    def total(self):\n        result = 0\n        for item in self.items:\n            result += ";
+    assert_eq!(enclosing_function(prefix, "python").as_deref(), Some("total"));
+
+    let new_line = "async def fetch(url):\n";
+    assert_eq!(enclosing_function(new_line, "python").as_deref(), Some("fetch"));
+
+    let top_level = "def helper():\n    pass\n\nx = ";
+    assert_eq!(enclosing_function(top_level, "python"), None);
+}
+
+#[test]
+fn test_enclosing_signature() {
+    let rust = "impl Parser {\n    pub fn parse(\n        &mut self,\n        input: &str,\n    ) -> Result<Ast> {\n        let ";
+    assert_eq!(
+        enclosing_signature(rust, "rust").as_deref(),
+        Some("pub fn parse( &mut self, input: &str, ) -> Result<Ast>")
+    );
+
+    // Between methods, the type is the nearest scope
+    let between = "impl<T> Stack<T> {\n    fn push(&mut self) {}\n\n    ";
+    assert_eq!(enclosing_signature(between, "rust").as_deref(), Some("impl<T> Stack<T>"));
+
+    let java = "public class Cart {\n    public int total(List<Item> items) {\n        return items.";
+    assert_eq!(
+        enclosing_signature(java, "java").as_deref(),
+        Some("public int total(List<Item> items)")
+    );
+
+    assert_eq!(enclosing_signature("let x = ", "rust"), None);
+}
+
+#[test]
+fn test_enclosing_signature_python() {
+    let prefix = "class Cart(Base):\n    def total(self,\n              tax: float) -> float:\n        with open(path) as f:\n            ";
+    assert_eq!(
+        enclosing_signature(prefix, "python").as_deref(),
+        Some("def total(self, tax: float) -> float")
+    );
+    assert_eq!(enclosing_function(prefix, "python").as_deref(), Some("total"));
+
+    let in_class = "class Cart(Base):\n    items = []\n    ";
+    assert_eq!(enclosing_signature(in_class, "python").as_deref(), Some("class Cart(Base)"));
+}
+
+#[test]
+fn test_import_lines() {
+    let rust = "//! Docs\nuse std::io;\npub use crate::a::{\n    B,\n    C,\n};\n\nfn main() {\n    use_it();\n}\n";
+    assert_eq!(
+        import_lines(rust, "rust"),
+        vec!["use std::io;", "pub use crate::a::{\n    B,\n    C,\n};"]
+    );
+
+    let python = "import os\nfrom typing import (\n    List,\n)\n\ndef imported():\n    return os\n";
+    assert_eq!(
+        import_lines(python, "python"),
+        vec!["import os", "from typing import (\n    List,\n)"]
+    );
+
+    let go = "package main\n\nimport (\n\t\"fmt\"\n)\n\nfunc main() {}\n";
+    assert_eq!(import_lines(go, "go"), vec!["import (\n\t\"fmt\"\n)"]);
+
+    assert_eq!(import_lines("#include <stdio.h>\nint x;", "c"), vec!["#include <stdio.h>"]);
+    assert!(import_lines("use x;", "plaintext").is_empty());
+}
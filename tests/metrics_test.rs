@@ -0,0 +1,93 @@
+//! Integration tests for the metrics endpoint
+
+mod common;
+
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use common::{completion_body, MockResponse, MockServer};
+use snek::document_store::DocumentStore;
+use snek::lsp::backend::{Backend, InlineCompletionParams};
+use snek::metrics;
+use snek::model::ModelClient;
+use snek::snapshot::ContextSnapshot;
+use tokio::sync::RwLock;
+use tower_lsp::lsp_types::{Position, TextDocumentIdentifier, Url};
+use tower_lsp::LspService;
+
+fn inline_params(uri: &str) -> InlineCompletionParams {
+    InlineCompletionParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::parse(uri).unwrap(),
+        },
+        position: Position {
+            line: 0,
+            character: 20,
+        },
+        selection: None,
+        extra_context: None,
+        instruction: None,
+        continuation: false,
+        language: None,
+        context: None,
+        invoked: false,
+        temperature: None,
+        text: None,
+        context_filter: None,
+    }
+}
+
+#[tokio::test]
+async fn test_metrics_endpoint_reports_completion_series() {
+    let server = MockServer::start(vec![
+        MockResponse::new(200, completion_body("first()")),
+        MockResponse::new(500, r#"{"error":"down"}"#),
+    ])
+    .await;
+
+    let model = Arc::new(ModelClient::new(
+        server.url("/v1/chat/completions"),
+        "test-model".to_string(),
+    ));
+    let (service, _socket) = LspService::new(|client| {
+        Backend::new(
+            client,
+            Arc::new(ArcSwap::from_pointee(ContextSnapshot::default())),
+            Arc::new(DocumentStore::new()),
+            model.clone(),
+            Arc::new(RwLock::new("key".to_string())),
+        )
+    });
+    let backend = service.inner();
+
+    let uri = "file:///test/metrics.rs";
+    backend
+        .documents
+        .did_open(uri.to_string(), "rust".to_string(), "let x = ".to_string());
+
+    // Model call, then a cache hit for the same context
+    backend.handle_inline_completion(inline_params(uri)).await.unwrap();
+    backend.handle_inline_completion(inline_params(uri)).await.unwrap();
+
+    // An edit invalidates the cache; the model now fails with a 500
+    backend.documents.did_change(uri, "let y = ".to_string());
+    assert!(backend.handle_inline_completion(inline_params(uri)).await.is_err());
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(metrics::serve(listener, model.metrics().clone()));
+
+    let response = reqwest::get(format!("http://{}/metrics", addr)).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body = response.text().await.unwrap();
+
+    assert!(body.contains("snek_inline_requests_total 3\n"));
+    assert!(body.contains("snek_completion_cache_hits_total 1\n"));
+    assert!(body.contains("snek_model_requests_total 2\n"));
+    assert!(body.contains("snek_model_failures_total{reason=\"500\"} 1\n"));
+    assert!(body.contains("snek_model_request_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+    assert!(body.contains("snek_model_request_duration_seconds_count 2\n"));
+
+    let missing = reqwest::get(format!("http://{}/other", addr)).await.unwrap();
+    assert_eq!(missing.status(), 404);
+}
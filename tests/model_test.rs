@@ -0,0 +1,1329 @@
+//! Integration tests for model module
+
+mod common;
+
+use common::{completion_body, MockResponse, MockServer};
+use snek::error::SnekError;
+use snek::model::{
+    build_http_client, cursor_marker, extract_code_from_response, extract_line_range, FallbackModel, HttpOptions, ModelClient,
+    PromptOptions, RetryOptions,
+};
+use snek::snapshot::{ChatMessage, CodeContext, ContextSnapshot};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+#[test]
+fn test_build_http_client_accepts_socks_proxy() {
+    let options = HttpOptions {
+        proxy: Some("socks5://127.0.0.1:1080".to_string()),
+        no_proxy: Some("localhost,internal.example.com".to_string()),
+        ..Default::default()
+    };
+    assert!(build_http_client(&options).is_ok());
+}
+
+#[test]
+fn test_build_http_client_rejects_invalid_proxy() {
+    let options = HttpOptions {
+        proxy: Some("not a url".to_string()),
+        ..Default::default()
+    };
+    assert!(matches!(
+        build_http_client(&options),
+        Err(SnekError::InvalidProxy(_))
+    ));
+}
+
+fn tls_fixture(name: &str) -> Option<String> {
+    Some(format!("{}/tests/fixtures/tls/{}", env!("CARGO_MANIFEST_DIR"), name))
+}
+
+#[test]
+fn test_build_http_client_with_client_certificate() {
+    let options = HttpOptions {
+        client_cert: tls_fixture("client.pem"),
+        client_key: tls_fixture("client.key"),
+        ca_bundle: tls_fixture("ca.pem"),
+        ..Default::default()
+    };
+    assert!(build_http_client(&options).is_ok());
+}
+
+#[test]
+fn test_build_http_client_rejects_bad_tls_files() {
+    let missing = HttpOptions {
+        client_cert: tls_fixture("missing.pem"),
+        client_key: tls_fixture("client.key"),
+        ..Default::default()
+    };
+    assert!(matches!(
+        build_http_client(&missing),
+        Err(SnekError::WorkspaceIo { .. })
+    ));
+
+    // A certificate where the key should be
+    let invalid = HttpOptions {
+        client_cert: tls_fixture("client.pem"),
+        client_key: tls_fixture("ca.pem"),
+        ..Default::default()
+    };
+    assert!(matches!(build_http_client(&invalid), Err(SnekError::Config(_))));
+
+    let without_key = HttpOptions {
+        client_cert: tls_fixture("client.pem"),
+        ..Default::default()
+    };
+    assert!(matches!(build_http_client(&without_key), Err(SnekError::Config(_))));
+
+    let not_a_bundle = HttpOptions {
+        ca_bundle: tls_fixture("client.key"),
+        ..Default::default()
+    };
+    assert!(matches!(build_http_client(&not_a_bundle), Err(SnekError::Config(_))));
+}
+
+/// Serves a single HTTPS response with the self-signed test certificate
+async fn start_self_signed_server() -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_native_tls::native_tls;
+
+    let cert = std::fs::read(tls_fixture("client.pem").unwrap()).unwrap();
+    let key = std::fs::read(tls_fixture("client.key").unwrap()).unwrap();
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key).unwrap();
+    let acceptor =
+        tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                // Fails the handshake for clients that verify the certificate
+                let Ok(mut stream) = acceptor.accept(stream).await else {
+                    return;
+                };
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok")
+                    .await;
+            });
+        }
+    });
+    format!("https://{}/", addr)
+}
+
+#[tokio::test]
+async fn test_insecure_skip_tls_verify_accepts_self_signed_certificate() {
+    let url = start_self_signed_server().await;
+
+    let strict = HttpOptions::default();
+    assert!(strict.warning().is_none());
+    let client = build_http_client(&strict).unwrap();
+    assert!(client.get(&url).send().await.is_err());
+
+    let insecure = HttpOptions {
+        insecure_skip_tls_verify: true,
+        ..Default::default()
+    };
+    assert!(insecure.warning().unwrap().contains("DISABLED"));
+    let client = build_http_client(&insecure).unwrap();
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.text().await.unwrap(), "ok");
+}
+
+#[tokio::test]
+async fn test_requests_are_routed_through_proxy() {
+    let proxy = MockServer::start(vec![MockResponse::new(200, completion_body("x + 1"))]).await;
+
+    let client = ModelClient::new(
+        "http://model.invalid/v1/chat/completions".to_string(),
+        "test-model".to_string(),
+    );
+    client
+        .set_http_options(&HttpOptions {
+            proxy: Some(proxy.url("")),
+            no_proxy: None,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+    let completion = client
+        .complete(
+            &ContextSnapshot::default(),
+            "let y = ",
+            "",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(completion, "x + 1");
+    let requests = proxy.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(
+        requests[0].request_line,
+        "POST http://model.invalid/v1/chat/completions HTTP/1.1"
+    );
+}
+
+#[tokio::test]
+async fn test_https_proxy_leaves_http_endpoints_alone() {
+    let proxy = MockServer::start(vec![]).await;
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("direct"))]).await;
+
+    let client = ModelClient::new(server.url("/v1/chat/completions"), "test-model".to_string());
+    client
+        .set_http_options(&HttpOptions {
+            https_proxy: Some(proxy.url("")),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+    let completion = client
+        .complete(
+            &ContextSnapshot::default(),
+            "let y = ",
+            "",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(completion, "direct");
+    assert!(proxy.requests().is_empty());
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn test_fallback_model_serves_when_primary_fails() {
+    let primary = MockServer::start(vec![MockResponse::new(500, r#"{"error":"down"}"#)]).await;
+    let fallback = MockServer::start(vec![MockResponse::new(200, completion_body("fallback()"))]).await;
+
+    let client = ModelClient::new(primary.url("/v1/chat/completions"), "primary-model".to_string());
+    client
+        .set_fallback(Some(FallbackModel {
+            model_name: "fallback-model".to_string(),
+            api_url: Some(fallback.url("/v1/chat/completions")),
+            api_key: None,
+        }))
+        .await;
+
+    let completion = client
+        .complete(
+            &ContextSnapshot::default(),
+            "call",
+            "",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(completion, "fallback()");
+    assert_eq!(primary.requests().len(), 1);
+    let fallback_requests = fallback.requests();
+    assert_eq!(fallback_requests.len(), 1);
+    assert_eq!(fallback_requests[0].json()["model"], "fallback-model");
+    assert_eq!(fallback_requests[0].header("authorization"), Some("Bearer key"));
+}
+
+#[tokio::test]
+async fn test_fallback_not_used_for_client_errors() {
+    let server = MockServer::start(vec![
+        MockResponse::new(401, r#"{"error":"bad key"}"#),
+        MockResponse::new(200, completion_body("unreachable")),
+    ])
+    .await;
+
+    let client = ModelClient::new(server.url("/v1/chat/completions"), "primary-model".to_string());
+    client
+        .set_fallback(Some(FallbackModel {
+            model_name: "fallback-model".to_string(),
+            ..Default::default()
+        }))
+        .await;
+
+    let result = client
+        .complete(
+            &ContextSnapshot::default(),
+            "call",
+            "",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &CancellationToken::new(),
+        )
+        .await;
+
+    assert!(matches!(result, Err(SnekError::ModelAuth(_))));
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[test]
+fn test_extract_plain_completion() {
+    assert_eq!(extract_code_from_response("  x + 1\n"), "x + 1");
+}
+
+#[test]
+fn test_extract_fenced_completion() {
+    let response = "```rust\nlet x = 1;\nlet y = 2;\n```";
+    assert_eq!(extract_code_from_response(response), "let x = 1;\nlet y = 2;");
+}
+
+#[test]
+fn test_extract_skips_leading_prose() {
+    let response = "Here's the completion:\n\n```python\nreturn a + b\n```\nThis adds the numbers.";
+    assert_eq!(extract_code_from_response(response), "return a + b");
+}
+
+#[test]
+fn test_extract_takes_first_of_multiple_fences() {
+    let response = "```js\nfirst();\n```\n\nAlternatively:\n\n```js\nsecond();\n```";
+    assert_eq!(extract_code_from_response(response), "first();");
+}
+
+#[test]
+fn test_extract_tilde_fence() {
+    // A backtick line inside a tilde fence doesn't close it
+    let response = "~~~python\ndoc = \"\"\"\n```\n\"\"\"\n~~~\nDone.";
+    assert_eq!(extract_code_from_response(response), "doc = \"\"\"\n```\n\"\"\"");
+}
+
+#[test]
+fn test_extract_indented_fence() {
+    let response = "Sure:\n\n   ```rust\n   if ready {\n       go();\n   }\n   ```";
+    assert_eq!(extract_code_from_response(response), "if ready {\n    go();\n}");
+}
+
+#[test]
+fn test_extract_unterminated_fence() {
+    // No complete block, so nothing is taken out
+    let response = "```go\nfmt.Println(\"hi\")";
+    assert_eq!(extract_code_from_response(response), response);
+}
+
+#[test]
+fn test_extract_stray_closing_fence() {
+    let response = "foo()\n```";
+    assert_eq!(extract_code_from_response(response), response);
+
+    // A complete block after an unclosed fence line is still found
+    let response = "~~~\nSure:\n```rust\nbar();\n```";
+    assert_eq!(extract_code_from_response(response), "bar();");
+}
+
+#[test]
+fn test_extract_line_range_preserves_crlf() {
+    let content = "fn a() {\r\n    1\r\n}\r\nfn b() {}\r\n";
+    assert_eq!(extract_line_range(content, 0, 3), Some("fn a() {\r\n    1\r\n}"));
+    assert_eq!(extract_line_range(content, 3, 10), Some("fn b() {}"));
+    assert_eq!(extract_line_range(content, 4, 5), None);
+}
+
+#[test]
+fn test_extract_line_range_without_trailing_newline() {
+    let content = "one\ntwo\nthree";
+    assert_eq!(extract_line_range(content, 1, 3), Some("two\nthree"));
+    assert_eq!(extract_line_range(content, 2, 1), Some(""));
+}
+
+#[tokio::test]
+async fn test_chat_messages_are_sent_as_prior_turns() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("x"))]).await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+
+    let snapshot = ContextSnapshot {
+        chat_messages: vec![
+            ChatMessage {
+                role: "system".to_string(),
+                content: "Always use tabs".to_string(),
+            },
+            ChatMessage {
+                role: "user".to_string(),
+                content: "We target Rust 1.70".to_string(),
+            },
+        ],
+        ..ContextSnapshot::default()
+    };
+
+    model
+        .complete(
+            &snapshot,
+            "fn ",
+            "",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    let body = server.requests()[0].json();
+    let messages = body["messages"].as_array().unwrap();
+    assert_eq!(messages.len(), 4);
+    assert_eq!(messages[1]["role"], "system");
+    assert_eq!(messages[1]["content"], "Always use tabs");
+    assert_eq!(messages[2]["role"], "user");
+    assert_eq!(messages[2]["content"], "We target Rust 1.70");
+    assert!(messages[3]["content"].as_str().unwrap().contains("fn "));
+}
+
+#[tokio::test]
+async fn test_cache_control_marks_stable_prompt_prefix() {
+    let server = MockServer::start(vec![
+        MockResponse::new(200, completion_body("x")),
+        MockResponse::new(200, completion_body("x")),
+    ])
+    .await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+    model
+        .set_prompt_options(PromptOptions {
+            cache_control: true,
+            ..Default::default()
+        })
+        .await;
+
+    let mut snapshot = ContextSnapshot::default();
+    snapshot.cache_markdown("intent.md".to_string(), "Build a parser".to_string());
+    for snapshot in [&snapshot, &ContextSnapshot::default()] {
+        model
+            .complete(
+                snapshot,
+                "fn ",
+                "",
+                "rust",
+                "file:///a.rs",
+                "key",
+                &CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+    }
+
+    // Session context is cached; the file around the cursor is not
+    let body = server.requests()[0].json();
+    let messages = body["messages"].as_array().unwrap();
+    assert!(messages[0]["content"].is_string());
+    let parts = messages[1]["content"].as_array().unwrap();
+    assert_eq!(parts.len(), 2);
+    assert_eq!(parts[0]["cache_control"]["type"], "ephemeral");
+    assert!(parts[0]["text"].as_str().unwrap().contains("Build a parser"));
+    assert!(parts[1].get("cache_control").is_none());
+    assert!(parts[1]["text"]
+        .as_str()
+        .unwrap()
+        .contains("Complete the following code."));
+
+    // Without session context only the system prompt is
+    let body = server.requests()[1].json();
+    let messages = body["messages"].as_array().unwrap();
+    let parts = messages[0]["content"].as_array().unwrap();
+    assert_eq!(parts.len(), 1);
+    assert_eq!(parts[0]["cache_control"]["type"], "ephemeral");
+    assert!(messages[1]["content"].is_string());
+}
+
+#[tokio::test]
+async fn test_stored_snippet_code_is_preferred() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("x"))]).await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+
+    let mut snapshot = ContextSnapshot::default();
+    snapshot.code_contexts.push(CodeContext {
+        uri: "file:///stored.rs".to_string(),
+        start_line: 0,
+        end_line: 1,
+        language_id: "rust".to_string(),
+        code: "fn stored() {}".to_string(),
+        description: None,
+        last_modified: "2025-11-03T00:00:00+00:00".to_string(),
+        extra: Default::default(),
+    });
+    snapshot
+        .file_cache
+        .insert("file:///stored.rs".to_string(), "fn live() {}\n".to_string());
+
+    model
+        .complete(
+            &snapshot,
+            "fn ",
+            "",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    let body = server.requests()[0].json();
+    let prompt = body["messages"][1]["content"].as_str().unwrap();
+    assert!(prompt.contains("fn stored() {}"));
+    assert!(!prompt.contains("fn live() {}"));
+}
+
+#[tokio::test]
+async fn test_crlf_snippet_round_trips_into_prompt() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("x"))]).await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+
+    let mut snapshot = ContextSnapshot::default();
+    snapshot.code_contexts.push(CodeContext {
+        uri: "file:///win.rs".to_string(),
+        start_line: 1,
+        end_line: 3,
+        language_id: "rust".to_string(),
+        code: String::new(),
+        description: None,
+        last_modified: String::new(),
+        extra: Default::default(),
+    });
+    snapshot.file_cache.insert(
+        "file:///win.rs".to_string(),
+        "// header\r\nfn win() {\r\n}\r\n".to_string(),
+    );
+
+    model
+        .complete(
+            &snapshot,
+            "fn ",
+            "",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    let body = server.requests()[0].json();
+    let prompt = body["messages"][1]["content"].as_str().unwrap();
+    assert!(prompt.contains("```\nfn win() {\r\n}\n```"));
+}
+
+fn mixed_language_snapshot() -> ContextSnapshot {
+    let mut snapshot = ContextSnapshot::default();
+    for (uri, language_id, code) in [
+        ("file:///lib.rs", "rust", "fn rust_helper() {}"),
+        ("file:///util.py", "python", "def python_helper(): pass"),
+        ("file:///main.rs", "rust", "fn rust_main() {}"),
+    ] {
+        snapshot.code_contexts.push(CodeContext {
+            uri: uri.to_string(),
+            start_line: 0,
+            end_line: 1,
+            language_id: language_id.to_string(),
+            code: String::new(),
+            description: None,
+            last_modified: String::new(),
+            extra: Default::default(),
+        });
+        snapshot.file_cache.insert(uri.to_string(), code.to_string());
+    }
+    snapshot
+        .markdown_cache
+        .insert("intent.md".to_string(), "Build a parser".to_string());
+    snapshot
+}
+
+async fn prompt_for(options: PromptOptions) -> String {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("x"))]).await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+    model.set_prompt_options(options).await;
+
+    model
+        .complete(
+            &mixed_language_snapshot(),
+            "fn ",
+            "",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    let body = server.requests()[0].json();
+    body["messages"][1]["content"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn test_all_snippets_sent_by_default() {
+    let prompt = prompt_for(PromptOptions::default()).await;
+    assert!(prompt.contains("rust_helper"));
+    assert!(prompt.contains("python_helper"));
+    assert!(prompt.contains("rust_main"));
+}
+
+#[tokio::test]
+async fn test_match_snippet_language_filters_other_languages() {
+    let prompt = prompt_for(PromptOptions {
+        match_snippet_language: true,
+        ..PromptOptions::default()
+    })
+    .await;
+
+    assert!(prompt.contains("rust_helper"));
+    assert!(prompt.contains("rust_main"));
+    assert!(!prompt.contains("python_helper"));
+    assert!(prompt.contains("Build a parser"));
+}
+
+#[tokio::test]
+async fn test_cancel_drops_in_flight_request() {
+    let server = MockServer::start(vec![
+        MockResponse::new(200, completion_body("late()")).with_delay(Duration::from_secs(30)),
+    ])
+    .await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+
+    let cancel = CancellationToken::new();
+    let canceller = cancel.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        canceller.cancel();
+    });
+
+    let result = model
+        .complete(
+            &ContextSnapshot::default(),
+            "call",
+            "",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &cancel,
+        )
+        .await;
+    assert!(matches!(result, Err(SnekError::Cancelled)));
+    assert_eq!(server.requests().len(), 1);
+
+    // The mock server notices the client hanging up mid-request
+    for _ in 0..50 {
+        if server.dropped_connections() == 1 {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("connection was not closed after cancellation");
+}
+
+#[tokio::test]
+async fn test_sibling_outline_is_included() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let file = temp_dir.path().join("math.rs");
+    std::fs::write(&file, "fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n").unwrap();
+    std::fs::write(
+        temp_dir.path().join("math_test.rs"),
+        "fn test_add_handles_negatives() {\n    assert_eq!(add(-1, 1), 0);\n}\n",
+    )
+    .unwrap();
+    let uri = url::Url::from_file_path(&file).unwrap().to_string();
+
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("x"))]).await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+    model
+        .set_prompt_options(PromptOptions {
+            sibling_context: true,
+            ..PromptOptions::default()
+        })
+        .await;
+
+    model
+        .complete(
+            &ContextSnapshot::default(),
+            "fn ",
+            "",
+            "rust",
+            &uri,
+            "key",
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    let body = server.requests()[0].json();
+    let prompt = body["messages"][1]["content"].as_str().unwrap();
+    assert!(prompt.contains("fn test_add_handles_negatives() {"));
+    assert!(!prompt.contains("assert_eq!(add(-1, 1), 0)"));
+}
+
+#[tokio::test]
+async fn test_prompt_names_enclosing_function() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("x"))]).await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+
+    model
+        .complete(
+            &ContextSnapshot::default(),
+            "fn checksum(data: &[u8]) -> u32 {\n    let mut sum = ",
+            "\n}",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    let body = server.requests()[0].json();
+    let prompt = body["messages"][1]["content"].as_str().unwrap();
+    assert!(prompt.contains("You are completing inside function `checksum`."));
+}
+
+#[tokio::test]
+async fn test_empty_completion_retried_at_higher_temperature() {
+    let server = MockServer::start(vec![
+        MockResponse::new(200, completion_body("")),
+        MockResponse::new(200, completion_body("retry()")),
+    ])
+    .await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+    model
+        .set_retry_options(RetryOptions {
+            empty_retries: 2,
+            ..Default::default()
+        })
+        .await;
+
+    let completion = model
+        .complete(
+            &ContextSnapshot::default(),
+            "call",
+            "",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(completion, "retry()");
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].json()["temperature"], 0.0);
+    assert!(requests[1].json()["temperature"].as_f64().unwrap() > 0.0);
+}
+
+#[tokio::test]
+async fn test_empty_completion_not_retried_by_default() {
+    let server = MockServer::start(vec![
+        MockResponse::new(200, completion_body("")),
+        MockResponse::new(200, completion_body("retry()")),
+    ])
+    .await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+
+    let completion = model
+        .complete(
+            &ContextSnapshot::default(),
+            "call",
+            "",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    assert!(completion.is_empty());
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn test_http_errors_do_not_use_empty_retries() {
+    let server = MockServer::start(vec![
+        MockResponse::new(500, r#"{"error":"down"}"#),
+        MockResponse::new(200, completion_body("late()")),
+    ])
+    .await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+    model
+        .set_retry_options(RetryOptions {
+            empty_retries: 2,
+            ..Default::default()
+        })
+        .await;
+
+    let result = model
+        .complete(
+            &ContextSnapshot::default(),
+            "call",
+            "",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &CancellationToken::new(),
+        )
+        .await;
+
+    assert!(matches!(
+        result,
+        Err(SnekError::ModelHttp { status: 500, .. })
+    ));
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn test_error_object_with_ok_status_is_reported() {
+    let server = MockServer::start(vec![MockResponse::new(
+        200,
+        r#"{"error":{"message":"model is overloaded","type":"server_error"}}"#,
+    )])
+    .await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+
+    let result = model
+        .complete(
+            &ContextSnapshot::default(),
+            "call",
+            "",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &CancellationToken::new(),
+        )
+        .await;
+
+    let Err(SnekError::BadResponse(message)) = result else {
+        panic!("expected an error response, got {:?}", result);
+    };
+    assert_eq!(message, "model is overloaded");
+}
+
+fn truncated_body() -> String {
+    let body = completion_body("cut_off()");
+    body[..body.len() / 2].to_string()
+}
+
+#[tokio::test]
+async fn test_truncated_response_is_reported_as_incomplete() {
+    let server = MockServer::start(vec![MockResponse::new(200, truncated_body())]).await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+
+    let result = model
+        .complete(
+            &ContextSnapshot::default(),
+            "call",
+            "",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &CancellationToken::new(),
+        )
+        .await;
+
+    let Err(error) = result else {
+        panic!("expected an error, got {:?}", result);
+    };
+    assert!(matches!(error, SnekError::IncompleteResponse(_)));
+    assert!(error.is_retryable());
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn test_transient_retries_recover_from_truncated_response() {
+    let server = MockServer::start(vec![
+        MockResponse::new(200, truncated_body()),
+        MockResponse::new(200, completion_body("whole()")),
+    ])
+    .await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+    model
+        .set_retry_options(RetryOptions {
+            transient_retries: 1,
+            ..Default::default()
+        })
+        .await;
+
+    let completion = model
+        .complete(
+            &ContextSnapshot::default(),
+            "call",
+            "",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(completion, "whole()");
+    assert_eq!(server.requests().len(), 2);
+}
+
+#[tokio::test]
+async fn test_circuit_breaker_opens_and_recovers() {
+    let server = MockServer::start(vec![
+        MockResponse::new(500, r#"{"error":"down"}"#),
+        MockResponse::new(503, r#"{"error":"down"}"#),
+        MockResponse::new(200, completion_body("back()")),
+    ])
+    .await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+    model
+        .set_retry_options(RetryOptions {
+            circuit_breaker_threshold: 2,
+            circuit_breaker_cooldown: Duration::from_millis(200),
+            ..Default::default()
+        })
+        .await;
+
+    let snapshot = ContextSnapshot::default();
+    let cancel = CancellationToken::new();
+    let complete = || {
+        model.complete(
+            &snapshot,
+            "call",
+            "",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &cancel,
+        )
+    };
+
+    assert!(matches!(complete().await, Err(SnekError::ModelHttp { status: 500, .. })));
+    assert!(matches!(complete().await, Err(SnekError::ModelHttp { status: 503, .. })));
+
+    // Open: fails fast without reaching the endpoint
+    let result = complete().await;
+    assert!(matches!(result, Err(SnekError::CircuitOpen { .. })), "{:?}", result);
+    assert_eq!(server.requests().len(), 2);
+
+    // After the cooldown a probe goes through and closes it again
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    assert_eq!(complete().await.unwrap(), "back()");
+    assert_eq!(server.requests().len(), 3);
+}
+
+fn context_too_large() -> MockResponse {
+    MockResponse::new(
+        400,
+        r#"{"error":{"message":"This model's maximum context length is 8192 tokens","code":"context_length_exceeded"}}"#,
+    )
+}
+
+#[tokio::test]
+async fn test_oversize_prompt_is_retried_with_trimmed_context() {
+    let server = MockServer::start(vec![
+        context_too_large(),
+        MockResponse::new(200, completion_body("fits()")),
+    ])
+    .await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+    let prefix = format!("{}let x = ", "// filler\n".repeat(2000));
+
+    let completion = model
+        .complete(
+            &mixed_language_snapshot(),
+            &prefix,
+            "",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(completion, "fits()");
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    let first = requests[0].json()["messages"][1]["content"].as_str().unwrap().to_string();
+    let second = requests[1].json()["messages"][1]["content"].as_str().unwrap().to_string();
+    assert!(first.contains("Build a parser"));
+    assert!(!second.contains("Build a parser"));
+    assert!(!second.contains("rust_helper"));
+    assert!(second.contains("let x = <SNEK_CURSOR>"));
+    assert!(second.len() < first.len() / 2);
+}
+
+#[tokio::test]
+async fn test_still_oversize_prompt_degrades_to_empty() {
+    let server = MockServer::start(vec![context_too_large()]).await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+
+    let completion = model
+        .complete(
+            &mixed_language_snapshot(),
+            "let x = ",
+            "",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    assert!(completion.is_empty());
+    assert_eq!(server.requests().len(), 2);
+}
+
+#[tokio::test]
+async fn test_missing_api_key_is_an_auth_error() {
+    let model = ModelClient::new(
+        "http://model.invalid/v1/chat/completions".to_string(),
+        "primary".to_string(),
+    );
+
+    let result = model
+        .complete(
+            &ContextSnapshot::default(),
+            "call",
+            "",
+            "rust",
+            "file:///a.rs",
+            "",
+            &CancellationToken::new(),
+        )
+        .await;
+
+    let err = result.unwrap_err();
+    assert!(matches!(err, SnekError::ModelAuth(_)));
+    assert!(!err.is_retryable());
+}
+
+#[tokio::test]
+async fn test_empty_model_name_is_reported_before_any_request() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("x"))]).await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), " ".to_string());
+
+    let result = model
+        .complete(
+            &ContextSnapshot::default(),
+            "call",
+            "",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &CancellationToken::new(),
+        )
+        .await;
+
+    let err = result.unwrap_err();
+    assert!(matches!(err, SnekError::ModelNotConfigured));
+    assert!(err.to_string().contains("snek.model"));
+    assert!(server.requests().is_empty());
+}
+
+fn large_markdown_snapshot() -> ContextSnapshot {
+    let mut snapshot = ContextSnapshot::default();
+    snapshot
+        .markdown_cache
+        .insert("intent.md".to_string(), "Build a parser. ".repeat(100));
+    snapshot
+}
+
+#[tokio::test]
+async fn test_large_markdown_is_summarized_once() {
+    let server = MockServer::start(vec![
+        MockResponse::new(200, completion_body("A parser project.")),
+        MockResponse::new(200, completion_body("first()")),
+        MockResponse::new(200, completion_body("second()")),
+    ])
+    .await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+    model
+        .set_prompt_options(PromptOptions {
+            summarize_above_chars: Some(500),
+            summary_model: Some("cheap-model".to_string()),
+            ..PromptOptions::default()
+        })
+        .await;
+
+    let snapshot = large_markdown_snapshot();
+    for _ in 0..2 {
+        model
+            .complete(
+                &snapshot,
+                "fn ",
+                "",
+                "rust",
+                "file:///a.rs",
+                "key",
+                &CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+    }
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 3);
+    assert_eq!(requests[0].json()["model"], "cheap-model");
+    for request in &requests[1..] {
+        let body = request.json();
+        assert_eq!(body["model"], "primary");
+        let prompt = body["messages"][1]["content"].as_str().unwrap();
+        assert!(prompt.contains("A parser project."));
+        assert!(!prompt.contains("Build a parser."));
+    }
+}
+
+#[tokio::test]
+async fn test_changed_markdown_is_summarized_again() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("summary"))]).await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+    model
+        .set_prompt_options(PromptOptions {
+            summarize_above_chars: Some(500),
+            ..PromptOptions::default()
+        })
+        .await;
+
+    let mut snapshot = large_markdown_snapshot();
+    for _ in 0..2 {
+        model
+            .complete(
+                &snapshot,
+                "fn ",
+                "",
+                "rust",
+                "file:///a.rs",
+                "key",
+                &CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        snapshot
+            .markdown_cache
+            .insert("more.md".to_string(), "Also a lexer.".to_string());
+    }
+
+    // Two summaries and two completions
+    assert_eq!(server.requests().len(), 4);
+}
+
+#[tokio::test]
+async fn test_small_markdown_is_sent_raw() {
+    let prompt = prompt_for(PromptOptions {
+        summarize_above_chars: Some(500),
+        ..PromptOptions::default()
+    })
+    .await;
+    assert!(prompt.contains("Build a parser"));
+}
+
+#[test]
+fn test_cursor_marker_avoids_buffer_contents() {
+    assert_eq!(cursor_marker("<SNEK_CURSOR>", "let a = ", ";"), "<SNEK_CURSOR>");
+    assert_eq!(
+        cursor_marker("<SNEK_CURSOR>", "// <SNEK_CURSOR> <SNEK_CURSOR_1>\n", ""),
+        "<SNEK_CURSOR_2>"
+    );
+    assert_eq!(cursor_marker("@@", "a @", "@ b"), "@@_1");
+}
+
+#[tokio::test]
+async fn test_literal_marker_in_buffer_gets_alternative() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("x"))]).await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+    model
+        .set_prompt_options(PromptOptions {
+            cursor_marker: "<CURSOR>".to_string(),
+            ..PromptOptions::default()
+        })
+        .await;
+
+    model
+        .complete(
+            &ContextSnapshot::default(),
+            "const TEMPLATE = \"<CURSOR>\";\nlet y = ",
+            "",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    let body = server.requests()[0].json();
+    let system = body["messages"][0]["content"].as_str().unwrap();
+    let prompt = body["messages"][1]["content"].as_str().unwrap();
+    assert!(prompt.contains("const TEMPLATE = \"<CURSOR>\";\nlet y = <CURSOR_1>"));
+    assert!(prompt.contains("The cursor is at <CURSOR_1>."));
+    assert!(system.contains("<CURSOR_1>"));
+    assert!(!system.contains("<CURSOR>"));
+}
+
+fn git(dir: &std::path::Path, args: &[&str]) {
+    let status = std::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["-c", "user.name=snek", "-c", "user.email=snek@example.com"])
+        .args(args)
+        .output()
+        .unwrap()
+        .status;
+    assert!(status.success(), "git {:?} failed", args);
+}
+
+#[tokio::test]
+async fn test_git_changed_snippets_come_first() {
+    let repo = tempfile::TempDir::new().unwrap();
+    let repo_path = repo.path().canonicalize().unwrap();
+    std::fs::write(repo_path.join("stale.rs"), "fn stale() {}\n").unwrap();
+    std::fs::write(repo_path.join("fresh.rs"), "fn fresh() {}\n").unwrap();
+    git(&repo_path, &["init", "-q"]);
+    git(&repo_path, &["add", "."]);
+    git(&repo_path, &["commit", "-q", "-m", "init"]);
+    std::fs::write(repo_path.join("fresh.rs"), "fn fresh_edited() {}\n").unwrap();
+
+    let mut snapshot = ContextSnapshot::default();
+    for name in ["stale.rs", "fresh.rs"] {
+        let uri = url::Url::from_file_path(repo_path.join(name)).unwrap().to_string();
+        snapshot.code_contexts.push(CodeContext {
+            uri: uri.clone(),
+            start_line: 0,
+            end_line: 1,
+            language_id: "rust".to_string(),
+            code: String::new(),
+            description: None,
+            last_modified: String::new(),
+            extra: Default::default(),
+        });
+        let content = std::fs::read_to_string(repo_path.join(name)).unwrap();
+        snapshot.file_cache.insert(uri, content);
+    }
+    let current = url::Url::from_file_path(repo_path.join("main.rs")).unwrap().to_string();
+
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("x"))]).await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+    for prioritize_git_changes in [false, true] {
+        model
+            .set_prompt_options(PromptOptions {
+                prioritize_git_changes,
+                ..PromptOptions::default()
+            })
+            .await;
+        model
+            .complete(&snapshot, "fn ", "", "rust", &current, "key", &CancellationToken::new())
+            .await
+            .unwrap();
+    }
+
+    let requests = server.requests();
+    let position = |i: usize, needle: &str| {
+        requests[i].json()["messages"][1]["content"]
+            .as_str()
+            .unwrap()
+            .find(needle)
+            .unwrap()
+    };
+    assert!(position(0, "fn stale()") < position(0, "fn fresh_edited()"));
+    assert!(position(1, "fn fresh_edited()") < position(1, "fn stale()"));
+}
+
+#[tokio::test]
+async fn test_prompt_includes_signature_of_long_function() {
+    let server = MockServer::start(vec![MockResponse::new(200, completion_body("x"))]).await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+    model
+        .set_prompt_options(PromptOptions {
+            scope_signature: true,
+            ..PromptOptions::default()
+        })
+        .await;
+
+    let mut prefix = "def resample(series: list[float], rate: int, *, method: str = \"mean\") -> list[float]:\n".to_string();
+    for i in 0..200 {
+        prefix.push_str(&format!("    step_{} = series[{}] * rate\n", i, i));
+    }
+    prefix.push_str("    ");
+
+    model
+        .complete(
+            &ContextSnapshot::default(),
+            &prefix,
+            "",
+            "python",
+            "file:///a.py",
+            "key",
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    let body = server.requests()[0].json();
+    let prompt = body["messages"][1]["content"].as_str().unwrap();
+    assert!(prompt.contains(
+        "The cursor is inside this declaration:\n```\ndef resample(series: list[float], rate: int, *, method: str = \"mean\") -> list[float]\n```"
+    ));
+    assert!(!prompt.contains("You are completing inside function"));
+}
+
+#[tokio::test]
+async fn test_temporary_redirect_is_followed_with_body() {
+    let server = MockServer::start(vec![
+        MockResponse::new(307, "").with_header("Location", "/v1/chat/completions"),
+        MockResponse::new(200, completion_body("redirected()")),
+    ])
+    .await;
+    let model = ModelClient::new(server.url("/old/chat/completions"), "primary".to_string());
+
+    let completion = model
+        .complete(
+            &ContextSnapshot::default(),
+            "call",
+            "",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(completion, "redirected()");
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    assert!(requests[0].request_line.starts_with("POST /old/chat/completions "));
+    assert!(requests[1].request_line.starts_with("POST /v1/chat/completions "));
+    assert_eq!(requests[1].body, requests[0].body);
+}
+
+#[tokio::test]
+async fn test_redirect_that_drops_post_body_is_reported() {
+    let server = MockServer::start(vec![MockResponse::new(302, "")
+        .with_header("Location", "https://api.example.com/v1/chat/completions")])
+    .await;
+    let model = ModelClient::new(server.url("/v1/chat/completions"), "primary".to_string());
+
+    let result = model
+        .complete(
+            &ContextSnapshot::default(),
+            "call",
+            "",
+            "rust",
+            "file:///a.rs",
+            "key",
+            &CancellationToken::new(),
+        )
+        .await;
+
+    let Err(SnekError::ModelRedirect { status, location }) = result else {
+        panic!("expected a redirect error, got {:?}", result);
+    };
+    assert_eq!(status, 302);
+    assert_eq!(location, "https://api.example.com/v1/chat/completions");
+    assert_eq!(server.requests().len(), 1);
+}
@@ -0,0 +1,154 @@
+//! Integration tests for postprocess module
+
+use snek::postprocess::{
+    adjust_leading_newline, stop_at_block_end, trim_prefix_overlap, truncate_completion,
+};
+
+#[test]
+fn test_trims_echoed_partial_line() {
+    let prefix = "class Math:\n    def fibona";
+    assert_eq!(trim_prefix_overlap(prefix, "def fibonacci(n):"), "cci(n):");
+}
+
+#[test]
+fn test_trims_echoed_partial_token() {
+    let prefix = "class Math:\n    def fibona";
+    assert_eq!(trim_prefix_overlap(prefix, "fibonacci(n):"), "cci(n):");
+}
+
+#[test]
+fn test_trims_echoed_lines() {
+    let prefix = "fn main() {\n    let x = 1;\n    let y";
+    let completion = "let x = 1;\n    let y = x + 1;";
+    assert_eq!(trim_prefix_overlap(prefix, completion), " = x + 1;");
+}
+
+#[test]
+fn test_ignores_overlap_starting_mid_token() {
+    // "ona" is a suffix of the prefix, but it starts inside "fibona"
+    let prefix = "def fibona";
+    assert_eq!(trim_prefix_overlap(prefix, "onanism"), "onanism");
+}
+
+#[test]
+fn test_ignores_single_char_overlap() {
+    let prefix = "let total = x";
+    assert_eq!(trim_prefix_overlap(prefix, "x + 1"), "x + 1");
+}
+
+#[test]
+fn test_no_overlap_is_unchanged() {
+    let prefix = "def fibona";
+    assert_eq!(trim_prefix_overlap(prefix, "cci(n):"), "cci(n):");
+}
+
+#[test]
+fn test_multibyte_overlap() {
+    let prefix = "let s = \"héllo wör";
+    assert_eq!(trim_prefix_overlap(prefix, "wörld\";"), "ld\";");
+}
+
+#[test]
+fn test_truncate_to_max_lines() {
+    let completion = "a\nb\nc\nd";
+    assert_eq!(truncate_completion(completion, Some(2), None), "a\nb");
+    assert_eq!(truncate_completion(completion, Some(10), None), completion);
+    assert_eq!(truncate_completion(completion, None, None), completion);
+    assert_eq!(truncate_completion(completion, Some(0), None), completion);
+}
+
+#[test]
+fn test_truncate_to_max_chars_at_line_boundary() {
+    let completion = "first line\nsecond line\nthird";
+    // 15 chars fits "first line\nsecon" -> keep only the whole first line
+    assert_eq!(truncate_completion(completion, None, Some(15)), "first line");
+    // A single overlong line is cut at the cap
+    assert_eq!(truncate_completion("abcdefghij", None, Some(4)), "abcd");
+}
+
+#[test]
+fn test_truncate_chars_is_utf8_safe() {
+    assert_eq!(truncate_completion("日本語のテキスト", None, Some(3)), "日本語");
+}
+
+#[test]
+fn test_newline_added_at_end_of_block_opener() {
+    let prefix = "fn main() {\n    let x = 1;\n    if x > 0 {";
+    assert_eq!(
+        adjust_leading_newline(prefix, "\n    }\n}", "println!(\"{}\", x);", "rust"),
+        "\n        println!(\"{}\", x);"
+    );
+}
+
+#[test]
+fn test_newline_added_after_statement() {
+    let prefix = "fn main() {\n    let x = 1;";
+    assert_eq!(
+        adjust_leading_newline(prefix, "\n}", "let y = 2;", "rust"),
+        "\n    let y = 2;"
+    );
+}
+
+#[test]
+fn test_newline_after_python_block() {
+    let prefix = "def total(items):";
+    assert_eq!(
+        adjust_leading_newline(prefix, "", "return sum(items)", "python"),
+        "\n    return sum(items)"
+    );
+    // A type annotation colon is not a block opener outside Python
+    assert_eq!(adjust_leading_newline("let x:", "", "i32 = 5;", "rust"), "i32 = 5;");
+}
+
+#[test]
+fn test_no_newline_when_line_continues() {
+    assert_eq!(adjust_leading_newline("let x = foo", "\n", "(1);", "rust"), "(1);");
+    assert_eq!(adjust_leading_newline("let v = vec![1;", "", " 3];", "rust"), " 3];");
+    assert_eq!(adjust_leading_newline("if a {\n} ", "", "else {", "rust"), "else {");
+    assert_eq!(adjust_leading_newline("    }", "", "else {", "rust"), "else {");
+}
+
+#[test]
+fn test_newline_stripped_mid_line() {
+    assert_eq!(
+        adjust_leading_newline("let x = ", "; // answer\n", "\n42", "rust"),
+        "42"
+    );
+}
+
+#[test]
+fn test_blank_line_left_alone() {
+    assert_eq!(adjust_leading_newline("fn a() {\n    ", "\n}", "run();", "rust"), "run();");
+}
+
+#[test]
+fn test_stop_at_block_end_finishes_rust_function() {
+    let prefix = "fn add(a: i32, b: i32) -> i32 {\n    ";
+    let completion = "let sum = a + b; // {\n    sum\n}\n\nfn sub(a: i32, b: i32) -> i32 {\n    a - b\n}\n";
+    assert_eq!(
+        stop_at_block_end(prefix, completion, "rust"),
+        "let sum = a + b; // {\n    sum\n}"
+    );
+
+    // Blocks opened by the completion itself, or closed and reopened on
+    // one line, don't end it
+    let prefix = "fn check(x: i32) {\n    ";
+    let completion = "if x > 0 {\n        println!(\"}\");\n    } else {\n        return;\n    }\n}\nfn next() {}";
+    assert_eq!(
+        stop_at_block_end(prefix, completion, "rust"),
+        "if x > 0 {\n        println!(\"}\");\n    } else {\n        return;\n    }\n}"
+    );
+
+    // Closing a call keeps the rest of its line
+    assert_eq!(stop_at_block_end("let v = max(", "a, b);\nlet w = 2;", "rust"), "a, b);");
+    assert_eq!(stop_at_block_end("fn f() {\n    ", "g()\n}", "rust"), "g()\n}");
+    assert_eq!(stop_at_block_end("fn f() {\n    ", "g();\n    h();", "rust"), "g();\n    h();");
+}
+
+#[test]
+fn test_stop_at_block_end_ignores_languages_without_braces() {
+    let prefix = "def add(a, b):\n    ";
+    let completion = "return (a + b))\n\ndef sub(a, b):\n    return a - b\n";
+    assert_eq!(stop_at_block_end(prefix, completion, "python"), completion);
+    assert_eq!(stop_at_block_end("", "}\nmore", "plaintext"), "}\nmore");
+}
@@ -0,0 +1,61 @@
+//! Integration tests for project_config module
+
+use serde_json::json;
+use snek::error::SnekError;
+use snek::project_config::ProjectConfig;
+use tempfile::TempDir;
+
+#[test]
+fn test_load_reads_startup_defaults() {
+    let temp_dir = TempDir::new().unwrap();
+    std::fs::write(
+        temp_dir.path().join("config.json"),
+        r#"{
+  "model": "project-model",
+  "apiUrl": "http://localhost:8080/v1/chat/completions",
+  "apiKey": "sk-committed-by-mistake",
+  "provider": "anthropic",
+  "temperature": 0.3,
+  "maxTokns": 100,
+}"#,
+    )
+    .unwrap();
+
+    let config = ProjectConfig::load(temp_dir.path()).unwrap();
+    assert_eq!(config.model(), Some("project-model"));
+    assert_eq!(
+        config.api_url(),
+        Some("http://localhost:8080/v1/chat/completions")
+    );
+    // The API key doesn't belong in a committed file and is never read from it
+    assert_eq!(config.unknown_keys(), vec!["apiKey", "maxTokns"]);
+
+    // The client's settings win on conflict, key by key
+    let merged = config.merge(Some(&json!({ "temperature": 0.9, "model": null })));
+    assert_eq!(merged["temperature"], 0.9);
+    assert_eq!(merged["model"], "project-model");
+    assert_eq!(merged["provider"], "anthropic");
+
+    let missing = TempDir::new().unwrap();
+    assert_eq!(ProjectConfig::load(missing.path()).unwrap(), ProjectConfig::default());
+}
+
+#[test]
+fn test_load_rejects_mistyped_values() {
+    let temp_dir = TempDir::new().unwrap();
+    for content in [
+        r#"{ "temperature": "low" }"#,
+        r#"{ "provider": "unknown" }"#,
+        r#"{ "apiUrl": 8080 }"#,
+    ] {
+        std::fs::write(temp_dir.path().join("config.json"), content).unwrap();
+        let result = ProjectConfig::load(temp_dir.path());
+        assert!(matches!(result, Err(SnekError::Config(_))), "{}", content);
+    }
+
+    std::fs::write(temp_dir.path().join("config.json"), "[1, 2]").unwrap();
+    assert!(matches!(
+        ProjectConfig::load(temp_dir.path()),
+        Err(SnekError::SessionParse { .. })
+    ));
+}
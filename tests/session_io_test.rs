@@ -1,7 +1,11 @@
 //! Integration tests for session_io module
 
 use anyhow::Result;
-use snek::session_io::{load_snapshot, resolve_active_session, update_context_from_file};
+use snek::error::SnekError;
+use snek::session_io::{
+    find_workspace_root, find_workspace_root_from, load_snapshot, locate_workspace_root, prune_sessions, resolve_active_session,
+    resolve_session_path, restore_context_snapshot, save_context_snapshot, update_context_from_file,
+};
 use snek::snapshot::CodeContext;
 use std::path::Path;
 use tempfile::TempDir;
@@ -111,6 +115,7 @@ fn test_update_context_from_file() -> Result<()> {
         code: String::new(),
         description: None,
         last_modified: String::new(),
+        extra: Default::default(),
     };
 
     update_context_from_file(&mut context)?;
@@ -138,6 +143,7 @@ fn test_update_context_invalid_range() -> Result<()> {
         code: String::new(),
         description: None,
         last_modified: String::new(),
+        extra: Default::default(),
     };
 
     let result = update_context_from_file(&mut context);
@@ -145,3 +151,543 @@ fn test_update_context_invalid_range() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_update_context_end_past_eof() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let test_file = temp_dir.path().join("test.rs");
+    std::fs::write(&test_file, "line 0\nline 1\n")?;
+
+    let mut context = CodeContext {
+        uri: format!("file://{}", test_file.display()),
+        start_line: 1,
+        end_line: 5,
+        language_id: "rust".to_string(),
+        code: String::new(),
+        description: None,
+        last_modified: String::new(),
+        extra: Default::default(),
+    };
+
+    let result = update_context_from_file(&mut context);
+    assert!(matches!(
+        result,
+        Err(SnekError::LineRange { start: 1, end: 5, lines: 2, .. })
+    ));
+    assert!(context.code.is_empty());
+
+    // Ending exactly at the last line is fine
+    context.end_line = 2;
+    update_context_from_file(&mut context)?;
+    assert_eq!(context.code, "line 1");
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_load_snapshot_symlinked_context_dir() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    create_test_session(temp_dir.path())?;
+
+    let shared_context = temp_dir.path().join("shared-context");
+    std::fs::create_dir_all(&shared_context)?;
+    std::fs::write(shared_context.join("architecture.md"), "# Architecture")?;
+
+    let session_dir = temp_dir.path().join("sessions/test-session-123");
+    std::os::unix::fs::symlink(&shared_context, session_dir.join("context"))?;
+
+    let snapshot = load_snapshot(&session_dir)?;
+    assert_eq!(
+        snapshot.markdown_cache.get("architecture.md").map(String::as_str),
+        Some("# Architecture")
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_load_snapshot_symlink_loop_context_dir() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    create_test_session(temp_dir.path())?;
+
+    let session_dir = temp_dir.path().join("sessions/test-session-123");
+    std::os::unix::fs::symlink(session_dir.join("context"), session_dir.join("context"))?;
+
+    let snapshot = load_snapshot(&session_dir)?;
+    assert!(snapshot.markdown_cache.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_resolve_session_path_relative() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    create_test_session(temp_dir.path())?;
+
+    let session_dir = resolve_session_path(temp_dir.path(), "sessions/test-session-123")?;
+    assert_eq!(session_dir, temp_dir.path().join("sessions/test-session-123"));
+
+    Ok(())
+}
+
+#[test]
+fn test_resolve_session_path_absolute() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    create_test_session(temp_dir.path())?;
+    let absolute = temp_dir.path().join("sessions").join("test-session-123");
+
+    // Absolute paths inside the root are allowed
+    let session_dir = resolve_session_path(temp_dir.path(), absolute.to_str().unwrap())?;
+    assert_eq!(session_dir, absolute);
+
+    Ok(())
+}
+
+#[test]
+fn test_resolve_session_path_outside_root() -> Result<()> {
+    let snek_root = TempDir::new()?;
+    let elsewhere = TempDir::new()?;
+    let outside = elsewhere.path().to_str().unwrap();
+
+    assert!(matches!(
+        resolve_session_path(snek_root.path(), outside),
+        Err(SnekError::SessionOutsideRoot { .. })
+    ));
+
+    std::fs::create_dir_all(snek_root.path().join("sessions"))?;
+    assert!(resolve_session_path(snek_root.path(), "sessions/../..").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_resolve_active_session_absolute_path() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    create_test_session(temp_dir.path())?;
+    let absolute = temp_dir.path().join("sessions").join("test-session-123");
+
+    let active = serde_json::json!({
+        "schema": 1,
+        "id": "test-session-123",
+        "path": absolute.to_str().unwrap()
+    });
+    std::fs::write(
+        temp_dir.path().join("active.json"),
+        serde_json::to_string_pretty(&active)?,
+    )?;
+
+    let session_dir = resolve_active_session(temp_dir.path())?;
+    assert_eq!(session_dir, absolute);
+    load_snapshot(&session_dir)?;
+
+    // A session outside .snek is refused
+    let elsewhere = TempDir::new()?;
+    let active = serde_json::json!({
+        "schema": 1,
+        "id": "test-session-123",
+        "path": elsewhere.path().to_str().unwrap()
+    });
+    std::fs::write(
+        temp_dir.path().join("active.json"),
+        serde_json::to_string_pretty(&active)?,
+    )?;
+    assert!(matches!(
+        resolve_active_session(temp_dir.path()),
+        Err(SnekError::SessionOutsideRoot { .. })
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_load_snapshot_missing_session_is_io_error() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+
+    let err = load_snapshot(temp_dir.path()).unwrap_err();
+    assert!(matches!(err, SnekError::WorkspaceIo { .. }));
+
+    Ok(())
+}
+
+#[test]
+fn test_load_snapshot_invalid_session_is_parse_error() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    std::fs::write(temp_dir.path().join("session.json"), "{ not json")?;
+
+    let err = load_snapshot(temp_dir.path()).unwrap_err();
+    assert!(matches!(err, SnekError::SessionParse { .. }));
+
+    Ok(())
+}
+
+#[test]
+fn test_load_snapshot_dedups_snippets() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    create_test_session(temp_dir.path())?;
+    let session_dir = temp_dir.path().join("sessions").join("test-session-123");
+
+    let snippet = |start: u32, end: u32| {
+        serde_json::json!({
+            "uri": "file:///tmp/lib.rs",
+            "start_line": start,
+            "end_line": end,
+            "language_id": "rust"
+        })
+    };
+    let snippets = serde_json::json!({
+        "schema": 1,
+        "snippets": [snippet(0, 10), snippet(0, 10), snippet(5, 10), snippet(0, 10)]
+    });
+    std::fs::write(
+        session_dir.join("code_snippets.json"),
+        serde_json::to_string_pretty(&snippets)?,
+    )?;
+
+    let snapshot = load_snapshot(&session_dir)?;
+    assert_eq!(snapshot.code_contexts.len(), 2);
+    assert_eq!(snapshot.code_contexts[0].start_line, 0);
+    assert_eq!(snapshot.code_contexts[1].start_line, 5);
+
+    Ok(())
+}
+
+#[test]
+fn test_load_snapshot_skips_binary_snippet_sources() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    create_test_session(temp_dir.path())?;
+    let session_dir = temp_dir.path().join("sessions/test-session-123");
+
+    let text_path = temp_dir.path().join("lib.rs");
+    std::fs::write(&text_path, "fn lib() {}\n")?;
+    let binary_path = temp_dir.path().join("logo.png");
+    std::fs::write(&binary_path, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR")?;
+
+    let snippet = |path: &Path| {
+        serde_json::json!({
+            "uri": url::Url::from_file_path(path).unwrap().to_string(),
+            "start_line": 0,
+            "end_line": 1,
+            "language_id": "rust"
+        })
+    };
+    let snippets = serde_json::json!({
+        "schema": 1,
+        "snippets": [snippet(&text_path), snippet(&binary_path)]
+    });
+    std::fs::write(
+        session_dir.join("code_snippets.json"),
+        serde_json::to_string_pretty(&snippets)?,
+    )?;
+
+    let snapshot = load_snapshot(&session_dir)?;
+    assert_eq!(snapshot.code_contexts.len(), 2);
+    assert_eq!(snapshot.file_cache.len(), 1);
+    assert_eq!(
+        snapshot.file_cache.get(&snapshot.code_contexts[0].uri).map(String::as_str),
+        Some("fn lib() {}\n")
+    );
+    assert!(!snapshot.file_cache.contains_key(&snapshot.code_contexts[1].uri));
+    assert_eq!(snapshot.skipped_sources, vec![snapshot.code_contexts[1].uri.clone()]);
+
+    let warning = snapshot.skipped_sources_warning(None).expect("no warning for the binary source");
+    assert!(warning.contains("logo.png"));
+
+    Ok(())
+}
+
+#[test]
+fn test_load_snapshot_accepts_comments_and_trailing_commas() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let session_dir = temp_dir.path().join("sessions").join("annotated");
+    std::fs::create_dir_all(&session_dir)?;
+
+    std::fs::write(
+        session_dir.join("session.json"),
+        r#"{
+  // Hand-edited
+  "schema": 1,
+  "id": "annotated",
+  "name": "annotated",
+  "version": 3, // bumped by hand
+  "limits": { "max_tokens": 2000, },
+  "updated_at": "2025-11-03T00:00:00Z",
+}"#,
+    )?;
+    std::fs::write(
+        temp_dir.path().join("active.json"),
+        r#"{
+  /* switched manually */
+  "schema": 1,
+  "id": "annotated",
+  "path": "sessions/annotated",
+}"#,
+    )?;
+
+    let resolved = resolve_active_session(temp_dir.path())?;
+    assert_eq!(resolved, session_dir);
+
+    let snapshot = load_snapshot(&resolved)?;
+    assert_eq!(snapshot.session_id, "annotated");
+    assert_eq!(snapshot.version, 3);
+    assert_eq!(snapshot.limits.max_tokens, 2000);
+
+    Ok(())
+}
+
+#[test]
+fn test_record_requests_used_leaves_session_json_alone() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let session_dir = temp_dir.path().join("sessions").join("annotated");
+    std::fs::create_dir_all(&session_dir)?;
+    let session = r#"{
+  // Hand-edited
+  "schema": 1,
+  "id": "annotated",
+  "name": "annotated",
+  "version": 3,
+  "limits": { "max_tokens": 2000, },
+  "updated_at": "2025-11-03T00:00:00Z",
+  "requests_used": 2,
+}"#;
+    std::fs::write(session_dir.join("session.json"), session)?;
+
+    // Older sessions kept the count in session.json
+    assert_eq!(load_snapshot(&session_dir)?.requests_used, 2);
+
+    snek::session_io::record_requests_used(&session_dir, 5)?;
+    assert_eq!(std::fs::read_to_string(session_dir.join("session.json"))?, session);
+    assert_eq!(load_snapshot(&session_dir)?.requests_used, 5);
+
+    // A stale count written after a newer one is dropped
+    snek::session_io::record_requests_used(&session_dir, 4)?;
+    assert_eq!(load_snapshot(&session_dir)?.requests_used, 5);
+
+    Ok(())
+}
+
+#[test]
+fn test_prune_sessions_removes_oldest_inactive() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let snek_root = temp_dir.path();
+
+    let write_session = |id: &str, updated_at: &str| -> Result<()> {
+        let dir = snek_root.join("sessions").join(id);
+        std::fs::create_dir_all(&dir)?;
+        let session = serde_json::json!({
+            "schema": 1,
+            "id": id,
+            "name": id,
+            "version": 0,
+            "limits": { "max_tokens": 1600 },
+            "updated_at": updated_at
+        });
+        std::fs::write(dir.join("session.json"), session.to_string())?;
+        Ok(())
+    };
+
+    // The active session is the oldest, so it must be skipped
+    write_session("active", "2025-01-01T00:00:00Z")?;
+    write_session("old", "2025-02-01T00:00:00Z")?;
+    write_session("older", "2025-01-15T00:00:00Z")?;
+    write_session("recent", "2025-03-01T00:00:00Z")?;
+    write_session("newest", "2025-04-01T00:00:00Z")?;
+    std::fs::write(
+        snek_root.join("active.json"),
+        r#"{"schema": 1, "id": "active", "path": "sessions/active"}"#,
+    )?;
+
+    let removed = prune_sessions(snek_root, 3)?;
+
+    let sessions = snek_root.join("sessions");
+    assert_eq!(removed, vec![sessions.join("older"), sessions.join("old")]);
+    assert!(sessions.join("active").exists());
+    assert!(sessions.join("recent").exists());
+    assert!(sessions.join("newest").exists());
+
+    // Already within the limit
+    assert!(prune_sessions(snek_root, 3)?.is_empty());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_find_workspace_root_through_symlinks() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let project = temp_dir.path().join("real").join("project");
+    let nested = project.join("src").join("nested");
+    std::fs::create_dir_all(&nested)?;
+    std::fs::create_dir_all(project.join(".snek"))?;
+
+    // A link from outside the project and a cycle back into it
+    let outside = temp_dir.path().join("outside");
+    std::fs::create_dir_all(&outside)?;
+    std::os::unix::fs::symlink(&nested, outside.join("link"))?;
+    std::os::unix::fs::symlink(project.join("src"), nested.join("loop"))?;
+
+    let expected = project.join(".snek").canonicalize()?;
+
+    let found = find_workspace_root_from(&outside.join("link"))?;
+    assert_eq!(found.canonicalize()?, expected);
+
+    let found = find_workspace_root_from(&nested.join("loop").join("nested").join("loop"))?;
+    assert_eq!(found.canonicalize()?, expected);
+
+    // Nothing was created outside the project
+    assert!(!outside.join(".snek").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_snek_file_is_reported_not_skipped() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let project = temp_dir.path().join("project");
+    let nested = project.join("src");
+    std::fs::create_dir_all(&nested)?;
+    std::fs::write(project.join(".snek"), "not a directory")?;
+
+    for result in [
+        find_workspace_root(Some(project.clone())),
+        find_workspace_root_from(&nested),
+    ] {
+        let err = result.unwrap_err();
+        assert!(matches!(err, SnekError::SnekDirNotDirectory(_)));
+        let message = err.to_string();
+        assert!(message.contains(&project.join(".snek").display().to_string()));
+        assert!(message.contains("not a directory"));
+    }
+
+    // Nothing was created in its place
+    assert!(!nested.join(".snek").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_locate_workspace_root_writes_nothing() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let project = temp_dir.path().join("project");
+    std::fs::create_dir_all(&project)?;
+
+    assert_eq!(locate_workspace_root(Some(project.clone()))?, None);
+    assert_eq!(std::fs::read_dir(&project)?.count(), 0);
+
+    let snek_dir = find_workspace_root(Some(project.clone()))?;
+    assert_eq!(locate_workspace_root(Some(project))?, Some(snek_dir));
+
+    Ok(())
+}
+
+#[test]
+fn test_load_snapshot_reads_chat_messages() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    create_test_session(temp_dir.path())?;
+
+    let session_dir = temp_dir.path().join("sessions/test-session-123");
+    let snapshot = load_snapshot(&session_dir)?;
+    assert_eq!(snapshot.chat_messages.len(), 2);
+    assert_eq!(snapshot.chat_messages[0].role, "system");
+    assert_eq!(snapshot.chat_messages[1].role, "user");
+    assert_eq!(snapshot.chat_messages[1].content, "Hello");
+
+    // Optional
+    std::fs::remove_file(session_dir.join("chat.json"))?;
+    assert!(load_snapshot(&session_dir)?.chat_messages.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_context_snapshot_save_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    create_test_session(temp_dir.path())?;
+    let session_dir = temp_dir.path().join("sessions/test-session-123");
+    std::fs::create_dir_all(session_dir.join("context/notes"))?;
+    std::fs::write(session_dir.join("context/intent.md"), "# Known good")?;
+    std::fs::write(session_dir.join("context/notes/api.md"), "nested")?;
+
+    let saved = save_context_snapshot(temp_dir.path(), &session_dir, "known-good")?;
+    assert_eq!(saved, temp_dir.path().join("snapshots/known-good"));
+    let before = load_snapshot(&session_dir)?;
+
+    // Churn: edit a file, add one, add snippets and bump the version
+    std::fs::write(session_dir.join("context/intent.md"), "# Experiment")?;
+    std::fs::write(session_dir.join("context/scratch.md"), "throwaway")?;
+    std::fs::write(
+        session_dir.join("code_snippets.json"),
+        r#"{"schema": 1, "snippets": []}"#,
+    )?;
+    let session = std::fs::read_to_string(session_dir.join("session.json"))?;
+    std::fs::write(
+        session_dir.join("session.json"),
+        session.replace("\"version\": 42", "\"version\": 43"),
+    )?;
+    snek::session_io::record_requests_used(&session_dir, 7)?;
+
+    restore_context_snapshot(temp_dir.path(), &session_dir, "known-good")?;
+    let after = load_snapshot(&session_dir)?;
+    assert_eq!(after.version, 42);
+    assert_eq!(after.markdown_cache, before.markdown_cache);
+    assert_eq!(
+        std::fs::read_to_string(session_dir.join("context/notes/api.md"))?,
+        "nested"
+    );
+    assert!(!session_dir.join("context/scratch.md").exists());
+    assert!(!session_dir.join("code_snippets.json").exists());
+    // The request count isn't part of the context
+    assert_eq!(after.requests_used, 7);
+
+    // No staging directories are left behind
+    let leftovers: Vec<_> = std::fs::read_dir(&session_dir)?
+        .chain(std::fs::read_dir(temp_dir.path().join("snapshots"))?)
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with('.'))
+        .collect();
+    assert!(leftovers.is_empty());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_context_snapshot_skips_symlinks() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    create_test_session(temp_dir.path())?;
+    let session_dir = temp_dir.path().join("sessions/test-session-123");
+    std::fs::create_dir_all(session_dir.join("context"))?;
+    std::fs::write(session_dir.join("context/intent.md"), "# Intent")?;
+
+    let outside = TempDir::new()?;
+    std::fs::write(outside.path().join("secret.md"), "outside")?;
+    std::os::unix::fs::symlink(outside.path(), session_dir.join("context/linked"))?;
+    std::os::unix::fs::symlink(session_dir.join("context"), session_dir.join("context/loop"))?;
+
+    let saved = save_context_snapshot(temp_dir.path(), &session_dir, "links")?;
+    assert!(saved.join("context/intent.md").is_file());
+    assert!(!saved.join("context/linked").exists());
+    assert!(!saved.join("context/loop").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_context_snapshot_names_are_validated() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    create_test_session(temp_dir.path())?;
+    let session_dir = temp_dir.path().join("sessions/test-session-123");
+
+    for name in ["", "../escape", "a/b", ".hidden", "spaces here"] {
+        let result = save_context_snapshot(temp_dir.path(), &session_dir, name);
+        assert!(matches!(result, Err(SnekError::Config(_))), "{:?}", name);
+    }
+    assert!(!temp_dir.path().join("snapshots").exists());
+
+    let missing = restore_context_snapshot(temp_dir.path(), &session_dir, "never-saved");
+    assert!(matches!(missing, Err(SnekError::Config(_))));
+    assert_eq!(load_snapshot(&session_dir)?.version, 42);
+
+    Ok(())
+}
@@ -0,0 +1,49 @@
+//! Integration tests for siblings module
+
+use snek::siblings::{find_siblings, outline, DEFAULT_PATTERNS};
+use tempfile::TempDir;
+
+fn default_patterns() -> Vec<String> {
+    DEFAULT_PATTERNS.iter().map(|p| p.to_string()).collect()
+}
+
+#[test]
+fn test_finds_test_sibling() {
+    let temp_dir = TempDir::new().unwrap();
+    let file = temp_dir.path().join("parser.rs");
+    std::fs::write(&file, "fn parse() {}").unwrap();
+    std::fs::write(temp_dir.path().join("parser_test.rs"), "fn test_parse() {}").unwrap();
+    std::fs::write(temp_dir.path().join("lexer_test.rs"), "fn test_lex() {}").unwrap();
+
+    let siblings = find_siblings(&file, &default_patterns(), 2);
+    assert_eq!(siblings, vec![temp_dir.path().join("parser_test.rs")]);
+}
+
+#[test]
+fn test_finds_base_from_test_file() {
+    let temp_dir = TempDir::new().unwrap();
+    std::fs::write(temp_dir.path().join("app.js"), "export function app() {}").unwrap();
+    let file = temp_dir.path().join("app.test.js");
+    std::fs::write(&file, "test('app', () => {})").unwrap();
+
+    let siblings = find_siblings(&file, &default_patterns(), 2);
+    assert_eq!(siblings, vec![temp_dir.path().join("app.js")]);
+}
+
+#[test]
+fn test_sibling_count_is_capped() {
+    let temp_dir = TempDir::new().unwrap();
+    let file = temp_dir.path().join("util.py");
+    for name in ["util.py", "util_test.py", "test_util.py", "util_spec.py"] {
+        std::fs::write(temp_dir.path().join(name), "def f(): pass").unwrap();
+    }
+
+    assert_eq!(find_siblings(&file, &default_patterns(), 1).len(), 1);
+    assert_eq!(find_siblings(&file, &default_patterns(), 0).len(), 0);
+}
+
+#[test]
+fn test_outline_keeps_declarations_only() {
+    let source = "use std::fmt;\n\npub struct Point {\n    x: i32,\n}\n\nfn distance(a: &Point) -> f64 {\n    let d = 1.0;\n    d\n}\n";
+    assert_eq!(outline(source), "pub struct Point {\nfn distance(a: &Point) -> f64 {");
+}
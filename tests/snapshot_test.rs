@@ -0,0 +1,141 @@
+//! Integration tests for snapshot module
+
+use snek::snapshot::{
+    CacheKind, CodeContext, ContextFilter, ContextSnapshot, Limits, ProjectMeta, MIN_USEFUL_MAX_TOKENS,
+};
+
+#[test]
+fn test_code_context_preserves_unknown_fields() {
+    let json = serde_json::json!({
+        "uri": "file:///src/lib.rs",
+        "start_line": 3,
+        "end_line": 9,
+        "language_id": "rust",
+        "tags": ["core", "parser"],
+        "added_by": "newer-tool"
+    });
+
+    let context: CodeContext = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(context.start_line, 3);
+    assert_eq!(context.extra.get("added_by"), Some(&serde_json::json!("newer-tool")));
+
+    let round_tripped = serde_json::to_value(&context).unwrap();
+    assert_eq!(round_tripped, json);
+}
+
+#[test]
+fn test_limits_warning_for_tiny_max_tokens() {
+    let limits = Limits {
+        max_tokens: 16,
+        ..Limits::default()
+    };
+    let warning = limits.warning().expect("expected a warning");
+    assert!(warning.contains("max_tokens is 16"));
+
+    assert!(Limits::default().warning().is_none());
+    let limits = Limits {
+        max_tokens: MIN_USEFUL_MAX_TOKENS,
+        ..Limits::default()
+    };
+    assert!(limits.warning().is_none());
+}
+
+#[test]
+fn test_cache_limit_evicts_least_recently_cached() {
+    let mut snapshot = ContextSnapshot::default();
+    snapshot.set_max_cache_bytes(Some(10_000));
+
+    for i in 0..10 {
+        snapshot.cache_markdown(format!("notes-{}.md", i), "m".repeat(3_000));
+        snapshot.cache_file(format!("file:///src/{}.rs", i), "f".repeat(3_000));
+        assert!(snapshot.cache_bytes() <= 10_000);
+    }
+
+    // Only the three most recent entries fit
+    assert_eq!(snapshot.markdown_cache.len() + snapshot.file_cache.len(), 3);
+    assert!(snapshot.file_cache.contains_key("file:///src/9.rs"));
+    assert!(snapshot.markdown_cache.contains_key("notes-9.md"));
+    assert!(snapshot.file_cache.contains_key("file:///src/8.rs"));
+
+    // Re-caching refreshes an entry
+    snapshot.cache_file("file:///src/8.rs".to_string(), "f".repeat(3_000));
+    snapshot.cache_markdown("notes-new.md".to_string(), "m".repeat(3_000));
+    assert!(snapshot.file_cache.contains_key("file:///src/8.rs"));
+    assert!(!snapshot.markdown_cache.contains_key("notes-9.md"));
+
+    // Lowering the cap applies right away
+    snapshot.set_max_cache_bytes(Some(3_000));
+    assert_eq!(snapshot.cache_bytes(), 3_000);
+    assert!(snapshot.markdown_cache.contains_key("notes-new.md"));
+}
+
+#[test]
+fn test_cache_limit_keeps_entries_prompts_use() {
+    let mut snapshot = ContextSnapshot::default();
+    snapshot.set_max_cache_bytes(Some(9_000));
+
+    for name in ["hot.md", "cold.md", "new.md"] {
+        snapshot.cache_markdown(name.to_string(), "m".repeat(3_000));
+    }
+    // A prompt reads the oldest entry, so the next one in line goes instead
+    snapshot.mark_used(CacheKind::Markdown, "hot.md");
+    snapshot.cache_file("file:///src/lib.rs".to_string(), "f".repeat(3_000));
+
+    assert!(snapshot.markdown_cache.contains_key("hot.md"));
+    assert!(!snapshot.markdown_cache.contains_key("cold.md"));
+    assert!(snapshot.markdown_cache.contains_key("new.md"));
+
+    // Entries put in the map directly are evicted after the tracked ones
+    snapshot
+        .markdown_cache
+        .insert("untracked.md".to_string(), "u".repeat(3_000));
+    snapshot.set_max_cache_bytes(Some(3_000));
+    assert_eq!(snapshot.markdown_cache.len() + snapshot.file_cache.len(), 1);
+    assert!(snapshot.markdown_cache.contains_key("untracked.md"));
+}
+
+#[test]
+fn test_context_filter_matches_globs() {
+    let filter = ContextFilter {
+        include: vec!["api-*.md".to_string(), "notes.md".to_string()],
+        exclude: vec!["*-draft?.md".to_string()],
+    };
+    assert!(filter.allows("api-auth.md"));
+    assert!(filter.allows("api-.md"));
+    assert!(filter.allows("notes.md"));
+    assert!(!filter.allows("notes.md.bak"));
+    assert!(!filter.allows("design.md"));
+    assert!(!filter.allows("api-auth-draft1.md"));
+    assert!(filter.allows("api-auth-draft.md"));
+
+    let everything_but_drafts = ContextFilter {
+        include: vec![],
+        exclude: vec!["draft*".to_string()],
+    };
+    assert!(everything_but_drafts.allows("design.md"));
+    assert!(!everything_but_drafts.allows("draft.md"));
+}
+
+#[test]
+fn test_project_meta_hint() {
+    let meta = |language: &str, framework: &str, description: &str| ProjectMeta {
+        language: Some(language.to_string()),
+        framework: Some(framework.to_string()),
+        description: Some(description.to_string()),
+    };
+
+    assert_eq!(
+        meta("Python", "Django", "").hint().as_deref(),
+        Some("This is a Python project using Django.")
+    );
+    assert_eq!(
+        meta(" ", "Rails", "Billing API").hint().as_deref(),
+        Some("This project uses Rails. Billing API")
+    );
+    assert_eq!(meta("", "", "Billing API").hint().as_deref(), Some("Billing API"));
+    assert_eq!(meta("", " ", "").hint(), None);
+    assert_eq!(ProjectMeta::default().hint(), None);
+
+    let long = meta("Go", "", &"x".repeat(2000)).hint().unwrap();
+    assert!(long.len() < 600);
+}
@@ -0,0 +1,84 @@
+//! Integration tests for sse module
+
+use snek::sse::{SseParser, DONE};
+
+#[test]
+fn test_keep_alive_comments_between_events_are_skipped() {
+    let stream = concat!(
+        ": connected\n\n",
+        "data: {\"choices\":[{\"delta\":{\"content\":\"fn\"}}]}\n\n",
+        ": keep-alive\n",
+        "\n",
+        "event: ping\n",
+        "id: 7\n",
+        "retry: 1000\n\n",
+        ":\n",
+        "data: {\"choices\":[{\"delta\":{\"content\":\" main\"}}]}\r\n\r\n",
+        "data: [DONE]\n\n",
+    );
+
+    let mut parser = SseParser::new();
+    let events = parser.feed(stream.as_bytes());
+    assert_eq!(
+        events,
+        vec![
+            "{\"choices\":[{\"delta\":{\"content\":\"fn\"}}]}",
+            "{\"choices\":[{\"delta\":{\"content\":\" main\"}}]}",
+            DONE,
+        ]
+    );
+    for event in &events[..2] {
+        assert!(serde_json::from_str::<serde_json::Value>(event).is_ok());
+    }
+    assert_eq!(parser.finish(), None);
+}
+
+#[test]
+fn test_multi_line_data_and_split_chunks() {
+    let stream = "data: first\ndata:second\n: interleaved\ndata\n\ndata: caf\u{e9}\n\n".as_bytes();
+
+    let mut parser = SseParser::new();
+    let mut events = vec![];
+    // One byte at a time, splitting lines and the two-byte `é`
+    for byte in stream {
+        events.extend(parser.feed(std::slice::from_ref(byte)));
+    }
+    assert_eq!(events, vec!["first\nsecond\n", "caf\u{e9}"]);
+}
+
+#[test]
+fn test_finish_flushes_unterminated_event() {
+    let mut parser = SseParser::new();
+    assert!(parser.feed(b"data: partial\ndata: tail").is_empty());
+    assert_eq!(parser.finish().as_deref(), Some("partial\ntail"));
+    assert_eq!(parser.finish(), None);
+}
+
+#[test]
+fn test_crlf_stream_split_between_cr_and_lf() {
+    let stream = concat!(
+        ": keep-alive\r\n\r\n",
+        "data: {\"choices\":[{\"delta\":{\"content\":\"let\"}}]}\r\n\r\n",
+        ":\r\n",
+        "data: {\"choices\":[{\"delta\":\r\n",
+        "data: {\"content\":\" x\"}}]}\r\n\r\n",
+        "data: [DONE]\r\n\r\n",
+    );
+
+    let mut parser = SseParser::new();
+    let mut events = vec![];
+    for chunk in stream.as_bytes().split_inclusive(|&b| b == b'\r') {
+        events.extend(parser.feed(chunk));
+    }
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[2], DONE);
+
+    let tokens: Vec<String> = events[..2]
+        .iter()
+        .map(|event| {
+            let value: serde_json::Value = serde_json::from_str(event).unwrap();
+            value["choices"][0]["delta"]["content"].as_str().unwrap().to_string()
+        })
+        .collect();
+    assert_eq!(tokens, vec!["let", " x"]);
+}
@@ -0,0 +1,466 @@
+//! Integration tests for watcher module
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use arc_swap::ArcSwap;
+use futures::StreamExt;
+use snek::document_store::DocumentStore;
+use snek::lsp::backend::Backend;
+use snek::model::ModelClient;
+use snek::project_config::ProjectConfig;
+use snek::session_io::{find_workspace_root, load_snapshot, resolve_active_session};
+use snek::snapshot::ContextSnapshot;
+use snek::watcher::SessionWatcher;
+use tempfile::TempDir;
+use tokio::sync::{watch, RwLock};
+use tower::{Service, ServiceExt};
+use tower_lsp::jsonrpc::Request;
+use tower_lsp::LspService;
+
+/// Helper to poll the snapshot until `predicate` holds or the timeout expires
+async fn wait_for<F>(snapshot: &Arc<ArcSwap<ContextSnapshot>>, predicate: F) -> bool
+where
+    F: Fn(&ContextSnapshot) -> bool,
+{
+    for _ in 0..50 {
+        if predicate(&snapshot.load()) {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    false
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_markdown_change_is_delivered() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let snek_root = find_workspace_root(Some(temp_dir.path().to_path_buf()))?;
+    let session_dir = resolve_active_session(&snek_root)?;
+
+    let snapshot = Arc::new(ArcSwap::from_pointee(load_snapshot(&session_dir)?));
+    let _watcher = SessionWatcher::start(snek_root.clone(), snapshot.clone(), None)?;
+
+    std::fs::write(session_dir.join("context").join("notes.md"), "# Notes")?;
+
+    let loaded = wait_for(&snapshot, |s| {
+        s.markdown_cache.get("notes.md").map(String::as_str) == Some("# Notes")
+    })
+    .await;
+    assert!(loaded, "markdown change was not picked up by the watcher");
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_markdown_change_drops_summary() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let snek_root = find_workspace_root(Some(temp_dir.path().to_path_buf()))?;
+    let session_dir = resolve_active_session(&snek_root)?;
+
+    let snapshot = Arc::new(ArcSwap::from_pointee(load_snapshot(&session_dir)?));
+    let _watcher = SessionWatcher::start(snek_root.clone(), snapshot.clone(), None)?;
+
+    let before = snapshot.load();
+    before.markdown_summary.set(before.markdown_hash(), "old summary".to_string());
+
+    std::fs::write(session_dir.join("context").join("notes.md"), "# Notes")?;
+
+    let loaded = wait_for(&snapshot, |s| s.markdown_cache.contains_key("notes.md")).await;
+    assert!(loaded, "markdown change was not picked up by the watcher");
+
+    let after = snapshot.load();
+    assert!(after.markdown_summary.get(before.markdown_hash()).is_none());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_chat_messages_are_reloaded() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let snek_root = find_workspace_root(Some(temp_dir.path().to_path_buf()))?;
+    let session_dir = resolve_active_session(&snek_root)?;
+    std::fs::write(
+        session_dir.join("chat.json"),
+        r#"{ "schema": 1, "messages": [{ "role": "system", "content": "Use tabs" }] }"#,
+    )?;
+
+    let snapshot = Arc::new(ArcSwap::from_pointee(load_snapshot(&session_dir)?));
+    assert_eq!(snapshot.load().chat_messages.len(), 1);
+    let _watcher = SessionWatcher::start(snek_root.clone(), snapshot.clone(), None)?;
+
+    std::fs::write(
+        session_dir.join("chat.json"),
+        r#"{ "schema": 1, "messages": [
+            { "role": "system", "content": "Use spaces" },
+            { "role": "user", "content": "Prefer iterators" }
+        ] }"#,
+    )?;
+
+    let loaded = wait_for(&snapshot, |s| {
+        s.chat_messages.len() == 2 && s.chat_messages[0].content == "Use spaces"
+    })
+    .await;
+    assert!(loaded, "chat.json change was not picked up by the watcher");
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_watch_task_is_restarted_after_dying() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let snek_root = find_workspace_root(Some(temp_dir.path().to_path_buf()))?;
+    let session_dir = resolve_active_session(&snek_root)?;
+
+    let snapshot = Arc::new(ArcSwap::from_pointee(load_snapshot(&session_dir)?));
+    let watcher = SessionWatcher::start(snek_root.clone(), snapshot.clone(), None)?;
+    assert_eq!(watcher.restarts(), 0);
+
+    watcher.abort_watch_task();
+
+    let mut restarted = false;
+    for _ in 0..50 {
+        if watcher.restarts() == 1 {
+            restarted = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(restarted, "watch task was not restarted");
+
+    // The restarted task still delivers changes
+    std::fs::write(session_dir.join("context").join("notes.md"), "# After restart")?;
+    let loaded = wait_for(&snapshot, |s| {
+        s.markdown_cache.get("notes.md").map(String::as_str) == Some("# After restart")
+    })
+    .await;
+    assert!(loaded, "markdown change was not picked up after the restart");
+
+    Ok(())
+}
+
+/// Creates `sessions/<id>` and points active.json at it the way scripts
+/// do: write a temp file, then rename it over.
+fn switch_session_atomically(snek_root: &std::path::Path, id: &str) -> Result<()> {
+    let session_dir = snek_root.join("sessions").join(id);
+    std::fs::create_dir_all(session_dir.join("context"))?;
+    let session = serde_json::json!({
+        "schema": 1,
+        "id": id,
+        "name": id,
+        "version": 0,
+        "limits": { "max_tokens": 1600 },
+        "updated_at": "2025-11-03T00:00:00Z"
+    });
+    std::fs::write(session_dir.join("session.json"), session.to_string())?;
+
+    let active = serde_json::json!({ "schema": 1, "id": id, "path": format!("sessions/{}", id) });
+    let temp = snek_root.join("active.json.tmp");
+    std::fs::write(&temp, active.to_string())?;
+    std::fs::rename(&temp, snek_root.join("active.json"))?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_atomic_active_json_replace_switches_every_time() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let snek_root = find_workspace_root(Some(temp_dir.path().to_path_buf()))?;
+    let session_dir = resolve_active_session(&snek_root)?;
+
+    let snapshot = Arc::new(ArcSwap::from_pointee(load_snapshot(&session_dir)?));
+    let _watcher = SessionWatcher::start(snek_root.clone(), snapshot.clone(), None)?;
+
+    // The second replace is the one a watch on the old inode would miss
+    for id in ["second", "third"] {
+        switch_session_atomically(&snek_root, id)?;
+        let switched = wait_for(&snapshot, |s| s.session_id == id).await;
+        assert!(switched, "switch to {} was not picked up by the watcher", id);
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_project_config_change_is_reloaded() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let snek_root = find_workspace_root(Some(temp_dir.path().to_path_buf()))?;
+    let session_dir = resolve_active_session(&snek_root)?;
+    std::fs::write(snek_root.join("config.json"), r#"{"model": "first"}"#)?;
+
+    let snapshot = Arc::new(ArcSwap::from_pointee(load_snapshot(&session_dir)?));
+    let project_config = Arc::new(ArcSwap::from_pointee(ProjectConfig::load(&snek_root)?));
+    let _watcher = SessionWatcher::start_with_project_config(
+        snek_root.clone(),
+        snapshot.clone(),
+        None,
+        Some(project_config.clone()),
+        watch::channel(None).1,
+    )?;
+    assert_eq!(project_config.load().model(), Some("first"));
+
+    std::fs::write(snek_root.join("config.json"), r#"{"model": "second"}"#)?;
+
+    let mut reloaded = false;
+    for _ in 0..50 {
+        if project_config.load().model() == Some("second") {
+            reloaded = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(reloaded, "config.json change was not picked up by the watcher");
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_periodic_resync_catches_missed_change() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let snek_root = find_workspace_root(Some(temp_dir.path().to_path_buf()))?;
+    let session_dir = resolve_active_session(&snek_root)?;
+
+    let snapshot = Arc::new(ArcSwap::from_pointee(load_snapshot(&session_dir)?));
+    let (resync_tx, resync_rx) = watch::channel(None);
+    let _watcher = SessionWatcher::start_with_project_config(
+        snek_root.clone(),
+        snapshot.clone(),
+        None,
+        None,
+        resync_rx,
+    )?;
+
+    // session.json isn't watched, so this change is never delivered as an event
+    let session_path = session_dir.join("session.json");
+    let mut session: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&session_path)?)?;
+    session["limits"]["max_tokens"] = serde_json::json!(777);
+    std::fs::write(&session_path, serde_json::to_string_pretty(&session)?)?;
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_ne!(snapshot.load().limits.max_tokens, 777);
+
+    let unchanged = snapshot.load_full();
+    resync_tx.send(Some(Duration::from_secs(1)))?;
+    let resynced = wait_for(&snapshot, |s| s.limits.max_tokens == 777).await;
+    assert!(resynced, "periodic resync did not pick up the missed change");
+
+    // Once in sync, further resyncs leave the snapshot alone
+    let synced = snapshot.load_full();
+    assert!(!Arc::ptr_eq(&unchanged, &synced));
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert!(Arc::ptr_eq(&synced, &snapshot.load_full()));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_snippet_reload_is_delivered() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let snek_root = find_workspace_root(Some(temp_dir.path().to_path_buf()))?;
+    let session_dir = resolve_active_session(&snek_root)?;
+
+    let source = temp_dir.path().join("lib.rs");
+    std::fs::write(&source, "fn a() {}\nfn b() {}\n")?;
+
+    let snapshot = Arc::new(ArcSwap::from_pointee(load_snapshot(&session_dir)?));
+    let _watcher = SessionWatcher::start(snek_root.clone(), snapshot.clone(), None)?;
+
+    let snippets = serde_json::json!({
+        "schema": 1,
+        "snippets": [{
+            "uri": url::Url::from_file_path(&source).unwrap().to_string(),
+            "start_line": 0,
+            "end_line": 1,
+            "language_id": "rust"
+        }]
+    });
+    std::fs::write(
+        session_dir.join("code_snippets.json"),
+        serde_json::to_string_pretty(&snippets)?,
+    )?;
+
+    let reloaded = wait_for(&snapshot, |s| {
+        s.code_contexts.len() == 1 && s.file_cache.len() == 1
+    })
+    .await;
+    assert!(reloaded, "code_snippets.json reload was not picked up by the watcher");
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_context_changed_notification_after_reload() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let snek_root = find_workspace_root(Some(temp_dir.path().to_path_buf()))?;
+    let session_dir = resolve_active_session(&snek_root)?;
+
+    let snapshot = Arc::new(ArcSwap::from_pointee(load_snapshot(&session_dir)?));
+
+    let mut lsp_client = None;
+    let (mut service, mut socket) = LspService::new(|client| {
+        lsp_client = Some(client.clone());
+        Backend::new(
+            client,
+            snapshot.clone(),
+            Arc::new(DocumentStore::new()),
+            Arc::new(ModelClient::new(String::new(), "test-model".to_string())),
+            Arc::new(RwLock::new(String::new())),
+        )
+    });
+
+    // Notifications are only delivered once the server is initialized
+    let initialize = Request::build("initialize")
+        .params(serde_json::json!({ "capabilities": {} }))
+        .id(1)
+        .finish();
+    service.ready().await.unwrap().call(initialize).await.unwrap();
+
+    let _watcher = SessionWatcher::start(snek_root.clone(), snapshot.clone(), lsp_client)?;
+
+    let snippets = serde_json::json!({ "schema": 1, "snippets": [] });
+    std::fs::write(
+        session_dir.join("code_snippets.json"),
+        serde_json::to_string_pretty(&snippets)?,
+    )?;
+
+    let notification = tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(request) = socket.next().await {
+            if request.method() == "snek/contextChanged" {
+                return Some(request);
+            }
+        }
+        None
+    })
+    .await?
+    .expect("client socket closed without a notification");
+
+    let params = notification.params().unwrap();
+    assert_eq!(params["session_id"], snapshot.load().session_id.as_str());
+    assert_eq!(params["version"], 0);
+    assert_eq!(params["reloads"], 1);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_tiny_max_tokens_shows_warning() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let snek_root = find_workspace_root(Some(temp_dir.path().to_path_buf()))?;
+    let session_dir = resolve_active_session(&snek_root)?;
+
+    let snapshot = Arc::new(ArcSwap::from_pointee(load_snapshot(&session_dir)?));
+
+    let mut lsp_client = None;
+    let (mut service, mut socket) = LspService::new(|client| {
+        lsp_client = Some(client.clone());
+        Backend::new(
+            client,
+            snapshot.clone(),
+            Arc::new(DocumentStore::new()),
+            Arc::new(ModelClient::new(String::new(), "test-model".to_string())),
+            Arc::new(RwLock::new(String::new())),
+        )
+    });
+
+    let initialize = Request::build("initialize")
+        .params(serde_json::json!({ "capabilities": {} }))
+        .id(1)
+        .finish();
+    service.ready().await.unwrap().call(initialize).await.unwrap();
+
+    let _watcher = SessionWatcher::start(snek_root.clone(), snapshot.clone(), lsp_client)?;
+
+    let session_path = session_dir.join("session.json");
+    let mut session: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&session_path)?)?;
+    session["limits"]["max_tokens"] = 16.into();
+    std::fs::write(&session_path, serde_json::to_string_pretty(&session)?)?;
+
+    // session.json isn't watched on its own; a snippet change triggers the reload
+    let snippets = serde_json::json!({ "schema": 1, "snippets": [] });
+    std::fs::write(
+        session_dir.join("code_snippets.json"),
+        serde_json::to_string_pretty(&snippets)?,
+    )?;
+
+    let message = tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(request) = socket.next().await {
+            if request.method() == "window/showMessage" {
+                return Some(request);
+            }
+        }
+        None
+    })
+    .await?
+    .expect("client socket closed without a warning");
+
+    let params = message.params().unwrap();
+    assert_eq!(params["type"], 2);
+    assert!(params["message"].as_str().unwrap().contains("max_tokens is 16"));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_binary_snippet_source_shows_warning() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let snek_root = find_workspace_root(Some(temp_dir.path().to_path_buf()))?;
+    let session_dir = resolve_active_session(&snek_root)?;
+
+    let binary_path = temp_dir.path().join("logo.png");
+    std::fs::write(&binary_path, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR")?;
+
+    let snapshot = Arc::new(ArcSwap::from_pointee(load_snapshot(&session_dir)?));
+
+    let mut lsp_client = None;
+    let (mut service, mut socket) = LspService::new(|client| {
+        lsp_client = Some(client.clone());
+        Backend::new(
+            client,
+            snapshot.clone(),
+            Arc::new(DocumentStore::new()),
+            Arc::new(ModelClient::new(String::new(), "test-model".to_string())),
+            Arc::new(RwLock::new(String::new())),
+        )
+    });
+
+    let initialize = Request::build("initialize")
+        .params(serde_json::json!({ "capabilities": {} }))
+        .id(1)
+        .finish();
+    service.ready().await.unwrap().call(initialize).await.unwrap();
+
+    let _watcher = SessionWatcher::start(snek_root.clone(), snapshot.clone(), lsp_client)?;
+
+    let snippets = serde_json::json!({
+        "schema": 1,
+        "snippets": [{
+            "uri": url::Url::from_file_path(&binary_path).unwrap().to_string(),
+            "start_line": 0,
+            "end_line": 1,
+            "language_id": "rust"
+        }]
+    });
+    std::fs::write(
+        session_dir.join("code_snippets.json"),
+        serde_json::to_string_pretty(&snippets)?,
+    )?;
+
+    let message = tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(request) = socket.next().await {
+            if request.method() == "window/showMessage" {
+                return Some(request);
+            }
+        }
+        None
+    })
+    .await?
+    .expect("client socket closed without a warning");
+
+    let params = message.params().unwrap();
+    assert_eq!(params["type"], 2);
+    assert!(params["message"].as_str().unwrap().contains("logo.png"));
+
+    Ok(())
+}